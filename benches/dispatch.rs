@@ -0,0 +1,45 @@
+//! Compares walking the `Op` tree, the flat bytecode loop and tiered
+//! execution on a program dominated by small moves and adds.
+//!
+//! Run with `cargo bench --bench dispatch`.
+
+use std::io;
+use std::time::{Duration, Instant};
+use trainfuck::bytecode::Bytecode;
+use trainfuck::interpreter::{self, VM};
+use trainfuck::tiered;
+
+const ROUNDS: usize = 5;
+
+fn main() {
+    // Nested counting loops; every inner iteration is `>+>++<<-`
+    let source = "++++++++[>++++++++[>++++++++[>++++++++[>++++++++[>+>++<<-]<-]<-]<-]<-]";
+    let ops = interpreter::parse(source).expect("benchmark program parses");
+    let bytecode = Bytecode::compile(&ops);
+
+    let tree = best(|vm| vm.execute(&ops));
+    let flat = best(|vm| vm.execute_bytecode(&bytecode));
+    let tiered = best(|vm| vm.execute_tiered(&bytecode, tiered::DEFAULT_THRESHOLD));
+    println!(
+        "tree {:>10.2?}  bytecode {:>10.2?} ({:.1}x)  tiered {:>10.2?} ({:.1}x)",
+        tree,
+        flat,
+        tree.as_secs_f64() / flat.as_secs_f64(),
+        tiered,
+        tree.as_secs_f64() / tiered.as_secs_f64()
+    );
+}
+
+/// Best of `ROUNDS` runs on a fresh VM
+fn best(mut run: impl FnMut(&mut VM) -> interpreter::Result<()>) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let mut vm = VM::new();
+            vm.output = Box::new(io::sink());
+            let start = Instant::now();
+            run(&mut vm).expect("benchmark program runs");
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
@@ -0,0 +1,46 @@
+//! Compares plain loops against fused superinstructions on programs that
+//! spend their time in scan, clear and copy loops.
+//!
+//! Run with `cargo bench --bench superinstructions`.
+
+use std::io;
+use std::time::{Duration, Instant};
+use trainfuck::interpreter::{self, Op, VM};
+
+const ROUNDS: usize = 5;
+
+fn main() {
+    // 20000 non-zero cells, then 200 round trips scanning across them
+    let scan = format!("{}>{}[<<[<]>[>]>-]", "+>".repeat(20_000), "+".repeat(200));
+    // Fill and clear a 255-valued cell 10000 times
+    let clear = format!("{}[>{}[-]<-]", "+".repeat(100), "-[-]".repeat(100));
+    // Copy a cell to two neighbours and back, 255 * 200 times
+    let copy = format!("{}[>-[->+>+<<]>>[-<<+>>]<[-<+>]<<-]", "+".repeat(200));
+
+    for (name, source) in [("scan", &scan), ("clear", &clear), ("copy", &copy)] {
+        let ops = interpreter::parse(source).expect("benchmark program parses");
+        let plain = time(&ops);
+        let fused = time(&ops.optimize());
+        println!(
+            "{:<6} plain {:>10.2?}  fused {:>10.2?}  ({:.1}x)",
+            name,
+            plain,
+            fused,
+            plain.as_secs_f64() / fused.as_secs_f64()
+        );
+    }
+}
+
+/// Best of `ROUNDS` runs
+fn time(ops: &[Op]) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let mut vm = VM::new();
+            vm.output = Box::new(io::sink());
+            let start = Instant::now();
+            vm.execute(ops).expect("benchmark program runs");
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
@@ -0,0 +1,2 @@
+Takes one from zero and counts the result into the next cell to print it
+-[->+<]>.
@@ -0,0 +1,7 @@
+Loops that leave at other cells than they started on
+Three times three counted into the third cell and printed as a digit
++++[>+++[>+<-]<-]>>>++++++[<++++++++>-]<.
+Ones in three cells then a scan back to the zero before them
+[-]>>+>+>+[<]>[[-]>]<<<<+++++++++++++[<++++>-]<.
+Then a newline
+[-]++++++++++.
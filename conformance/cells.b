@@ -0,0 +1,2 @@
+Computes two hundred fifty six and prints one if it wrapped to zero
+++++++++[>++++++++<-]>[<++++>-]+<[>-<[-]]>.
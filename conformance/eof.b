@@ -0,0 +1,2 @@
+Reads on an empty input into a cell holding five and prints what is left
++++++,.
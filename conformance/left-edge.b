@@ -0,0 +1,2 @@
+Walks left from the first cell until it comes back round to it
+-<+[[-]<+]+.
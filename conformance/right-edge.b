@@ -0,0 +1,2 @@
+Walks right from the first cell until it comes back round to it
+->+[[-]>+]+.
@@ -0,0 +1,4 @@
+A loop on a zero cell is skipped whole
+[None of these run ,.<>+- and the [nested] loops in it are skipped too]
+Then ok and a newline
++++++++++++[>++++++++++>+<<-]>+.----.>-.
@@ -0,0 +1,2 @@
+A close with no open
++]
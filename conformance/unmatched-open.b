@@ -0,0 +1,2 @@
+An open with no close
+[+
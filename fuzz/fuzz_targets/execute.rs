@@ -0,0 +1,73 @@
+//! Run arbitrary short programs, optimized at an arbitrary level, on a
+//! small tape under a step limit, with canned input and the mock network:
+//! nothing panics, whatever the program does
+
+#![no_main]
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use std::io;
+use trainfuck::interpreter::{Extensions, VM};
+use trainfuck::mock_net::MockNetwork;
+use trainfuck::pipeline::Pipeline;
+use trainfuck::program::{Metadata, Program};
+
+/// Longest program worth running
+const MAX_SOURCE: usize = 4096;
+const MAX_STEPS: u64 = 100_000;
+
+#[derive(Arbitrary, Debug)]
+struct Run<'a> {
+    tape_size: u8,
+    level: u8,
+    /// Which of the extensions that need neither threads nor a real
+    /// network are on
+    extensions: u16,
+    source: &'a [u8],
+    input: &'a [u8],
+    /// What every connection reads
+    script: &'a [u8],
+}
+
+fuzz_target!(|run: Run| {
+    if run.source.len() > MAX_SOURCE {
+        return;
+    }
+    let on = |bit: u16| run.extensions & (1 << bit) != 0;
+    let extensions = Extensions {
+        yielding: on(0),
+        checksum: on(1),
+        base64: on(2),
+        xor: on(3),
+        peek: on(4),
+        line: on(5),
+        channels: on(6),
+        backpressure: on(7),
+        blocks: on(8),
+        trap: on(9),
+        features: on(10),
+        ..Extensions::default()
+    };
+    let Ok(program) = Program::parse(run.source, extensions) else {
+        return;
+    };
+    let tape_size = usize::from(run.tape_size.max(1));
+    let level = run.level % 4;
+    let vm = || {
+        let mut vm = VM::with_tape_size(tape_size);
+        vm.input = Box::new(io::Cursor::new(run.input.to_vec()));
+        vm.output = Box::new(io::sink());
+        vm.network = Box::new(MockNetwork::new(run.script.to_vec()));
+        vm.max_steps = Some(MAX_STEPS);
+        vm
+    };
+
+    let _ = vm().execute(program.ops());
+    let (ops, spans) =
+        Pipeline::level(level, tape_size).run(program.ops().to_vec(), program.spans());
+    let metadata = Metadata {
+        optimized: true,
+        ..program.metadata().clone()
+    };
+    let _ = vm().execute_program(&Program::new(ops, spans, metadata));
+});
@@ -0,0 +1,44 @@
+//! Parse arbitrary bytes with arbitrary extensions: parsing never panics,
+//! spans stay inside the source, and what parses prints back to source
+//! that parses again
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trainfuck::interpreter::{self, Extensions};
+use trainfuck::program::Program;
+
+fuzz_target!(|input: (u16, &[u8])| {
+    let (bits, source) = input;
+    let Ok(program) = Program::parse(source, extensions(bits)) else {
+        return;
+    };
+    for span in program.spans().spans() {
+        assert!(span.start <= span.end && span.end <= source.len());
+    }
+    let printed = interpreter::to_source(program.ops()).expect("parsed ops have source");
+    let extensions = program.metadata().extensions;
+    Program::parse(&printed, extensions).expect("printed source parses");
+});
+
+fn extensions(bits: u16) -> Extensions {
+    let on = |bit: u16| bits & (1 << bit) != 0;
+    Extensions {
+        shared: on(0),
+        actors: on(1),
+        yielding: on(2),
+        kv: on(3),
+        http: on(4),
+        checksum: on(5),
+        base64: on(6),
+        xor: on(7),
+        peek: on(8),
+        line: on(9),
+        dns: on(10),
+        channels: on(11),
+        backpressure: on(12),
+        blocks: on(13),
+        trap: on(14),
+        features: on(15),
+    }
+}
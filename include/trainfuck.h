@@ -0,0 +1,92 @@
+#ifndef TRAINFUCK_H
+#define TRAINFUCK_H
+
+/* Generated by cbindgen from src/capi.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * A parsed program together with the VM running it
+ */
+typedef struct TfVm TfVm;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Parse a NUL-terminated program and create a VM for it.
+ * Returns NULL on error.
+ *
+ * # Safety
+ * `source` must be a valid NUL-terminated string.
+ */
+struct TfVm *tf_vm_new(const char *source);
+
+/**
+ * Destroy a VM created by `tf_vm_new`, closing its sockets.
+ *
+ * # Safety
+ * `vm` must come from `tf_vm_new` and not be used afterwards.
+ */
+void tf_vm_free(struct TfVm *vm);
+
+/**
+ * Feed `len` bytes as the program's input instead of stdin.
+ * Returns 0 on success, -1 on error.
+ *
+ * # Safety
+ * `vm` must be a live handle and `data` must point to `len` readable bytes.
+ */
+int tf_vm_set_input(struct TfVm *vm, const uint8_t *data, size_t len);
+
+/**
+ * Run the program to completion (continuing after any `tf_vm_step` calls).
+ * Returns 0 on success, -1 on error.
+ *
+ * # Safety
+ * `vm` must be a live handle.
+ */
+int tf_vm_run(struct TfVm *vm);
+
+/**
+ * Execute up to `count` steps. Returns 1 when the program has finished,
+ * 0 if it is still running, -1 on error.
+ *
+ * # Safety
+ * `vm` must be a live handle.
+ */
+int tf_vm_step(struct TfVm *vm, uint64_t count);
+
+/**
+ * Pointer to the tape; its length is stored in `*len` if `len` is not NULL.
+ * Valid until the next call that runs the VM or frees it.
+ *
+ * # Safety
+ * `vm` must be a live handle and `len` NULL or writable.
+ */
+uint8_t *tf_vm_tape_ptr(struct TfVm *vm, size_t *len);
+
+/**
+ * Current data pointer position.
+ *
+ * # Safety
+ * `vm` must be a live handle.
+ */
+size_t tf_vm_pointer(const struct TfVm *vm);
+
+/**
+ * Message for the most recent error on this thread, or NULL if none.
+ * Valid until the next failing call on this thread.
+ */
+const char *tf_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* TRAINFUCK_H */
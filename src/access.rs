@@ -0,0 +1,87 @@
+//! Which peers `@` accepts (`--allow-from`, `--deny-from`)
+//!
+//! An [`AccessList`] holds address ranges in CIDR notation. `@` refuses a
+//! connection from a peer in a denied range, or outside every allowed range
+//! when there are any, and keeps waiting for the next one, so the program
+//! never sees it. Peers whose address the network doesn't know (MQTT, IRC)
+//! only get through when no range is allowed explicitly.
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// An address range such as `10.0.0.0/8` or `::1/128`; a bare address is
+/// a range of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("bad address in {:?}", s))?;
+        let max: u8 = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("bad prefix length in {:?} (at most {})", s, max))?,
+        };
+        // Peers are compared by their canonical address too
+        Ok(match addr.to_canonical() {
+            IpAddr::V4(v4) if addr.is_ipv6() => Cidr {
+                addr: IpAddr::V4(v4),
+                prefix: prefix.saturating_sub(96),
+            },
+            addr => Cidr { addr, prefix },
+        })
+    }
+}
+
+/// Ranges of peers to accept and to refuse; denying wins
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessList {
+    /// When not empty, only peers in one of these are accepted
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Whether a connection from `peer` may reach the program
+    pub fn admits(&self, peer: Option<SocketAddr>) -> bool {
+        let Some(ip) = peer.map(|peer| peer.ip()) else {
+            return self.allow.is_empty();
+        };
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
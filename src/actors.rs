@@ -0,0 +1,194 @@
+//! Child VMs and mailboxes (`--ext actors`)
+//!
+//! - `&` starts the program spelled by the zero-terminated cells after the
+//!   pointer in a new VM on its own thread, and writes the child's id
+//!   (1-255) to the current cell, or 0 if it couldn't be started
+//! - `^` posts the byte in the next cell to the mailbox of the VM whose id
+//!   is in the current cell; a child reaches its parent as id 0
+//! - `?` waits for a message, writing its byte to the current cell and the
+//!   sender's id to the next. It reads 0 from sender 0 once nobody is left
+//!   who could send one.
+//!
+//! Children get the parent's tape size, limits, extensions, shared
+//! segment and key-value store, no input, and write `.` output to stdout. They are stopped at
+//! their next instruction when the parent shuts down.
+
+use crate::bytecode::Bytecode;
+use crate::interpreter::{Parser, Result, TrainfuckError, VM};
+use crate::optimizer;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Most children a VM can start over its lifetime; ids are one cell wide
+pub const MAX_CHILDREN: usize = 255;
+
+/// How often a waiting `?` checks for interrupts and exited children
+const RECEIVE_POLL: Duration = Duration::from_millis(50);
+
+/// Sender id and byte
+type Message = (u8, u8);
+
+struct Child {
+    mailbox: Sender<Message>,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+/// A VM's place in the actor tree
+#[derive(Default)]
+pub(crate) struct Actors {
+    /// Where messages for this VM arrive, created when first needed
+    inbox: Option<(Sender<Message>, Receiver<Message>)>,
+    /// This VM's id at its parent and the parent's inbox, if a child
+    parent: Option<(u8, Sender<Message>)>,
+    children: Vec<Child>,
+}
+
+impl Drop for Actors {
+    /// Stop every child at its next instruction
+    fn drop(&mut self) {
+        for child in &self.children {
+            child.stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl VM {
+    /// `&`: start a child running the source after the pointer
+    pub(crate) fn spawn_actor(&mut self) -> Result<()> {
+        self.check_sandbox('&')?;
+        let len = self.tape.len();
+        let source: Vec<u8> = (1..len)
+            .map(|i| self.tape[(self.pointer + i) % len])
+            .take_while(|&c| c != 0)
+            .collect();
+
+        let id = match self.start_child(&source) {
+            Ok(id) => id,
+            Err(e) => {
+                eprintln!("[trainfuck] Could not start actor: {}", e);
+                0
+            }
+        };
+        self.tape[self.pointer] = id;
+        Ok(())
+    }
+
+    fn start_child(&mut self, source: &[u8]) -> std::result::Result<u8, String> {
+        if self.actors.children.len() >= MAX_CHILDREN {
+            return Err(format!("already started {} actors", MAX_CHILDREN));
+        }
+        let mut parser =
+            Parser::with_max_depth(self.max_loop_depth).with_extensions(self.extensions);
+        parser.feed(source).map_err(|e| e.to_string())?;
+        let ops = parser.finish().map_err(|e| e.to_string())?;
+        let program = Bytecode::compile(&optimizer::optimize(ops));
+        let id = (self.actors.children.len() + 1) as u8;
+
+        let parent = self
+            .actors
+            .inbox
+            .get_or_insert_with(mpsc::channel)
+            .0
+            .clone();
+        let inbox = mpsc::channel();
+        let mailbox = inbox.0.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // A VM's I/O streams can't cross threads, so the child is built on
+        // its own from the settings it inherits
+        let (tape_size, tape_model) = (self.tape.len(), self.tape_model);
+        let (max_steps, deadline) = (self.max_steps, self.deadline);
+        let (network_enabled, max_loop_depth) = (self.network_enabled, self.max_loop_depth);
+        let (extensions, shared, kv) = (self.extensions, self.shared.clone(), self.kv.clone());
+        let interrupt = stop.clone();
+        let thread = thread::Builder::new()
+            .name(format!("trainfuck-actor-{}", id))
+            .spawn(move || {
+                let mut vm = VM::with_tape_size(tape_size);
+                vm.tape_model = tape_model;
+                vm.input = Box::new(io::empty());
+                vm.interrupt = Some(interrupt);
+                vm.max_steps = max_steps;
+                vm.deadline = deadline;
+                vm.network_enabled = network_enabled;
+                vm.max_loop_depth = max_loop_depth;
+                vm.extensions = extensions;
+                vm.shared = shared;
+                vm.kv = kv;
+                vm.actors.inbox = Some(inbox);
+                vm.actors.parent = Some((id, parent));
+                let result = vm.execute_bytecode(&program);
+                let _ = vm.shutdown();
+                match result {
+                    Ok(()) | Err(TrainfuckError::Interrupted) => {}
+                    Err(e) => eprintln!("[trainfuck] Actor {} failed: {}", id, e),
+                }
+            })
+            .map_err(|e| e.to_string())?;
+
+        self.actors.children.push(Child {
+            mailbox,
+            stop,
+            thread,
+        });
+        Ok(id)
+    }
+
+    /// `^`: post the next cell's byte to the VM named by the current cell
+    pub(crate) fn send_message(&mut self) -> Result<()> {
+        self.check_sandbox('^')?;
+        let to = self.tape[self.pointer];
+        let byte = self.tape[(self.pointer + 1) % self.tape.len()];
+        let (mailbox, from) = match to {
+            0 => match &self.actors.parent {
+                Some((id, parent)) => (parent, *id),
+                None => return Ok(()),
+            },
+            id => match self.actors.children.get(usize::from(id) - 1) {
+                Some(child) => (&child.mailbox, 0),
+                None => return Ok(()),
+            },
+        };
+        // Messages to a VM that has exited are dropped, like those to ids
+        // that were never started
+        let _ = mailbox.send((from, byte));
+        Ok(())
+    }
+
+    /// `?`: wait for the next message
+    pub(crate) fn receive_message(&mut self) -> Result<()> {
+        self.check_sandbox('?')?;
+        let (from, byte) = loop {
+            if let Some(message) = self.poll_mailbox() {
+                break message;
+            }
+            if let Some(ref flag) = self.interrupt {
+                if flag.load(Ordering::Relaxed) {
+                    return Err(TrainfuckError::Interrupted);
+                }
+            }
+        };
+        let len = self.tape.len();
+        self.tape[self.pointer] = byte;
+        self.tape[(self.pointer + 1) % len] = from;
+        Ok(())
+    }
+
+    /// Wait up to [`RECEIVE_POLL`] for a message; `(0, 0)` if none can come
+    fn poll_mailbox(&mut self) -> Option<Message> {
+        // A child's parent can always send: when it goes, so does the child
+        let orphaned = self.actors.parent.is_none()
+            && self.actors.children.iter().all(|c| c.thread.is_finished());
+        let (_, inbox) = self.actors.inbox.get_or_insert_with(mpsc::channel);
+        if orphaned {
+            return Some(inbox.try_recv().unwrap_or((0, 0)));
+        }
+        // Never disconnected: this VM holds a sender itself
+        inbox.recv_timeout(RECEIVE_POLL).ok()
+    }
+}
@@ -0,0 +1,40 @@
+//! `--addr-map peers.toml`: name addresses by number instead of encoding
+//! them on the tape
+//!
+//! ```toml
+//! 0 = "0.0.0.0:7000"          # `$` on a cell holding 0 listens here
+//! 1 = "chat.example.org:7000" # `%` on a cell holding 1 dials this
+//! 2 = "[::]:7000"
+//! ```
+//!
+//! Keys are cell values from 0 to 255. Host names are resolved once, when
+//! the file is loaded, preferring an IPv4 address since `%` only dials
+//! those. See [`VM::addr_map`](trainfuck::interpreter::VM::addr_map).
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+
+/// Read and resolve the address map at `path`
+pub fn load(path: &Path) -> Result<BTreeMap<u8, SocketAddr>, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let entries: BTreeMap<String, String> = toml::from_str(&text).map_err(|e| e.to_string())?;
+    let mut map = BTreeMap::new();
+    for (key, target) in entries {
+        let index = key
+            .parse::<u8>()
+            .map_err(|_| format!("{:?} is not a cell value from 0 to 255", key))?;
+        let addrs: Vec<SocketAddr> = target
+            .to_socket_addrs()
+            .map_err(|e| format!("cannot resolve {:?}: {}", target, e))?
+            .collect();
+        let addr = addrs
+            .iter()
+            .find(|addr| addr.is_ipv4())
+            .or(addrs.first())
+            .ok_or_else(|| format!("{:?} has no address", target))?;
+        map.insert(index, *addr);
+    }
+    Ok(map)
+}
@@ -0,0 +1,438 @@
+//! Static analysis (`trainfuck analyze`)
+//!
+//! Abstract interpretation over the parsed op tree: the pointer is tracked
+//! as an exact offset while it can be, cells as known values, and the
+//! connection as open, closed or unknown. Each loop body is analyzed once
+//! from a state where everything the body might change is already unknown,
+//! so findings hold for every iteration.
+//!
+//! Reported as likely bugs:
+//! - the pointer provably leaving the tape (where it would wrap around)
+//! - loops that can't terminate because the body never changes their cell
+//! - `'`/`` ` `` while no connection can be open
+//! - `.`/`'` of a cell nothing has written
+
+use crate::features::FEATURE_CELLS;
+use crate::interpreter::Op;
+use crate::span::{SourceMap, Span};
+use std::collections::{HashMap, HashSet};
+use std::iter;
+
+/// A likely bug and where it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub span: Span,
+    pub message: String,
+}
+
+/// Analyze `ops` (unoptimized, as parsed) for a tape of `tape_size` cells
+pub fn analyze(ops: &[Op], spans: &SourceMap, tape_size: usize) -> Vec<Finding> {
+    run(ops, spans, tape_size).findings
+}
+
+/// Spans of the loops in `ops` that are entered on a non-zero cell their
+/// body never changes, and so never terminate
+pub fn endless_loops(ops: &[Op], spans: &SourceMap, tape_size: usize) -> Vec<Span> {
+    run(ops, spans, tape_size).endless
+}
+
+fn run<'a>(
+    ops: &[Op],
+    spans: &'a SourceMap,
+    tape_size: usize,
+) -> Analyzer<impl Iterator<Item = Span> + 'a> {
+    let mut analyzer = Analyzer {
+        tape_size: tape_size as isize,
+        spans: spans
+            .spans()
+            .iter()
+            .copied()
+            .chain(iter::repeat(Span::default())),
+        findings: Vec::new(),
+        endless: Vec::new(),
+    };
+    let mut state = State {
+        pointer: Some(0),
+        cells: HashMap::new(),
+        untracked: Cell {
+            value: Some(0),
+            written: false,
+        },
+        connection: Net::No,
+    };
+    analyzer.block(ops, &mut state);
+    analyzer
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Net {
+    No,
+    Maybe,
+    Yes,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cell {
+    /// Known value, if any
+    value: Option<u8>,
+    /// Whether anything may have written the cell
+    written: bool,
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    /// Offset from cell 0, if known
+    pointer: Option<isize>,
+    cells: HashMap<isize, Cell>,
+    /// What every cell not in `cells` holds
+    untracked: Cell,
+    connection: Net,
+}
+
+impl State {
+    fn cell(&self, offset: isize) -> Cell {
+        self.cells.get(&offset).copied().unwrap_or(self.untracked)
+    }
+
+    fn current(&self) -> Option<Cell> {
+        self.pointer.map(|p| self.cell(p))
+    }
+
+    /// Record a write of `value` (None: unknown) to the current cell
+    fn write(&mut self, value: Option<u8>) {
+        match self.pointer {
+            Some(p) => {
+                self.cells.insert(
+                    p,
+                    Cell {
+                        value,
+                        written: true,
+                    },
+                );
+            }
+            None => self.forget_all(),
+        }
+    }
+
+    /// Any cell may now hold anything
+    fn forget_all(&mut self) {
+        self.cells.clear();
+        self.untracked = Cell {
+            value: None,
+            written: true,
+        };
+    }
+}
+
+/// What a loop body may do, relative to the pointer at loop entry
+#[derive(Debug, Default)]
+struct Effects {
+    /// Net pointer movement, if the same on every path
+    shift: Option<isize>,
+    /// Offsets possibly written; meaningless unless `shift` is known
+    writes: HashSet<isize>,
+    /// Whether it contains ops that open or close connections
+    network: bool,
+}
+
+fn effects(ops: &[Op]) -> Effects {
+    let mut result = Effects {
+        shift: Some(0),
+        ..Effects::default()
+    };
+    let mut offset = 0isize;
+    for op in ops {
+        match op {
+            Op::MoveRight(n) => offset = offset.saturating_add_unsigned(*n),
+            Op::MoveLeft(n) => offset = offset.saturating_sub_unsigned(*n),
+            Op::Increment(_) | Op::Decrement(_) | Op::Input | Op::Receive | Op::Peek => {
+                result.writes.insert(offset);
+            }
+            Op::CompareSwap => {
+                result.writes.insert(offset + 1);
+            }
+            Op::Spawn => {
+                result.writes.insert(offset);
+            }
+            Op::ReceiveMessage => result.writes.extend([offset, offset + 1]),
+            Op::Checksum => result.writes.extend(offset..offset + 4),
+            Op::Features(_) => result
+                .writes
+                .extend(offset..offset + FEATURE_CELLS as isize),
+            // Write as many cells as a value, response or message is long, which
+            // can't be described as a set of offsets
+            Op::KvGet
+            | Op::Http
+            | Op::Base64Encode
+            | Op::Base64Decode
+            | Op::Xor
+            | Op::Trap
+            | Op::ReceiveLine
+            | Op::ReceiveBlock
+            | Op::Resolve => result.shift = None,
+            Op::SelectChannel => {
+                result.writes.insert(offset);
+            }
+            Op::Channels => result.writes.extend([offset, offset + 1]),
+            Op::Connect | Op::Accept => result.network = true,
+            Op::Loop(body) => {
+                let inner = effects(body);
+                result.network |= inner.network;
+                match inner.shift {
+                    Some(0) => result
+                        .writes
+                        .extend(inner.writes.iter().map(|w| w + offset)),
+                    // Where the pointer ends up depends on the data
+                    _ => result.shift = None,
+                }
+            }
+            _ => {}
+        }
+    }
+    if result.shift.is_some() {
+        result.shift = Some(offset);
+    }
+    result
+}
+
+struct Analyzer<I> {
+    tape_size: isize,
+    spans: I,
+    findings: Vec<Finding>,
+    endless: Vec<Span>,
+}
+
+impl<I: Iterator<Item = Span>> Analyzer<I> {
+    fn report(&mut self, span: Span, message: String) {
+        self.findings.push(Finding { span, message });
+    }
+
+    fn block(&mut self, ops: &[Op], state: &mut State) {
+        for op in ops {
+            let span = self.spans.next().unwrap_or_default();
+            self.op(op, span, state);
+        }
+    }
+
+    /// Consume the spans of ops that are never analyzed
+    fn skip(&mut self, ops: &[Op]) {
+        for op in ops {
+            self.spans.next();
+            if let Op::Loop(body) = op {
+                self.skip(body);
+            }
+        }
+    }
+
+    fn op(&mut self, op: &Op, span: Span, state: &mut State) {
+        match op {
+            Op::MoveRight(n) => self.step(span, state, isize::try_from(*n).ok()),
+            Op::MoveLeft(n) => self.step(span, state, isize::try_from(*n).ok().map(|n| -n)),
+            Op::Increment(n) => {
+                let value = state.current().and_then(|c| c.value);
+                state.write(value.map(|v| v.wrapping_add(*n)));
+            }
+            Op::Decrement(n) => {
+                let value = state.current().and_then(|c| c.value);
+                state.write(value.map(|v| v.wrapping_sub(*n)));
+            }
+            Op::Output => self.check_read(span, state, "`.` outputs"),
+            Op::Input => state.write(None),
+            Op::Send => {
+                self.check_connection(span, state, "`'` sends");
+                self.check_read(span, state, "`'` sends");
+            }
+            Op::Flush => self.check_connection(span, state, "`_` sends"),
+            Op::SendBlock => {
+                self.check_connection(span, state, "`'` sends");
+                self.check_read(span, state, "`'` sends");
+            }
+            Op::ReceiveBlock => {
+                self.check_connection(span, state, "`` ` `` receives");
+                state.forget_all();
+            }
+            Op::Receive => {
+                self.check_connection(span, state, "`` ` `` receives");
+                state.write(None);
+            }
+            Op::Peek => {
+                self.check_connection(span, state, "`=` peeks");
+                state.write(None);
+            }
+            Op::ReceiveLine => {
+                self.check_connection(span, state, "`\"` receives");
+                state.forget_all();
+            }
+            Op::Connect => state.connection = Net::Yes,
+            Op::Accept => {
+                // `@` closes an open connection, otherwise accepts one
+                state.connection = match state.connection {
+                    Net::Yes => Net::No,
+                    _ => Net::Maybe,
+                }
+            }
+            Op::Listen => {}
+            // Whether the channel switched to has a connection isn't known
+            Op::SelectChannel => {
+                state.write(None);
+                state.connection = Net::Maybe;
+            }
+            Op::Channels => {
+                state.write(None);
+                self.write_ahead(state, 1);
+            }
+            // Overwrites the expected value with what the shared cell held
+            Op::CompareSwap => self.write_ahead(state, 1),
+            Op::Spawn => state.write(None),
+            Op::SendMessage | Op::Yield | Op::KvPut | Op::Unread => {}
+            Op::KvGet
+            | Op::Http
+            | Op::Base64Encode
+            | Op::Base64Decode
+            | Op::Xor
+            | Op::Trap
+            | Op::Resolve => state.forget_all(),
+            Op::ReceiveMessage => {
+                state.write(None);
+                self.write_ahead(state, 1);
+            }
+            Op::Checksum => {
+                state.write(None);
+                for offset in 1..4 {
+                    self.write_ahead(state, offset);
+                }
+            }
+            Op::Features(_) => {
+                state.write(None);
+                for offset in 1..FEATURE_CELLS as isize {
+                    self.write_ahead(state, offset);
+                }
+            }
+            Op::Loop(body) => self.enter_loop(body, span, state),
+            // Only produced by the optimizer
+            Op::Clear
+            | Op::ScanRight(_)
+            | Op::ScanLeft(_)
+            | Op::MulAdd(_)
+            | Op::Print(_)
+            | Op::Load(..) => state.forget_all(),
+        }
+    }
+
+    fn step(&mut self, span: Span, state: &mut State, by: Option<isize>) {
+        let (Some(pointer), Some(by)) = (state.pointer, by) else {
+            state.pointer = None;
+            return;
+        };
+        let target = pointer.saturating_add(by);
+        if target < 0 || target >= self.tape_size {
+            let side = if target < 0 {
+                "left of cell 0"
+            } else {
+                "past the last cell"
+            };
+            self.report(
+                span,
+                format!(
+                    "pointer moves to cell {}, {} of a {}-cell tape, and wraps around",
+                    target, side, self.tape_size
+                ),
+            );
+        }
+        state.pointer = Some(target.rem_euclid(self.tape_size));
+    }
+
+    /// Record an unknown value written `offset` cells after the pointer
+    fn write_ahead(&self, state: &mut State, offset: isize) {
+        match state.pointer {
+            Some(p) => {
+                let cell = (p + offset).rem_euclid(self.tape_size);
+                state.cells.insert(
+                    cell,
+                    Cell {
+                        value: None,
+                        written: true,
+                    },
+                );
+            }
+            None => state.forget_all(),
+        }
+    }
+
+    fn check_connection(&mut self, span: Span, state: &State, what: &str) {
+        if state.connection == Net::No {
+            self.report(span, format!("{} while no connection is open", what));
+        }
+    }
+
+    fn check_read(&mut self, span: Span, state: &State, what: &str) {
+        if let (Some(pointer), Some(cell)) = (state.pointer, state.current()) {
+            if !cell.written {
+                self.report(
+                    span,
+                    format!("{} cell {}, which is never written", what, pointer),
+                );
+            }
+        }
+    }
+
+    fn enter_loop(&mut self, body: &[Op], span: Span, state: &mut State) {
+        let entry = state.current();
+        if entry.and_then(|c| c.value) == Some(0) {
+            // Never entered (e.g. a leading comment loop)
+            self.skip(body);
+            return;
+        }
+
+        let effects = effects(body);
+        if effects.shift == Some(0) && !effects.writes.contains(&0) {
+            let message = match entry.and_then(|c| c.value) {
+                Some(_) => {
+                    self.endless.push(span);
+                    "loop never terminates: its cell is non-zero and the body never changes it"
+                }
+                None => "loop never terminates once entered: the body never changes its cell",
+            };
+            self.report(span, message.into());
+        }
+
+        // Start the body from a state covering every iteration
+        match (effects.shift, state.pointer) {
+            (Some(0), Some(pointer)) => {
+                for offset in &effects.writes {
+                    let cell = (pointer + offset).rem_euclid(self.tape_size);
+                    state.cells.insert(
+                        cell,
+                        Cell {
+                            value: None,
+                            written: true,
+                        },
+                    );
+                }
+            }
+            (Some(0), None) => {}
+            _ => {
+                state.pointer = None;
+                state.forget_all();
+            }
+        }
+        if effects.network {
+            state.connection = Net::Maybe;
+        }
+
+        let mut inside = state.clone();
+        self.block(body, &mut inside);
+
+        // The loop exits on a zero cell
+        if let Some(p) = state.pointer {
+            let written = state.cell(p).written;
+            state.cells.insert(
+                p,
+                Cell {
+                    value: Some(0),
+                    written,
+                },
+            );
+        }
+    }
+}
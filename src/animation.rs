@@ -0,0 +1,296 @@
+//! Animated tape export (`--render-animation out.gif`)
+//!
+//! While the program runs, the start of the tape is captured every
+//! `--frame-interval` steps, and once it stops the frames are written as a
+//! looping GIF or an animated SVG (chosen by the file's extension). Cells are
+//! squares, 32 to a row, colored from dark blue (0) to yellow (255); the
+//! cell under the pointer is outlined in red.
+//!
+//! A run that would produce more than [`MAX_FRAMES`] frames drops every
+//! other one and captures half as often from then on, so long programs
+//! still fit, just in coarser steps.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use trainfuck::interpreter::VM;
+
+/// Most cells shown, counted from the start of the tape
+const MAX_CELLS: usize = 1024;
+/// Most frames kept
+const MAX_FRAMES: usize = 1000;
+const COLUMNS: usize = 32;
+/// Side of a cell in pixels, including a one-pixel gap
+const CELL_SIZE: usize = 12;
+/// How long each frame is shown, in hundredths of a second
+const FRAME_DELAY: u16 = 10;
+
+/// Palette entries after the 128 cell colors
+const POINTER: u8 = 128;
+const BACKGROUND: u8 = 129;
+const POINTER_RGB: [u8; 3] = [0xe5, 0x39, 0x35];
+const BACKGROUND_RGB: [u8; 3] = [0x10, 0x10, 0x14];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Gif,
+    Svg,
+}
+
+struct Frame {
+    /// Cells up to the last non-zero one or the pointer
+    cells: Vec<u8>,
+    pointer: usize,
+}
+
+/// Frames captured so far, and where they go
+pub struct Animation {
+    path: PathBuf,
+    format: Format,
+    frames: Vec<Frame>,
+    /// Captures skipped between frames, doubled each time frames run over
+    stride: u64,
+    calls: u64,
+}
+
+impl Animation {
+    /// An animation to be written to `path`, which must end in `.gif` or
+    /// `.svg`
+    pub fn new(path: &Path) -> Result<Self, String> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        let format = match extension.as_deref() {
+            Some("gif") => Format::Gif,
+            Some("svg") => Format::Svg,
+            _ => return Err(format!("{:?} should end in .gif or .svg", path)),
+        };
+        Ok(Animation {
+            path: path.to_path_buf(),
+            format,
+            frames: Vec::new(),
+            stride: 1,
+            calls: 0,
+        })
+    }
+
+    /// Called every `--frame-interval` steps
+    pub fn capture(&mut self, vm: &VM) {
+        self.calls += 1;
+        if !(self.calls - 1).is_multiple_of(self.stride) {
+            return;
+        }
+        self.push(vm);
+        if self.frames.len() == MAX_FRAMES {
+            let mut index = 0;
+            self.frames.retain(|_| {
+                index += 1;
+                index % 2 == 1
+            });
+            self.stride *= 2;
+        }
+    }
+
+    /// Capture the final state, then write the file; returns the number of
+    /// frames
+    pub fn finish(&mut self, vm: &VM) -> io::Result<usize> {
+        self.push(vm);
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        match self.format {
+            Format::Gif => self.write_gif(&mut out)?,
+            Format::Svg => out.write_all(self.svg().as_bytes())?,
+        }
+        out.flush()?;
+        Ok(self.frames.len())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn push(&mut self, vm: &VM) {
+        let tape = vm.tape();
+        let used = tape.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1);
+        let end = used.max(vm.pointer() + 1).min(MAX_CELLS).min(tape.len());
+        self.frames.push(Frame {
+            cells: tape[..end].to_vec(),
+            pointer: vm.pointer(),
+        });
+    }
+
+    /// Cells shown in every frame: as many as the widest one needs
+    fn cells(&self) -> usize {
+        self.frames.iter().map(|f| f.cells.len()).max().unwrap_or(1)
+    }
+
+    /// Width and height in pixels
+    fn size(&self) -> (usize, usize) {
+        let cells = self.cells();
+        (
+            cells.min(COLUMNS) * CELL_SIZE + 1,
+            cells.div_ceil(COLUMNS) * CELL_SIZE + 1,
+        )
+    }
+
+    fn write_gif(&self, out: &mut impl Write) -> io::Result<()> {
+        let (width, height) = self.size();
+        let cells = self.cells();
+        let mut palette = Vec::with_capacity(130 * 3);
+        for level in 0..128 {
+            let value = match level {
+                0 => 0,
+                _ => 1 + (level - 1) * 254 / 126,
+            };
+            palette.extend_from_slice(&heat(value as u8));
+        }
+        palette.extend_from_slice(&POINTER_RGB);
+        palette.extend_from_slice(&BACKGROUND_RGB);
+
+        let mut encoder = gif::Encoder::new(out, width as u16, height as u16, &palette)
+            .map_err(io::Error::other)?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(io::Error::other)?;
+        let mut pixels = vec![BACKGROUND; width * height];
+        for frame in &self.frames {
+            pixels.fill(BACKGROUND);
+            for index in 0..cells {
+                let level = palette_level(frame.cells.get(index).copied().unwrap_or(0));
+                let (left, top) = cell_origin(index);
+                for y in 0..CELL_SIZE - 1 {
+                    for x in 0..CELL_SIZE - 1 {
+                        let edge = x < 2 || y < 2 || x >= CELL_SIZE - 3 || y >= CELL_SIZE - 3;
+                        pixels[(top + y) * width + left + x] = if index == frame.pointer && edge {
+                            POINTER
+                        } else {
+                            level
+                        };
+                    }
+                }
+            }
+            let mut gif_frame = gif::Frame {
+                width: width as u16,
+                height: height as u16,
+                delay: FRAME_DELAY,
+                ..gif::Frame::default()
+            };
+            gif_frame.buffer = std::borrow::Cow::Borrowed(&pixels);
+            encoder.write_frame(&gif_frame).map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    /// One rect per cell whose fill steps through its values, and a pointer
+    /// outline that steps between cells
+    fn svg(&self) -> String {
+        let (width, height) = self.size();
+        let cells = self.cells();
+        let duration = self.frames.len() as f64 * f64::from(FRAME_DELAY) / 100.0;
+        let animate = |attribute: &str, values: &[String]| {
+            if values.iter().all(|v| *v == values[0]) {
+                String::new()
+            } else {
+                format!(
+                    "<animate attributeName=\"{}\" values=\"{}\" dur=\"{}s\" \
+                     calcMode=\"discrete\" repeatCount=\"indefinite\"/>",
+                    attribute,
+                    values.join(";"),
+                    duration
+                )
+            }
+        };
+
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" \
+             viewBox=\"0 0 {0} {1}\">",
+            width, height
+        );
+        let _ = writeln!(
+            svg,
+            "<rect width=\"100%\" height=\"100%\" fill=\"{}\"/>",
+            hex(BACKGROUND_RGB)
+        );
+        for index in 0..cells {
+            let fills: Vec<String> = self
+                .frames
+                .iter()
+                .map(|frame| hex(heat(frame.cells.get(index).copied().unwrap_or(0))))
+                .collect();
+            let (x, y) = cell_origin(index);
+            let _ = writeln!(
+                svg,
+                "<rect x=\"{}\" y=\"{}\" width=\"{2}\" height=\"{2}\" fill=\"{3}\">{4}</rect>",
+                x,
+                y,
+                CELL_SIZE - 1,
+                fills[0],
+                animate("fill", &fills)
+            );
+        }
+
+        // A pointer past the cells shown is parked off the image
+        let (xs, ys): (Vec<String>, Vec<String>) = self
+            .frames
+            .iter()
+            .map(|frame| {
+                let (x, y) = if frame.pointer < cells {
+                    cell_origin(frame.pointer)
+                } else {
+                    (width + CELL_SIZE, 0)
+                };
+                ((x + 1).to_string(), (y + 1).to_string())
+            })
+            .unzip();
+        let _ = writeln!(
+            svg,
+            "<rect x=\"{}\" y=\"{}\" width=\"{2}\" height=\"{2}\" fill=\"none\" \
+             stroke=\"{3}\" stroke-width=\"2\">{4}{5}</rect>",
+            xs[0],
+            ys[0],
+            CELL_SIZE - 3,
+            hex(POINTER_RGB),
+            animate("x", &xs),
+            animate("y", &ys)
+        );
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+/// Top-left pixel of a cell
+fn cell_origin(index: usize) -> (usize, usize) {
+    (
+        (index % COLUMNS) * CELL_SIZE + 1,
+        (index / COLUMNS) * CELL_SIZE + 1,
+    )
+}
+
+/// GIF palette entry for a cell value: 0 has its own, the rest share 127
+fn palette_level(value: u8) -> u8 {
+    match value {
+        0 => 0,
+        _ => 1 + ((u16::from(value) - 1) * 126 / 254) as u8,
+    }
+}
+
+/// Color of a cell value: dark blue through teal to yellow
+fn heat(value: u8) -> [u8; 3] {
+    const STOPS: [[u8; 3]; 3] = [[0x1a, 0x23, 0x7e], [0x00, 0x96, 0x88], [0xff, 0xeb, 0x3b]];
+    let (from, to, t) = match value {
+        0 => return [0x26, 0x26, 0x30],
+        1..=127 => (STOPS[0], STOPS[1], u16::from(value) * 2),
+        _ => (STOPS[1], STOPS[2], (u16::from(value) - 128) * 2),
+    };
+    std::array::from_fn(|i| {
+        let (from, to) = (u16::from(from[i]), u16::from(to[i]));
+        ((from * (255 - t.min(255)) + to * t.min(255)) / 255) as u8
+    })
+}
+
+fn hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
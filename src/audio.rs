@@ -0,0 +1,128 @@
+//! Audio output (`--audio out.wav`)
+//!
+//! Instead of printing, every byte the program outputs with `.` is played
+//! as a note: the value is a MIDI note number (60 is middle C, 69 the A at
+//! 440 Hz) and 0 is a rest. Notes are sine tones of `--note-length` each,
+//! faded in and out so they don't click, written as 16-bit mono WAV.
+//!
+//! With `--audio -` the WAV goes to stdout as it is made, for a player
+//! reading a pipe (`trainfuck --audio - song.bf | aplay`); the header then
+//! claims the longest possible length, as streamed WAV usually does.
+
+use std::f64::consts::TAU;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const SAMPLE_RATE: u32 = 22_050;
+/// Peak amplitude, leaving headroom below i16::MAX
+const VOLUME: f64 = 12_000.0;
+/// Length of the fade at each end of a note
+const FADE: Duration = Duration::from_millis(5);
+/// Size of the RIFF header before the samples
+const HEADER_LEN: u32 = 44;
+
+enum Sink {
+    /// Sizes in the header are rewritten at every flush
+    File(BufWriter<File>),
+    Stream(io::Stdout),
+}
+
+/// A writer that turns bytes into notes
+pub struct Synth {
+    sink: Sink,
+    samples_per_note: usize,
+    /// Samples written so far
+    written: u32,
+}
+
+impl Synth {
+    /// Write to `path`, or to stdout for `-`
+    pub fn create(path: &Path, note_length: Duration) -> io::Result<Self> {
+        let sink = if path == Path::new("-") {
+            Sink::Stream(io::stdout())
+        } else {
+            Sink::File(BufWriter::new(File::create(path)?))
+        };
+        let mut synth = Synth {
+            sink,
+            samples_per_note: (note_length.as_secs_f64() * f64::from(SAMPLE_RATE)) as usize,
+            written: 0,
+        };
+        let data_len = match synth.sink {
+            Sink::File(_) => 0,
+            Sink::Stream(_) => u32::MAX - HEADER_LEN,
+        };
+        synth.sink().write_all(&header(data_len))?;
+        Ok(synth)
+    }
+
+    fn sink(&mut self) -> &mut dyn Write {
+        match self.sink {
+            Sink::File(ref mut file) => file,
+            Sink::Stream(ref mut stdout) => stdout,
+        }
+    }
+
+    /// Samples of one note, or silence for 0
+    fn note(&self, value: u8) -> Vec<u8> {
+        let frequency = 440.0 * 2f64.powf((f64::from(value) - 69.0) / 12.0);
+        let fade = (FADE.as_secs_f64() * f64::from(SAMPLE_RATE)) as usize;
+        let len = self.samples_per_note;
+        let mut samples = Vec::with_capacity(len * 2);
+        for i in 0..len {
+            let sample = if value == 0 {
+                0
+            } else {
+                let envelope = (i.min(len - i) as f64 / fade as f64).min(1.0);
+                let t = i as f64 / f64::from(SAMPLE_RATE);
+                (VOLUME * envelope * (TAU * frequency * t).sin()) as i16
+            };
+            samples.extend_from_slice(&sample.to_le_bytes());
+        }
+        samples
+    }
+}
+
+impl Write for Synth {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &value in buf {
+            let samples = self.note(value);
+            self.sink().write_all(&samples)?;
+            self.written = self.written.saturating_add(self.samples_per_note as u32);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let data_len = self.written.saturating_mul(2).min(u32::MAX - HEADER_LEN);
+        match self.sink {
+            Sink::File(ref mut file) => {
+                file.seek(SeekFrom::Start(0))?;
+                file.write_all(&header(data_len))?;
+                file.seek(SeekFrom::End(0))?;
+                file.flush()
+            }
+            Sink::Stream(ref mut stdout) => stdout.flush(),
+        }
+    }
+}
+
+/// RIFF header for `data_len` bytes of samples
+fn header(data_len: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(data_len + HEADER_LEN - 8).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    header.extend_from_slice(&1u16.to_le_bytes()); // mono
+    header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    header.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header
+}
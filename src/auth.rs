@@ -0,0 +1,106 @@
+//! Shared-token authentication (`--auth-token`)
+//!
+//! [`AuthNetwork`] wraps another [`Network`] so that a connection only
+//! reaches the program once its peer has proven it knows the token: an
+//! accepted connection must start with the token's bytes, which are read
+//! and stripped before `@` returns it, and connections that send anything
+//! else (or close first) are dropped while `@` waits for the next one.
+//! Connections opened with `%` send the token first, so two programs given
+//! the same token can talk to each other.
+
+use crate::net::{Listener, Network, Stream};
+use std::io::{self, Read};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A [`Network`] whose connections start with a shared token
+pub struct AuthNetwork {
+    inner: Box<dyn Network>,
+    token: Arc<[u8]>,
+}
+
+impl AuthNetwork {
+    pub fn new(inner: Box<dyn Network>, token: &[u8]) -> Self {
+        AuthNetwork {
+            inner,
+            token: token.into(),
+        }
+    }
+}
+
+impl Network for AuthNetwork {
+    fn listen(&mut self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(AuthListener {
+            inner: self.inner.listen(addr)?,
+            token: self.token.clone(),
+        }))
+    }
+
+    fn connect(&mut self, addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        let mut stream = self.inner.connect(addr)?;
+        stream.write_all(&self.token)?;
+        stream.flush()?;
+        Ok(stream)
+    }
+}
+
+struct AuthListener {
+    inner: Box<dyn Listener>,
+    token: Arc<[u8]>,
+}
+
+impl Listener for AuthListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        loop {
+            let stream = self.inner.accept()?;
+            if let Some(stream) = self.check(stream) {
+                return Ok(stream);
+            }
+        }
+    }
+
+    fn accept_timeout(&mut self, timeout: Duration) -> io::Result<Option<Box<dyn Stream>>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some(stream) = self.inner.accept_timeout(remaining)? else {
+                return Ok(None);
+            };
+            if let Some(stream) = self.check(stream) {
+                return Ok(Some(stream));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl AuthListener {
+    /// The connection with the token stripped, or `None` if it didn't
+    /// present the token
+    fn check(&self, mut stream: Box<dyn Stream>) -> Option<Box<dyn Stream>> {
+        let mut presented = vec![0; self.token.len()];
+        let valid = match stream.read_exact(&mut presented) {
+            Ok(()) => constant_time_eq(&presented, &self.token),
+            Err(_) => false,
+        };
+        if valid {
+            return Some(stream);
+        }
+        match stream.peer_addr() {
+            Some(peer) => eprintln!("[trainfuck] Rejected connection from {}: bad token", peer),
+            None => eprintln!("[trainfuck] Rejected connection: bad token"),
+        }
+        stream.shutdown();
+        None
+    }
+}
+
+/// Compare without stopping at the first difference, so response times
+/// don't tell a guesser how much of the token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,79 @@
+//! Sending without waiting on a full connection (`--ext backpressure`)
+//!
+//! On a non-blocking network, a `'` whose byte the connection can't take
+//! yet normally fails with `WouldBlock` and is retried later, so under a
+//! [`Scheduler`](crate::scheduler::Scheduler) a program producing faster
+//! than its peer reads is stuck at that `'` until the peer catches up.
+//! With the extension the byte is kept instead, `'` writes
+//! [`NetStatus::Pending`] to the status cell and the program carries on:
+//!
+//! - `'` sends whatever earlier sends kept before its own byte, so bytes
+//!   still go out in order.
+//! - `_` sends what is kept and flushes the connection, writing
+//!   [`NetStatus::Ok`] once everything has gone out, or
+//!   [`NetStatus::Pending`] while some of it still can't.
+//!
+//! Once [`MAX_UNSENT`] bytes are waiting, `'` waits again as it does
+//! without the extension. Bytes kept for a connection that closes are
+//! dropped with it.
+
+use crate::interpreter::{net_error, NetErrors, NetStatus, Result, VM};
+use std::io;
+
+/// Most bytes `'` keeps for a connection that can't take them
+pub const MAX_UNSENT: usize = 64 * 1024;
+
+impl VM {
+    /// Send `bytes` after the ones waiting, keeping what the connection
+    /// doesn't take yet. `WouldBlock` without keeping them when too many
+    /// are waiting already.
+    pub(crate) fn send_or_keep(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.unsent.len() + bytes.len() > MAX_UNSENT {
+            self.send_unsent()?;
+        }
+        if self.unsent.len() + bytes.len() > MAX_UNSENT {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.unsent.extend_from_slice(bytes);
+        match self.send_unsent() {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Write the bytes waiting to be sent and flush the connection
+    pub(crate) fn send_unsent(&mut self) -> io::Result<()> {
+        let Some(ref mut stream) = self.connection else {
+            self.unsent.clear();
+            return Ok(());
+        };
+        while !self.unsent.is_empty() {
+            match stream.write(&self.unsent) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.unsent.drain(..n);
+                    self.bytes_sent += n as u64;
+                    self.session.bytes_sent += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        stream.flush()
+    }
+
+    /// `_`: send what `'` kept
+    pub(crate) fn net_flush(&mut self) -> Result<()> {
+        let status = match self.send_unsent() {
+            Ok(()) => NetStatus::Ok,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => NetStatus::Pending,
+            Err(e) if self.net_errors == Some(NetErrors::Status) => {
+                eprintln!("[trainfuck] Send error: {}", e);
+                NetStatus::from_error(&e)
+            }
+            Err(e) => return Err(net_error("Send failed", e)),
+        };
+        self.report(status);
+        Ok(())
+    }
+}
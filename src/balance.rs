@@ -0,0 +1,82 @@
+//! Loop-invariant pointer analysis
+//!
+//! A loop whose body always brings the pointer back to the cell it started
+//! on is balanced: every iteration starts on the same cell, so if every
+//! cell the body can touch from there is on the tape when the loop is
+//! entered, it is on the tape in every iteration. [`loop_reach`] proves a
+//! loop balanced from its bytecode and bounds how far its body reaches, and
+//! the tiered compiler uses that to check the tape bounds once per entry
+//! instead of wrapping the pointer at every move inside the loop.
+//!
+//! Bodies that scan, or contain a loop that isn't balanced itself, move the
+//! pointer by amounts only known at run time and are never balanced. Ops
+//! run through the interpreter, such as I/O, don't move the pointer and
+//! wrap the cells they touch themselves, so they don't count.
+
+use crate::bytecode::{self, read_targets, read_u32, Bytecode};
+
+/// Offsets from its starting cell that a balanced loop body touches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reach {
+    pub lowest: isize,
+    pub highest: isize,
+}
+
+impl Reach {
+    /// Whether every cell within reach of `pointer` is on a tape of `len`
+    /// cells, so moves inside the loop never wrap
+    pub fn fits(&self, pointer: usize, len: usize) -> bool {
+        let pointer = pointer as isize;
+        pointer + self.lowest >= 0 && pointer + self.highest < len as isize
+    }
+
+    fn include(&mut self, offset: isize) {
+        self.lowest = self.lowest.min(offset);
+        self.highest = self.highest.max(offset);
+    }
+}
+
+/// How far the body of the loop whose `JUMP_ZERO` is at `pc` reaches, or
+/// None if it isn't balanced
+pub fn loop_reach(bytecode: &Bytecode, pc: usize) -> Option<Reach> {
+    let code = &bytecode.code;
+    match code.get(pc) {
+        Some(&bytecode::JUMP_ZERO) => body_reach(code, pc + 5, read_u32(code, pc + 1) - 5),
+        _ => None,
+    }
+}
+
+/// Reach of the instructions in `code[pc..end]` if they end where they
+/// started. Loops already patched to `COMPILED` count as loops.
+pub(crate) fn body_reach(code: &[u8], mut pc: usize, end: usize) -> Option<Reach> {
+    let mut offset = 0isize;
+    let mut reach = Reach {
+        lowest: 0,
+        highest: 0,
+    };
+    while pc < end {
+        let n = || read_u32(code, pc + 1) as isize;
+        match code[pc] {
+            bytecode::RIGHT | bytecode::RIGHT_ADD => offset = offset.checked_add(n())?,
+            bytecode::LEFT | bytecode::LEFT_ADD => offset = offset.checked_sub(n())?,
+            bytecode::MUL_ADD => {
+                for (target, _) in read_targets(code, pc) {
+                    reach.include(offset.checked_add(target)?);
+                }
+            }
+            bytecode::JUMP_ZERO | bytecode::COMPILED => {
+                let after = read_u32(code, pc + 1);
+                let inner = body_reach(code, pc + 5, after - 5)?;
+                reach.include(offset.checked_add(inner.lowest)?);
+                reach.include(offset.checked_add(inner.highest)?);
+                pc = after;
+                continue;
+            }
+            bytecode::SCAN_RIGHT | bytecode::SCAN_LEFT => return None,
+            _ => {}
+        }
+        reach.include(offset);
+        pc += bytecode::width(code, pc).expect("compiled bytecode is well formed");
+    }
+    (offset == 0).then_some(reach)
+}
@@ -0,0 +1,181 @@
+//! Resource accounting for embedders
+//!
+//! [`VM::usage`] reports what the program has used since the VM was
+//! created or last reset: steps, tape cells, bytes in and out and how
+//! deeply its loops nested. A multi-tenant host can also give VMs a
+//! [`Budget`] of steps and bytes: every step, and every byte read with `,`,
+//! written with `.` or moved over a connection, is taken from it, and once
+//! either runs out the program stops with
+//! [`TrainfuckError::BudgetExhausted`]. Clones of a budget draw from the
+//! same allowance, so one can cover all of a tenant's VMs on any number of
+//! threads, and [`Budget::refill`] tops it up, e.g. every second to grant
+//! a rate.
+//!
+//! ```
+//! use trainfuck::budget::Budget;
+//! use trainfuck::interpreter::{parse, TrainfuckError, VM};
+//!
+//! let tenant = Budget::new(1_000, 100);
+//! let mut vm = VM::with_io(std::io::empty(), std::io::sink());
+//! vm.set_budget(Some(tenant.clone()));
+//! let result = vm.execute(&parse("+[]").unwrap());
+//! assert!(matches!(result, Err(TrainfuckError::BudgetExhausted("steps"))));
+//! assert_eq!(vm.usage().steps, 1_001);
+//! ```
+//!
+//! Steps are taken [`STEP_BATCH`] at a time, so VMs sharing a budget don't
+//! contend on every instruction; what a VM took and didn't use goes back
+//! when it is reset or dropped, or its budget is replaced.
+
+use crate::interpreter::{Result, TrainfuckError, VM};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Steps a VM takes from its budget at once
+pub const STEP_BATCH: u64 = 1024;
+
+/// What a program has used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub steps: u64,
+    /// Tape cells up to the last non-zero one or the pointer, whichever is
+    /// further
+    pub cells: usize,
+    /// Bytes read with `,`
+    pub bytes_input: u64,
+    /// Bytes written with `.`
+    pub bytes_output: u64,
+    /// Bytes received with `` ` `` and the other receiving ops
+    pub bytes_received: u64,
+    /// Bytes sent with `'`
+    pub bytes_sent: u64,
+    /// Deepest loop nesting entered
+    pub peak_loop_depth: usize,
+}
+
+/// Steps and bytes left for the VMs sharing it
+#[derive(Debug, Clone)]
+pub struct Budget(Arc<Allowance>);
+
+#[derive(Debug)]
+struct Allowance {
+    steps: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Budget {
+    pub fn new(steps: u64, bytes: u64) -> Self {
+        Budget(Arc::new(Allowance {
+            steps: AtomicU64::new(steps),
+            bytes: AtomicU64::new(bytes),
+        }))
+    }
+
+    /// Steps left, not counting those VMs have taken and not used yet
+    pub fn steps(&self) -> u64 {
+        self.0.steps.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.0.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Add to what is left
+    pub fn refill(&self, steps: u64, bytes: u64) {
+        give(&self.0.steps, steps);
+        give(&self.0.bytes, bytes);
+    }
+}
+
+/// Take up to `wanted` from `counter`, returning how much was there to take
+fn take(counter: &AtomicU64, wanted: u64) -> u64 {
+    let left = counter
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+            Some(left - left.min(wanted))
+        })
+        .expect("the update always succeeds");
+    left.min(wanted)
+}
+
+fn give(counter: &AtomicU64, amount: u64) {
+    let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+        Some(left.saturating_add(amount))
+    });
+}
+
+impl VM {
+    /// What the program has used since the VM was created or last reset
+    pub fn usage(&self) -> Usage {
+        let used = self.tape.iter().rposition(|&c| c != 0).unwrap_or(0);
+        Usage {
+            steps: self.steps,
+            cells: used.max(self.pointer) + 1,
+            bytes_input: self.bytes_input,
+            bytes_output: self.bytes_output,
+            bytes_received: self.bytes_received,
+            bytes_sent: self.bytes_sent,
+            peak_loop_depth: self.peak_loop_depth,
+        }
+    }
+
+    /// Charge steps and bytes from now on to `budget`, or to nothing
+    pub fn set_budget(&mut self, budget: Option<Budget>) {
+        self.return_steps();
+        self.budget = budget;
+    }
+
+    pub fn budget(&self) -> Option<&Budget> {
+        self.budget.as_ref()
+    }
+
+    /// Give the steps taken from the budget and not used back to it
+    pub(crate) fn return_steps(&mut self) {
+        if let Some(ref budget) = self.budget {
+            give(&budget.0.steps, self.prepaid_steps);
+        }
+        self.prepaid_steps = 0;
+    }
+
+    /// Charge one step
+    pub(crate) fn charge_step(&mut self) -> Result<()> {
+        let Some(ref budget) = self.budget else {
+            return Ok(());
+        };
+        if self.prepaid_steps == 0 {
+            self.prepaid_steps = take(&budget.0.steps, STEP_BATCH);
+            if self.prepaid_steps == 0 {
+                return Err(TrainfuckError::BudgetExhausted("steps"));
+            }
+        }
+        self.prepaid_steps -= 1;
+        Ok(())
+    }
+
+    /// Charge `count` bytes read or written
+    pub(crate) fn charge_bytes(&mut self, count: usize) -> Result<()> {
+        let Some(ref budget) = self.budget else {
+            return Ok(());
+        };
+        let count = count as u64;
+        match take(&budget.0.bytes, count) == count {
+            true => Ok(()),
+            false => Err(TrainfuckError::BudgetExhausted("bytes")),
+        }
+    }
+
+    /// Note entering a loop, for [`Usage::peak_loop_depth`]
+    pub(crate) fn enter_loop(&mut self) {
+        self.loop_depth += 1;
+        self.peak_loop_depth = self.peak_loop_depth.max(self.loop_depth);
+    }
+
+    pub(crate) fn leave_loop(&mut self) {
+        self.loop_depth = self.loop_depth.saturating_sub(1);
+    }
+}
+
+impl Drop for VM {
+    fn drop(&mut self) {
+        self.return_steps();
+    }
+}
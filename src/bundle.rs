@@ -0,0 +1,127 @@
+//! `trainfuck bundle`: programs carried inside a copy of the interpreter
+//!
+//! A bundle is the running `trainfuck` executable with a payload appended:
+//! the run options the program was bundled with and its source files, then
+//! the payload's length and [`MAGIC`]. Operating systems ignore what follows
+//! an executable's image, so the copy still starts as `trainfuck`, and
+//! [`read`] finds the payload at the end of its own file before any
+//! command-line parsing happens.
+//!
+//! Payload layout, all lengths little-endian `u32`s:
+//!
+//! ```text
+//! option count, then each option as length + bytes
+//! file count, then each file as name length + name + length + bytes
+//! payload length (u64), MAGIC
+//! ```
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use trainfuck::span::SourceFiles;
+
+/// Marks the end of a bundled executable
+pub const MAGIC: &[u8; 16] = b"trainfuck-bundle";
+
+/// What a bundled executable runs
+pub struct Bundle {
+    /// Run options, as given after `--` to `bundle`
+    pub options: Vec<String>,
+    pub sources: SourceFiles,
+}
+
+/// Copy the running executable to `output` with `bundle` appended
+pub fn write(output: &Path, bundle: &Bundle) -> io::Result<()> {
+    let exe = std::env::current_exe()?;
+    // Bundling from a bundle keeps only the interpreter
+    let interpreter = match payload_start(&mut File::open(&exe)?)? {
+        Some(end) => end,
+        None => fs::metadata(&exe)?.len(),
+    };
+
+    let mut payload = Vec::new();
+    put_count(&mut payload, bundle.options.len());
+    for option in &bundle.options {
+        put_bytes(&mut payload, option.as_bytes());
+    }
+    put_count(&mut payload, bundle.sources.files().count());
+    for (name, bytes) in bundle.sources.files() {
+        put_bytes(&mut payload, name.as_bytes());
+        put_bytes(&mut payload, bytes);
+    }
+    payload.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    payload.extend_from_slice(MAGIC);
+
+    // Write then rename, so a failed bundle doesn't leave half an executable
+    let partial = output.with_extension(format!("tmp{}", std::process::id()));
+    let written = (|| {
+        let mut file = File::create(&partial)?;
+        io::copy(&mut File::open(&exe)?.take(interpreter), &mut file)?;
+        file.write_all(&payload)?;
+        file.set_permissions(fs::metadata(&exe)?.permissions())?;
+        fs::rename(&partial, output)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    written
+}
+
+/// The bundle appended to the running executable, if there is one
+pub fn read() -> Option<Bundle> {
+    let mut file = File::open(std::env::current_exe().ok()?).ok()?;
+    let start = payload_start(&mut file).ok()??;
+    let end = file.seek(SeekFrom::End(-24)).ok()?;
+    file.seek(SeekFrom::Start(start)).ok()?;
+    let mut payload = vec![0; usize::try_from(end - start).ok()?];
+    file.read_exact(&mut payload).ok()?;
+
+    let mut rest = payload.as_slice();
+    let options = (0..take_count(&mut rest)?)
+        .map(|_| String::from_utf8(take_bytes(&mut rest)?.to_vec()).ok())
+        .collect::<Option<_>>()?;
+    let mut sources = SourceFiles::new();
+    for _ in 0..take_count(&mut rest)? {
+        let name = String::from_utf8_lossy(take_bytes(&mut rest)?).into_owned();
+        sources.push(name, take_bytes(&mut rest)?);
+    }
+    Some(Bundle { options, sources })
+}
+
+/// Offset of the payload in `file`, or None if it doesn't end with one
+fn payload_start(file: &mut File) -> io::Result<Option<u64>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < 24 {
+        return Ok(None);
+    }
+    let mut trailer = [0u8; 24];
+    file.seek(SeekFrom::End(-24))?;
+    file.read_exact(&mut trailer)?;
+    if &trailer[8..] != MAGIC {
+        return Ok(None);
+    }
+    let payload = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    Ok((len - 24).checked_sub(payload))
+}
+
+fn put_count(payload: &mut Vec<u8>, count: usize) {
+    payload.extend_from_slice(&(count as u32).to_le_bytes());
+}
+
+fn put_bytes(payload: &mut Vec<u8>, bytes: &[u8]) {
+    put_count(payload, bytes.len());
+    payload.extend_from_slice(bytes);
+}
+
+fn take_count(rest: &mut &[u8]) -> Option<usize> {
+    let (count, tail) = rest.split_first_chunk::<4>()?;
+    *rest = tail;
+    Some(u32::from_le_bytes(*count) as usize)
+}
+
+fn take_bytes<'a>(rest: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = take_count(rest)?;
+    let (bytes, tail) = (rest.get(..len)?, &rest[len..]);
+    *rest = tail;
+    Some(bytes)
+}
@@ -0,0 +1,526 @@
+//! Compact bytecode and its dispatch loop
+//!
+//! Walking the `Op` tree costs a recursive call per loop and an enum match
+//! per op. [`Bytecode`] flattens a program into a byte string of one-byte
+//! opcodes followed by little-endian operands, with loops turned into
+//! absolute jumps, and [`VM::execute_bytecode`] runs it in a single loop.
+//!
+//! Common pairs are fused into one instruction so they cost one dispatch:
+//! a move followed by `+`/`-` (`>+`, `<<--`) and `+`/`-` followed by `.`.
+//! A fused pair counts as one step towards `max_steps`.
+
+use crate::interpreter::{Op, Result, TrainfuckError, VM};
+use crate::kernel::{self, address, fits, push_move, push_u32};
+use crate::span::{SourceMap, Span};
+
+pub(crate) use crate::kernel::opcode::*;
+pub(crate) use crate::kernel::{read_targets, read_u32};
+
+/// Lowercase name of an opcode, as reports show it
+pub(crate) fn opcode_name(opcode: u8) -> &'static str {
+    const NAMES: [&str; 46] = [
+        "halt",
+        "right",
+        "left",
+        "add",
+        "output",
+        "input",
+        "jump_zero",
+        "jump_nonzero",
+        "clear",
+        "scan_right",
+        "scan_left",
+        "mul_add",
+        "right_add",
+        "left_add",
+        "add_output",
+        "listen",
+        "accept",
+        "connect",
+        "receive",
+        "send",
+        "compiled",
+        "print",
+        "load",
+        "compare_swap",
+        "spawn",
+        "send_message",
+        "receive_message",
+        "yield",
+        "kv_put",
+        "kv_get",
+        "http",
+        "checksum",
+        "base64_encode",
+        "base64_decode",
+        "xor",
+        "peek",
+        "unread",
+        "receive_line",
+        "resolve",
+        "select_channel",
+        "channels",
+        "flush",
+        "send_block",
+        "receive_block",
+        "trap",
+        "features",
+    ];
+    NAMES.get(usize::from(opcode)).copied().unwrap_or("invalid")
+}
+
+/// A program compiled for [`VM::execute_bytecode`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bytecode {
+    pub(crate) code: Vec<u8>,
+    /// Source span of each instruction that has one, by address
+    spans: Vec<(usize, Span)>,
+}
+
+impl Bytecode {
+    /// Compile parsed (and optionally optimized) ops
+    pub fn compile(ops: &[Op]) -> Self {
+        Self::compile_with_spans(ops, &SourceMap::default())
+    }
+
+    /// Compile ops along with their source map, so errors can be located
+    pub fn compile_with_spans(ops: &[Op], spans: &SourceMap) -> Self {
+        let mut emitter = Emitter {
+            code: Vec::new(),
+            spans_in: spans.spans().iter(),
+            spans: Vec::new(),
+        };
+        emitter.emit(ops);
+        emitter.code.push(HALT);
+        Bytecode {
+            code: emitter.code,
+            spans: emitter.spans,
+        }
+    }
+
+    /// Source span of the instruction at `pc`, if known
+    pub fn span_at(&self, pc: usize) -> Option<Span> {
+        let index = self.spans.binary_search_by_key(&pc, |&(at, _)| at).ok()?;
+        Some(self.spans[index].1)
+    }
+
+    /// Addresses and source spans of the instructions that have one
+    pub fn spans(&self) -> &[(usize, Span)] {
+        &self.spans
+    }
+
+    /// The encoded instructions, ending in `HALT`
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Size of the encoded program in bytes
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.len() == 1
+    }
+
+    /// Serialize the program and its spans, e.g. for caching on disk
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.code.len() + self.spans.len() * 20);
+        push_u32(&mut bytes, self.code.len());
+        bytes.extend(&self.code);
+        push_u32(&mut bytes, self.spans.len());
+        for &(pc, span) in &self.spans {
+            push_u32(&mut bytes, pc);
+            bytes.extend((span.start as u64).to_le_bytes());
+            bytes.extend((span.end as u64).to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Load bytes produced by [`to_bytes`](Self::to_bytes), checking every
+    /// opcode, operand and jump so a corrupt file can't crash the VM
+    pub fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        let code_len = read_u32(bytes.get(..4)?, 0);
+        let code = bytes.get(4..4 + code_len)?.to_vec();
+        let rest = &bytes[4 + code_len..];
+        let count = read_u32(rest.get(..4)?, 0);
+        let entries = rest.get(4..)?;
+        if entries.len() != count.checked_mul(20)? {
+            return None;
+        }
+        let read_u64 = |at: &[u8]| u64::from_le_bytes(at.try_into().unwrap()) as usize;
+        let spans: Vec<(usize, Span)> = entries
+            .chunks_exact(20)
+            .map(|entry| {
+                let span = Span::new(read_u64(&entry[4..12]), read_u64(&entry[12..20]));
+                (read_u32(entry, 0), span)
+            })
+            .collect();
+        if spans.windows(2).any(|w| w[0].0 >= w[1].0)
+            || spans.iter().any(|&(pc, _)| pc >= code.len())
+        {
+            return None;
+        }
+
+        let mut open = Vec::new();
+        let mut pc = 0;
+        while pc < code.len() {
+            if code[pc] == HALT {
+                break;
+            }
+            let width = width(&code, pc)?;
+            if pc + width > code.len() {
+                return None;
+            }
+            match code[pc] {
+                JUMP_ZERO => open.push(pc),
+                JUMP_NONZERO => {
+                    let start = open.pop()?;
+                    let after = pc + 5;
+                    if read_u32(&code, start + 1) != after || read_u32(&code, pc + 1) != start + 5 {
+                        return None;
+                    }
+                }
+                _ => {}
+            }
+            pc += width;
+        }
+        // Exactly one HALT, at the very end, with every loop closed
+        if !open.is_empty() || pc + 1 != code.len() || code[pc] != HALT {
+            return None;
+        }
+        Some(Bytecode { code, spans })
+    }
+
+    /// Address of the first `ACCEPT` not inside a loop
+    pub(crate) fn first_outer_accept(&self) -> Option<usize> {
+        let mut pc = 0;
+        while self.code[pc] != HALT {
+            match self.code[pc] {
+                ACCEPT => return Some(pc),
+                JUMP_ZERO => pc = read_u32(&self.code, pc + 1),
+                _ => pc += width(&self.code, pc).expect("compiled bytecode is well formed"),
+            }
+        }
+        None
+    }
+
+    /// Addresses of the `JUMP_ZERO`s of loops not inside another loop, in
+    /// program order
+    pub(crate) fn outer_loops(&self) -> Vec<usize> {
+        let mut loops = Vec::new();
+        let mut pc = 0;
+        while self.code[pc] != HALT {
+            if self.code[pc] == JUMP_ZERO {
+                loops.push(pc);
+                pc = read_u32(&self.code, pc + 1);
+            } else {
+                pc += width(&self.code, pc).expect("compiled bytecode is well formed");
+            }
+        }
+        loops
+    }
+}
+
+struct Emitter<'a> {
+    code: Vec<u8>,
+    /// Spans of the ops being compiled, in pre-order; empty if unknown
+    spans_in: std::slice::Iter<'a, Span>,
+    spans: Vec<(usize, Span)>,
+}
+
+impl Emitter<'_> {
+    /// Record the span of the next op for the instruction at `pc`
+    fn locate(&mut self, pc: usize) -> Option<Span> {
+        let span = *self.spans_in.next()?;
+        self.spans.push((pc, span));
+        Some(span)
+    }
+
+    /// A fused pair covers the spans of both ops
+    fn locate_pair(&mut self, pc: usize) {
+        if let (Some(first), Some(second)) = (self.locate(pc), self.spans_in.next()) {
+            self.spans.last_mut().unwrap().1 = first.to(*second);
+        }
+    }
+
+    fn emit(&mut self, ops: &[Op]) {
+        let mut i = 0;
+        while i < ops.len() {
+            let next = ops.get(i + 1);
+            let pc = self.code.len();
+            let code = &mut self.code;
+            let fused = match (&ops[i], next) {
+                (Op::MoveRight(n), Some(Op::Increment(_) | Op::Decrement(_))) if fits(*n) => {
+                    code.push(RIGHT_ADD);
+                    push_u32(code, *n);
+                    code.push(delta(next.unwrap()));
+                    true
+                }
+                (Op::MoveLeft(n), Some(Op::Increment(_) | Op::Decrement(_))) if fits(*n) => {
+                    code.push(LEFT_ADD);
+                    push_u32(code, *n);
+                    code.push(delta(next.unwrap()));
+                    true
+                }
+                (op @ (Op::Increment(_) | Op::Decrement(_)), Some(Op::Output)) => {
+                    code.extend([ADD_OUTPUT, delta(op)]);
+                    true
+                }
+                _ => false,
+            };
+            if fused {
+                self.locate_pair(pc);
+                i += 2;
+                continue;
+            }
+
+            let span = self.locate(pc);
+            let code = &mut self.code;
+            match &ops[i] {
+                Op::MoveRight(n) => push_move(code, RIGHT, *n),
+                Op::MoveLeft(n) => push_move(code, LEFT, *n),
+                op @ (Op::Increment(_) | Op::Decrement(_)) => code.extend([ADD, delta(op)]),
+                Op::Output => code.push(OUTPUT),
+                Op::Input => code.push(INPUT),
+                Op::Loop(body) => {
+                    code.push(JUMP_ZERO);
+                    push_u32(code, 0);
+                    self.emit(body);
+                    let end = self.code.len();
+                    if let Some(span) = span {
+                        self.spans.push((end, span));
+                    }
+                    let code = &mut self.code;
+                    code.push(JUMP_NONZERO);
+                    push_u32(code, pc + 5);
+                    let after = code.len();
+                    code[pc + 1..pc + 5].copy_from_slice(&address(after).to_le_bytes());
+                }
+                Op::Clear => code.push(CLEAR),
+                Op::ScanRight(stride) => {
+                    code.push(SCAN_RIGHT);
+                    push_u32(code, *stride);
+                }
+                Op::ScanLeft(stride) => {
+                    code.push(SCAN_LEFT);
+                    push_u32(code, *stride);
+                }
+                Op::MulAdd(targets) => {
+                    code.push(MUL_ADD);
+                    push_u32(code, targets.len());
+                    for &(offset, factor) in targets {
+                        code.extend((offset as i64).to_le_bytes());
+                        code.push(factor);
+                    }
+                }
+                Op::Print(bytes) => {
+                    code.push(PRINT);
+                    push_u32(code, bytes.len());
+                    code.extend(bytes);
+                }
+                Op::Load(start, cells) => {
+                    code.push(LOAD);
+                    code.extend((*start as u64).to_le_bytes());
+                    push_u32(code, cells.len());
+                    code.extend(cells);
+                }
+                Op::Listen => code.push(LISTEN),
+                Op::Accept => code.push(ACCEPT),
+                Op::Connect => code.push(CONNECT),
+                Op::Receive => code.push(RECEIVE),
+                Op::Send => code.push(SEND),
+                Op::CompareSwap => code.push(COMPARE_SWAP),
+                Op::Spawn => code.push(SPAWN),
+                Op::SendMessage => code.push(SEND_MESSAGE),
+                Op::ReceiveMessage => code.push(RECEIVE_MESSAGE),
+                Op::Yield => code.push(YIELD),
+                Op::KvPut => code.push(KV_PUT),
+                Op::KvGet => code.push(KV_GET),
+                Op::Http => code.push(HTTP),
+                Op::Checksum => code.push(CHECKSUM),
+                Op::Base64Encode => code.push(BASE64_ENCODE),
+                Op::Base64Decode => code.push(BASE64_DECODE),
+                Op::Xor => code.push(XOR),
+                Op::Peek => code.push(PEEK),
+                Op::Unread => code.push(UNREAD),
+                Op::ReceiveLine => code.push(RECEIVE_LINE),
+                Op::Resolve => code.push(RESOLVE),
+                Op::SelectChannel => code.push(SELECT_CHANNEL),
+                Op::Channels => code.push(CHANNELS),
+                Op::Flush => code.push(FLUSH),
+                Op::SendBlock => code.push(SEND_BLOCK),
+                Op::ReceiveBlock => code.push(RECEIVE_BLOCK),
+                Op::Trap => code.push(TRAP),
+                Op::Features(mask) => {
+                    code.push(FEATURES);
+                    code.extend_from_slice(&mask.to_le_bytes());
+                }
+            }
+            i += 1;
+        }
+    }
+}
+
+/// The wrapping amount `+`/`-` adds to a cell
+fn delta(op: &Op) -> u8 {
+    match op {
+        Op::Increment(n) => *n,
+        Op::Decrement(n) => n.wrapping_neg(),
+        _ => unreachable!("only called for + and -"),
+    }
+}
+
+impl VM {
+    /// Run a compiled program from the start
+    pub fn execute_bytecode(&mut self, bytecode: &Bytecode) -> Result<()> {
+        let code = bytecode.code.as_slice();
+        let mut pc = 0;
+        self.fault_pc = None;
+        self.loop_depth = 0;
+        while code[pc] != HALT {
+            pc = self
+                .dispatch(code, pc)
+                .inspect_err(|_| self.fault_pc = Some(pc))?;
+        }
+        Ok(())
+    }
+
+    /// Address of the instruction that failed in the last bytecode run;
+    /// look it up with [`Bytecode::span_at`]
+    pub fn fault_pc(&self) -> Option<usize> {
+        self.fault_pc
+    }
+
+    /// Execute the instruction at `pc`, returning where to continue. The
+    /// kernel runs the core instructions; the extensions' are run here.
+    pub(crate) fn dispatch(&mut self, code: &[u8], pc: usize) -> Result<usize> {
+        let opcode = code[pc];
+        self.current_pc = Some(pc);
+        self.tick()?;
+        let pointer = self.pointer;
+        let next = match kernel::step(self, code, pc)? {
+            Some(next) => next,
+            None => {
+                self.extension(code, pc)?;
+                pc + width(code, pc).expect("compiled bytecode is well formed")
+            }
+        };
+        if !self.guards.is_empty() {
+            self.check_guards()?;
+        }
+        if self.history.is_some() {
+            self.record_instruction(pc, opcode, pointer);
+        }
+        if self.histogram.is_some() {
+            self.count_instruction(code, pc, next);
+        }
+        Ok(next)
+    }
+
+    /// Execute the opt-in extension's instruction at `pc`
+    fn extension(&mut self, code: &[u8], pc: usize) -> Result<()> {
+        match code[pc] {
+            FLUSH | PEEK | RECEIVE_LINE | SEND_BLOCK | RECEIVE_BLOCK => {
+                self.check_network()?;
+                match code[pc] {
+                    PEEK => self.net_peek()?,
+                    RECEIVE_LINE => self.net_receive_line()?,
+                    FLUSH => self.net_flush()?,
+                    SEND_BLOCK => self.net_send_block()?,
+                    _ => self.net_receive_block()?,
+                }
+            }
+                COMPARE_SWAP => self.compare_swap()?,
+                SPAWN => self.spawn_actor()?,
+                SEND_MESSAGE => self.send_message()?,
+                RECEIVE_MESSAGE => self.receive_message()?,
+                YIELD => self.yielded = true,
+                KV_PUT => self.kv_put()?,
+                KV_GET => self.kv_get()?,
+                HTTP => self.http_request()?,
+                RESOLVE => self.resolve()?,
+                SELECT_CHANNEL => self.select_channel(),
+                CHANNELS => self.channel_status(),
+                CHECKSUM => self.checksum(),
+                BASE64_ENCODE => self.base64_encode(),
+                BASE64_DECODE => self.base64_decode(),
+                XOR => self.xor(),
+                TRAP => self.trap()?,
+                FEATURES => self.detect_features(read_u32(code, pc + 1) as u32),
+                UNREAD => self.unread(),
+                opcode => unreachable!("invalid opcode {} at {}", opcode, pc),
+        }
+        Ok(())
+    }
+
+    fn check_network(&self) -> Result<()> {
+        match self.network_enabled {
+            true => Ok(()),
+            false => Err(TrainfuckError::NetworkDisabled),
+        }
+    }
+
+    pub(crate) fn add(&mut self, delta: u8) {
+        self.tape[self.pointer] = self.tape[self.pointer].wrapping_add(delta);
+    }
+}
+
+impl kernel::Core for VM {
+    type Error = TrainfuckError;
+
+    fn cell(&mut self) -> &mut u8 {
+        &mut self.tape[self.pointer]
+    }
+
+    fn move_right(&mut self, n: usize) {
+        VM::move_right(self, n)
+    }
+
+    fn move_left(&mut self, n: usize) {
+        VM::move_left(self, n)
+    }
+
+    fn scan_right(&mut self, stride: usize) -> Result<()> {
+        VM::scan_right(self, stride)
+    }
+
+    fn scan_left(&mut self, stride: usize) -> Result<()> {
+        VM::scan_left(self, stride)
+    }
+
+    fn mul_add(&mut self, targets: impl Iterator<Item = (isize, u8)>) {
+        VM::mul_add(self, targets)
+    }
+
+    fn load(&mut self, start: usize, cells: &[u8]) {
+        VM::load(self, start, cells)
+    }
+
+    fn output(&mut self, bytes: &[u8]) -> Result<()> {
+        self.print(bytes)
+    }
+
+    fn input(&mut self) -> Result<()> {
+        self.input_cell()
+    }
+
+    fn network(&mut self, opcode: u8) -> Result<()> {
+        self.check_network()?;
+        match opcode {
+            LISTEN => self.net_listen(),
+            ACCEPT => self.net_accept(),
+            CONNECT => self.net_connect(),
+            RECEIVE => self.net_receive(),
+            _ => self.net_send(),
+        }
+    }
+
+    fn enter_loop(&mut self) {
+        VM::enter_loop(self)
+    }
+
+    fn leave_loop(&mut self) {
+        VM::leave_loop(self)
+    }
+}
@@ -0,0 +1,92 @@
+//! On-disk bytecode cache
+//!
+//! Parsing and optimizing a multi-megabyte generated program takes longer
+//! than running a short one, so the compiled [`Bytecode`] is stored under
+//! the cache directory, named after a hash of the source and the settings
+//! that shaped it. The warnings found while compiling are stored with it,
+//! to be printed again on every run that loads it. A missing, stale or
+//! corrupt entry just means compiling again.
+
+use crate::RunArgs;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use trainfuck::bytecode::Bytecode;
+use trainfuck::span::SourceFiles;
+use trainfuck::warnings::Warning;
+
+/// Bumped whenever the layout of an entry changes
+const FORMAT: u32 = 4;
+
+/// `$XDG_CACHE_HOME/trainfuck`, falling back to `~/.cache/trainfuck`
+pub fn default_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("trainfuck"))
+}
+
+/// Cache key for `sources` compiled by this version with the given settings
+pub fn key(sources: &SourceFiles, args: &RunArgs) -> String {
+    let source = sources.source();
+    // Where the files start decides which `#!` lines are skipped
+    let lengths: Vec<usize> = sources.files().map(|(_, bytes)| bytes.len()).collect();
+    // FNV-1a: stable across builds, unlike std's randomly seeded hasher
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let salt = format!(
+        "{}\0{}\0{}\0{:?}\0{}\0{}\0{:?}\0{:?}\0{}\0{:?}\0{:?}\0{:?}\0",
+        FORMAT,
+        env!("CARGO_PKG_VERSION"),
+        args.opt_level,
+        args.passes,
+        args.max_loop_depth,
+        // -O2 bakes pointer wrapping for this tape size into the program
+        args.tape_size(),
+        args.extensions(),
+        // Programs that fail strict parsing mustn't come from the cache
+        args.dialect(),
+        // -O2 skips folding when the runtime writes a status cell
+        args.status_cell.is_some(),
+        // ...and when the tape can grow
+        args.tape_model,
+        // Lints that are allowed aren't stored, and denied ones that found
+        // something fail compiling, so nothing is stored at all
+        args.lints.levels(),
+        lengths
+    );
+    for &byte in salt.as_bytes().iter().chain(source) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:016x}-{}", hash, source.len())
+}
+
+/// The bytecode and warnings stored under `key`
+pub fn load(dir: &Path, key: &str) -> Option<(Bytecode, Vec<Warning>)> {
+    let bytes = fs::read(entry(dir, key)).ok()?;
+    // The bytecode's length, the bytecode, then the warnings as JSON
+    let len = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+    let bytecode = bytes.get(4..4 + len)?;
+    let warnings = serde_json::from_slice(&bytes[4 + len..]).ok()?;
+    Some((Bytecode::from_bytes(bytecode.to_vec())?, warnings))
+}
+
+pub fn store(dir: &Path, key: &str, bytecode: &Bytecode, warnings: &[Warning]) -> io::Result<()> {
+    let code = bytecode.to_bytes();
+    let len = u32::try_from(code.len()).map_err(io::Error::other)?;
+    let mut bytes = len.to_le_bytes().to_vec();
+    bytes.extend(code);
+    bytes.extend(serde_json::to_vec(warnings)?);
+
+    fs::create_dir_all(dir)?;
+    // Write then rename so a concurrent run never sees a partial entry
+    let path = entry(dir, key);
+    let partial = path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&partial, bytes)?;
+    fs::rename(&partial, &path)
+}
+
+fn entry(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.tfbc", key))
+}
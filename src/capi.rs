@@ -0,0 +1,164 @@
+//! C API (`capi` feature)
+//!
+//! Exposes the VM to C/C++ through an opaque `TfVm` handle. Building with
+//! `--features capi` also regenerates `include/trainfuck.h`.
+//!
+//! Functions that can fail return a negative value (or NULL) and record a
+//! message retrievable with `tf_last_error` on the same thread.
+
+use crate::interpreter::{self, VM};
+use crate::program::Program;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::io::Cursor;
+use std::ptr;
+
+/// A parsed program together with the VM running it
+pub struct TfVm {
+    vm: VM,
+    ops: Program,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // Interior NULs can't be represented; replace rather than lose the message
+    let message = CString::new(message.replace('\0', "\\0")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Parse a NUL-terminated program and create a VM for it.
+/// Returns NULL on error.
+///
+/// # Safety
+/// `source` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn tf_vm_new(source: *const c_char) -> *mut TfVm {
+    if source.is_null() {
+        set_last_error("source is NULL".into());
+        return ptr::null_mut();
+    }
+
+    match interpreter::parse_bytes(CStr::from_ptr(source).to_bytes()) {
+        Ok(ops) => Box::into_raw(Box::new(TfVm { vm: VM::new(), ops })),
+        Err(e) => {
+            set_last_error(format!("Parse error: {}", e));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Destroy a VM created by `tf_vm_new`, closing its sockets.
+///
+/// # Safety
+/// `vm` must come from `tf_vm_new` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn tf_vm_free(vm: *mut TfVm) {
+    if !vm.is_null() {
+        let mut vm = Box::from_raw(vm);
+        let _ = vm.vm.shutdown();
+    }
+}
+
+/// Feed `len` bytes as the program's input instead of stdin.
+/// Returns 0 on success, -1 on error.
+///
+/// # Safety
+/// `vm` must be a live handle and `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tf_vm_set_input(vm: *mut TfVm, data: *const u8, len: usize) -> c_int {
+    let Some(vm) = vm.as_mut() else {
+        set_last_error("vm is NULL".into());
+        return -1;
+    };
+    let bytes = if len == 0 {
+        Vec::new()
+    } else if data.is_null() {
+        set_last_error("data is NULL".into());
+        return -1;
+    } else {
+        std::slice::from_raw_parts(data, len).to_vec()
+    };
+    vm.vm.input = Box::new(Cursor::new(bytes));
+    0
+}
+
+/// Run the program to completion (continuing after any `tf_vm_step` calls).
+/// Returns 0 on success, -1 on error.
+///
+/// # Safety
+/// `vm` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn tf_vm_run(vm: *mut TfVm) -> c_int {
+    let Some(vm) = vm.as_mut() else {
+        set_last_error("vm is NULL".into());
+        return -1;
+    };
+    match vm.vm.resume(&vm.ops) {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(format!("Runtime error: {}", e));
+            -1
+        }
+    }
+}
+
+/// Execute up to `count` steps. Returns 1 when the program has finished,
+/// 0 if it is still running, -1 on error.
+///
+/// # Safety
+/// `vm` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn tf_vm_step(vm: *mut TfVm, count: u64) -> c_int {
+    let Some(vm) = vm.as_mut() else {
+        set_last_error("vm is NULL".into());
+        return -1;
+    };
+    for _ in 0..count {
+        match vm.vm.step(&vm.ops) {
+            Ok(true) => return 1,
+            Ok(false) => {}
+            Err(e) => {
+                set_last_error(format!("Runtime error: {}", e));
+                return -1;
+            }
+        }
+    }
+    0
+}
+
+/// Pointer to the tape; its length is stored in `*len` if `len` is not NULL.
+/// Valid until the next call that runs the VM or frees it.
+///
+/// # Safety
+/// `vm` must be a live handle and `len` NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn tf_vm_tape_ptr(vm: *mut TfVm, len: *mut usize) -> *mut u8 {
+    let Some(vm) = vm.as_mut() else {
+        set_last_error("vm is NULL".into());
+        return ptr::null_mut();
+    };
+    let tape = vm.vm.tape_mut();
+    if let Some(len) = len.as_mut() {
+        *len = tape.len();
+    }
+    tape.as_mut_ptr()
+}
+
+/// Current data pointer position.
+///
+/// # Safety
+/// `vm` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn tf_vm_pointer(vm: *const TfVm) -> usize {
+    vm.as_ref().map_or(0, |vm| vm.vm.pointer())
+}
+
+/// Message for the most recent error on this thread, or NULL if none.
+/// Valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn tf_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
@@ -0,0 +1,119 @@
+//! Several open connections at once (`--ext channels`)
+//!
+//! Without the extension a VM has one connection, which `@` and `%` open and
+//! close. With it there are 255 channels, numbered from 1, each of which can
+//! hold a connection; `@`, `%`, `` ` ``, `'` and the other networking ops
+//! work on the active one, as if it were the only one, and the rest stay
+//! open in the background. A server keeps its clients on separate channels
+//! and serves whichever has something to say:
+//!
+//! - `(`: make the channel the current cell names active. 0 picks the
+//!   lowest free channel (one without a connection) and writes its number
+//!   to the cell, or writes 0 if all are taken. Channel 1 is active at the
+//!   start.
+//! - `)`: write how many channels have a connection open to the current
+//!   cell, and to the next the lowest channel whose connection has data
+//!   waiting (or has ended), 0 if none has.
+//!
+//! Bytes `=` and `\` put back, and bytes `'` kept with `--ext
+//! backpressure`, stay with their channel.
+
+use crate::idle::Liveness;
+use crate::interpreter::{ConnectionInfo, VM};
+use crate::net::Stream;
+use std::net::SocketAddrV4;
+
+/// The channel active when a VM starts
+pub const FIRST_CHANNEL: u8 = 1;
+
+/// A connection on an inactive channel, with what the VM keeps about it
+pub(crate) struct Parked {
+    pub(crate) connection: Box<dyn Stream>,
+    session: ConnectionInfo,
+    pushback: Vec<u8>,
+    unsent: Vec<u8>,
+    dialed: Option<SocketAddrV4>,
+    pub(crate) liveness: Liveness,
+}
+
+impl VM {
+    /// `(`: switch to the channel the current cell names
+    pub(crate) fn select_channel(&mut self) {
+        self.close_idle_parked();
+        let mut channel = self.tape[self.pointer];
+        if channel == 0 {
+            let free = (1..=u8::MAX).find(|&c| match c == self.channel {
+                true => self.connection.is_none(),
+                false => !self.parked.contains_key(&c),
+            });
+            channel = free.unwrap_or(0);
+            self.tape[self.pointer] = channel;
+            if channel == 0 {
+                return;
+            }
+        }
+        if channel == self.channel {
+            return;
+        }
+
+        match self.connection.take() {
+            Some(connection) => {
+                let parked = Parked {
+                    connection,
+                    session: std::mem::take(&mut self.session),
+                    pushback: std::mem::take(&mut self.pushback),
+                    unsent: std::mem::take(&mut self.unsent),
+                    dialed: self.dialed.take(),
+                    liveness: self.liveness,
+                };
+                self.parked.insert(self.channel, parked);
+            }
+            None => {
+                self.pushback.clear();
+                self.unsent.clear();
+                self.dialed = None;
+            }
+        }
+        self.channel = channel;
+        if let Some(parked) = self.parked.remove(&channel) {
+            self.connection = Some(parked.connection);
+            self.session = parked.session;
+            self.pushback = parked.pushback;
+            self.unsent = parked.unsent;
+            self.dialed = parked.dialed;
+            self.liveness = parked.liveness;
+        }
+    }
+
+    /// `)`: count the open channels and find one with data waiting
+    pub(crate) fn channel_status(&mut self) {
+        self.close_idle_parked();
+        let open = self.parked.len() + usize::from(self.connection.is_some());
+        let active = match self.connection {
+            Some(ref connection) => !self.pushback.is_empty() || connection.has_pending(),
+            None => false,
+        };
+        // In channel order, so the first one found is the lowest
+        let parked = self
+            .parked
+            .iter()
+            .find(|(_, parked)| !parked.pushback.is_empty() || parked.connection.has_pending())
+            .map(|(&channel, _)| channel);
+        let pending = match (active, parked) {
+            (true, Some(channel)) => channel.min(self.channel),
+            (true, None) => self.channel,
+            (false, channel) => channel.unwrap_or(0),
+        };
+        let len = self.tape.len();
+        self.tape[self.pointer] = open as u8;
+        self.tape[(self.pointer + 1) % len] = pending;
+    }
+
+    /// Close the connections on inactive channels
+    pub(crate) fn close_parked(&mut self) {
+        for (_, mut parked) in std::mem::take(&mut self.parked) {
+            parked.connection.shutdown();
+            eprintln!("[trainfuck] Connection closed");
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! `trainfuck chat-server`: host many chat users around one program
+//!
+//! When the program listens with `$`, the runtime opens the TCP listener at
+//! that address itself and takes care of the users that connect: the first
+//! line each one sends is their nickname, `/join room` moves them to
+//! another room (everyone starts in `lobby`), and `/who` lists who is in
+//! theirs. Everything else they say reaches the program as a single
+//! conversation, the one `@` accepts, one line at a time prefixed with the
+//! sender's `nick: `. Each line the program sends back is relayed to the
+//! room of the message it read last, so the echo server in
+//! `chat/server.bf` turns into a minimal IRC.
+//!
+//! With `--room-cell`, the program also finds the name of that room on its
+//! tape (see [`VM::room_cell`](trainfuck::interpreter::VM::room_cell)), to
+//! treat rooms differently.
+//!
+//! With `--history FILE`, the lines relayed to each room are appended to
+//! `FILE`, one `room<TAB>line` per line, and users are shown the last few
+//! said in a room when they arrive there, even after a restart.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use trainfuck::net::{Listener, Network, Stream};
+
+/// Longest nickname or room name, in bytes
+const MAX_NAME: usize = 16;
+
+/// Longest line a user may send; the rest is cut off
+const MAX_LINE: usize = 400;
+
+/// The room users start in
+const LOBBY: &str = "lobby";
+
+/// A connected user
+struct User {
+    /// None until the first line registers one
+    nick: Option<String>,
+    room: String,
+    writer: TcpStream,
+}
+
+/// A user's line for the program, and the room it was said in
+struct Message {
+    room: String,
+    line: Vec<u8>,
+}
+
+/// Lines relayed to each room, on disk and the most recent in memory
+pub struct ChatLog {
+    file: File,
+    /// Lines to replay
+    keep: usize,
+    recent: HashMap<String, VecDeque<Vec<u8>>>,
+}
+
+impl ChatLog {
+    /// Open the log at `path`, creating it if needed, and load the last
+    /// `keep` lines of each room
+    pub fn open(path: &Path, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut log = ChatLog {
+            file,
+            keep,
+            recent: HashMap::new(),
+        };
+        for entry in BufReader::new(log.file.try_clone()?).split(b'\n') {
+            let mut entry = entry?;
+            let Some(tab) = entry.iter().position(|&b| b == b'\t') else {
+                continue;
+            };
+            let line = entry.split_off(tab + 1);
+            let room = String::from_utf8_lossy(&entry[..tab]).into_owned();
+            log.remember(room, line);
+        }
+        Ok(log)
+    }
+
+    fn remember(&mut self, room: String, mut line: Vec<u8>) {
+        if self.keep == 0 {
+            return;
+        }
+        if line.last() != Some(&b'\n') {
+            line.push(b'\n');
+        }
+        let recent = self.recent.entry(room).or_default();
+        if recent.len() == self.keep {
+            recent.pop_front();
+        }
+        recent.push_back(line);
+    }
+
+    /// Append a line relayed to `room`
+    fn record(&mut self, room: &str, line: &[u8]) {
+        let mut entry = format!("{}\t", room).into_bytes();
+        entry.extend(line.strip_suffix(b"\n").unwrap_or(line));
+        entry.push(b'\n');
+        if let Err(e) = self.file.write_all(&entry) {
+            eprintln!("[trainfuck] Writing chat history failed: {}", e);
+        }
+        self.remember(room.to_string(), line.to_vec());
+    }
+}
+
+/// The users and the conversation with the program
+#[derive(Clone)]
+pub struct ChatHub {
+    users: Arc<Mutex<BTreeMap<u64, User>>>,
+    /// User lines on their way to the program
+    to_program: Sender<Message>,
+    from_users: Arc<Mutex<Receiver<Message>>>,
+    log: Option<Arc<Mutex<ChatLog>>>,
+}
+
+impl ChatHub {
+    pub fn new(log: Option<ChatLog>) -> Self {
+        let (to_program, from_users) = mpsc::channel();
+        ChatHub {
+            users: Arc::default(),
+            to_program,
+            from_users: Arc::new(Mutex::new(from_users)),
+            log: log.map(|log| Arc::new(Mutex::new(log))),
+        }
+    }
+
+    /// Take users on `listener` from a background thread
+    fn serve(&self, listener: TcpListener) {
+        let hub = self.clone();
+        thread::spawn(move || {
+            for (id, stream) in (1..).zip(listener.incoming()) {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("[trainfuck] Accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let Ok(writer) = stream.try_clone() else {
+                    continue;
+                };
+                match stream.peer_addr() {
+                    Ok(peer) => eprintln!("[trainfuck] Accepted connection from {}", peer),
+                    Err(_) => eprintln!("[trainfuck] Accepted connection"),
+                }
+                let _ = (&writer).write_all(b"* Welcome! What's your nickname?\n");
+                let user = User {
+                    nick: None,
+                    room: LOBBY.to_string(),
+                    writer,
+                };
+                hub.users.lock().unwrap().insert(id, user);
+                let hub = hub.clone();
+                thread::spawn(move || hub.converse(id, BufReader::new(stream)));
+            }
+        });
+    }
+
+    /// Handle one user's lines until they disconnect
+    fn converse(&self, id: u64, reader: impl BufRead) {
+        for line in reader.split(b'\n') {
+            let Ok(mut line) = line else {
+                break;
+            };
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            line.truncate(MAX_LINE);
+            let line = String::from_utf8_lossy(&line);
+            let (nick, room) = {
+                let users = self.users.lock().unwrap();
+                (users[&id].nick.clone(), users[&id].room.clone())
+            };
+            let Some(nick) = nick else {
+                self.register(id, line.trim());
+                continue;
+            };
+            match line.trim().split_once(' ').unwrap_or((line.trim(), "")) {
+                ("/who", _) => {
+                    let nicks = self.nicks(&room).join(", ");
+                    self.tell(id, &format!("* In {}: {}", room, nicks));
+                }
+                ("/join", new) => self.join(id, &nick, &room, new.trim()),
+                _ => {
+                    let line = format!("{}: {}\n", nick, line).into_bytes();
+                    let _ = self.to_program.send(Message { room, line });
+                }
+            }
+        }
+
+        let user = self.users.lock().unwrap().remove(&id);
+        if let Some(User {
+            nick: Some(nick),
+            room,
+            ..
+        }) = user
+        {
+            self.announce(&room, &format!("* {} left", nick));
+        }
+    }
+
+    /// Give user `id` the nickname `nick`, if it's valid and free
+    fn register(&self, id: u64, nick: &str) {
+        if !valid_name(nick) {
+            self.tell(id, &format!("* Nicknames are {}; try again", NAME_RULE));
+            return;
+        }
+        let taken = {
+            let mut users = self.users.lock().unwrap();
+            let taken = users.values().any(|user| {
+                user.nick
+                    .as_deref()
+                    .is_some_and(|n| n.eq_ignore_ascii_case(nick))
+            });
+            if !taken {
+                users.get_mut(&id).unwrap().nick = Some(nick.to_string());
+            }
+            taken
+        };
+        if taken {
+            self.tell(id, &format!("* {} is taken; try another", nick));
+            return;
+        }
+        eprintln!("[trainfuck] {} joined", nick);
+        self.tell(
+            id,
+            &format!("* Hi {}! /who lists who's here, /join room moves you", nick),
+        );
+        self.replay(id, LOBBY);
+        self.announce(LOBBY, &format!("* {} joined", nick));
+    }
+
+    /// Move user `id` from room `from` to room `to`
+    fn join(&self, id: u64, nick: &str, from: &str, to: &str) {
+        if !valid_name(to) {
+            self.tell(id, &format!("* Room names are {}", NAME_RULE));
+            return;
+        }
+        if to == from {
+            return;
+        }
+        if let Some(user) = self.users.lock().unwrap().get_mut(&id) {
+            user.room = to.to_string();
+        }
+        self.announce(from, &format!("* {} left for {}", nick, to));
+        self.replay(id, to);
+        self.announce(to, &format!("* {} joined {}", nick, to));
+    }
+
+    /// Show user `id` the last lines said in `room`
+    fn replay(&self, id: u64, room: &str) {
+        let Some(ref log) = self.log else {
+            return;
+        };
+        let log = log.lock().unwrap();
+        let Some(recent) = log.recent.get(room) else {
+            return;
+        };
+        if let Some(user) = self.users.lock().unwrap().get(&id) {
+            for line in recent {
+                let _ = (&user.writer).write_all(line);
+            }
+        }
+    }
+
+    /// Registered nicknames in `room`, in the order they connected
+    fn nicks(&self, room: &str) -> Vec<String> {
+        let users = self.users.lock().unwrap();
+        let here = users.values().filter(|user| user.room == room);
+        here.filter_map(|user| user.nick.clone()).collect()
+    }
+
+    /// Send a line to one user
+    fn tell(&self, id: u64, line: &str) {
+        if let Some(user) = self.users.lock().unwrap().get(&id) {
+            let _ = (&user.writer).write_all(format!("{}\n", line).as_bytes());
+        }
+    }
+
+    /// Send a line to every registered user in `room`
+    fn announce(&self, room: &str, line: &str) {
+        self.relay(Some(room), format!("{}\n", line).as_bytes());
+    }
+
+    /// Send bytes to every registered user in `room`, or everywhere
+    fn relay(&self, room: Option<&str>, bytes: &[u8]) {
+        for user in self.users.lock().unwrap().values() {
+            if user.nick.is_some() && room.is_none_or(|room| user.room == room) {
+                let _ = (&user.writer).write_all(bytes);
+            }
+        }
+    }
+
+    /// A view of the conversation, for the program's `@`
+    fn stream(&self) -> ChatStream {
+        ChatStream {
+            hub: self.clone(),
+            pending: Vec::new(),
+            room: None,
+            line: Vec::new(),
+        }
+    }
+}
+
+/// What nicknames and room names may look like
+const NAME_RULE: &str = "1 to 16 letters, digits, '_' or '-'";
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// User lines in, program lines out to their room
+struct ChatStream {
+    hub: ChatHub,
+    /// Rest of a user line not read yet
+    pending: Vec<u8>,
+    /// Room of the line read last; none before the first
+    room: Option<String>,
+    /// Output since the last newline
+    line: Vec<u8>,
+}
+
+impl ChatStream {
+    fn relay_line(&mut self) {
+        self.hub.relay(self.room.as_deref(), &self.line);
+        // Lines sent before anyone spoke went to every room
+        if let (Some(log), Some(room)) = (&self.hub.log, &self.room) {
+            log.lock().unwrap().record(room, &self.line);
+        }
+        self.line.clear();
+    }
+}
+
+impl Read for ChatStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.hub.from_users.lock().unwrap().recv() {
+                Ok(message) => {
+                    self.pending = message.line;
+                    self.room = Some(message.room);
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for ChatStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.line.push(byte);
+            if byte == b'\n' || self.line.len() >= MAX_LINE {
+                self.relay_line();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Lines are only relayed once complete, as `'` flushes after every byte
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for ChatStream {
+    fn room(&self) -> Option<&[u8]> {
+        self.room.as_ref().map(|room| room.as_bytes())
+    }
+}
+
+impl Drop for ChatStream {
+    /// Relay a last line that wasn't ended with a newline
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.relay_line();
+        }
+    }
+}
+
+/// `$` opens the users' listener, and `@` leads to their conversation
+pub struct ChatNetwork(pub ChatHub);
+
+impl Network for ChatNetwork {
+    fn listen(&mut self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        self.0.serve(trainfuck::net::bind(addr)?);
+        Ok(Box::new(ChatListener(self.0.clone())))
+    }
+
+    fn connect(&mut self, _addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "chat-server programs listen with `$` rather than dial",
+        ))
+    }
+}
+
+struct ChatListener(ChatHub);
+
+impl Listener for ChatListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(self.0.stream()))
+    }
+}
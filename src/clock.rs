@@ -0,0 +1,59 @@
+//! Where a VM's time comes from (`--deterministic`)
+//!
+//! Time reaches a program through the Unix time in `--peer-cell` records,
+//! `--step-delay` pacing, the backoff between `%` retries and the sandbox's
+//! time limit. On [`Clock::Virtual`] none of them look at the real clock:
+//! virtual time starts at the Unix epoch, every op takes [`VIRTUAL_STEP`]
+//! of it and waits pass instantly, so a program fed the same input sees the
+//! same times however busy the machine is.
+
+use crate::interpreter::VM;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Virtual time each op takes
+pub const VIRTUAL_STEP: Duration = Duration::from_micros(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Clock {
+    /// The machine's clocks
+    #[default]
+    System,
+    /// Time that only moves with the program
+    Virtual,
+}
+
+impl VM {
+    /// The current time on the VM's clock
+    pub(crate) fn now(&self) -> Instant {
+        match self.clock {
+            Clock::System => Instant::now(),
+            Clock::Virtual => self.started + self.virtual_elapsed(),
+        }
+    }
+
+    /// Time since the Unix epoch on the VM's clock
+    pub(crate) fn unix_time(&self) -> Duration {
+        match self.clock {
+            Clock::System => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            Clock::Virtual => self.virtual_elapsed(),
+        }
+    }
+
+    /// Let `duration` pass: sleep through it on the system clock, skip
+    /// ahead on the virtual one
+    pub(crate) fn sleep(&mut self, duration: Duration) {
+        match self.clock {
+            Clock::System => thread::sleep(duration),
+            Clock::Virtual => self.waited += duration,
+        }
+    }
+
+    /// Virtual time since the VM was created
+    pub(crate) fn virtual_elapsed(&self) -> Duration {
+        let steps = (VIRTUAL_STEP.as_nanos() as u64).saturating_mul(self.steps);
+        self.waited + Duration::from_nanos(steps)
+    }
+}
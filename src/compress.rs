@@ -0,0 +1,220 @@
+//! Compressed connections (`--compress`, `compress` feature)
+//!
+//! [`CompressedNetwork`] wraps another [`Network`] so every connection
+//! carries a gzip or zstd stream in each direction; both ends must use the
+//! same codec. `'` flushes after every byte, which would cost a compressed
+//! block per byte, so sent bytes are batched instead and flushed at the end
+//! of each line, before the program waits on `` ` ``, and when the
+//! connection closes.
+
+use crate::net::{Listener, Network, Stream};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Decoded bytes asked for at once. The decoders only return output they
+/// hold once asked for enough of it, and otherwise wait on the socket for
+/// more input first, so `` ` `` reading one byte at a time could stall
+/// with the rest of a message already received.
+const DECODE_CHUNK: usize = 64 * 1024;
+
+/// Stream format for [`CompressedNetwork`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+/// A [`Network`] whose connections are compressed with a [`Codec`]
+pub struct CompressedNetwork {
+    inner: Box<dyn Network>,
+    codec: Codec,
+}
+
+impl CompressedNetwork {
+    pub fn new(inner: Box<dyn Network>, codec: Codec) -> Self {
+        CompressedNetwork { inner, codec }
+    }
+}
+
+impl Network for CompressedNetwork {
+    fn listen(&mut self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(CompressedListener {
+            inner: self.inner.listen(addr)?,
+            codec: self.codec,
+        }))
+    }
+
+    fn connect(&mut self, addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        CompressedStream::wrap(self.inner.connect(addr)?, self.codec)
+    }
+}
+
+struct CompressedListener {
+    inner: Box<dyn Listener>,
+    codec: Codec,
+}
+
+impl Listener for CompressedListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        CompressedStream::wrap(self.inner.accept()?, self.codec)
+    }
+
+    fn accept_timeout(&mut self, timeout: Duration) -> io::Result<Option<Box<dyn Stream>>> {
+        match self.inner.accept_timeout(timeout)? {
+            Some(stream) => CompressedStream::wrap(stream, self.codec).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+/// One side of a connection, for an encoder or decoder to own
+struct Half(Rc<RefCell<Box<dyn Stream>>>);
+
+impl Read for Half {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+impl Write for Half {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// An encoder that can end its stream without being consumed
+trait Encoder: Write {
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+impl Encoder for GzEncoder<Half> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()
+    }
+}
+
+impl Encoder for zstd::stream::write::Encoder<'static, Half> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.do_finish()
+    }
+}
+
+struct CompressedStream {
+    inner: Rc<RefCell<Box<dyn Stream>>>,
+    codec: Codec,
+    /// Created on the first read, as reading the gzip header waits for the
+    /// peer to send something
+    decoder: Option<Box<dyn Read>>,
+    encoder: Box<dyn Encoder>,
+    /// Decoded bytes not read yet
+    decoded: Vec<u8>,
+    /// Start of the unread part of `decoded`
+    offset: usize,
+    /// Whether bytes were written since the last flush
+    unflushed: bool,
+}
+
+impl CompressedStream {
+    fn wrap(stream: Box<dyn Stream>, codec: Codec) -> io::Result<Box<dyn Stream>> {
+        let inner = Rc::new(RefCell::new(stream));
+        let writer = Half(inner.clone());
+        let encoder: Box<dyn Encoder> = match codec {
+            Codec::Gzip => Box::new(GzEncoder::new(writer, flate2::Compression::default())),
+            Codec::Zstd => Box::new(zstd::stream::write::Encoder::new(writer, 0)?),
+        };
+        Ok(Box::new(CompressedStream {
+            inner,
+            codec,
+            decoder: None,
+            encoder,
+            decoded: Vec::new(),
+            offset: 0,
+            unflushed: false,
+        }))
+    }
+
+    fn decoder(&mut self) -> io::Result<&mut Box<dyn Read>> {
+        if self.decoder.is_none() {
+            let reader = Half(self.inner.clone());
+            self.decoder = Some(match self.codec {
+                Codec::Gzip => Box::new(GzDecoder::new(reader)),
+                Codec::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+            });
+        }
+        Ok(self.decoder.as_mut().unwrap())
+    }
+
+    fn send_batch(&mut self) -> io::Result<()> {
+        if self.unflushed {
+            self.encoder.flush()?;
+            self.unflushed = false;
+        }
+        Ok(())
+    }
+}
+
+impl Read for CompressedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // The peer may be waiting for what we sent before answering
+        self.send_batch()?;
+        if self.offset == self.decoded.len() {
+            let mut decoded = std::mem::take(&mut self.decoded);
+            self.offset = 0;
+            decoded.resize(DECODE_CHUNK, 0);
+            let n = self.decoder()?.read(&mut decoded)?;
+            decoded.truncate(n);
+            self.decoded = decoded;
+        }
+        let n = buf.len().min(self.decoded.len() - self.offset);
+        buf[..n].copy_from_slice(&self.decoded[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+impl Write for CompressedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.encoder.write(buf)?;
+        self.unflushed |= n > 0;
+        if buf[..n].contains(&b'\n') {
+            self.send_batch()?;
+        }
+        Ok(n)
+    }
+
+    /// Batched: see the module docs for when bytes actually go out
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for CompressedStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.borrow().peer_addr()
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.encoder.finish();
+        self.inner.borrow_mut().shutdown()
+    }
+}
+
+impl Drop for CompressedStream {
+    fn drop(&mut self) {
+        // End the stream properly so the peer sees a clean end of data
+        let _ = self.encoder.finish();
+    }
+}
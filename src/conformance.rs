@@ -0,0 +1,302 @@
+//! `trainfuck conformance`: which Brainfuck semantics this build implements
+//!
+//! Runs the programs in `conformance/`, bundled into the executable, under
+//! every optimization level and tape model, or just those the flags pick.
+//! Tests have one right answer: bracket matching, skipped and deeply nested
+//! loops, unmatched brackets rejected when parsing. Probes have none, since
+//! Brainfuck interpreters disagree on them; their output shows what `,` does
+//! at the end of input, how wide cells are, what `-` does at zero and what
+//! lies past either end of the tape. Each program runs in a fresh
+//! networkless VM with a step limit, so a probe that walks off into an
+//! unbounded tape stops instead of running forever.
+
+use std::cell::RefCell;
+use std::io::{self, Cursor, Write};
+use std::rc::Rc;
+use trainfuck::interpreter::{TapeModel, TrainfuckError, VM};
+use trainfuck::pipeline::Pipeline;
+use trainfuck::program::{Metadata, Program};
+
+pub struct ConformanceConfig {
+    /// Optimization levels to run each program at
+    pub opt_levels: Vec<u8>,
+    pub tape_models: Vec<TapeModel>,
+    pub tape_size: usize,
+    pub max_steps: u64,
+}
+
+/// A bundled program and how to read its output
+struct Case {
+    name: &'static str,
+    source: &'static [u8],
+    check: Check,
+}
+
+enum Check {
+    /// A test, passing when the program prints this
+    Output(&'static [u8]),
+    /// A test, passing when the program doesn't parse
+    ParseError,
+    /// A probe, describing what a run found
+    Probe(fn(&Run) -> Option<&'static str>),
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "hello",
+        source: include_bytes!("../conformance/hello.b"),
+        check: Check::Output(b"Hello World!\n"),
+    },
+    Case {
+        name: "skip",
+        source: include_bytes!("../conformance/skip.b"),
+        check: Check::Output(b"ok\n"),
+    },
+    Case {
+        name: "nesting",
+        source: include_bytes!("../conformance/nesting.b"),
+        check: Check::Output(b"ok\n"),
+    },
+    Case {
+        name: "brackets",
+        source: include_bytes!("../conformance/brackets.b"),
+        check: Check::Output(b"94\n"),
+    },
+    Case {
+        name: "unmatched-close",
+        source: include_bytes!("../conformance/unmatched-close.b"),
+        check: Check::ParseError,
+    },
+    Case {
+        name: "unmatched-open",
+        source: include_bytes!("../conformance/unmatched-open.b"),
+        check: Check::ParseError,
+    },
+    Case {
+        name: "eof",
+        source: include_bytes!("../conformance/eof.b"),
+        check: Check::Probe(|run| match run.finished()? {
+            [0] => Some("`,` at the end of input sets the cell to 0"),
+            [5] => Some("`,` at the end of input leaves the cell unchanged"),
+            [255] => Some("`,` at the end of input sets the cell to -1 (255)"),
+            _ => None,
+        }),
+    },
+    Case {
+        name: "cells",
+        source: include_bytes!("../conformance/cells.b"),
+        check: Check::Probe(|run| match run.finished()? {
+            [1] => Some("8-bit cells: 256 wraps around to 0"),
+            [0] => Some("cells wider than 8 bits"),
+            _ => None,
+        }),
+    },
+    Case {
+        name: "below-zero",
+        source: include_bytes!("../conformance/below-zero.b"),
+        check: Check::Probe(|run| match (&run.outcome, &run.output[..]) {
+            (Outcome::Finished, [255]) => Some("`-` on 0 wraps around to 255"),
+            (Outcome::Finished, [0]) => Some("`-` on 0 leaves it at 0"),
+            (Outcome::Unfinished, _) => Some("`-` on 0 goes below 0"),
+            _ => None,
+        }),
+    },
+    Case {
+        name: "left-edge",
+        source: include_bytes!("../conformance/left-edge.b"),
+        check: Check::Probe(|run| edge(run, true)),
+    },
+    Case {
+        name: "right-edge",
+        source: include_bytes!("../conformance/right-edge.b"),
+        check: Check::Probe(|run| edge(run, false)),
+    },
+];
+
+/// What the probe walking off the left end of the tape, or the right,
+/// found
+fn edge(run: &Run, left: bool) -> Option<&'static str> {
+    match (&run.outcome, &run.output[..], left) {
+        (Outcome::Finished, [1], true) => Some("`<` on the first cell wraps around to the last"),
+        (Outcome::Finished, [1], false) => Some("`>` on the last cell wraps around to the first"),
+        (Outcome::Unfinished, _, true) => Some("`<` on the first cell adds cells there"),
+        (Outcome::Unfinished, _, false) => Some("`>` on the last cell adds cells there"),
+        _ => None,
+    }
+}
+
+/// Run every case under every configuration, printing what each found;
+/// exits 1 if any test failed
+pub fn run(config: &ConformanceConfig) -> i32 {
+    let configurations: Vec<(u8, TapeModel)> = config
+        .opt_levels
+        .iter()
+        .flat_map(|&level| config.tape_models.iter().map(move |&model| (level, model)))
+        .collect();
+
+    let mut failed = 0;
+    for case in CASES {
+        // Configurations by what the case found under them, in the order
+        // first found
+        let mut found: Vec<(String, bool, Vec<String>)> = Vec::new();
+        for &(level, model) in &configurations {
+            let (result, passed) = case.result(level, model, config);
+            let label = label(level, model);
+            match found.iter_mut().find(|(seen, _, _)| *seen == result) {
+                Some((_, _, labels)) => labels.push(label),
+                None => found.push((result, passed, vec![label])),
+            }
+        }
+        let kind = match case.check {
+            Check::Probe(_) => "probe",
+            _ => "test",
+        };
+        if found.iter().any(|&(_, passed, _)| !passed) {
+            failed += 1;
+        }
+        match &found[..] {
+            [(result, _, _)] => println!("{:<5} {:<16} {}", kind, case.name, result),
+            _ => {
+                println!(
+                    "{:<5} {:<16} depends on the configuration:",
+                    kind, case.name
+                );
+                for (result, _, labels) in &found {
+                    println!("{:<22}   {} ({})", "", result, labels.join(", "));
+                }
+            }
+        }
+    }
+
+    println!(
+        "{} programs under {} configuration{} (tape of {} cells): {}",
+        CASES.len(),
+        configurations.len(),
+        if configurations.len() == 1 { "" } else { "s" },
+        config.tape_size,
+        match failed {
+            0 => "every test passed".to_string(),
+            1 => "1 program failed".to_string(),
+            n => format!("{} programs failed", n),
+        }
+    );
+    i32::from(failed > 0)
+}
+
+/// `-O1 classic`
+fn label(level: u8, model: TapeModel) -> String {
+    let model = match model {
+        TapeModel::Classic => "classic",
+        TapeModel::Unbounded => "unbounded",
+    };
+    format!("-O{} {}", level, model)
+}
+
+impl Case {
+    /// What this case found under `-O<level>` on a `model` tape, and
+    /// whether that is a pass
+    fn result(&self, level: u8, model: TapeModel, config: &ConformanceConfig) -> (String, bool) {
+        let program = match Program::parse(self.source, Default::default()) {
+            Ok(program) => program,
+            Err(e) => {
+                return match self.check {
+                    Check::ParseError => (format!("pass: rejected ({})", e), true),
+                    _ => (format!("FAIL: parse error: {}", e), false),
+                }
+            }
+        };
+        let run = Run::new(&program, level, model, config);
+        match self.check {
+            Check::Output(expected) if run.finished() == Some(expected) => {
+                ("pass".to_string(), true)
+            }
+            Check::Output(expected) => (
+                format!(
+                    "FAIL: expected {:?}, got {}",
+                    String::from_utf8_lossy(expected),
+                    run
+                ),
+                false,
+            ),
+            Check::ParseError => ("FAIL: parsed".to_string(), false),
+            Check::Probe(describe) => match describe(&run) {
+                Some(description) => (description.to_string(), true),
+                None => (format!("unexpected result: {}", run), true),
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Finished,
+    Failed(String),
+    /// Hit the step limit
+    Unfinished,
+}
+
+struct Run {
+    output: Vec<u8>,
+    outcome: Outcome,
+}
+
+impl Run {
+    fn new(program: &Program, level: u8, model: TapeModel, config: &ConformanceConfig) -> Run {
+        let mut pipeline = Pipeline::level(level, config.tape_size);
+        // Both -O2 passes assume a tape that wraps, as `run` knows too
+        if model == TapeModel::Unbounded {
+            pipeline.retain(|name| name != "evaluate" && name != "fold");
+        }
+        let (ops, spans) = pipeline.run(program.ops().to_vec(), program.spans());
+        let metadata = Metadata {
+            optimized: level > 0,
+            ..program.metadata().clone()
+        };
+
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::with_tape_size(config.tape_size);
+        vm.tape_model = model;
+        vm.input = Box::new(Cursor::new(Vec::new()));
+        vm.output = Box::new(Collect(output.clone()));
+        vm.network_enabled = false;
+        vm.max_steps = Some(config.max_steps);
+        let outcome = match vm.execute_program(&Program::new(ops, spans, metadata)) {
+            Ok(()) => Outcome::Finished,
+            Err(TrainfuckError::StepLimitExceeded(_)) => Outcome::Unfinished,
+            Err(e) => Outcome::Failed(e.to_string()),
+        };
+        Run {
+            output: output.take(),
+            outcome,
+        }
+    }
+
+    /// The output, if the program ran to its end
+    fn finished(&self) -> Option<&[u8]> {
+        (self.outcome == Outcome::Finished).then_some(&self.output[..])
+    }
+}
+
+impl std::fmt::Display for Run {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(&self.output))?;
+        match &self.outcome {
+            Outcome::Finished => Ok(()),
+            Outcome::Failed(e) => write!(f, " then error: {}", e),
+            Outcome::Unfinished => write!(f, " before hitting the step limit"),
+        }
+    }
+}
+
+struct Collect(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Collect {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,573 @@
+//! JSON-RPC control interface (`--control /tmp/tf.sock`)
+//!
+//! External tools connect to a Unix socket and send newline-delimited
+//! JSON-RPC 2.0 requests. The socket is served on a background thread, but
+//! every request is executed by the VM itself at an instruction boundary, so
+//! tape reads and writes never race with the running program. Requests sent
+//! while the VM is blocked (e.g. in `@` waiting for a client) are answered
+//! once the blocking op returns.
+//!
+//! Methods:
+//! - `pause`, `resume`
+//! - `step` `{"count": N}`: run N more steps, then pause again
+//! - `status`: pointer, steps executed, paused flag
+//! - `read_tape` `{"start": S, "len": N}`
+//! - `set_cell` `{"index": I, "value": V}`
+//! - `connections`: open connections with their ids, peers, direction and
+//!   bytes received and sent
+//! - `listener`: address `$` is listening on, connections accepted so far
+//!   and how long `@` waits
+//! - `history` `{"count": N}`: the last N instructions recorded with
+//!   `--record-history`, oldest first
+//! - `cell_history` `{"index": I, "count": N}`: the last N recorded
+//!   instructions that changed cell I, most recent first
+//! - `tape_at` `{"back": K, "start": S, "len": N}`: pointer and tape as they
+//!   were K recorded instructions ago
+//! - `break` `{"line": L, "column": C, "if": "cell(3) > 10"}`: pause before
+//!   the instructions at that position while the condition holds (column
+//!   and condition are optional; see [`debug`](crate::debug))
+//! - `delete` `{"id": I}`, `breakpoints`
+//! - `evaluate` `{"expr": "cell(3)"}`: a value, or whether a condition holds
+//!
+//! A [`Script`] of the same commands can drive the VM instead of, or
+//! before, a socket client. There `info WHAT` runs method `WHAT`, as in
+//! `info connections` or `info breakpoints`.
+
+use crate::bytecode::Bytecode;
+use crate::debug::{self, Breakpoint, Condition, Positions, Script};
+use crate::history::Entry;
+use crate::interpreter::VM;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// How often a paused VM wakes up to check for Ctrl-C
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Largest tape range `read_tape` returns in one call
+const MAX_READ_LEN: usize = 65_536;
+
+struct ControlRequest {
+    method: String,
+    params: Value,
+    reply: Sender<RpcResult>,
+}
+
+/// A result value or a JSON-RPC error code and message
+type RpcResult = std::result::Result<Value, (i32, String)>;
+
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const NOT_RECORDING: i32 = -32001;
+
+/// Every method the controller answers, for the socket and scripts alike
+const METHODS: &[&str] = &[
+    "pause",
+    "resume",
+    "step",
+    "status",
+    "read_tape",
+    "set_cell",
+    "connections",
+    "listener",
+    "break",
+    "delete",
+    "breakpoints",
+    "evaluate",
+    "history",
+    "cell_history",
+    "tape_at",
+];
+
+/// VM-side end of the control socket
+pub struct Controller {
+    rx: Receiver<ControlRequest>,
+    paused: bool,
+    /// Steps left before pausing again after a `step` request
+    pending_steps: u64,
+    /// Source positions of the running program, once known
+    positions: Option<Positions>,
+    breakpoints: Vec<Breakpoint>,
+    next_breakpoint: usize,
+    /// The breakpoint the VM last paused at
+    hit: Option<usize>,
+    script: Option<Script>,
+    /// Script lines that failed so far
+    script_errors: usize,
+    /// Whether a socket client may still send commands
+    served: bool,
+}
+
+impl Controller {
+    /// Bind the control socket and start serving it in the background
+    pub fn bind(path: &Path) -> io::Result<Controller> {
+        // A socket left behind by a previous run would make bind fail
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        eprintln!("[trainfuck] Control socket at {:?}", path);
+
+        let (tx, rx) = mpsc::channel();
+        let mut controller = Controller::new(rx);
+        controller.served = true;
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve_client(stream, tx) {
+                        eprintln!("[trainfuck] Control client error: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(controller)
+    }
+
+    /// A controller with no socket, to be driven by a [`Script`]
+    pub fn without_socket() -> Controller {
+        Controller::new(mpsc::channel().1)
+    }
+
+    fn new(rx: Receiver<ControlRequest>) -> Controller {
+        Controller {
+            rx,
+            paused: false,
+            pending_steps: 0,
+            positions: None,
+            breakpoints: Vec::new(),
+            next_breakpoint: 1,
+            hit: None,
+            script: None,
+            script_errors: 0,
+            served: false,
+        }
+    }
+
+    /// Tell the controller which program runs, so breakpoints can be set by
+    /// source position and reported instructions located
+    pub fn set_program(&mut self, bytecode: &Bytecode, source: &[u8]) {
+        self.positions = Some(Positions::new(bytecode, source));
+    }
+
+    /// Run `script` before the first instruction, and on from there each
+    /// time the VM pauses; the program runs on once it is used up
+    pub fn run_script(&mut self, script: Script) {
+        self.script = Some(script);
+        self.paused = true;
+    }
+
+    /// Called by the VM before every step: answers queued requests and
+    /// blocks for as long as the VM is paused
+    pub fn poll(&mut self, vm: &mut VM) {
+        while let Ok(request) = self.rx.try_recv() {
+            self.answer(vm, request);
+        }
+
+        if self.pending_steps > 0 {
+            self.pending_steps -= 1;
+            return;
+        }
+
+        if !self.paused && !self.breakpoints.is_empty() {
+            self.check_breakpoints(vm);
+        }
+
+        while self.paused && self.pending_steps == 0 {
+            if vm
+                .interrupt
+                .as_ref()
+                .is_some_and(|flag| flag.load(Ordering::Relaxed))
+            {
+                return;
+            }
+            if let Some(ref mut script) = self.script {
+                match script.next() {
+                    Some((line, command)) => self.run_command(vm, line, &command),
+                    None => {
+                        self.script = None;
+                        self.paused = false;
+                        if !self.served {
+                            // Nobody is left to continue from them
+                            self.breakpoints.clear();
+                        }
+                    }
+                }
+                continue;
+            }
+            match self.rx.recv_timeout(PAUSE_POLL_INTERVAL) {
+                Ok(request) => self.answer(vm, request),
+                Err(RecvTimeoutError::Timeout) => {}
+                // Nobody can resume us any more
+                Err(RecvTimeoutError::Disconnected) => self.paused = false,
+            }
+        }
+
+        if self.pending_steps > 0 {
+            self.pending_steps -= 1;
+        }
+    }
+
+    /// Pause if the VM is at a breakpoint whose condition holds
+    fn check_breakpoints(&mut self, vm: &VM) {
+        let Some(pc) = vm.current_pc else {
+            return;
+        };
+        let hit = self.breakpoints.iter().find(|breakpoint| {
+            breakpoint.pcs.binary_search(&pc).is_ok()
+                && breakpoint
+                    .condition
+                    .as_ref()
+                    .is_none_or(|condition| condition.holds(vm))
+        });
+        if let Some(breakpoint) = hit {
+            let (line, column) = self.locate(pc).unwrap_or((breakpoint.line, 0));
+            eprintln!(
+                "[trainfuck] Breakpoint {} at {}:{} (step {})",
+                breakpoint.id,
+                line,
+                column,
+                vm.steps()
+            );
+            self.hit = Some(breakpoint.id);
+            self.paused = true;
+        }
+    }
+
+    /// Run one line of a debugger script, printing the result
+    fn run_command(&mut self, vm: &mut VM, line: usize, command: &str) {
+        let (word, rest) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(word, rest)| (word, rest.trim()));
+        let request = match word {
+            "break" => {
+                let (location, condition) = match rest.split_once(" if ") {
+                    Some((location, condition)) => (location.trim(), Some(condition.trim())),
+                    None => (rest, None),
+                };
+                debug::parse_location(location).map(|(line, column)| {
+                    (
+                        "break",
+                        json!({ "line": line, "column": column, "if": condition }),
+                    )
+                })
+            }
+            "delete" => rest
+                .parse::<u64>()
+                .map(|id| ("delete", json!({ "id": id })))
+                .map_err(|_| format!("expected a breakpoint id, not {:?}", rest)),
+            "continue" => Ok(("resume", Value::Null)),
+            "step" => match rest {
+                "" => Ok(("step", Value::Null)),
+                count => count
+                    .parse::<u64>()
+                    .map(|count| ("step", json!({ "count": count })))
+                    .map_err(|_| format!("expected a step count, not {:?}", count)),
+            },
+            "print" => Ok(("evaluate", json!({ "expr": rest }))),
+            "info" => match rest {
+                "connections" | "listener" | "breakpoints" => Ok((rest, Value::Null)),
+                _ => Err(format!(
+                    "expected info connections, listener or breakpoints, not {:?}",
+                    command
+                )),
+            },
+            "tape" => {
+                let numbers: Vec<_> = rest.split_whitespace().map(str::parse::<u64>).collect();
+                match numbers[..] {
+                    [Ok(start), Ok(len)] => {
+                        Ok(("read_tape", json!({ "start": start, "len": len })))
+                    }
+                    _ => Err(format!("expected tape START LEN, not {:?}", command)),
+                }
+            }
+            method if !METHODS.contains(&method) => {
+                Err(format!("unknown command {:?}", method))
+            }
+            // Any other control method, with its params as JSON
+            method => match rest {
+                "" => Ok((method, Value::Null)),
+                params => serde_json::from_str(params)
+                    .map(|params| (method, params))
+                    .map_err(|e| format!("invalid params for {}: {}", method, e)),
+            },
+        };
+        let result = request
+            .and_then(|(method, params)| self.dispatch(vm, method, &params).map_err(|(_, e)| e));
+        match result {
+            Ok(value) => eprintln!("[trainfuck] {}: {}", command, value),
+            Err(e) => {
+                self.script_errors += 1;
+                eprintln!("[trainfuck] Debug script line {}: {}", line, e);
+            }
+        }
+    }
+
+    /// Lines of the debugger script that failed so far
+    pub fn script_errors(&self) -> usize {
+        self.script_errors
+    }
+
+    fn locate(&self, pc: usize) -> Option<(usize, usize)> {
+        self.positions.as_ref()?.locate(pc)
+    }
+
+    fn answer(&mut self, vm: &mut VM, request: ControlRequest) {
+        let result = self.dispatch(vm, &request.method, &request.params);
+        // The client may have hung up; that's its business
+        let _ = request.reply.send(result);
+    }
+
+    fn dispatch(&mut self, vm: &mut VM, method: &str, params: &Value) -> RpcResult {
+        let invalid = |message: String| (INVALID_PARAMS, message);
+        if !METHODS.contains(&method) {
+            return Err((METHOD_NOT_FOUND, format!("unknown method {:?}", method)));
+        }
+        match method {
+            "pause" => {
+                self.paused = true;
+                Ok(json!(true))
+            }
+            "resume" => {
+                self.paused = false;
+                self.pending_steps = 0;
+                Ok(json!(true))
+            }
+            "step" => {
+                let count = param_u64(params, "count").unwrap_or(1);
+                self.paused = true;
+                self.pending_steps = count;
+                Ok(json!(true))
+            }
+            "status" => Ok(json!({
+                "pointer": vm.pointer(),
+                "steps": vm.steps(),
+                "paused": self.paused,
+                "breakpoint": self.hit.filter(|_| self.paused),
+                "position": vm.current_pc.and_then(|pc| self.locate(pc)),
+            })),
+            "read_tape" => {
+                let start = param_u64(params, "start").unwrap_or(0) as usize;
+                let len = (param_u64(params, "len").unwrap_or(16) as usize).min(MAX_READ_LEN);
+                let tape = vm.tape();
+                let start = start.min(tape.len());
+                let end = start.saturating_add(len).min(tape.len());
+                Ok(json!(tape[start..end]))
+            }
+            "set_cell" => {
+                let index = param_u64(params, "index")
+                    .ok_or_else(|| invalid("missing \"index\"".into()))?
+                    as usize;
+                let value = param_u64(params, "value")
+                    .and_then(|v| u8::try_from(v).ok())
+                    .ok_or_else(|| invalid("\"value\" must be 0-255".into()))?;
+                if !vm.set_cell(index, value) {
+                    return Err(invalid(format!("cell {} is outside the tape", index)));
+                }
+                Ok(json!(true))
+            }
+            "connections" => Ok(json!({
+                "listener": vm.listener_addr().map(|a| a.to_string()),
+                "connections": vm
+                    .connection_info()
+                    .map(|info| {
+                        vec![json!({
+                            "id": info.id,
+                            "peer": info.peer.map(|peer| peer.to_string()),
+                            "direction": if info.dialed { "dialed" } else { "accepted" },
+                            "opened_at_step": info.opened_at_step,
+                            "bytes_received": info.bytes_received,
+                            "bytes_sent": info.bytes_sent,
+                        })]
+                    })
+                    .unwrap_or_default(),
+            })),
+            "listener" => Ok(vm.listener_addr().map_or(Value::Null, |address| {
+                json!({
+                    "address": address.to_string(),
+                    "accepted": vm.connections_accepted(),
+                    "accept_timeout_ms": vm.accept_timeout.map(|t| t.as_millis() as u64),
+                    "connection_open": vm.connection_info().is_some(),
+                })
+            })),
+            "break" => {
+                let line = param_u64(params, "line")
+                    .ok_or_else(|| invalid("missing \"line\"".into()))?
+                    as usize;
+                let column = param_u64(params, "column").map(|c| c as usize);
+                let condition_text = params.get("if").and_then(Value::as_str);
+                let condition = condition_text
+                    .map(Condition::parse)
+                    .transpose()
+                    .map_err(invalid)?;
+                let positions = self
+                    .positions
+                    .as_ref()
+                    .ok_or_else(|| invalid("no program to set breakpoints in".into()))?;
+                let pcs = positions.find(line, column);
+                if pcs.is_empty() {
+                    return Err(invalid(match column {
+                        Some(column) => format!("no instruction starts at {}:{}", line, column),
+                        None => format!("no instruction starts on line {}", line),
+                    }));
+                }
+                let id = self.next_breakpoint;
+                self.next_breakpoint += 1;
+                let instructions = pcs.len();
+                self.breakpoints.push(Breakpoint {
+                    id,
+                    line,
+                    column,
+                    pcs,
+                    condition,
+                    condition_text: condition_text.map(str::to_string),
+                });
+                Ok(json!({ "id": id, "instructions": instructions }))
+            }
+            "delete" => {
+                let id = param_u64(params, "id").ok_or_else(|| invalid("missing \"id\"".into()))?
+                    as usize;
+                let before = self.breakpoints.len();
+                self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+                if self.breakpoints.len() == before {
+                    return Err(invalid(format!("no breakpoint {}", id)));
+                }
+                Ok(json!(true))
+            }
+            "breakpoints" => Ok(self
+                .breakpoints
+                .iter()
+                .map(|breakpoint| {
+                    json!({
+                        "id": breakpoint.id,
+                        "line": breakpoint.line,
+                        "column": breakpoint.column,
+                        "if": breakpoint.condition_text,
+                    })
+                })
+                .collect()),
+            "evaluate" => {
+                let expr = params
+                    .get("expr")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| invalid("missing \"expr\"".into()))?;
+                let condition = Condition::parse(expr).map_err(invalid)?;
+                match condition.as_value() {
+                    Some(value) => Ok(json!(value.eval(vm))),
+                    None => Ok(json!(condition.holds(vm))),
+                }
+            }
+            "history" | "cell_history" | "tape_at" => {
+                let history = vm.history.as_ref().ok_or_else(|| {
+                    (
+                        NOT_RECORDING,
+                        "history is off; run with --record-history N".to_string(),
+                    )
+                })?;
+                let count = param_u64(params, "count").unwrap_or(16) as usize;
+                match method {
+                    "history" => {
+                        let entries = history.entries();
+                        let skip = entries.len().saturating_sub(count);
+                        Ok(entries.iter().skip(skip).map(|e| self.entry(e)).collect())
+                    }
+                    "cell_history" => {
+                        let index = param_u64(params, "index")
+                            .ok_or_else(|| invalid("missing \"index\"".into()))?
+                            as usize;
+                        let writes = history.writes_to(index).take(count);
+                        Ok(writes.map(|e| self.entry(e)).collect())
+                    }
+                    _ => {
+                        let back = param_u64(params, "back")
+                            .ok_or_else(|| invalid("missing \"back\"".into()))?
+                            as usize;
+                        let (tape, pointer) = history
+                            .rewind(vm.tape(), vm.pointer(), back)
+                            .ok_or_else(|| {
+                                let recorded = history.entries().len();
+                                invalid(format!("only {} instructions are recorded", recorded))
+                            })?;
+                        let start =
+                            (param_u64(params, "start").unwrap_or(0) as usize).min(tape.len());
+                        let len =
+                            (param_u64(params, "len").unwrap_or(16) as usize).min(MAX_READ_LEN);
+                        let end = start.saturating_add(len).min(tape.len());
+                        Ok(json!({ "pointer": pointer, "tape": tape[start..end] }))
+                    }
+                }
+            }
+            _ => unreachable!("{:?} is missing from METHODS", method),
+        }
+    }
+
+    fn entry(&self, entry: &Entry) -> Value {
+        let position = entry.pc.and_then(|pc| self.locate(pc));
+        json!({
+            "step": entry.step,
+            "pc": entry.pc,
+            "line": position.map(|(line, _)| line),
+            "column": position.map(|(_, column)| column),
+            "pointer": entry.pointer,
+            "moved": entry.moved,
+            "changes": entry.changes,
+        })
+    }
+}
+
+fn param_u64(params: &Value, name: &str) -> Option<u64> {
+    params.get(name).and_then(Value::as_u64)
+}
+
+/// Read JSON-RPC requests line by line and relay them to the VM
+fn serve_client(stream: UnixStream, tx: Sender<ControlRequest>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Value>(&line) {
+            Ok(request) => {
+                let id = request.get("id").cloned().unwrap_or(Value::Null);
+                let method = request
+                    .get("method")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string();
+                let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+                let (reply, result) = mpsc::channel();
+                let request = ControlRequest {
+                    method,
+                    params,
+                    reply,
+                };
+                match tx.send(request).ok().and_then(|_| result.recv().ok()) {
+                    Some(Ok(value)) => json!({"jsonrpc": "2.0", "id": id, "result": value}),
+                    Some(Err((code, message))) => error_response(id, code, &message),
+                    None => error_response(id, -32000, "VM has exited"),
+                }
+            }
+            Err(e) => error_response(Value::Null, -32700, &e.to_string()),
+        };
+
+        writeln!(writer, "{}", response)?;
+    }
+    Ok(())
+}
+
+fn error_response(id: Value, code: i32, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
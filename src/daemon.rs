@@ -0,0 +1,149 @@
+//! Daemon mode support
+//!
+//! `--daemon` detaches the interpreter from the terminal (double fork +
+//! `setsid`), records its pid in a pidfile and sends stdout/stderr to a log
+//! file. `trainfuck stop <pidfile>` signals it with SIGTERM, which the
+//! interpreter treats like Ctrl-C and shuts down cleanly, also when it is
+//! waiting in `@` for a client or on a TCP read: those wake up every 50ms
+//! to check for the signal.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long `stop` waits for the daemon to exit before giving up
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Removes the pidfile when the daemon exits
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn read_pid(pidfile: &Path) -> io::Result<i32> {
+    let contents = fs::read_to_string(pidfile)?;
+    contents.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} does not contain a pid", pidfile),
+        )
+    })
+}
+
+#[cfg(unix)]
+fn process_alive(pid: i32) -> bool {
+    // Signal 0 only checks that the process exists
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+/// Detach from the terminal, write the pidfile and redirect stdio to `log_file`
+#[cfg(unix)]
+pub fn daemonize(pidfile: &Path, log_file: &Path) -> io::Result<PidFile> {
+    use std::fs::OpenOptions;
+    use std::os::unix::io::AsRawFd;
+
+    if let Ok(pid) = read_pid(pidfile) {
+        if process_alive(pid) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("already running with pid {} ({:?})", pid, pidfile),
+            ));
+        }
+    }
+
+    // Open everything before forking so errors reach the terminal
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)?;
+    let null = OpenOptions::new().read(true).open("/dev/null")?;
+    let pidfile = std::path::absolute(pidfile)?;
+
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Fork again so the daemon can never reacquire a controlling terminal
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+    }
+
+    fs::write(&pidfile, format!("{}\n", std::process::id()))?;
+
+    unsafe {
+        if libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(PidFile { path: pidfile })
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_pidfile: &Path, _log_file: &Path) -> io::Result<PidFile> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "daemon mode is only supported on Unix",
+    ))
+}
+
+/// Send SIGTERM to the daemon recorded in `pidfile` and wait for it to exit
+#[cfg(unix)]
+pub fn stop(pidfile: &Path) -> io::Result<()> {
+    let pid = read_pid(pidfile)?;
+
+    if !process_alive(pid) {
+        // Stale pidfile from a crashed daemon
+        fs::remove_file(pidfile)?;
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no process with pid {}, removed stale pidfile", pid),
+        ));
+    }
+
+    if unsafe { libc::kill(pid, libc::SIGTERM) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let started = Instant::now();
+    while process_alive(pid) {
+        if started.elapsed() > STOP_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("pid {} did not exit after SIGTERM", pid),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    // The daemon normally removes its own pidfile
+    match fs::remove_file(pidfile) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn stop(_pidfile: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "daemon mode is only supported on Unix",
+    ))
+}
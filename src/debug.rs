@@ -0,0 +1,255 @@
+//! Breakpoints and debugger scripts for the control interface
+//!
+//! A breakpoint names a source position, `LINE` or `LINE:COLUMN`, and
+//! optionally a condition; the VM pauses before any instruction compiled
+//! from there while the condition holds. Conditions compare values with
+//! `==`, `!=`, `<`, `<=`, `>` and `>=` and combine comparisons with `and`
+//! and `or` (`and` binding tighter). Values are numbers (`10`, `0x41`),
+//! `cell(N)` for tape cell N, `cell` for the cell at the pointer, `ptr`
+//! and `steps`; a value on its own is true when non-zero.
+//!
+//! A debugger script (`--debug-script`) holds one command per line, with
+//! `#` starting a comment:
+//!
+//! - `break LINE[:COLUMN] [if CONDITION]`, `delete ID`
+//! - `continue`, `step [N]`
+//! - `print VALUE`, `tape START LEN`, `status`, `info connections`,
+//!   `info listener`, `info breakpoints`
+
+use crate::bytecode::Bytecode;
+use crate::interpreter::VM;
+use std::collections::VecDeque;
+
+/// A condition or value, as parsed from text like `cell(3) > 10`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Or(Vec<Condition>),
+    And(Vec<Condition>),
+    Compare(Value, Comparison, Value),
+    Value(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    Number(u64),
+    Cell(usize),
+    Current,
+    Pointer,
+    Steps,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        let any = split_words(text, "or")
+            .into_iter()
+            .map(|part| {
+                let all = split_words(part, "and")
+                    .into_iter()
+                    .map(Self::parse_comparison)
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Condition::And(all))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Condition::Or(any))
+    }
+
+    fn parse_comparison(text: &str) -> Result<Condition, String> {
+        // Two-character operators first, so `<=` isn't read as `<`
+        const OPERATORS: [(&str, Comparison); 6] = [
+            ("==", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+        ];
+        for (symbol, comparison) in OPERATORS {
+            if let Some((left, right)) = text.split_once(symbol) {
+                return Ok(Condition::Compare(
+                    Value::parse(left)?,
+                    comparison,
+                    Value::parse(right)?,
+                ));
+            }
+        }
+        Ok(Condition::Value(Value::parse(text)?))
+    }
+
+    /// The value, if the text was a single value rather than a condition
+    pub fn as_value(&self) -> Option<&Value> {
+        match self {
+            Condition::Or(any) | Condition::And(any) if any.len() == 1 => any[0].as_value(),
+            Condition::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn holds(&self, vm: &VM) -> bool {
+        match self {
+            Condition::Or(any) => any.iter().any(|c| c.holds(vm)),
+            Condition::And(all) => all.iter().all(|c| c.holds(vm)),
+            Condition::Compare(left, comparison, right) => {
+                let (left, right) = (left.eval(vm), right.eval(vm));
+                match comparison {
+                    Comparison::Equal => left == right,
+                    Comparison::NotEqual => left != right,
+                    Comparison::Less => left < right,
+                    Comparison::LessOrEqual => left <= right,
+                    Comparison::Greater => left > right,
+                    Comparison::GreaterOrEqual => left >= right,
+                }
+            }
+            Condition::Value(value) => value.eval(vm) != 0,
+        }
+    }
+}
+
+impl Value {
+    pub fn parse(text: &str) -> Result<Value, String> {
+        let text = text.trim();
+        let number = |digits: &str| match digits.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => digits.parse().ok(),
+        };
+        match text {
+            "cell" => Ok(Value::Current),
+            "ptr" => Ok(Value::Pointer),
+            "steps" => Ok(Value::Steps),
+            _ => {
+                if let Some(index) = text
+                    .strip_prefix("cell(")
+                    .and_then(|rest| rest.strip_suffix(')'))
+                {
+                    return number(index.trim())
+                        .map(|index| Value::Cell(index as usize))
+                        .ok_or_else(|| format!("invalid cell index in {:?}", text));
+                }
+                number(text).map(Value::Number).ok_or_else(|| {
+                    format!(
+                        "expected a number, cell(N), cell, ptr or steps, not {:?}",
+                        text
+                    )
+                })
+            }
+        }
+    }
+
+    /// The value now; cells outside the tape read as 0
+    pub fn eval(&self, vm: &VM) -> u64 {
+        match *self {
+            Value::Number(n) => n,
+            Value::Cell(index) => vm.tape().get(index).copied().unwrap_or(0).into(),
+            Value::Current => vm.tape()[vm.pointer()].into(),
+            Value::Pointer => vm.pointer() as u64,
+            Value::Steps => vm.steps(),
+        }
+    }
+}
+
+/// Split on a keyword standing as a separate word
+fn split_words<'a>(text: &'a str, word: &str) -> Vec<&'a str> {
+    let separator = format!(" {} ", word);
+    text.split(separator.as_str()).collect()
+}
+
+/// Source positions of a program's instructions
+pub struct Positions {
+    /// Address, line and column, by address
+    positions: Vec<(usize, usize, usize)>,
+}
+
+impl Positions {
+    pub fn new(bytecode: &Bytecode, source: &[u8]) -> Self {
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(memchr::memchr_iter(b'\n', source).map(|i| i + 1))
+            .collect();
+        let positions = bytecode
+            .spans()
+            .iter()
+            .map(|&(pc, span)| {
+                let line = line_starts.partition_point(|&start| start <= span.start);
+                (pc, line, span.start - line_starts[line - 1] + 1)
+            })
+            .collect();
+        Positions { positions }
+    }
+
+    /// Line and column the instruction at `pc` came from
+    pub fn locate(&self, pc: usize) -> Option<(usize, usize)> {
+        let index = self
+            .positions
+            .binary_search_by_key(&pc, |&(at, ..)| at)
+            .ok()?;
+        let (_, line, column) = self.positions[index];
+        Some((line, column))
+    }
+
+    /// Addresses of the instructions starting at `line` (and `column`)
+    pub fn find(&self, line: usize, column: Option<usize>) -> Vec<usize> {
+        self.positions
+            .iter()
+            .filter(|&&(_, l, c)| l == line && column.is_none_or(|column| c == column))
+            .map(|&(pc, ..)| pc)
+            .collect()
+    }
+}
+
+/// A place to pause
+pub struct Breakpoint {
+    pub id: usize,
+    pub line: usize,
+    pub column: Option<usize>,
+    /// Addresses it pauses at, sorted
+    pub pcs: Vec<usize>,
+    pub condition: Option<Condition>,
+    /// The condition as written
+    pub condition_text: Option<String>,
+}
+
+/// Parse `LINE` or `LINE:COLUMN`
+pub fn parse_location(text: &str) -> Result<(usize, Option<usize>), String> {
+    let invalid = || format!("expected LINE or LINE:COLUMN, not {:?}", text);
+    match text.split_once(':') {
+        Some((line, column)) => Ok((
+            line.parse().map_err(|_| invalid())?,
+            Some(column.parse().map_err(|_| invalid())?),
+        )),
+        None => Ok((text.parse().map_err(|_| invalid())?, None)),
+    }
+}
+
+/// Debugger commands waiting to run, with their line numbers
+pub struct Script {
+    commands: VecDeque<(usize, String)>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Script {
+        let commands = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.split('#').next().unwrap_or("").trim()))
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(number, line)| (number, line.to_string()))
+            .collect();
+        Script { commands }
+    }
+}
+
+impl Iterator for Script {
+    type Item = (usize, String);
+
+    fn next(&mut self) -> Option<(usize, String)> {
+        self.commands.pop_front()
+    }
+}
@@ -0,0 +1,184 @@
+//! Directive lines: settings a program carries in its source
+//!
+//! A line starting with `#!` is a directive, read by the
+//! [`Parser`](crate::interpreter::Parser) instead of parsed as code, so a
+//! program can say what it needs instead of relying on being run with the
+//! right flags:
+//!
+//! | Directive              | Meaning                                           |
+//! | ---------------------- | ------------------------------------------------- |
+//! | `#! tape-size 64K`     | The tape has this many cells, as `--tape-size`    |
+//! | `#! cell-width 8`      | Cells are 8 bits wide, the only width there is    |
+//! | `#! ext checksum, kv`  | These extensions' commands are on, as `--ext`     |
+//!
+//! A `#!` line naming an interpreter, such as `#!/usr/bin/env trainfuck`,
+//! is a shebang and is skipped. Directives go before the first command of
+//! their file; the extensions they turn on are on for the rest of the
+//! program. `;;` starts a comment running to the end of the line, in which
+//! even command characters are comments.
+//!
+//! ```
+//! use trainfuck::program::Program;
+//! use trainfuck::interpreter::Extensions;
+//!
+//! let source = b"#! tape-size 64K\n#! ext checksum\n;; CRC of no cells: #\n#.";
+//! let program = Program::parse(source, Extensions::default())?;
+//! assert_eq!(program.metadata().directives.tape_size, Some(64 * 1024));
+//! assert!(program.metadata().extensions.checksum);
+//! assert_eq!(program.len(), 2);
+//! # Ok::<(), trainfuck::interpreter::TrainfuckError>(())
+//! ```
+//!
+//! The CLI reads the directives with [`Directives::scan`] before compiling,
+//! since the tape size shapes how the optimizer folds the program.
+
+use crate::interpreter::{Extensions, Result, TrainfuckError};
+use crate::span::SourceFiles;
+use crate::tape;
+use serde::{Deserialize, Serialize};
+
+/// What a program's directives ask for
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Directives {
+    /// Cells on the tape
+    pub tape_size: Option<usize>,
+    /// Extensions the program needs, named as `--ext` names them
+    pub extensions: Vec<String>,
+}
+
+impl Directives {
+    /// Read the directives of every file in `sources`, parsed with
+    /// `extensions`, looking no further in each than its first line of code
+    pub fn scan(sources: &SourceFiles, mut extensions: Extensions) -> Result<Self> {
+        let mut directives = Directives::default();
+        let mut position = 0;
+        for (_, bytes) in sources.files() {
+            let end = position + bytes.len();
+            for line in bytes.split_inclusive(|&byte| byte == b'\n') {
+                let start = position;
+                position += line.len();
+                if let Some(text) = line.strip_prefix(b"#!") {
+                    directives.apply(text, start, &mut extensions)?;
+                    continue;
+                }
+                let code = match line.windows(2).position(|pair| pair == b";;") {
+                    Some(comment) => &line[..comment],
+                    None => line,
+                };
+                if code.iter().any(|&byte| extensions.is_command(byte)) {
+                    break;
+                }
+            }
+            position = end;
+        }
+        Ok(directives)
+    }
+
+    /// The `#!` lines that ask for these directives, each ending in a
+    /// newline
+    pub fn to_source(&self) -> String {
+        let mut source = String::new();
+        if let Some(size) = self.tape_size {
+            source.push_str(&format!("#! tape-size {}\n", size));
+        }
+        if !self.extensions.is_empty() {
+            source.push_str(&format!("#! ext {}\n", self.extensions.join(", ")));
+        }
+        source
+    }
+
+    /// Apply the `#!` line at `position` whose text after the `#!` is
+    /// `text`, turning on the extensions it names in `extensions`
+    pub(crate) fn apply(
+        &mut self,
+        text: &[u8],
+        position: usize,
+        extensions: &mut Extensions,
+    ) -> Result<()> {
+        if is_shebang(text) {
+            return Ok(());
+        }
+        let text = String::from_utf8_lossy(text);
+        let (name, value) = text
+            .trim()
+            .split_once(char::is_whitespace)
+            .unwrap_or((text.trim(), ""));
+        let value = value.trim();
+        let invalid = |why: String| TrainfuckError::InvalidDirective(why, position);
+        match name {
+            "tape-size" => {
+                let size =
+                    tape::parse_size(value).map_err(|e| invalid(format!("tape-size: {}", e)))?;
+                match self.tape_size {
+                    Some(earlier) if earlier != size => {
+                        return Err(invalid(format!(
+                            "tape-size {} conflicts with the earlier tape-size {}",
+                            size, earlier
+                        )))
+                    }
+                    _ => self.tape_size = Some(size),
+                }
+            }
+            "cell-width" if value == "8" => {}
+            "cell-width" => {
+                return Err(invalid(format!(
+                    "cell-width {:?}: cells are 8 bits wide",
+                    value
+                )))
+            }
+            "ext" => {
+                let names: Vec<&str> = value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|name| !name.is_empty())
+                    .collect();
+                if names.is_empty() {
+                    return Err(invalid("ext needs the names of extensions".into()));
+                }
+                for name in names {
+                    if !enable(extensions, name) {
+                        return Err(invalid(format!("unknown extension {:?}", name)));
+                    }
+                    if !self.extensions.iter().any(|known| known == name) {
+                        self.extensions.push(name.to_string());
+                    }
+                }
+            }
+            _ => return Err(invalid(format!("unknown directive {:?}", name))),
+        }
+        Ok(())
+    }
+}
+
+/// Whether the `#!` line with `text` after the `#!` is a shebang, or empty,
+/// rather than a directive
+pub(crate) fn is_shebang(text: &[u8]) -> bool {
+    match text.trim_ascii_start().first() {
+        Some(&byte) => byte == b'/',
+        None => true,
+    }
+}
+
+/// Turn on the extension `--ext` calls `name`, if there is one
+fn enable(extensions: &mut Extensions, name: &str) -> bool {
+    let flag = match name {
+        "shared" => &mut extensions.shared,
+        "actors" => &mut extensions.actors,
+        "yield" => &mut extensions.yielding,
+        "kv" => &mut extensions.kv,
+        "http" => &mut extensions.http,
+        "checksum" => &mut extensions.checksum,
+        "base64" => &mut extensions.base64,
+        "xor" => &mut extensions.xor,
+        "peek" => &mut extensions.peek,
+        "line" => &mut extensions.line,
+        "dns" => &mut extensions.dns,
+        "channels" => &mut extensions.channels,
+        "backpressure" => &mut extensions.backpressure,
+        "blocks" => &mut extensions.blocks,
+        "trap" => &mut extensions.trap,
+        "features" => &mut extensions.features,
+        _ => return false,
+    };
+    *flag = true;
+    true
+}
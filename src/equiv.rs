@@ -0,0 +1,221 @@
+//! `trainfuck equiv`: differential testing of two programs
+//!
+//! Both programs run on every file of an input corpus plus a batch of
+//! random inputs, each in a fresh networkless VM with a step limit. Runs
+//! that hit the limit only count against equivalence when the output they
+//! got as far as already disagrees with the other program's.
+
+use crate::rng::XorShift;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use trainfuck::bytecode::Bytecode;
+use trainfuck::interpreter::{self, TrainfuckError, VM};
+use trainfuck::optimizer;
+
+pub struct EquivConfig {
+    /// Directory whose files are each one input
+    pub inputs: Option<PathBuf>,
+    /// Random inputs to try on top of the corpus
+    pub fuzz: usize,
+    pub seed: u64,
+    pub max_steps: u64,
+    /// Tape size of both programs, rather than each one's `#! tape-size`
+    pub tape_size: Option<usize>,
+}
+
+/// Compare `a` and `b`, printing each disagreement; exits 1 if there is any
+pub fn run(a: &Path, b: &Path, config: &EquivConfig) -> i32 {
+    let (programs, inputs) = match (load(a, config), load(b, config), corpus(config)) {
+        (Ok(a), Ok(b), Ok(inputs)) => ([a, b], inputs),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    let (mut differ, mut undecided) = (0, 0);
+    for (name, input) in &inputs {
+        let [x, y] = programs
+            .each_ref()
+            .map(|(program, tape_size)| Run::new(program, *tape_size, input, config));
+        match x.compare(&y) {
+            Verdict::Same => {}
+            Verdict::Undecided => undecided += 1,
+            Verdict::Differ => {
+                differ += 1;
+                println!("{}: outputs differ", name);
+                println!("  {}: {}", a.display(), x);
+                println!("  {}: {}", b.display(), y);
+            }
+        }
+    }
+
+    println!(
+        "{} inputs: {} same, {} differ, {} undecided (step limit)",
+        inputs.len(),
+        inputs.len() - differ - undecided,
+        differ,
+        undecided
+    );
+    i32::from(differ > 0)
+}
+
+/// The program at `path` and the tape size it runs with
+fn load(path: &Path, config: &EquivConfig) -> Result<(Bytecode, usize), String> {
+    let source = fs::read(path).map_err(|e| format!("Error reading file {:?}: {}", path, e))?;
+    let program = interpreter::parse_bytes(&source)
+        .map_err(|e| format!("Parse error in {:?}: {}", path, e))?;
+    let tape_size = config
+        .tape_size
+        .or(program.metadata().directives.tape_size)
+        .unwrap_or(interpreter::TAPE_SIZE);
+    let (ops, spans) = optimizer::optimize_with_spans(program.ops().to_vec(), program.spans());
+    Ok((Bytecode::compile_with_spans(&ops, &spans), tape_size))
+}
+
+/// Named inputs: the empty input, the corpus files in name order, then
+/// the random ones
+fn corpus(config: &EquivConfig) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut inputs = vec![("empty input".to_string(), Vec::new())];
+    if let Some(dir) = &config.inputs {
+        let error = |e: io::Error| format!("Error reading inputs {:?}: {}", dir, e);
+        let mut paths = fs::read_dir(dir)
+            .map_err(error)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(error)?;
+        paths.retain(|p| p.is_file());
+        paths.sort();
+        for path in paths {
+            let input = fs::read(&path).map_err(error)?;
+            inputs.push((path.display().to_string(), input));
+        }
+    }
+
+    let mut rng = XorShift::new(config.seed);
+    for i in 0..config.fuzz {
+        let input = random_input(&mut rng);
+        inputs.push((
+            format!("random input #{} {:?}", i, String::from_utf8_lossy(&input)),
+            input,
+        ));
+    }
+    Ok(inputs)
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Finished,
+    Failed(String),
+    /// Hit the step limit
+    Unfinished,
+}
+
+enum Verdict {
+    Same,
+    Differ,
+    Undecided,
+}
+
+struct Run {
+    output: Vec<u8>,
+    outcome: Outcome,
+}
+
+impl Run {
+    fn new(program: &Bytecode, tape_size: usize, input: &[u8], config: &EquivConfig) -> Run {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::with_tape_size(tape_size);
+        vm.input = Box::new(Cursor::new(input.to_vec()));
+        vm.output = Box::new(Collect(output.clone()));
+        vm.network_enabled = false;
+        vm.max_steps = Some(config.max_steps);
+        let outcome = match vm.execute_bytecode(program) {
+            Ok(()) => Outcome::Finished,
+            Err(TrainfuckError::StepLimitExceeded(_)) => Outcome::Unfinished,
+            Err(e) => Outcome::Failed(e.to_string()),
+        };
+        Run {
+            output: output.take(),
+            outcome,
+        }
+    }
+
+    fn compare(&self, other: &Run) -> Verdict {
+        match (&self.outcome, &other.outcome) {
+            (Outcome::Unfinished, _) | (_, Outcome::Unfinished) => {
+                // An unfinished run may still catch up with a longer output,
+                // but a finished one can't
+                let shorter = self.output.len().min(other.output.len());
+                let consistent = self.output[..shorter] == other.output[..shorter]
+                    && (self.output.len() <= other.output.len()
+                        || other.outcome == Outcome::Unfinished)
+                    && (other.output.len() <= self.output.len()
+                        || self.outcome == Outcome::Unfinished);
+                match consistent {
+                    true => Verdict::Undecided,
+                    false => Verdict::Differ,
+                }
+            }
+            (x, y) if x == y && self.output == other.output => Verdict::Same,
+            _ => Verdict::Differ,
+        }
+    }
+}
+
+/// Bytes of a run's output printed when reporting a difference
+const SHOWN_OUTPUT: usize = 80;
+
+impl std::fmt::Display for Run {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let shown = &self.output[..self.output.len().min(SHOWN_OUTPUT)];
+        write!(f, "{:?}", String::from_utf8_lossy(shown))?;
+        if shown.len() < self.output.len() {
+            write!(f, "... ({} bytes)", self.output.len())?;
+        }
+        match &self.outcome {
+            Outcome::Finished => Ok(()),
+            Outcome::Failed(e) => write!(f, " then error: {}", e),
+            Outcome::Unfinished => write!(f, " before hitting the step limit"),
+        }
+    }
+}
+
+struct Collect(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Collect {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Mostly short printable lines, with the bytes programs tend to
+/// special-case (0, newline, 255) mixed in
+fn random_input(rng: &mut XorShift) -> Vec<u8> {
+    let len = match rng.below(8) {
+        0 => 0,
+        7 => 256,
+        _ => 32,
+    };
+    let len = rng.below(len + 1) as usize;
+    (0..len)
+        .map(|_| {
+            let r = rng.next_u64();
+            match r % 10 {
+                0 => 0,
+                1 => b'\n',
+                2 => 255,
+                3 => (r >> 8) as u8,
+                _ => b' ' + ((r >> 8) % 95) as u8,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,123 @@
+//! Feature detection (`--ext features`)
+//!
+//! With [`Extensions::features`](crate::interpreter::Extensions::features),
+//! `?` writes what the interpreter running the program offers over the
+//! current cell and the [`FEATURE_CELLS`]` - 1` after it: the version as
+//! major, minor and patch, then a 32-bit mask of features, big-endian. A
+//! program can check for what it needs and fall back to plainer code when
+//! it is missing, instead of failing halfway through. The extension bits
+//! are those of the extensions the program was parsed with, and
+//! [`NETWORK`] is clear on VMs with networking off, as under `--sandbox`.
+//!
+//! | Bit | Feature                                      |
+//! | --- | -------------------------------------------- |
+//! | 0   | [`NETWORK`]: the networking commands work    |
+//! | 1   | `shared`                                     |
+//! | 2   | `actors`                                     |
+//! | 3   | `yield`                                      |
+//! | 4   | `kv`                                         |
+//! | 5   | `http`                                       |
+//! | 6   | `checksum`                                   |
+//! | 7   | `base64`                                     |
+//! | 8   | `xor`                                        |
+//! | 9   | `peek`                                       |
+//! | 10  | `line`                                       |
+//! | 11  | `dns`                                        |
+//! | 12  | `channels`, for several connections at once  |
+//! | 13  | `backpressure`                               |
+//! | 14  | `blocks`                                     |
+//! | 15  | `trap`                                       |
+//!
+//! An interpreter without the extension reads `?` as a comment, so clear
+//! the cells before asking: if they are still zero afterwards, nothing is
+//! known beyond standard Trainfuck.
+//!
+//! ```
+//! use trainfuck::features::{self, FEATURE_CELLS};
+//! use trainfuck::interpreter::{Extensions, VM};
+//! use trainfuck::program::Program;
+//!
+//! let extensions = Extensions { features: true, checksum: true, ..Extensions::default() };
+//! let program = Program::parse(b"?", extensions)?;
+//! let mut vm = VM::with_io(std::io::empty(), std::io::sink());
+//! vm.run_collect(&program);
+//! let cells = &vm.tape()[..FEATURE_CELLS];
+//! let mask = u32::from_be_bytes(cells[3..].try_into().unwrap());
+//! assert_eq!(cells[..3], features::version());
+//! assert_eq!(mask, features::NETWORK | features::CHECKSUM);
+//! # Ok::<(), trainfuck::interpreter::TrainfuckError>(())
+//! ```
+//!
+//! `?` is `actors`' command too; with both extensions on, it detects
+//! features.
+
+use crate::interpreter::{Extensions, VM};
+
+/// Cells `?` writes: three of version, four of feature mask
+pub const FEATURE_CELLS: usize = 7;
+
+pub const NETWORK: u32 = 1 << 0;
+pub const SHARED: u32 = 1 << 1;
+pub const ACTORS: u32 = 1 << 2;
+pub const YIELD: u32 = 1 << 3;
+pub const KV: u32 = 1 << 4;
+pub const HTTP: u32 = 1 << 5;
+pub const CHECKSUM: u32 = 1 << 6;
+pub const BASE64: u32 = 1 << 7;
+pub const XOR: u32 = 1 << 8;
+pub const PEEK: u32 = 1 << 9;
+pub const LINE: u32 = 1 << 10;
+pub const DNS: u32 = 1 << 11;
+pub const CHANNELS: u32 = 1 << 12;
+pub const BACKPRESSURE: u32 = 1 << 13;
+pub const BLOCKS: u32 = 1 << 14;
+pub const TRAP: u32 = 1 << 15;
+
+/// Major, minor and patch version of this interpreter
+pub fn version() -> [u8; 3] {
+    [
+        env!("CARGO_PKG_VERSION_MAJOR"),
+        env!("CARGO_PKG_VERSION_MINOR"),
+        env!("CARGO_PKG_VERSION_PATCH"),
+    ]
+    .map(|part| part.parse().unwrap_or(u8::MAX))
+}
+
+/// Feature bits of `extensions`, which `?` carries from the parser
+pub fn mask(extensions: &Extensions) -> u32 {
+    [
+        (extensions.shared, SHARED),
+        (extensions.actors, ACTORS),
+        (extensions.yielding, YIELD),
+        (extensions.kv, KV),
+        (extensions.http, HTTP),
+        (extensions.checksum, CHECKSUM),
+        (extensions.base64, BASE64),
+        (extensions.xor, XOR),
+        (extensions.peek, PEEK),
+        (extensions.line, LINE),
+        (extensions.dns, DNS),
+        (extensions.channels, CHANNELS),
+        (extensions.backpressure, BACKPRESSURE),
+        (extensions.blocks, BLOCKS),
+        (extensions.trap, TRAP),
+    ]
+    .into_iter()
+    .filter(|&(on, _)| on)
+    .fold(0, |mask, (_, bit)| mask | bit)
+}
+
+impl VM {
+    /// `?`: write the version and feature mask, with the networking bit
+    /// added to the extensions' `mask`, from the pointer on
+    pub(crate) fn detect_features(&mut self, mut mask: u32) {
+        if self.network_enabled {
+            mask |= NETWORK;
+        }
+        let len = self.tape.len();
+        let cells = version().into_iter().chain(mask.to_be_bytes());
+        for (i, byte) in cells.enumerate() {
+            self.tape[(self.pointer + i) % len] = byte;
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Flood protection for accepted connections (`--flood-*`)
+//!
+//! [`FloodNetwork`] wraps another [`Network`] and gives every connection
+//! `@` accepts a token bucket for lines (messages) and one for bytes, each
+//! refilling at its rate per second and holding at most a second's worth.
+//! Data a client sends beyond them is handled as [`FloodAction`] says, so
+//! one noisy client can't keep a single-threaded server busy while the
+//! others wait. Whenever that happens, the receive reports
+//! [`NetStatus::Flooded`](crate::interpreter::NetStatus::Flooded) in the
+//! status cell. Connections opened with `%` aren't limited.
+
+use crate::net::{Listener, Network, Stream};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What to do with data over a connection's limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloodAction {
+    /// Discard it: bytes over the byte rate, and whole lines over the
+    /// message rate
+    Drop,
+    /// Hold it back until the client is within its limits again
+    Throttle,
+    /// Close the connection
+    Disconnect,
+}
+
+/// Rates each accepted connection may send at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FloodLimits {
+    /// Lines per second; unlimited when unset
+    pub messages_per_sec: Option<u32>,
+    /// Bytes per second; unlimited when unset
+    pub bytes_per_sec: Option<u32>,
+    pub action: FloodAction,
+}
+
+/// A [`Network`] whose accepted connections are rate limited
+pub struct FloodNetwork {
+    inner: Box<dyn Network>,
+    limits: FloodLimits,
+}
+
+impl FloodNetwork {
+    pub fn new(inner: Box<dyn Network>, limits: FloodLimits) -> Self {
+        FloodNetwork { inner, limits }
+    }
+}
+
+impl Network for FloodNetwork {
+    fn listen(&mut self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(FloodListener {
+            inner: self.inner.listen(addr)?,
+            limits: self.limits,
+        }))
+    }
+
+    fn connect(&mut self, addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        self.inner.connect(addr)
+    }
+}
+
+struct FloodListener {
+    inner: Box<dyn Listener>,
+    limits: FloodLimits,
+}
+
+impl Listener for FloodListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        let stream = self.inner.accept()?;
+        Ok(self.limit(stream))
+    }
+
+    fn accept_timeout(&mut self, timeout: Duration) -> io::Result<Option<Box<dyn Stream>>> {
+        let stream = self.inner.accept_timeout(timeout)?;
+        Ok(stream.map(|stream| self.limit(stream)))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl FloodListener {
+    fn limit(&self, inner: Box<dyn Stream>) -> Box<dyn Stream> {
+        Box::new(FloodStream {
+            inner,
+            action: self.limits.action,
+            messages: self.limits.messages_per_sec.map(Bucket::new),
+            bytes: self.limits.bytes_per_sec.map(Bucket::new),
+            line_start: true,
+            dropping_line: false,
+            cut_off: false,
+            over: false,
+        })
+    }
+}
+
+/// Tokens for one rate, refilled continuously
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u32) -> Self {
+        let rate = f64::from(rate.max(1));
+        Bucket {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + earned).min(self.rate);
+        self.refilled = now;
+    }
+
+    fn take(&mut self) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Wait for the next token and take it
+    fn take_late(&mut self) {
+        while !self.take() {
+            thread::sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.rate));
+        }
+    }
+}
+
+struct FloodStream {
+    inner: Box<dyn Stream>,
+    action: FloodAction,
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+    /// The next byte starts a line
+    line_start: bool,
+    /// Discarding the rest of a line over the message rate
+    dropping_line: bool,
+    /// Closed for going over the limits
+    cut_off: bool,
+    /// Went over the limits since [`over_limit`](Stream::over_limit) was
+    /// last asked
+    over: bool,
+}
+
+impl FloodStream {
+    /// Whether `byte` may go through, waiting for it to if throttling
+    fn admit(&mut self, byte: u8) -> bool {
+        let starts_line = std::mem::replace(&mut self.line_start, byte == b'\n');
+        if starts_line {
+            self.dropping_line = false;
+            if let Some(ref mut messages) = self.messages {
+                if !messages.take() {
+                    self.over = true;
+                    match self.action {
+                        FloodAction::Drop => self.dropping_line = true,
+                        FloodAction::Throttle => messages.take_late(),
+                        FloodAction::Disconnect => self.cut_off = true,
+                    }
+                }
+            }
+        }
+        if self.dropping_line || self.cut_off {
+            return false;
+        }
+        if let Some(ref mut bytes) = self.bytes {
+            if !bytes.take() {
+                self.over = true;
+                match self.action {
+                    FloodAction::Drop => return false,
+                    FloodAction::Throttle => bytes.take_late(),
+                    FloodAction::Disconnect => {
+                        self.cut_off = true;
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Read for FloodStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.cut_off || buf.is_empty() {
+                return Ok(0);
+            }
+            let n = self.inner.read(buf)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut kept = 0;
+            for i in 0..n {
+                if self.admit(buf[i]) {
+                    buf[kept] = buf[i];
+                    kept += 1;
+                }
+            }
+            if self.cut_off {
+                eprintln!("[trainfuck] Disconnecting a client over its flood limits");
+                self.inner.shutdown();
+            }
+            // All of it dropped: wait for more rather than report the end
+            if kept > 0 || self.cut_off {
+                return Ok(kept);
+            }
+        }
+    }
+}
+
+impl Write for FloodStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Stream for FloodStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown();
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn has_pending(&self) -> bool {
+        self.cut_off || self.inner.has_pending()
+    }
+
+    fn room(&self) -> Option<&[u8]> {
+        self.inner.room()
+    }
+
+    fn over_limit(&mut self) -> bool {
+        std::mem::take(&mut self.over) || self.cut_off
+    }
+}
@@ -0,0 +1,83 @@
+//! Per-connection handlers (`--handler`)
+//!
+//! [`VM::execute_handler`] splits a server at its first `@` that isn't
+//! inside a loop. Everything before it is the prologue, run once: it
+//! typically writes the address and `$` listens. Everything from the `@` on
+//! is the handler, run once per client, CGI style: the runtime accepts a
+//! connection, runs the handler to the end of the program, closes the
+//! connection and goes back to the `@` for the next client.
+//!
+//! Each run starts with the pointer where the prologue left it, and the
+//! cells from there to the end of the tape as the prologue left them, so a
+//! handler has fresh scratch space whatever the previous one did. Cells to
+//! the left of that pointer keep their values from one client to the next,
+//! for configuration or counters.
+//!
+//! A runtime error in a handler is reported and only ends that client's
+//! connection. Interrupts and resource limits stop the server. Restoring
+//! the scratch space assumes the classic tape model, whose tape never
+//! grows.
+
+use crate::bytecode::{Bytecode, HALT};
+use crate::interpreter::{Result, TrainfuckError, VM};
+
+impl VM {
+    /// Run the prologue of `bytecode` once and its handler for every
+    /// connection, until interrupted or stopped by a limit
+    pub fn execute_handler(&mut self, bytecode: &Bytecode) -> Result<()> {
+        let code = bytecode.code.as_slice();
+        let start = bytecode
+            .first_outer_accept()
+            .ok_or(TrainfuckError::NoHandler)?;
+        self.fault_pc = None;
+        self.run_until(code, 0, start)?;
+
+        let home = self.pointer;
+        let scratch = self.tape[home..].to_vec();
+        loop {
+            // What `@` does with no connection open: wait for a client
+            let mut pc = self
+                .dispatch(code, start)
+                .inspect_err(|_| self.fault_pc = Some(start))?;
+            if self.connection_info().is_none() {
+                // The accept timeout ran out; wait again
+                continue;
+            }
+            let handled = self.run_until(code, pc, usize::MAX);
+            match handled {
+                Err(e) if fatal(&e) => return Err(e),
+                Err(e) => eprintln!("[trainfuck] Handler failed: {}", e),
+                Ok(()) => {}
+            }
+            self.pointer = home;
+            // An unbounded tape may have grown; only the original cells
+            // are restored
+            let end = self.tape.len().min(home + scratch.len());
+            self.tape[home..end].copy_from_slice(&scratch[..end - home]);
+            if self.connection_info().is_some() {
+                // `@` with a connection open closes it
+                pc = start;
+                self.dispatch(code, pc)
+                    .inspect_err(|_| self.fault_pc = Some(pc))?;
+            }
+        }
+    }
+
+    /// Run from `pc` until reaching `end` or the end of the program
+    fn run_until(&mut self, code: &[u8], mut pc: usize, end: usize) -> Result<()> {
+        while pc != end && code[pc] != HALT {
+            pc = self
+                .dispatch(code, pc)
+                .inspect_err(|_| self.fault_pc = Some(pc))?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `e` should stop the server rather than just the one handler
+fn fatal(e: &TrainfuckError) -> bool {
+    matches!(
+        e,
+        TrainfuckError::Interrupted | TrainfuckError::OutputClosed
+    ) || e.limit().is_some()
+}
@@ -0,0 +1,96 @@
+//! Liveness and readiness of a running VM (`--health-addr`)
+//!
+//! A VM with a [`Health`] attached publishes its step count as it runs,
+//! whether it is waiting on I/O (`@`, `` ` ``, `,`, ...), whether `$` has a
+//! listener open, and whether the program has finished. Another thread,
+//! such as a probe endpoint, reads them without stopping the VM.
+
+use crate::interpreter::VM;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// What the VM reports about itself, shared with whoever checks on it
+#[derive(Debug, Default)]
+pub struct Health {
+    steps: AtomicU64,
+    waiting: AtomicBool,
+    listening: AtomicBool,
+    finished: AtomicBool,
+}
+
+impl Health {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Steps executed so far
+    pub fn steps(&self) -> u64 {
+        self.steps.load(Ordering::Relaxed)
+    }
+
+    /// Whether the VM is blocked waiting for a client, the network or input,
+    /// so steps not advancing is expected
+    pub fn is_waiting(&self) -> bool {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// Whether `$` has a listener open
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::Relaxed)
+    }
+
+    /// Whether the program has stopped running
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    /// Mark the program as stopped, once its execute call has returned
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+        self.waiting.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Marks the VM as waiting until dropped
+pub(crate) struct Waiting(Option<Arc<Health>>);
+
+impl Waiting {
+    /// For when the rest of the VM is borrowed
+    pub(crate) fn of(health: &Option<Arc<Health>>) -> Self {
+        if let Some(health) = health {
+            health.waiting.store(true, Ordering::Relaxed);
+        }
+        Waiting(health.clone())
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if let Some(ref health) = self.0 {
+            health.waiting.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+impl VM {
+    /// Report the VM as waiting on I/O for as long as the result is alive
+    pub(crate) fn waiting(&self) -> Waiting {
+        Waiting::of(&self.health)
+    }
+
+    /// Report the current step count
+    pub(crate) fn report_steps(&self) {
+        if let Some(ref health) = self.health {
+            health.steps.store(self.steps, Ordering::Relaxed);
+        }
+    }
+
+    /// Report whether a listener is open
+    pub(crate) fn report_listening(&self) {
+        if let Some(ref health) = self.health {
+            health
+                .listening
+                .store(self.listener.is_some(), Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Annotated source output (`trainfuck highlight`)
+//!
+//! Renders a program as ANSI-colored text or standalone HTML. Matching
+//! brackets share a color that cycles with nesting depth, networking ops are
+//! highlighted distinctly, and comments, `#!` lines and `;;` comments
+//! among them, are dimmed. Given execution counts (a JSON object mapping
+//! byte offsets to counts, as `--profile` writes), ops that never ran are
+//! dimmed and hot ops are emphasized; HTML output also shows the count on
+//! hover.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use trainfuck::interpreter::Parser;
+
+/// Colors cycled through by bracket nesting depth
+const RAINBOW_ANSI: [&str; 6] = ["31", "33", "32", "36", "34", "35"];
+const RAINBOW_HTML: [&str; 6] = [
+    "#e53935", "#fb8c00", "#43a047", "#00acc1", "#1e88e5", "#8e24aa",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    Ansi,
+    Html,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+    Arith,
+    Move,
+    Io,
+    Net,
+    Bracket(usize),
+    Comment,
+}
+
+fn classify(c: char, depth: usize) -> Class {
+    match c {
+        '+' | '-' => Class::Arith,
+        '<' | '>' => Class::Move,
+        '.' | ',' => Class::Io,
+        '%' | '$' | '@' | '`' | '\'' => Class::Net,
+        '[' | ']' => Class::Bracket(depth),
+        _ => Class::Comment,
+    }
+}
+
+/// Classify each char, with its byte offset, tracking bracket depth so
+/// partners share a color
+fn classify_source(source: &str) -> Vec<(usize, char, Class)> {
+    // The parser knows which `#` and `;` start a directive line or comment.
+    // Fed a byte at a time, it carries on past errors such as an unmatched
+    // `]`, so a broken program still gets its comments found.
+    let mut parser = Parser::new();
+    for byte in source.as_bytes() {
+        let _ = parser.feed(std::slice::from_ref(byte));
+    }
+    let mut comments = parser.comments().into_iter().peekable();
+
+    let mut depth = 0usize;
+    source
+        .char_indices()
+        .map(|(offset, c)| {
+            while comments.next_if(|comment| comment.end <= offset).is_some() {}
+            if comments
+                .peek()
+                .is_some_and(|comment| comment.start <= offset)
+            {
+                return (offset, c, Class::Comment);
+            }
+            let class = match c {
+                '[' => {
+                    depth += 1;
+                    classify(c, depth - 1)
+                }
+                ']' => {
+                    depth = depth.saturating_sub(1);
+                    classify(c, depth)
+                }
+                _ => classify(c, depth),
+            };
+            (offset, c, class)
+        })
+        .collect()
+}
+
+/// Render `source`, optionally annotated with execution counts by byte
+/// offset
+pub fn highlight(source: &str, format: Format, counts: Option<&HashMap<usize, u64>>) -> String {
+    let chars = classify_source(source);
+    // Anything at or above this many executions counts as hot
+    let hot = counts.map(|counts| {
+        let mut values: Vec<u64> = counts.values().copied().filter(|&n| n > 0).collect();
+        values.sort_unstable();
+        values
+            .get(values.len() * 9 / 10)
+            .copied()
+            .unwrap_or(u64::MAX)
+    });
+
+    let mut out = String::new();
+    // ANSI style currently in effect, so runs of like ops share one escape
+    let mut active = String::new();
+    if format == Format::Html {
+        out.push_str(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><style>\n\
+             pre { font-family: monospace; }\n\
+             .arith { color: #333; } .move { color: #1565c0; } .io { color: #2e7d32; font-weight: bold; }\n\
+             .net { color: #fff; background: #c2185b; font-weight: bold; } .comment { color: #999; }\n\
+             .cold { opacity: 0.35; } .hot { background: #ffecb3; }\n\
+             </style></head><body><pre>",
+        );
+    }
+
+    for &(offset, c, class) in &chars {
+        let count = counts.map(|counts| counts.get(&offset).copied().unwrap_or(0));
+        let is_op = class != Class::Comment;
+        let cold = is_op && count == Some(0);
+        let is_hot = is_op && matches!((count, hot), (Some(n), Some(h)) if n >= h);
+
+        match format {
+            Format::Ansi => {
+                let mut codes: Vec<&str> = match class {
+                    Class::Arith => vec![],
+                    Class::Move => vec!["34"],
+                    Class::Io => vec!["1", "32"],
+                    Class::Net => vec!["1", "97", "45"],
+                    Class::Bracket(depth) => vec!["1", RAINBOW_ANSI[depth % RAINBOW_ANSI.len()]],
+                    Class::Comment => vec!["2"],
+                };
+                if cold {
+                    codes.push("2");
+                }
+                if is_hot {
+                    codes.push("4");
+                }
+                let style = codes.join(";");
+                if style != active {
+                    out.push_str("\x1b[0m");
+                    if !style.is_empty() {
+                        let _ = write!(out, "\x1b[{}m", style);
+                    }
+                    active = style;
+                }
+                out.push(c);
+            }
+            Format::Html => {
+                let mut classes = match class {
+                    Class::Arith => "arith",
+                    Class::Move => "move",
+                    Class::Io => "io",
+                    Class::Net => "net",
+                    Class::Bracket(_) => "bracket",
+                    Class::Comment => "comment",
+                }
+                .to_string();
+                if cold {
+                    classes.push_str(" cold");
+                }
+                if is_hot {
+                    classes.push_str(" hot");
+                }
+
+                let _ = write!(out, "<span class=\"{}\"", classes);
+                if let Class::Bracket(depth) = class {
+                    let color = RAINBOW_HTML[depth % RAINBOW_HTML.len()];
+                    let _ = write!(out, " style=\"color: {}; font-weight: bold\"", color);
+                }
+                if let (true, Some(n)) = (is_op, count) {
+                    let _ = write!(out, " title=\"offset {}: executed {} times\"", offset, n);
+                }
+                out.push('>');
+                match c {
+                    '<' => out.push_str("&lt;"),
+                    '>' => out.push_str("&gt;"),
+                    '&' => out.push_str("&amp;"),
+                    '"' => out.push_str("&quot;"),
+                    _ => out.push(c),
+                }
+                out.push_str("</span>");
+            }
+        }
+    }
+
+    match format {
+        Format::Ansi if !active.is_empty() => out.push_str("\x1b[0m"),
+        Format::Html => out.push_str("</pre></body></html>\n"),
+        _ => {}
+    }
+    out
+}
+
+/// Load execution counts written as `{"<byte offset>": count, ...}`
+pub fn load_counts(path: &Path) -> io::Result<HashMap<usize, u64>> {
+    let text = std::fs::read_to_string(path)?;
+    let raw: HashMap<String, u64> =
+        serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    raw.into_iter()
+        .map(|(offset, count)| {
+            offset.parse().map(|offset| (offset, count)).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} is not a source offset", offset),
+                )
+            })
+        })
+        .collect()
+}
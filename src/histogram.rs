@@ -0,0 +1,277 @@
+//! Instruction and loop statistics (`--histogram`, `--profile`)
+//!
+//! With a [`Histogram`] attached, the VM counts every bytecode instruction
+//! it runs by opcode, and every pair of instructions run one after the
+//! other: frequent pairs are the candidates for the next fused
+//! superinstruction. For each loop it also records how many iterations each
+//! entry into it took, bucketed by powers of two (0, 1, 2-3, 4-7, ...).
+//!
+//! Loops the optimizer turned into single instructions (clears, scans,
+//! copies) count as those instructions, not as loops.
+//!
+//! It also counts each instruction by address, which
+//! [`profile`](Histogram::profile) maps back onto the source bytes the
+//! instruction came from.
+
+use crate::bytecode::{self, read_u32, Bytecode};
+use crate::interpreter::{Extensions, VM};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+/// Iteration counts of one loop
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoopStats {
+    /// Times the loop was reached, including those where it was skipped
+    pub entries: u64,
+    pub iterations: u64,
+    /// Most iterations in one entry
+    pub max: u64,
+    /// Entries by iteration count: bucket 0 for none, then bucket k for
+    /// 2^(k-1) to 2^k - 1 iterations
+    pub buckets: BTreeMap<u32, u64>,
+}
+
+/// Counts gathered while the VM runs
+pub struct Histogram {
+    opcodes: [u64; 256],
+    /// Consecutive opcode pairs, indexed by `first << 8 | second`
+    pairs: Vec<u64>,
+    previous: Option<u8>,
+    /// Iterations so far of the loops running now, by `JUMP_ZERO` address
+    running: HashMap<usize, u64>,
+    loops: BTreeMap<usize, LoopStats>,
+    /// Times each instruction ran, by address
+    addresses: Vec<u64>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            opcodes: [0; 256],
+            pairs: vec![0; 1 << 16],
+            previous: None,
+            running: HashMap::new(),
+            loops: BTreeMap::new(),
+            addresses: Vec::new(),
+        }
+    }
+}
+
+impl Histogram {
+    /// Instructions run, by opcode name, most frequent first
+    pub fn opcodes(&self) -> Vec<(&'static str, u64)> {
+        sorted(
+            self.opcodes
+                .iter()
+                .enumerate()
+                .map(|(opcode, &count)| (bytecode::opcode_name(opcode as u8), count)),
+        )
+    }
+
+    /// Pairs of instructions run one after the other, as `first second`,
+    /// most frequent first
+    pub fn pairs(&self) -> Vec<(String, u64)> {
+        sorted(self.pairs.iter().enumerate().map(|(pair, &count)| {
+            let name = format!(
+                "{} {}",
+                bytecode::opcode_name((pair >> 8) as u8),
+                bytecode::opcode_name(pair as u8)
+            );
+            (name, count)
+        }))
+    }
+
+    /// Statistics of each loop that was reached, by the address of its
+    /// `[`
+    pub fn loops(&self) -> &BTreeMap<usize, LoopStats> {
+        &self.loops
+    }
+
+    /// Write the counts as CSV rows of `kind,name,bucket,count`; `locate`
+    /// gives the line and column of an address
+    pub fn write_csv(
+        &self,
+        w: &mut dyn Write,
+        characters: &[(char, u64)],
+        locate: impl Fn(usize) -> Option<(usize, usize)>,
+    ) -> io::Result<()> {
+        writeln!(w, "kind,name,bucket,count")?;
+        for &(c, count) in characters {
+            // Quote the characters CSV treats specially
+            match c {
+                ',' | '"' => writeln!(
+                    w,
+                    "character,\"{}\",,{}",
+                    c.to_string().replace('"', "\"\""),
+                    count
+                )?,
+                _ => writeln!(w, "character,{},,{}", c, count)?,
+            }
+        }
+        for (name, count) in self.opcodes() {
+            writeln!(w, "opcode,{},,{}", name, count)?;
+        }
+        for (name, count) in self.pairs() {
+            writeln!(w, "pair,{},,{}", name, count)?;
+        }
+        for (&pc, stats) in &self.loops {
+            let name = position(pc, &locate);
+            for (&bucket, &count) in &stats.buckets {
+                writeln!(w, "loop,{},{},{}", name, bucket_label(bucket), count)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Times the command at each byte offset of the source ran, for every
+    /// command `bytecode` has a span for: `[` counts the times its loop was
+    /// reached and `]` its iterations, and every byte an instruction was compiled
+    /// from, a `+++` run or a loop the optimizer turned into a clear, gets
+    /// its count. Commands compiled away entirely have no entry.
+    pub fn profile(&self, bytecode: &Bytecode) -> BTreeMap<usize, u64> {
+        let mut counts = BTreeMap::new();
+        for &(pc, span) in bytecode.spans() {
+            let count = self.addresses.get(pc).copied().unwrap_or(0);
+            let offsets = match bytecode.code()[pc] {
+                bytecode::JUMP_ZERO => span.start..span.start + 1,
+                bytecode::JUMP_NONZERO => span.end - 1..span.end,
+                _ => span.start..span.end,
+            };
+            for offset in offsets {
+                *counts.entry(offset).or_insert(0) += count;
+            }
+        }
+        counts
+    }
+
+    /// The counts as one JSON object
+    pub fn to_json(
+        &self,
+        characters: &[(char, u64)],
+        locate: impl Fn(usize) -> Option<(usize, usize)>,
+    ) -> Value {
+        let object = |entries: Vec<(String, u64)>| {
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k, json!(v)))
+                    .collect::<Map<_, _>>(),
+            )
+        };
+        let loops: Vec<Value> = self
+            .loops
+            .iter()
+            .map(|(&pc, stats)| {
+                let (line, column) = locate(pc).unzip();
+                json!({
+                    "address": pc,
+                    "line": line,
+                    "column": column,
+                    "entries": stats.entries,
+                    "iterations": stats.iterations,
+                    "max": stats.max,
+                    "distribution": object(
+                        stats
+                            .buckets
+                            .iter()
+                            .map(|(&bucket, &count)| (bucket_label(bucket), count))
+                            .collect()
+                    ),
+                })
+            })
+            .collect();
+        json!({
+            "characters": object(characters.iter().map(|&(c, n)| (c.to_string(), n)).collect()),
+            "opcodes": object(self.opcodes().into_iter().map(|(k, v)| (k.to_string(), v)).collect()),
+            "pairs": object(self.pairs()),
+            "loops": loops,
+        })
+    }
+
+    /// Count the instruction at `at`, which sent execution on to `next`
+    fn record(&mut self, code: &[u8], at: usize, next: usize) {
+        let opcode = code[at];
+        self.opcodes[usize::from(opcode)] += 1;
+        if self.addresses.len() < code.len() {
+            self.addresses.resize(code.len(), 0);
+        }
+        self.addresses[at] += 1;
+        if let Some(previous) = self.previous.replace(opcode) {
+            self.pairs[usize::from(previous) << 8 | usize::from(opcode)] += 1;
+        }
+        match opcode {
+            bytecode::JUMP_ZERO if next == at + 5 => {
+                self.running.insert(at, 1);
+            }
+            bytecode::JUMP_ZERO => self.finish_loop(at, 0),
+            bytecode::JUMP_NONZERO => {
+                let start = read_u32(code, at + 1) - 5;
+                if next == at + 5 {
+                    let iterations = self.running.remove(&start).unwrap_or(0);
+                    self.finish_loop(start, iterations);
+                } else {
+                    *self.running.entry(start).or_insert(0) += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn finish_loop(&mut self, start: usize, iterations: u64) {
+        let stats = self.loops.entry(start).or_default();
+        stats.entries += 1;
+        stats.iterations += iterations;
+        stats.max = stats.max.max(iterations);
+        *stats
+            .buckets
+            .entry(u64::BITS - iterations.leading_zeros())
+            .or_insert(0) += 1;
+    }
+}
+
+impl VM {
+    /// Count the bytecode instruction at `at` that just ran
+    pub(crate) fn count_instruction(&mut self, code: &[u8], at: usize, next: usize) {
+        if let Some(ref mut histogram) = self.histogram {
+            histogram.record(code, at, next);
+        }
+    }
+}
+
+/// How often each command character appears in `source`, most frequent
+/// first
+pub fn character_counts(source: &[u8], extensions: &Extensions) -> Vec<(char, u64)> {
+    let mut counts = [0u64; 256];
+    for &b in source {
+        counts[usize::from(b)] += 1;
+    }
+    sorted(
+        (0..=255u8)
+            .filter(|&b| extensions.is_command(b))
+            .map(|b| (char::from(b), counts[usize::from(b)])),
+    )
+}
+
+/// Non-zero counts, most frequent first, then by name
+fn sorted<N: Ord>(counts: impl Iterator<Item = (N, u64)>) -> Vec<(N, u64)> {
+    let mut counts: Vec<_> = counts.filter(|&(_, count)| count > 0).collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+/// `2-3` for bucket 2, and so on
+fn bucket_label(bucket: u32) -> String {
+    match bucket {
+        0 => "0".to_string(),
+        1 => "1".to_string(),
+        _ => format!("{}-{}", 1u64 << (bucket - 1), (1u128 << bucket) - 1),
+    }
+}
+
+fn position(pc: usize, locate: &impl Fn(usize) -> Option<(usize, usize)>) -> String {
+    match locate(pc) {
+        Some((line, column)) => format!("{}:{}", line, column),
+        None => format!("@{}", pc),
+    }
+}
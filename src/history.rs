@@ -0,0 +1,217 @@
+//! Execution history for looking back (`--record-history N`)
+//!
+//! With a [`History`] attached, the VM records for each of the last N
+//! instructions where it ran, where the pointer was and went, and which
+//! cells it changed from what to what. That is enough to undo them, so the
+//! control socket can show the tape as it was some steps back and list the
+//! instructions that wrote a cell ("how did this cell become 0x41").
+//!
+//! Changes are found by comparing the tape with a copy kept in step with
+//! it. Most instructions can only change the cells under the pointer
+//! before and after them (and the status cell), so only those are
+//! compared; instructions that write further afield compare the whole tape.
+
+use crate::bytecode;
+use crate::interpreter::{Op, VM};
+use std::collections::VecDeque;
+
+/// One recorded instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Steps executed once it had run
+    pub step: u64,
+    /// Its bytecode address, or `None` for an op run from the op tree
+    pub pc: Option<usize>,
+    /// Where the pointer was before it ran
+    pub pointer: usize,
+    /// How far it moved the pointer
+    pub moved: isize,
+    /// Cells it changed: index, old value, new value
+    pub changes: Vec<(usize, u8, u8)>,
+}
+
+/// The last few instructions a VM ran, most recent last
+pub struct History {
+    capacity: usize,
+    entries: VecDeque<Entry>,
+    /// The tape as of the last recorded instruction
+    shadow: Vec<u8>,
+    /// The VM's origin when `shadow` was taken, to spot tapes grown left
+    origin: usize,
+}
+
+impl History {
+    /// Keep the last `capacity` instructions of a VM whose tape currently
+    /// holds `tape`
+    pub fn new(capacity: usize, tape: &[u8]) -> Self {
+        History {
+            capacity,
+            entries: VecDeque::with_capacity(capacity.min(1 << 16)),
+            shadow: tape.to_vec(),
+            origin: 0,
+        }
+    }
+
+    pub fn entries(&self) -> &VecDeque<Entry> {
+        &self.entries
+    }
+
+    /// Recorded instructions that changed `cell`, most recent first
+    pub fn writes_to(&self, cell: usize) -> impl Iterator<Item = &Entry> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(move |entry| entry.changes.iter().any(|&(index, ..)| index == cell))
+    }
+
+    /// Tape and pointer as they were `back` instructions ago, given the
+    /// current ones; `None` if fewer instructions were recorded
+    pub fn rewind(&self, tape: &[u8], pointer: usize, back: usize) -> Option<(Vec<u8>, usize)> {
+        if back > self.entries.len() {
+            return None;
+        }
+        let mut tape = tape.to_vec();
+        let mut pointer = pointer;
+        for entry in self.entries.iter().rev().take(back) {
+            for &(index, old, _) in &entry.changes {
+                tape[index] = old;
+            }
+            pointer = entry.pointer;
+        }
+        Some((tape, pointer))
+    }
+
+    /// Forget what was recorded, for a VM that starts over with `tape`
+    pub(crate) fn reset(&mut self, tape: &[u8]) {
+        self.entries.clear();
+        self.shadow = tape.to_vec();
+        self.origin = 0;
+    }
+
+    /// Note a cell changed from outside the program
+    pub(crate) fn sync_cell(&mut self, index: usize, value: u8) {
+        if let Some(cell) = self.shadow.get_mut(index) {
+            *cell = value;
+        }
+    }
+
+    fn record(&mut self, vm: &VM, pc: Option<usize>, mut pointer: usize, wide: bool) {
+        if vm.tape.len() != self.shadow.len() {
+            // An unbounded tape grew: shift everything recorded to match
+            let added = vm.origin() - self.origin;
+            let mut shadow = vec![0; vm.tape.len()];
+            shadow[added..added + self.shadow.len()].copy_from_slice(&self.shadow);
+            self.shadow = shadow;
+            self.origin = vm.origin();
+            for entry in &mut self.entries {
+                entry.pointer += added;
+                for change in &mut entry.changes {
+                    change.0 += added;
+                }
+            }
+            pointer += added;
+        }
+
+        let mut changes = Vec::new();
+        if wide {
+            for (index, (&new, old)) in vm.tape.iter().zip(&mut self.shadow).enumerate() {
+                if new != *old {
+                    changes.push((index, *old, new));
+                    *old = new;
+                }
+            }
+        } else {
+            let len = vm.tape.len();
+            let status = vm.status_cell.map(|cell| (vm.origin() + cell) % len);
+            for index in [Some(pointer), Some(vm.pointer), status]
+                .into_iter()
+                .flatten()
+            {
+                let (new, old) = (vm.tape[index], &mut self.shadow[index]);
+                if new != *old {
+                    changes.push((index, *old, new));
+                    *old = new;
+                }
+            }
+        }
+
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            step: vm.steps(),
+            pc,
+            pointer,
+            moved: vm.pointer as isize - pointer as isize,
+            changes,
+        });
+    }
+}
+
+impl VM {
+    /// Record the bytecode instruction at `pc` that just ran, having found
+    /// the pointer at `pointer`
+    pub(crate) fn record_instruction(&mut self, pc: usize, opcode: u8, pointer: usize) {
+        if let Some(mut history) = self.history.take() {
+            history.record(self, Some(pc), pointer, wide_opcode(opcode));
+            self.history = Some(history);
+        }
+    }
+
+    /// Record the op that just ran, having found the pointer at `pointer`
+    pub(crate) fn record_op(&mut self, op: &Op, pointer: usize) {
+        if let Some(mut history) = self.history.take() {
+            history.record(self, None, pointer, wide_op(op));
+            self.history = Some(history);
+        }
+    }
+}
+
+/// Whether the instruction may change cells other than those under the
+/// pointer before and after it
+fn wide_opcode(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        bytecode::MUL_ADD
+            | bytecode::LOAD
+            | bytecode::COMPARE_SWAP
+            | bytecode::RECEIVE_MESSAGE
+            | bytecode::KV_GET
+            | bytecode::HTTP
+            | bytecode::CHECKSUM
+            | bytecode::BASE64_ENCODE
+            | bytecode::BASE64_DECODE
+            | bytecode::XOR
+            | bytecode::RECEIVE_LINE
+            | bytecode::RESOLVE
+            | bytecode::CHANNELS
+            | bytecode::RECEIVE_BLOCK
+            | bytecode::TRAP
+            | bytecode::FEATURES
+    )
+}
+
+fn wide_op(op: &Op) -> bool {
+    matches!(
+        op,
+        Op::MulAdd(_)
+            | Op::Load(..)
+            | Op::CompareSwap
+            | Op::ReceiveMessage
+            | Op::KvGet
+            | Op::Http
+            | Op::Checksum
+            | Op::Base64Encode
+            | Op::Base64Decode
+            | Op::Xor
+            | Op::ReceiveLine
+            | Op::Resolve
+            | Op::Channels
+            | Op::ReceiveBlock
+            | Op::Trap
+            | Op::Features(_)
+    )
+}
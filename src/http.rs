@@ -0,0 +1,95 @@
+//! HTTP requests from programs (`--ext http`)
+//!
+//! `*` makes a request described by the tape at the pointer:
+//!
+//! - the current cell selects the method: 0 for GET, anything else for POST
+//! - the URL follows as zero-terminated bytes
+//! - for POST, the zero-terminated request body follows the URL
+//!
+//! It blocks until the response arrives, then writes the status class
+//! (`status / 100`, so 2 for success) to the current cell, or 0 if no
+//! response came, and the response body, zero-terminated, from the next
+//! cell on. Bodies are cut to fit the tape. Counts as networking, so it is
+//! refused when networking is disabled.
+
+use crate::interpreter::{Result, TrainfuckError, VM};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+
+/// How long a request may take, from connecting to reading the body
+#[cfg(not(target_arch = "wasm32"))]
+pub const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl VM {
+    /// `*`: make the request at the pointer and write back the response
+    pub(crate) fn http_request(&mut self) -> Result<()> {
+        self.check_sandbox('*')?;
+        if !self.network_enabled {
+            return Err(TrainfuckError::NetworkDisabled);
+        }
+        let len = self.tape.len();
+        let string = |from: usize| -> Vec<u8> {
+            (from..len)
+                .map(|i| self.tape[(self.pointer + i) % len])
+                .take_while(|&c| c != 0)
+                .collect()
+        };
+        let url = string(1);
+        let body = match self.tape[self.pointer] {
+            0 => None,
+            _ => Some(string(url.len() + 2)),
+        };
+
+        // Room for the body and its terminator without reaching the pointer
+        let room = len.saturating_sub(2);
+        let (status, response) = match fetch(&String::from_utf8_lossy(&url), body, room) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("[trainfuck] HTTP request failed: {}", e);
+                (0, Vec::new())
+            }
+        };
+        self.tape[self.pointer] = status;
+        for (i, &byte) in response.iter().chain(&[0]).enumerate() {
+            self.tape[(self.pointer + 1 + i) % len] = byte;
+        }
+        Ok(())
+    }
+}
+
+/// Status class and up to `limit` bytes of body
+#[cfg(not(target_arch = "wasm32"))]
+fn fetch(
+    url: &str,
+    body: Option<Vec<u8>>,
+    limit: usize,
+) -> std::result::Result<(u8, Vec<u8>), String> {
+    use std::io::Read;
+
+    let agent = ureq::AgentBuilder::new().timeout(HTTP_TIMEOUT).build();
+    let result = match body {
+        None => agent.get(url).call(),
+        Some(body) => agent.post(url).send_bytes(&body),
+    };
+    let response = match result {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => return Err(e.to_string()),
+    };
+    let status = (response.status() / 100) as u8;
+    let mut bytes = Vec::new();
+    response
+        .into_reader()
+        .take(limit as u64)
+        .read_to_end(&mut bytes)
+        .map_err(|e| e.to_string())?;
+    Ok((status, bytes))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn fetch(
+    _url: &str,
+    _body: Option<Vec<u8>>,
+    _limit: usize,
+) -> std::result::Result<(u8, Vec<u8>), String> {
+    Err("not available in browser builds".to_string())
+}
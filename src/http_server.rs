@@ -0,0 +1,144 @@
+//! HTTP server mode (`--http-server ADDR`)
+//!
+//! Runs the program once per request, on a fresh VM whose tape starts with
+//! the request path and body, each zero-terminated, and whose `,` reads the
+//! body. Whatever the program prints becomes the response body; a runtime
+//! error answers 500 with the error instead.
+
+use std::io::{self, Cursor, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Request, Response, Server};
+use trainfuck::bytecode::Bytecode;
+use trainfuck::interpreter::{Extensions, TapeModel, TrainfuckError, VM};
+use trainfuck::kv::SharedKvStore;
+use trainfuck::shared::SharedSegment;
+
+/// Requests handled at the same time
+const WORKERS: usize = 4;
+
+/// Largest request body accepted
+const MAX_REQUEST_BYTES: usize = 1 << 20;
+
+/// How often idle workers check for Ctrl-C/SIGTERM
+const INTERRUPT_POLL: Duration = Duration::from_millis(100);
+
+/// Settings each request's VM is built from
+pub struct Template {
+    pub tape_size: usize,
+    pub tape_model: TapeModel,
+    pub max_loop_depth: usize,
+    pub extensions: Extensions,
+    pub shared: Option<SharedSegment>,
+    pub kv: Option<SharedKvStore>,
+    /// Set on Ctrl-C/SIGTERM: stops running programs and the server
+    pub interrupt: Arc<AtomicBool>,
+}
+
+impl Template {
+    fn vm(&self) -> VM {
+        let mut vm = VM::with_tape_size(self.tape_size);
+        vm.tape_model = self.tape_model;
+        vm.max_loop_depth = self.max_loop_depth;
+        vm.extensions = self.extensions;
+        vm.shared = self.shared.clone();
+        vm.kv = self.kv.clone();
+        vm.interrupt = Some(self.interrupt.clone());
+        vm
+    }
+}
+
+/// Output sink that can be read back after the VM is done with it
+#[derive(Clone, Default)]
+struct Collect(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Collect {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Answer requests on `addr` with `program` until interrupted
+pub fn serve(addr: &str, program: Bytecode, template: Template) -> io::Result<()> {
+    let server = Server::http(addr).map_err(io::Error::other)?;
+    eprintln!(
+        "[trainfuck] Serving HTTP on http://{}",
+        server.server_addr()
+    );
+
+    let server = Arc::new(server);
+    let shared = Arc::new((program, template));
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let server = server.clone();
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let (program, template) = &*shared;
+                while !template.interrupt.load(Ordering::Relaxed) {
+                    let request = match server.recv_timeout(INTERRUPT_POLL) {
+                        Ok(Some(request)) => request,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            eprintln!("[trainfuck] Failed to receive request: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = handle(request, program, template) {
+                        eprintln!("[trainfuck] Failed to answer request: {}", e);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+fn handle(mut request: Request, program: &Bytecode, template: &Template) -> io::Result<()> {
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(MAX_REQUEST_BYTES as u64 + 1)
+        .read_to_end(&mut body)?;
+    if body.len() > MAX_REQUEST_BYTES {
+        return request.respond(Response::from_string("request too large\n").with_status_code(413));
+    }
+
+    let path = request.url().as_bytes();
+    let mut cells = Vec::with_capacity(path.len() + body.len() + 2);
+    cells.extend(path);
+    cells.push(0);
+    cells.extend(&body);
+    cells.push(0);
+    if cells.len() > template.tape_size {
+        return request.respond(Response::from_string("request too large\n").with_status_code(413));
+    }
+
+    let output = Collect::default();
+    let mut vm = template.vm();
+    vm.input = Box::new(Cursor::new(body));
+    vm.output = Box::new(output.clone());
+    vm.tape_mut()[..cells.len()].copy_from_slice(&cells);
+    let result = vm.execute_bytecode(program);
+    let _ = vm.shutdown();
+
+    let response = match result {
+        Ok(()) => Response::from_data(output.0.lock().unwrap().clone()),
+        Err(TrainfuckError::Interrupted) => {
+            Response::from_string("shutting down\n").with_status_code(503)
+        }
+        Err(e) => Response::from_string(format!("Runtime error: {}\n", e)).with_status_code(500),
+    };
+    let content_type = Header::from_bytes("Content-Type", "text/plain; charset=utf-8").unwrap();
+    request.respond(response.with_header(content_type))
+}
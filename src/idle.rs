@@ -0,0 +1,110 @@
+//! Closing idle connections (`--idle-timeout`, `--keepalive`)
+//!
+//! With [`VM::idle_timeout`] set, a connection the peer has sent nothing on
+//! for that long is closed, so clients that vanished without hanging up
+//! don't pile up on a server's channels. With [`VM::keepalive`] also set,
+//! the peer first gets that byte as a ping and one more timeout to answer
+//! with anything at all.
+//!
+//! `` ` `` and the other receiving ops notice while they wait: TCP reads
+//! wake up at the timeout, and a connection closed this way reads as 0 and
+//! reports [`NetStatus::Idle`]. Connections parked on other channels are
+//! checked whenever `(` or `)` runs, and count as active while they have
+//! data waiting.
+
+use crate::interpreter::{NetStatus, VM};
+use crate::net::Stream;
+use std::time::{Duration, Instant};
+
+/// When a connection was last heard from
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Liveness {
+    heard_at: Instant,
+    /// The keepalive byte went out since
+    pinged: bool,
+}
+
+impl Liveness {
+    pub(crate) fn new() -> Self {
+        Liveness {
+            heard_at: Instant::now(),
+            pinged: false,
+        }
+    }
+}
+
+/// Whether to keep `connection`, pinging it if it's due
+fn keep(
+    connection: &mut dyn Stream,
+    liveness: &mut Liveness,
+    timeout: Duration,
+    keepalive: Option<u8>,
+) -> bool {
+    if liveness.heard_at.elapsed() < timeout {
+        return true;
+    }
+    if let (Some(ping), false) = (keepalive, liveness.pinged) {
+        *liveness = Liveness {
+            heard_at: Instant::now(),
+            pinged: true,
+        };
+        let sent = connection
+            .write_all(&[ping])
+            .and_then(|()| connection.flush());
+        return sent.is_ok();
+    }
+    eprintln!(
+        "[trainfuck] Closing connection idle for {:?}",
+        liveness.heard_at.elapsed()
+    );
+    false
+}
+
+impl VM {
+    /// The open connection was heard from
+    pub(crate) fn heard(&mut self) {
+        self.liveness = Liveness::new();
+    }
+
+    /// Close the open connection if it has been idle too long, reporting
+    /// it; returns whether it was closed
+    pub(crate) fn close_if_idle(&mut self) -> bool {
+        let (Some(timeout), Some(connection)) = (self.idle_timeout, self.connection.as_mut())
+        else {
+            return false;
+        };
+        if keep(
+            connection.as_mut(),
+            &mut self.liveness,
+            timeout,
+            self.keepalive,
+        ) {
+            return false;
+        }
+        self.connection = None;
+        self.pushback.clear();
+        self.dialed = None;
+        self.report(NetStatus::Idle);
+        true
+    }
+
+    /// Close connections parked on other channels that have been idle too
+    /// long
+    pub(crate) fn close_idle_parked(&mut self) {
+        let Some(timeout) = self.idle_timeout else {
+            return;
+        };
+        let keepalive = self.keepalive;
+        self.parked.retain(|_, parked| {
+            if parked.connection.has_pending() {
+                parked.liveness = Liveness::new();
+            }
+            keep(
+                parked.connection.as_mut(),
+                &mut parked.liveness,
+                timeout,
+                keepalive,
+            )
+        });
+    }
+}
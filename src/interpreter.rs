@@ -16,13 +16,75 @@
 //! - `@` : Accept incoming connection / close connection
 //! - `` ` `` : Receive byte from network
 //! - `'` : Send byte to network
+//!
+//! With [`Extensions::channels`], `(` and `)` juggle several connections;
+//! see [`channels`](crate::channels). With [`Extensions::blocks`], `'` and
+//! `` ` `` move counted blocks of cells instead of single bytes.
 
+use crate::access::AccessList;
+use crate::actors::Actors;
+use crate::budget::Budget;
+use crate::channels::{Parked, FIRST_CHANNEL};
+use crate::clock::Clock;
+use crate::directives::{self, Directives};
+use crate::features;
+use crate::health::{Health, Waiting};
+use crate::histogram::Histogram;
+use crate::history::History;
+use crate::idle::Liveness;
+use crate::kv::SharedKvStore;
+use crate::kernel;
+use crate::net::{Listener, MultiListener, Network, Stream, TcpNetwork};
+use crate::program::{Metadata, Program};
+use crate::shared::SharedSegment;
+use crate::span::{SourceMap, Span, BOM};
+use crate::tape::Tape;
+use crate::trap::TrapHandler;
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, Engine, GeneralPurpose, GeneralPurposeConfig};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::io::{self, BufRead, Read, Write};
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 /// Memory tape size (30KB as per original Brainfuck spec)
-const TAPE_SIZE: usize = 30_000;
+pub const TAPE_SIZE: usize = kernel::TAPE_SIZE;
+
+/// Default limit on loop nesting, well within what the recursive passes
+/// (optimizer, tree executor, hot-loop compiler) can handle on a 2 MiB stack
+pub const DEFAULT_MAX_LOOP_DEPTH: usize = 1000;
+
+/// Tape cells [`VM::room_cell`] starts: a room name of up to 16 bytes and
+/// its terminator
+pub const ROOM_CELLS: usize = 17;
+
+/// Tape cells [`VM::peer_cell`] starts: the channel, the peer's IPv4
+/// address and port, and the Unix time in seconds, all big-endian
+pub const PEER_CELLS: usize = 11;
+
+/// How many steps run between wall-clock deadline checks
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// Longest wait between connection attempts, however many have failed
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How often a wait between connection attempts checks for interrupts
+const INTERRUPT_POLL: Duration = Duration::from_millis(50);
+
+/// Standard base64 for `:` and `;`; decoding accepts text without padding
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
 
 #[derive(Error, Debug)]
 pub enum TrainfuckError {
@@ -35,14 +97,115 @@ pub enum TrainfuckError {
     #[error("I/O error: {0}")]
     IoError(#[from] io::Error),
 
+    #[error("Output closed")]
+    OutputClosed,
+
+    #[error("Write to protected cell {0}")]
+    ProtectedWrite(usize),
+
+    #[error("Pointer moved to no-access cell {0}")]
+    NoAccess(usize),
+
     #[error("Network error: {0}")]
     NetworkError(String),
+
+    #[error("Interrupted")]
+    Interrupted,
+
+    #[error("Step limit of {0} exceeded")]
+    StepLimitExceeded(u64),
+
+    #[error("Time limit exceeded")]
+    TimeLimitExceeded,
+
+    #[error("Output limit of {0} bytes exceeded")]
+    OutputLimitExceeded(u64),
+
+    #[error("Tape is larger than the limit of {0} cells")]
+    TapeLimitExceeded(usize),
+
+    #[error("Networking is disabled")]
+    NetworkDisabled,
+
+    #[error("Operation would block")]
+    WouldBlock,
+
+    #[error("Loop at position {0} is nested more than {1} deep")]
+    NestingTooDeep(usize, usize),
+
+    #[error("{} at position {1} is not a Brainfuck command", describe_byte(*.0))]
+    NotBrainfuck(u8, usize),
+
+    #[error("Invalid directive at position {1}: {0}")]
+    InvalidDirective(String, usize),
+
+    #[error("Loops nested more than {0} deep")]
+    LoopDepthExceeded(usize),
+
+    #[error("No shared segment is attached")]
+    NoSharedSegment,
+
+    #[error("No key-value store is open")]
+    NoKvStore,
+
+    #[error("No '@' outside a loop to start the connection handler at")]
+    NoHandler,
+
+    #[error("No trap handler is installed")]
+    NoTrapHandler,
+
+    #[error("Trap {0} failed: {1}")]
+    TrapFailed(u8, String),
+
+    #[error("Budget of {0} used up")]
+    BudgetExhausted(&'static str),
+
+    #[error("'{0}' is not allowed in the sandbox")]
+    Sandboxed(char),
+}
+
+impl TrainfuckError {
+    /// Which resource limit stopped the program, if one did: `steps`,
+    /// `time`, `output`, `tape`, `loop_depth` or `budget`
+    pub fn limit(&self) -> Option<&'static str> {
+        match self {
+            TrainfuckError::StepLimitExceeded(_) => Some("steps"),
+            TrainfuckError::TimeLimitExceeded => Some("time"),
+            TrainfuckError::OutputLimitExceeded(_) => Some("output"),
+            TrainfuckError::TapeLimitExceeded(_) => Some("tape"),
+            TrainfuckError::NestingTooDeep(..) | TrainfuckError::LoopDepthExceeded(_) => {
+                Some("loop_depth")
+            }
+            TrainfuckError::BudgetExhausted(_) => Some("budget"),
+            _ => None,
+        }
+    }
+
+    /// Offset in the source a parse error points at
+    pub fn position(&self) -> Option<usize> {
+        match *self {
+            TrainfuckError::UnmatchedOpenBracket(position)
+            | TrainfuckError::UnmatchedCloseBracket(position)
+            | TrainfuckError::NestingTooDeep(position, _)
+            | TrainfuckError::NotBrainfuck(_, position)
+            | TrainfuckError::InvalidDirective(_, position) => Some(position),
+            _ => None,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, TrainfuckError>;
 
+/// `'x'` for printable ASCII, the byte's value otherwise
+fn describe_byte(byte: u8) -> String {
+    match byte {
+        b' '..=b'~' => format!("{:?}", byte as char),
+        _ => format!("Byte 0x{:02X}", byte),
+    }
+}
+
 /// Represents parsed Trainfuck operations
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Op {
     // Standard Brainfuck
     MoveRight(usize), // Optimized: multiple > combined
@@ -53,313 +216,2472 @@ pub enum Op {
     Input,
     Loop(Vec<Op>),
 
+    // Superinstructions produced by the optimizer
+    Clear,                    // [-]
+    ScanRight(usize),         // [>] with the given stride
+    ScanLeft(usize),          // [<] with the given stride
+    MulAdd(Vec<(isize, u8)>), // [->+<]: cell[p + offset] += cell[p] * factor; cell[p] = 0
+    Print(Vec<u8>),           // Output evaluated at compile time
+    Load(usize, Vec<u8>),     // Cells from the given index, evaluated at compile time
+
     // Trainfuck Networking
     Connect, // %
     Listen,  // $
     Accept,  // @
     Receive, // `
     Send,    // '
+
+    // Opt-in extensions, see `Extensions`
+    CompareSwap,    // ~
+    Spawn,          // &
+    SendMessage,    // ^
+    ReceiveMessage, // ?
+    Yield,          // |
+    KvPut,          // {
+    KvGet,          // }
+    Http,           // *
+    Checksum,       // #
+    Base64Encode,   // :
+    Base64Decode,   // ;
+    Xor,            // !
+    Peek,           // =
+    Unread,         // \
+    ReceiveLine,    // "
+    Resolve,        // /
+    SelectChannel,  // (
+    Channels,       // )
+    Flush,          // _
+    SendBlock,      // ' with `Extensions::blocks`
+    ReceiveBlock,   // ` with `Extensions::blocks`
+    Trap,           // ! with `Extensions::trap`
+    Features(u32),  // ? with `Extensions::features`: the parser's feature bits
 }
 
-/// Parses Trainfuck source code into operations
-pub fn parse(source: &str) -> Result<Vec<Op>> {
-    let chars: Vec<char> = source.chars().collect();
-    let mut ops = Vec::new();
-    let mut i = 0;
+impl Op {
+    /// Whether this is one of the Trainfuck networking extensions
+    pub fn is_network(&self) -> bool {
+        matches!(
+            self,
+            Op::Connect
+                | Op::Listen
+                | Op::Accept
+                | Op::Receive
+                | Op::Send
+                | Op::Flush
+                | Op::SendBlock
+                | Op::ReceiveBlock
+                | Op::Peek
+                | Op::ReceiveLine
+        )
+    }
 
-    while i < chars.len() {
-        match chars[i] {
-            '>' => {
-                let count = count_consecutive(&chars, i, '>');
-                ops.push(Op::MoveRight(count));
-                i += count;
-            }
-            '<' => {
-                let count = count_consecutive(&chars, i, '<');
-                ops.push(Op::MoveLeft(count));
-                i += count;
-            }
-            '+' => {
-                let count = count_consecutive(&chars, i, '+');
-                ops.push(Op::Increment((count % 256) as u8));
-                i += count;
-            }
-            '-' => {
-                let count = count_consecutive(&chars, i, '-');
-                ops.push(Op::Decrement((count % 256) as u8));
-                i += count;
-            }
-            '.' => {
-                ops.push(Op::Output);
-                i += 1;
-            }
-            ',' => {
-                ops.push(Op::Input);
-                i += 1;
-            }
-            '[' => {
-                let (loop_ops, end_pos) = parse_loop(&chars, i)?;
-                ops.push(Op::Loop(loop_ops));
-                i = end_pos + 1;
-            }
-            ']' => {
-                return Err(TrainfuckError::UnmatchedCloseBracket(i));
-            }
-            // Trainfuck networking
-            '%' => {
-                ops.push(Op::Connect);
-                i += 1;
-            }
-            '$' => {
-                ops.push(Op::Listen);
-                i += 1;
-            }
-            '@' => {
-                ops.push(Op::Accept);
-                i += 1;
-            }
-            '`' => {
-                ops.push(Op::Receive);
-                i += 1;
-            }
-            '\'' => {
-                ops.push(Op::Send);
-                i += 1;
-            }
-            // Everything else is a comment
-            _ => {
-                i += 1;
-            }
+    /// The character this op is parsed from, for ops of a single one
+    pub fn command(&self) -> Option<u8> {
+        Some(match self {
+            Op::Output => b'.',
+            Op::Input => b',',
+            Op::Connect => b'%',
+            Op::Listen => b'$',
+            Op::Accept => b'@',
+            Op::Receive | Op::ReceiveBlock => b'`',
+            Op::Send | Op::SendBlock => b'\'',
+            Op::CompareSwap => b'~',
+            Op::Spawn => b'&',
+            Op::SendMessage => b'^',
+            Op::ReceiveMessage | Op::Features(_) => b'?',
+            Op::Yield => b'|',
+            Op::KvPut => b'{',
+            Op::KvGet => b'}',
+            Op::Http => b'*',
+            Op::Checksum => b'#',
+            Op::Base64Encode => b':',
+            Op::Base64Decode => b';',
+            Op::Xor | Op::Trap => b'!',
+            Op::Peek => b'=',
+            Op::Unread => b'\\',
+            Op::ReceiveLine => b'"',
+            Op::Resolve => b'/',
+            Op::SelectChannel => b'(',
+            Op::Channels => b')',
+            Op::Flush => b'_',
+            _ => return None,
+        })
+    }
+}
+
+/// Opt-in commands beyond Trainfuck's. Their characters are comments
+/// unless enabled, so existing programs keep their meaning.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Extensions {
+    /// `~`: compare-and-swap on the VM's [`SharedSegment`]. With the cell
+    /// at the pointer holding a segment index and the next two an expected
+    /// and a new value, stores the new value if the segment cell holds the
+    /// expected one, and writes what it held over the expected value.
+    pub shared: bool,
+    /// `&`, `^` and `?`: start child VMs and exchange bytes with them; see
+    /// [`actors`](crate::actors)
+    pub actors: bool,
+    /// `|`: let a [`Scheduler`](crate::scheduler::Scheduler) switch to
+    /// another program; does nothing when running alone
+    pub yielding: bool,
+    /// `{` and `}`: store and look up byte strings in the VM's persistent
+    /// [`KvStore`](crate::kv::KvStore); see [`kv`](crate::kv)
+    pub kv: bool,
+    /// `*`: make an HTTP request and read the response; see
+    /// [`http`](crate::http)
+    pub http: bool,
+    /// `#`: CRC-32 of the cells before the pointer. With the current cell
+    /// holding a count, checksums that many cells immediately before it
+    /// and writes the result big-endian over the current cell and the
+    /// next three, so a checksum can be appended right after a message.
+    pub checksum: bool,
+    /// `:` and `;`: base64. `:` replaces the cells after the pointer,
+    /// as many as the current cell says, with their base64 text,
+    /// zero-terminated. `;` replaces the zero-terminated base64 text after
+    /// the pointer with the bytes it encodes and writes their count to the
+    /// current cell, or leaves the text and writes 0 if it isn't valid
+    /// base64 of at most 255 bytes; `:;` gets the original cells back.
+    pub base64: bool,
+    /// `!`: XOR the cells after the pointer, as many as the current cell
+    /// says, with the zero-terminated key that follows them, repeating the
+    /// key as needed. Running it again with the same key undoes it.
+    pub xor: bool,
+    /// `=` and `\`: look ahead on the connection. `=` reads the next byte
+    /// like `` ` `` but leaves it to be received again; `\` pushes the
+    /// current cell back, so the next `` ` `` (or `=`) gets it first. Both
+    /// stack up, and are forgotten when the connection changes.
+    pub peek: bool,
+    /// `"`: receive a line. With the current cell holding a limit, receives
+    /// into the cells after the pointer until a newline (which is kept) or
+    /// that many bytes, and writes how many arrived to the current cell,
+    /// fewer if the connection ended first.
+    pub line: bool,
+    /// `/`: look up the zero-terminated hostname at the pointer and write
+    /// its first IPv4 address over it, ready for `%` once the port is set,
+    /// clearing the rest of the name. Writes 0.0.0.0 if the lookup fails.
+    /// Counts as networking.
+    pub dns: bool,
+    /// `(` and `)`: keep several connections open on numbered channels,
+    /// switching between them with `(` and finding one with data waiting
+    /// with `)`; see [`channels`](crate::channels)
+    pub channels: bool,
+    /// `_`: with `'` keeping the bytes a non-blocking connection can't take
+    /// yet instead of waiting, send them; see
+    /// [`backpressure`](crate::backpressure)
+    pub backpressure: bool,
+    /// `'` and `` ` `` move blocks of cells instead of single ones, with
+    /// the current cell holding the count: `'` sends the cells after the
+    /// pointer in one write, and `` ` `` waits for data and receives as
+    /// much as has arrived, up to the count, into them, writing how much
+    /// to the current cell (0 when the connection ended)
+    pub blocks: bool,
+    /// `!`: call the host's [`on_trap`](VM::on_trap) handler with the
+    /// current cell as the call number; see [`trap`](crate::trap). Takes
+    /// `!` over from `xor`.
+    pub trap: bool,
+    /// `?`: write the interpreter's version and which features the program
+    /// can use from the pointer on; see [`features`](crate::features).
+    /// Takes `?` over from `actors`.
+    pub features: bool,
+}
+
+impl Extensions {
+    /// Whether the parser reads `byte` as a command rather than a comment
+    pub fn is_command(&self, byte: u8) -> bool {
+        match byte {
+            b'>' | b'<' | b'+' | b'-' | b'.' | b',' | b'[' | b']' => true,
+            b'%' | b'$' | b'@' | b'`' | b'\'' => true,
+            b'~' => self.shared,
+            b'&' | b'^' => self.actors,
+            b'?' => self.actors || self.features,
+            b'|' => self.yielding,
+            b'{' | b'}' => self.kv,
+            b'*' => self.http,
+            b'#' => self.checksum,
+            b':' | b';' => self.base64,
+            b'!' => self.xor || self.trap,
+            b'=' | b'\\' => self.peek,
+            b'"' => self.line,
+            b'/' => self.dns,
+            b'(' | b')' => self.channels,
+            b'_' => self.backpressure,
+            _ => false,
         }
     }
+}
 
-    Ok(ops)
+/// How `%` retries connections that are refused or time out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectRetry {
+    /// Attempts after the first one; 0 fails at once
+    pub retries: u32,
+    /// Wait before the first retry, doubled before each further one
+    pub backoff: Duration,
 }
 
-fn count_consecutive(chars: &[char], start: usize, target: char) -> usize {
-    chars[start..].iter().take_while(|&&c| c == target).count()
+impl Default for ConnectRetry {
+    fn default() -> Self {
+        ConnectRetry {
+            retries: 0,
+            backoff: Duration::from_millis(500),
+        }
+    }
 }
 
-fn parse_loop(chars: &[char], start: usize) -> Result<(Vec<Op>, usize)> {
-    let mut depth = 1;
-    let mut i = start + 1;
+/// Outcome of a networking op, written to the VM's
+/// [`status_cell`](VM::status_cell) when it has one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum NetStatus {
+    Ok = 0,
+    /// The connection had dropped and was dialed again (see
+    /// [`VM::reconnect`]): `'` sent its byte on the new connection, while
+    /// `` ` `` read 0 and the program should repeat what went unanswered
+    Reconnected = 1,
+    /// `@` found no connection waiting within
+    /// [`accept_timeout`](VM::accept_timeout) and left none open
+    NoConnection = 2,
+    /// The peer closed the connection: `` ` `` read 0 at its end, or `'`
+    /// found it gone
+    Closed = 3,
+    /// The connection was reset
+    Reset = 4,
+    /// Any other error on the connection
+    Failed = 5,
+    /// The peer went over its flood limits (see [`flood`](crate::flood)):
+    /// some of its data was dropped or held back, or the connection was
+    /// closed
+    Flooded = 6,
+    /// The connection was closed for sending nothing for
+    /// [`idle_timeout`](VM::idle_timeout)
+    Idle = 7,
+    /// The connection couldn't take everything sent yet; the rest waits
+    /// for `'` or `_` (see [`backpressure`](crate::backpressure))
+    Pending = 8,
+}
 
-    while i < chars.len() && depth > 0 {
-        match chars[i] {
-            '[' => depth += 1,
-            ']' => depth -= 1,
-            _ => {}
+impl NetStatus {
+    pub(crate) fn from_error(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::BrokenPipe | io::ErrorKind::UnexpectedEof => NetStatus::Closed,
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => NetStatus::Reset,
+            _ => NetStatus::Failed,
         }
-        if depth > 0 {
-            i += 1;
+    }
+}
+
+/// How `` ` `` and `'` handle errors on the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetErrors {
+    /// Carry on, reporting the error in the status cell; `` ` `` reads 0
+    Status,
+    /// Stop the program with [`TrainfuckError::NetworkError`]
+    Abort,
+}
+
+/// Which characters a [`Parser`] accepts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    /// Trainfuck's commands and those of the enabled extensions; anything
+    /// else is a comment
+    #[default]
+    Trainfuck,
+    /// Brainfuck's eight commands and whitespace only, for checking that a
+    /// file runs the same on any Brainfuck interpreter. Other characters,
+    /// a `#!` line included, are errors; a byte order mark is allowed.
+    StrictBf,
+}
+
+/// What happens when the pointer moves past an end of the tape
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TapeModel {
+    /// A fixed number of cells; moving past either end wraps around to the
+    /// other
+    #[default]
+    Classic,
+    /// Moving past either end adds zeroed cells there, so `<` on the first
+    /// cell reaches a new cell instead of the last one. Ops that work on
+    /// several cells after the pointer still wrap at the current ends.
+    Unbounded,
+}
+
+/// A tape range the program must not change, set with [`VM::protect`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Guard {
+    /// Cell indexes, counted from where the tape started
+    pub cells: Range<usize>,
+    /// Also stop the program when the pointer comes to rest in the range
+    pub no_access: bool,
+}
+
+/// A callback the VM makes every few steps, e.g. to capture its tape
+pub struct Watcher {
+    /// Steps between calls
+    pub interval: u64,
+    pub callback: Box<dyn FnMut(&VM)>,
+}
+
+/// The open connection, as [`VM::connection_info`] describes it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Connections the VM has opened, counting this one
+    pub id: u64,
+    pub peer: Option<SocketAddr>,
+    /// Whether `%` dialed it, rather than `@` accepting it
+    pub dialed: bool,
+    /// Steps executed when it was opened
+    pub opened_at_step: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+/// Limits for running code nobody has vetted, applied with [`VM::sandbox`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sandbox {
+    pub max_steps: u64,
+    /// Wall-clock time the program may run for
+    pub timeout: Duration,
+    /// Most tape cells the program may be given
+    pub max_tape_size: usize,
+    /// Most bytes the program may print with `.`
+    pub max_output: u64,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            max_steps: 10_000_000,
+            timeout: Duration::from_secs(5),
+            max_tape_size: 1 << 24,
+            max_output: 1 << 20,
         }
     }
+}
 
-    if depth != 0 {
-        return Err(TrainfuckError::UnmatchedOpenBracket(start));
+impl Extensions {
+    /// Whether a sandboxed program may use these: not `{`/`}`, which write
+    /// files, nor `&`, which starts threads
+    pub fn sandboxed(&self) -> bool {
+        !self.kv && !self.actors
     }
+}
 
-    // Parse the content between [ and ]
-    let inner_source: String = chars[start + 1..i].iter().collect();
-    let inner_ops = parse(&inner_source)?;
+/// What a program did during [`VM::run_collect`]
+#[derive(Debug)]
+pub struct RunReport {
+    /// Everything it printed with `.`
+    pub output: Vec<u8>,
+    /// Why it stopped, if it didn't run to the end
+    pub error: Option<TrainfuckError>,
+    /// Tape up to the last non-zero cell or the pointer, whichever is further
+    pub tape: Vec<u8>,
+    pub pointer: usize,
+    /// Operations executed during the run
+    pub steps: u64,
+    /// Bytes received with `` ` `` (and the other receiving ops) during the run
+    pub bytes_received: u64,
+    /// Bytes sent with `'` during the run
+    pub bytes_sent: u64,
+}
 
-    Ok((inner_ops, i))
+/// Parses Trainfuck source code into a [`Program`]. The source may be a
+/// `&str` or raw bytes: anything that isn't a command is a comment,
+/// including a UTF-8 byte order mark and bytes that aren't valid UTF-8.
+pub fn parse(source: impl AsRef<[u8]>) -> Result<Program> {
+    parse_bytes(source.as_ref())
 }
 
-/// The Trainfuck virtual machine
-pub struct VM {
-    tape: Vec<u8>,
-    pointer: usize,
+/// Parses source given as raw bytes; anything that isn't a command is a comment
+pub fn parse_bytes(source: &[u8]) -> Result<Program> {
+    Program::parse(source, Extensions::default())
+}
 
-    // Networking state
-    listener: Option<TcpListener>,
-    connection: Option<TcpStream>,
+/// Parses source, also returning where each op came from
+pub fn parse_with_spans(source: &[u8]) -> Result<(Vec<Op>, SourceMap)> {
+    let mut parser = Parser::new();
+    parser.feed(source)?;
+    parser.finish_with_spans()
+}
 
-    // I/O streams
-    pub input: Box<dyn BufRead>,
-    pub output: Box<dyn Write>,
+/// Source that parses, with the extensions `ops` were parsed with, back
+/// to `ops`. Superinstructions are written as the loops they stand for;
+/// ops the optimizer evaluated at compile time have no source, so with
+/// them there is none.
+pub fn to_source(ops: &[Op]) -> Option<Vec<u8>> {
+    fn moves(source: &mut Vec<u8>, offset: isize) {
+        let command = if offset > 0 { b'>' } else { b'<' };
+        source.extend(std::iter::repeat_n(command, offset.unsigned_abs()));
+    }
+
+    fn write(source: &mut Vec<u8>, ops: &[Op]) -> Option<()> {
+        for op in ops {
+            match op {
+                Op::MoveRight(n) => moves(source, *n as isize),
+                Op::MoveLeft(n) => moves(source, -(*n as isize)),
+                Op::Increment(n) => source.extend(std::iter::repeat_n(b'+', usize::from(*n))),
+                Op::Decrement(n) => source.extend(std::iter::repeat_n(b'-', usize::from(*n))),
+                Op::Loop(body) => {
+                    source.push(b'[');
+                    write(source, body)?;
+                    source.push(b']');
+                }
+                Op::Clear => source.extend_from_slice(b"[-]"),
+                Op::ScanRight(stride) => {
+                    source.push(b'[');
+                    moves(source, *stride as isize);
+                    source.push(b']');
+                }
+                Op::ScanLeft(stride) => {
+                    source.push(b'[');
+                    moves(source, -(*stride as isize));
+                    source.push(b']');
+                }
+                Op::MulAdd(targets) => {
+                    source.extend_from_slice(b"[-");
+                    for &(offset, factor) in targets {
+                        moves(source, offset);
+                        source.extend(std::iter::repeat_n(b'+', usize::from(factor)));
+                        moves(source, -offset);
+                    }
+                    source.push(b']');
+                }
+                Op::Print(_) | Op::Load(..) => return None,
+                op => push_command(source, op.command()?),
+            }
+        }
+        Some(())
+    }
+
+    let mut source = Vec::new();
+    write(&mut source, ops)?;
+    Some(source)
 }
 
-impl VM {
-    pub fn new() -> Self {
-        VM {
-            tape: vec![0u8; TAPE_SIZE],
-            pointer: 0,
-            listener: None,
-            connection: None,
-            input: Box::new(io::BufReader::new(io::stdin())),
-            output: Box::new(io::stdout()),
+/// Append `command` to `source`, after a space if it would otherwise make
+/// a `;;` comment or a `#!` directive of the command before it
+pub fn push_command(source: &mut Vec<u8>, command: u8) {
+    if matches!((source.last(), command), (Some(b';'), b';') | (Some(b'#'), b'!')) {
+        source.push(b' ');
+    }
+    source.push(command);
+}
+
+/// Parses source read incrementally, without holding it all in memory
+pub fn parse_reader(mut reader: impl Read) -> Result<Program> {
+    let mut parser = Parser::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return parser.finish_program(),
+            Ok(n) => parser.feed(&buf[..n])?,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
         }
     }
+}
 
-    /// Execute parsed operations
-    pub fn execute(&mut self, ops: &[Op]) -> Result<()> {
-        for op in ops {
-            self.execute_op(op)?;
+/// Single-pass parser with an explicit bracket stack, so nesting depth is
+/// bounded by memory rather than the call stack. Source can be fed in
+/// chunks; runs of `+-<>` are folded even across chunk boundaries.
+///
+/// Loops nested deeper than the parser's limit are rejected, since the
+/// passes that run on the parsed tree are recursive.
+///
+/// Lines starting with `#!` are [directives](crate::directives), or a
+/// shebang so a source file can be run as a script, and `;;` comments out
+/// the rest of its line, command characters included. Several files can be
+/// parsed as one program with [`next_file`](Parser::next_file) between
+/// them.
+#[derive(Debug)]
+pub struct Parser {
+    /// Ops of the innermost open loop (or the top level)
+    current: Vec<Op>,
+    /// Enclosing op lists and the offset of the `[` that opened each loop
+    open: Vec<(Vec<Op>, usize)>,
+    /// Pending run of a foldable command: the command, its offset and length
+    run: Option<(u8, usize, usize)>,
+    /// Span of every op so far, in pre-order; loop spans are completed at `]`
+    spans: Vec<Span>,
+    /// Index into `spans` of each open loop
+    open_spans: Vec<usize>,
+    /// Byte offset of the next byte fed
+    offset: usize,
+    max_depth: usize,
+    extensions: Extensions,
+    dialect: Dialect,
+    /// Bytes of a BOM [`Dialect::StrictBf`] has allowed in the current file
+    bom: usize,
+    comment: Comment,
+    /// Whether the next byte starts a line
+    line_start: bool,
+    /// Text of the `#!` line being read
+    line: Vec<u8>,
+    /// Spans of the `#!` lines and `;;` comments read so far
+    comments: Vec<Span>,
+    directives: Directives,
+    /// Offset of the start of the file being fed
+    file_start: usize,
+    /// Ops parsed before the file being fed
+    file_ops: usize,
+}
+
+/// Where the parser is in a `#!` line or `;;` comment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comment {
+    None,
+    /// After a `#` at the start of a line, at this offset, that may open a
+    /// `#!` line
+    Hash(usize),
+    /// After a `;`, at this offset, that may open a `;;` comment
+    Semicolon(usize),
+    /// Inside a `#!` line starting at this offset, up to its newline
+    Directive(usize),
+    /// Inside a `;;` comment starting at this offset, up to its newline
+    Line(usize),
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::with_max_depth(DEFAULT_MAX_LOOP_DEPTH)
+    }
+
+    /// A parser that accepts loops nested at most `max_depth` deep
+    pub fn with_max_depth(max_depth: usize) -> Self {
+        Parser {
+            current: Vec::new(),
+            open: Vec::new(),
+            run: None,
+            spans: Vec::new(),
+            open_spans: Vec::new(),
+            offset: 0,
+            max_depth,
+            extensions: Extensions::default(),
+            dialect: Dialect::default(),
+            bom: 0,
+            comment: Comment::None,
+            line_start: true,
+            line: Vec::new(),
+            comments: Vec::new(),
+            directives: Directives::default(),
+            file_start: 0,
+            file_ops: 0,
         }
-        Ok(())
     }
 
-    fn execute_op(&mut self, op: &Op) -> Result<()> {
-        match op {
-            Op::MoveRight(n) => {
-                self.pointer = self.pointer.wrapping_add(*n);
-                if self.pointer >= TAPE_SIZE {
-                    self.pointer = self.pointer % TAPE_SIZE;
+    /// Also recognize the commands of the given extensions
+    pub fn with_extensions(mut self, extensions: Extensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Accept the characters of `dialect`; [`Dialect::StrictBf`] ignores
+    /// the extensions and has no directives or comments
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Parse the next chunk of source
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<()> {
+        for &byte in bytes {
+            let position = self.offset;
+            self.offset += 1;
+
+            match self.comment {
+                Comment::Directive(_) if byte != b'\n' => {
+                    self.line.push(byte);
+                    continue;
                 }
-            }
-            Op::MoveLeft(n) => {
-                if *n > self.pointer {
-                    // Wrap around
-                    self.pointer = TAPE_SIZE - (*n - self.pointer);
-                } else {
-                    self.pointer -= *n;
+                Comment::Line(_) if byte != b'\n' => continue,
+                Comment::Directive(start) => {
+                    self.comment = Comment::None;
+                    self.comments.push(Span::new(start, position));
+                    self.directive(start)?;
                 }
-            }
-            Op::Increment(n) => {
-                self.tape[self.pointer] = self.tape[self.pointer].wrapping_add(*n);
-            }
-            Op::Decrement(n) => {
-                self.tape[self.pointer] = self.tape[self.pointer].wrapping_sub(*n);
-            }
-            Op::Output => {
-                let c = self.tape[self.pointer];
-                self.output.write_all(&[c])?;
-                self.output.flush()?;
-            }
-            Op::Input => {
-                let mut buf = [0u8; 1];
-                match self.input.read(&mut buf) {
-                    Ok(0) => self.tape[self.pointer] = 0,
-                    Ok(_) => self.tape[self.pointer] = buf[0],
-                    Err(e) => return Err(TrainfuckError::IoError(e)),
+                Comment::Line(start) => {
+                    self.comment = Comment::None;
+                    self.comments.push(Span::new(start, position));
                 }
-            }
-            Op::Loop(inner_ops) => {
-                while self.tape[self.pointer] != 0 {
-                    self.execute(inner_ops)?;
+                // Wait for the next byte to tell whether this `#` or `;` is
+                // a command
+                Comment::Hash(start) => {
+                    self.comment = Comment::None;
+                    if byte == b'!' {
+                        self.comment = Comment::Directive(start);
+                        continue;
+                    }
+                    self.command(b'#', start)?;
                 }
+                Comment::Semicolon(start) => {
+                    self.comment = Comment::None;
+                    if byte == b';' {
+                        self.comment = Comment::Line(start);
+                        continue;
+                    }
+                    self.command(b';', start)?;
+                }
+                Comment::None => {}
             }
 
-            // Networking operations
-            Op::Listen => self.net_listen()?,
-            Op::Accept => self.net_accept()?,
-            Op::Connect => self.net_connect()?,
-            Op::Receive => self.net_receive()?,
-            Op::Send => self.net_send()?,
+            let line_start = std::mem::replace(&mut self.line_start, byte == b'\n');
+            if self.dialect == Dialect::Trainfuck && (byte == b'#' && line_start || byte == b';') {
+                // A run can't carry on past a comment
+                self.flush_run();
+                self.comment = match byte {
+                    b'#' => Comment::Hash(position),
+                    _ => Comment::Semicolon(position),
+                };
+                continue;
+            }
+            self.command(byte, position)?;
         }
         Ok(())
     }
 
-    /// Listen on address:port from tape
-    /// Address: 4 bytes at pointer (big-endian IPv4)
-    /// Port: 2 bytes at pointer+4 (big-endian)
-    fn net_listen(&mut self) -> Result<()> {
-        if self.listener.is_some() {
-            // Already listening, close existing
-            self.listener = None;
-            return Ok(());
+    /// Spans of the `#!` lines, shebangs included, and `;;` comments fed so
+    /// far, without the newlines that end them
+    pub fn comments(&self) -> Vec<Span> {
+        let mut comments = self.comments.clone();
+        if let Comment::Directive(start) | Comment::Line(start) = self.comment {
+            comments.push(Span::new(start, self.offset));
         }
+        comments
+    }
 
-        let addr = self.read_address_from_tape();
-        let port = self.read_port_from_tape();
+    /// The bytes fed from now on are another file, which may start with
+    /// `#!` lines of its own. Its offsets carry on from the previous file's.
+    pub fn next_file(&mut self) -> Result<()> {
+        self.end_file()?;
+        self.file_start = self.offset;
+        self.bom = 0;
+        self.line_start = true;
+        self.file_ops = self.spans.len();
+        Ok(())
+    }
 
-        let socket_addr = SocketAddrV4::new(addr, port);
-        let listener = TcpListener::bind(socket_addr)
-            .map_err(|e| TrainfuckError::NetworkError(format!("Failed to bind: {}", e)))?;
+    /// Finish the comment or `#!` line the file ended in, or parse the `#`
+    /// or `;` it ended on
+    fn end_file(&mut self) -> Result<()> {
+        match std::mem::replace(&mut self.comment, Comment::None) {
+            Comment::Directive(start) => self.directive(start),
+            Comment::Hash(start) => self.command(b'#', start),
+            Comment::Semicolon(start) => self.command(b';', start),
+            Comment::Line(_) | Comment::None => Ok(()),
+        }
+    }
 
-        eprintln!("[trainfuck] Listening on {}:{}", addr, port);
-        self.listener = Some(listener);
-        Ok(())
+    /// Apply the `#!` line starting at `start`, which a directive may only
+    /// be before the file's first command
+    fn directive(&mut self, start: usize) -> Result<()> {
+        let text = std::mem::take(&mut self.line);
+        if self.spans.len() > self.file_ops && !directives::is_shebang(&text) {
+            return Err(TrainfuckError::InvalidDirective(
+                "directives go before the file's first command".into(),
+                start,
+            ));
+        }
+        self.directives.apply(&text, start, &mut self.extensions)
     }
 
-    /// Accept incoming connection
-    fn net_accept(&mut self) -> Result<()> {
-        if self.connection.is_some() {
-            // Close existing connection
-            self.connection = None;
-            eprintln!("[trainfuck] Connection closed");
-            return Ok(());
+    /// Parse the byte at offset `position`
+    fn command(&mut self, byte: u8, position: usize) -> Result<()> {
+        if let Some((command, _, count)) = self.run.as_mut() {
+            if *command == byte {
+                *count += 1;
+                return Ok(());
+            }
         }
+        self.flush_run();
 
-        if let Some(ref listener) = self.listener {
-            let (stream, peer) = listener
-                .accept()
-                .map_err(|e| TrainfuckError::NetworkError(format!("Accept failed: {}", e)))?;
-            eprintln!("[trainfuck] Accepted connection from {}", peer);
-            self.connection = Some(stream);
+        if self.dialect == Dialect::StrictBf && !b"><+-.,[]".contains(&byte) {
+            // A BOM's bytes, in order, at the start of a file
+            if position == self.file_start + self.bom && BOM.get(self.bom) == Some(&byte) {
+                self.bom += 1;
+                return Ok(());
+            }
+            return match byte.is_ascii_whitespace() {
+                true => Ok(()),
+                false => Err(TrainfuckError::NotBrainfuck(byte, position)),
+            };
         }
+
+        let op = match byte {
+            b'>' | b'<' | b'+' | b'-' => {
+                self.run = Some((byte, position, 1));
+                return Ok(());
+            }
+            b'.' => Op::Output,
+            b',' => Op::Input,
+            b'[' => {
+                if self.open.len() >= self.max_depth {
+                    return Err(TrainfuckError::NestingTooDeep(position, self.max_depth));
+                }
+                let outer = std::mem::take(&mut self.current);
+                self.open.push((outer, position));
+                self.open_spans.push(self.spans.len());
+                self.spans.push(Span::new(position, position + 1));
+                return Ok(());
+            }
+            b']' => {
+                let Some((outer, _)) = self.open.pop() else {
+                    return Err(TrainfuckError::UnmatchedCloseBracket(position));
+                };
+                let body = std::mem::replace(&mut self.current, outer);
+                let index = self.open_spans.pop().expect("pushed with the loop");
+                self.spans[index].end = position + 1;
+                self.current.push(Op::Loop(body));
+                return Ok(());
+            }
+            // Trainfuck networking
+            b'%' => Op::Connect,
+            b'$' => Op::Listen,
+            b'@' => Op::Accept,
+            b'`' if self.extensions.blocks => Op::ReceiveBlock,
+            b'\'' if self.extensions.blocks => Op::SendBlock,
+            b'`' => Op::Receive,
+            b'\'' => Op::Send,
+            b'~' if self.extensions.shared => Op::CompareSwap,
+            b'&' if self.extensions.actors => Op::Spawn,
+            b'^' if self.extensions.actors => Op::SendMessage,
+            b'?' if self.extensions.features => Op::Features(features::mask(&self.extensions)),
+            b'?' if self.extensions.actors => Op::ReceiveMessage,
+            b'|' if self.extensions.yielding => Op::Yield,
+            b'{' if self.extensions.kv => Op::KvPut,
+            b'}' if self.extensions.kv => Op::KvGet,
+            b'*' if self.extensions.http => Op::Http,
+            b'#' if self.extensions.checksum => Op::Checksum,
+            b':' if self.extensions.base64 => Op::Base64Encode,
+            b';' if self.extensions.base64 => Op::Base64Decode,
+            b'!' if self.extensions.trap => Op::Trap,
+            b'!' if self.extensions.xor => Op::Xor,
+            b'=' if self.extensions.peek => Op::Peek,
+            b'\\' if self.extensions.peek => Op::Unread,
+            b'"' if self.extensions.line => Op::ReceiveLine,
+            b'/' if self.extensions.dns => Op::Resolve,
+            b'(' if self.extensions.channels => Op::SelectChannel,
+            b')' if self.extensions.channels => Op::Channels,
+            b'_' if self.extensions.backpressure => Op::Flush,
+            // Everything else is a comment
+            _ => return Ok(()),
+        };
+        self.current.push(op);
+        self.spans.push(Span::new(position, position + 1));
         Ok(())
     }
 
-    /// Connect to address:port from tape
-    fn net_connect(&mut self) -> Result<()> {
-        if self.connection.is_some() {
-            // Already connected, close
-            self.connection = None;
-            return Ok(());
+    /// Finish parsing, checking that every loop was closed
+    pub fn finish(self) -> Result<Vec<Op>> {
+        self.finish_with_spans().map(|(ops, _)| ops)
+    }
+
+    /// Like [`finish`](Self::finish), also returning each op's span
+    pub fn finish_with_spans(mut self) -> Result<(Vec<Op>, SourceMap)> {
+        self.end_file()?;
+        self.flush_run();
+        match self.open.first() {
+            Some(&(_, position)) => Err(TrainfuckError::UnmatchedOpenBracket(position)),
+            None => Ok((self.current, SourceMap::new(self.spans))),
         }
+    }
 
-        let addr = self.read_address_from_tape();
-        let port = self.read_port_from_tape();
+    /// Like [`finish_with_spans`](Self::finish_with_spans), bundling the
+    /// results into a [`Program`]
+    pub fn finish_program(mut self) -> Result<Program> {
+        // The last line may be a directive
+        self.end_file()?;
+        let metadata = Metadata {
+            extensions: self.extensions,
+            source_len: self.offset,
+            optimized: false,
+            directives: self.directives.clone(),
+        };
+        let (ops, spans) = self.finish_with_spans()?;
+        Ok(Program::new(ops, spans, metadata))
+    }
 
-        let socket_addr = SocketAddrV4::new(addr, port);
-        let stream = TcpStream::connect(socket_addr)
-            .map_err(|e| TrainfuckError::NetworkError(format!("Connect failed: {}", e)))?;
+    fn flush_run(&mut self) {
+        let Some((command, start, count)) = self.run.take() else {
+            return;
+        };
+        self.spans.push(Span::new(start, start + count));
+        self.current.push(match command {
+            b'>' => Op::MoveRight(count),
+            b'<' => Op::MoveLeft(count),
+            b'+' => Op::Increment((count % 256) as u8),
+            _ => Op::Decrement((count % 256) as u8),
+        });
+    }
+}
 
-        eprintln!("[trainfuck] Connected to {}:{}", addr, port);
-        self.connection = Some(stream);
-        Ok(())
+/// The Trainfuck virtual machine
+pub struct VM {
+    pub(crate) tape: Tape,
+    pub(crate) pointer: usize,
+    pub tape_model: TapeModel,
+    /// Index of the cell the tape started at, which moves right as an
+    /// unbounded tape grows to the left
+    origin: usize,
+
+    // Networking state
+    pub network: Box<dyn Network>,
+    pub(crate) listener: Option<Box<dyn Listener>>,
+    pub(crate) connection: Option<Box<dyn Stream>>,
+    /// Channel `(` made active, whose connection is `connection`
+    pub(crate) channel: u8,
+    /// Connections on the other channels
+    pub(crate) parked: BTreeMap<u8, Parked>,
+
+    // I/O streams
+    pub input: Box<dyn BufRead>,
+    pub output: Box<dyn Write>,
+
+    /// When set, execution stops at the next instruction boundary. `@` and
+    /// TCP reads then wake up regularly to notice it rather than blocking.
+    pub interrupt: Option<Arc<AtomicBool>>,
+
+    // Sandbox limits
+    pub max_steps: Option<u64>,
+    /// Compared against [`clock`](VM::clock)
+    pub deadline: Option<Instant>,
+    pub network_enabled: bool,
+    /// Set by [`sandbox`](VM::sandbox): `&`, `^`, `?`, `{`, `}` and `*` fail
+    /// however the program turned them on
+    pub(crate) sandboxed: bool,
+    pub(crate) steps: u64,
+    /// Most bytes `.` may write in all
+    pub max_output: Option<u64>,
+    pub(crate) bytes_output: u64,
+    /// Bytes read with `,`
+    pub(crate) bytes_input: u64,
+    /// Times `,` found the input ended
+    pub(crate) eof_reads: u64,
+    /// Shared allowance steps and bytes are charged to, see
+    /// [`budget`](crate::budget)
+    pub(crate) budget: Option<Budget>,
+    /// Steps taken from the budget and not used yet
+    pub(crate) prepaid_steps: u64,
+    /// Time each operation takes at least, to watch a program run
+    pub step_delay: Option<Duration>,
+    /// When the next operation may run under `step_delay`
+    next_step_at: Option<Instant>,
+    /// What the program's time, waits and deadline are measured by
+    pub clock: Clock,
+    /// When the VM was created, where virtual time starts
+    pub(crate) started: Instant,
+    /// Virtual time spent waiting, or in steps before a reset
+    pub(crate) waited: Duration,
+
+    /// Retries for `%`
+    pub connect_retry: ConnectRetry,
+    /// When `` ` `` or `'` finds a connection opened by `%` dropped, dial
+    /// the same address again and carry on with the new connection
+    pub reconnect: bool,
+    /// Address the open connection was dialed at, for `reconnect`
+    pub(crate) dialed: Option<SocketAddrV4>,
+    /// Bytes `'` couldn't send yet, with [`Extensions::backpressure`]
+    pub(crate) unsent: Vec<u8>,
+    /// Bytes `=` and `\` put back, received again last in, first out
+    pub(crate) pushback: Vec<u8>,
+    /// Bytes read from and written to connections, for `run_collect`
+    pub(crate) bytes_received: u64,
+    pub(crate) bytes_sent: u64,
+    /// Id, peer and traffic of the open (or last) connection
+    pub(crate) session: ConnectionInfo,
+    /// Connections opened so far, for numbering them
+    opened: u64,
+    /// Connections `@` has accepted
+    accepted: u64,
+    /// Recent instructions, for looking back from the control socket
+    pub history: Option<History>,
+    /// Instruction and loop counts, for `--histogram`
+    pub histogram: Option<Histogram>,
+    /// Progress reported to health checks
+    pub health: Option<Arc<Health>>,
+    /// Protected ranges, with the values their cells must keep
+    pub(crate) guards: Vec<(Guard, Vec<u8>)>,
+    /// Tape cell every networking op writes its [`NetStatus`] to
+    pub status_cell: Option<usize>,
+    /// First of [`ROOM_CELLS`] cells that receiving a byte fills with the
+    /// zero-terminated name of the room it was said in, when the connection
+    /// has rooms (see [`Stream::room`]); longer names are cut short
+    pub room_cell: Option<usize>,
+    /// First of [`PEER_CELLS`] cells `@` fills with a record of the
+    /// connection it accepted. Peers with an IPv6 address, or none, are
+    /// recorded as 0.0.0.0 port 0.
+    pub peer_cell: Option<usize>,
+    /// How long `@` waits for a connection; zero polls. Waits forever when
+    /// unset.
+    pub accept_timeout: Option<Duration>,
+    /// Addresses `$` listens on instead of the one on the tape, all at
+    /// once; `@` accepts from whichever has a connection first
+    pub listen_addrs: Vec<SocketAddr>,
+    /// Address `%` dials instead of the one on the tape
+    pub connect_addr: Option<SocketAddrV4>,
+    /// Addresses by number: `$` and `%` read one byte from the current cell
+    /// and use the address it names instead of reading 6 bytes of address
+    /// and port. [`listen_addrs`](Self::listen_addrs) and
+    /// [`connect_addr`](Self::connect_addr) still take precedence.
+    pub addr_map: Option<BTreeMap<u8, SocketAddr>>,
+    /// Peers `@` accepts connections from; it refuses the rest and keeps
+    /// waiting. See [`access`](crate::access).
+    pub access: Option<AccessList>,
+    /// How `` ` `` and `'` handle connection errors. When unset, an error
+    /// stops the program in `'` but just reads as 0 in `` ` ``.
+    pub net_errors: Option<NetErrors>,
+    /// Grace period after an interrupt: the listener closes at once, but
+    /// the program keeps running until the open connection closes or this
+    /// runs out. Stops at once when unset.
+    pub drain_timeout: Option<Duration>,
+    /// When the drain under way has to finish
+    drain_deadline: Option<Instant>,
+    /// Close connections the peer has sent nothing on for this long; see
+    /// [`idle`](crate::idle)
+    pub idle_timeout: Option<Duration>,
+    /// Byte sent to a connection idle for `idle_timeout`, which then gets
+    /// as long again to answer before it's closed
+    pub keepalive: Option<u8>,
+    /// When the open connection was last heard from
+    pub(crate) liveness: Liveness,
+
+    /// Cells shared with other VMs, for `~`
+    pub shared: Option<SharedSegment>,
+    /// Persistent store for `{` and `}`
+    pub kv: Option<SharedKvStore>,
+
+    /// Extensions programs started by `&` are parsed with
+    pub extensions: Extensions,
+    pub(crate) actors: Actors,
+    /// Set by `|`, cleared by the scheduler that switches away
+    pub(crate) yielded: bool,
+
+    /// Resume position for `step`: index into the op list at each loop depth
+    frames: Vec<usize>,
+
+    /// Deepest loop nesting `execute`/`step` will enter, guarding the stack
+    /// against op trees built without the parser's limit
+    pub max_loop_depth: usize,
+    pub(crate) loop_depth: usize,
+    pub(crate) peak_loop_depth: usize,
+
+    /// Where the last bytecode run failed
+    pub(crate) fault_pc: Option<usize>,
+    /// Address of the bytecode instruction running now, for breakpoints
+    pub(crate) current_pc: Option<usize>,
+
+    /// Called between instructions, each every `interval` steps
+    pub watchers: Vec<Watcher>,
+    /// What `!` calls under `--ext trap`, see [`on_trap`](VM::on_trap)
+    pub trap_handler: Option<TrapHandler>,
+
+    /// JSON-RPC control socket, serviced between instructions
+    #[cfg(unix)]
+    pub controller: Option<crate::control::Controller>,
+}
+
+impl VM {
+    pub fn new() -> Self {
+        Self::with_tape_size(TAPE_SIZE)
     }
 
-    /// Receive a byte from network, store at pointer
-    fn net_receive(&mut self) -> Result<()> {
-        if let Some(ref mut stream) = self.connection {
-            let mut buf = [0u8; 1];
-            match stream.read(&mut buf) {
-                Ok(0) => {
-                    // Connection closed
-                    self.tape[self.pointer] = 0;
-                }
-                Ok(_) => {
-                    self.tape[self.pointer] = buf[0];
+    /// Create a VM with a tape of `size` cells instead of the standard 30,000
+    pub fn with_tape_size(size: usize) -> Self {
+        VM {
+            tape: Tape::new(size),
+            pointer: 0,
+            tape_model: TapeModel::Classic,
+            origin: 0,
+            network: Box::new(TcpNetwork),
+            listener: None,
+            connection: None,
+            channel: FIRST_CHANNEL,
+            parked: BTreeMap::new(),
+            input: Box::new(io::BufReader::new(io::stdin())),
+            output: Box::new(io::stdout()),
+            interrupt: None,
+            max_steps: None,
+            deadline: None,
+            step_delay: None,
+            next_step_at: None,
+            clock: Clock::System,
+            started: Instant::now(),
+            waited: Duration::ZERO,
+            network_enabled: true,
+            sandboxed: false,
+            steps: 0,
+            max_output: None,
+            bytes_output: 0,
+            bytes_input: 0,
+            eof_reads: 0,
+            budget: None,
+            prepaid_steps: 0,
+            connect_retry: ConnectRetry::default(),
+            reconnect: false,
+            dialed: None,
+            unsent: Vec::new(),
+            pushback: Vec::new(),
+            bytes_received: 0,
+            bytes_sent: 0,
+            session: ConnectionInfo::default(),
+            opened: 0,
+            accepted: 0,
+            history: None,
+            histogram: None,
+            health: None,
+            guards: Vec::new(),
+            status_cell: None,
+            room_cell: None,
+            peer_cell: None,
+            accept_timeout: None,
+            listen_addrs: Vec::new(),
+            connect_addr: None,
+            addr_map: None,
+            access: None,
+            drain_timeout: None,
+            drain_deadline: None,
+            idle_timeout: None,
+            keepalive: None,
+            liveness: Liveness::new(),
+            net_errors: None,
+            shared: None,
+            kv: None,
+            extensions: Extensions::default(),
+            actors: Actors::default(),
+            yielded: false,
+            frames: Vec::new(),
+            max_loop_depth: DEFAULT_MAX_LOOP_DEPTH,
+            loop_depth: 0,
+            peak_loop_depth: 0,
+            fault_pc: None,
+            current_pc: None,
+            watchers: Vec::new(),
+            trap_handler: None,
+            #[cfg(unix)]
+            controller: None,
+        }
+    }
+
+    /// Create a VM that reads `,` from `input` and writes `.` to `output`
+    /// instead of stdin and stdout
+    pub fn with_io(input: impl BufRead + 'static, output: impl Write + 'static) -> Self {
+        let mut vm = Self::new();
+        vm.input = Box::new(input);
+        vm.output = Box::new(output);
+        vm
+    }
+
+    /// Run `source` with `input` as its stdin and return what it printed.
+    /// Networking is disabled, so the result depends only on the arguments.
+    pub fn run_str(source: &str, input: &[u8]) -> Result<Vec<u8>> {
+        let ops = parse(source)?;
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::with_io(io::Cursor::new(input.to_vec()), Collect(output.clone()));
+        vm.network_enabled = false;
+        vm.execute(&ops)?;
+        Ok(output.take())
+    }
+
+    /// The tape contents
+    pub fn tape(&self) -> &[u8] {
+        &self.tape
+    }
+
+    /// Mutable access to the tape, for embedders
+    pub fn tape_mut(&mut self) -> &mut [u8] {
+        &mut self.tape
+    }
+
+    /// Current data pointer position
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    /// Index in [`tape`](VM::tape) of the cell the program started on; not
+    /// 0 once an unbounded tape has grown to the left
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    /// Overwrite a cell; returns false if `index` is outside the tape.
+    /// Protected cells can be set this way, and keep the new value.
+    pub fn set_cell(&mut self, index: usize, value: u8) -> bool {
+        match self.tape.get_mut(index) {
+            Some(cell) => {
+                *cell = value;
+                if let Some(ref mut history) = self.history {
+                    history.sync_cell(index, value);
                 }
-                Err(e) => {
-                    eprintln!("[trainfuck] Receive error: {}", e);
-                    self.tape[self.pointer] = 0;
+                let offset = index.checked_sub(self.origin);
+                for (guard, cells) in &mut self.guards {
+                    let i = offset.and_then(|offset| offset.checked_sub(guard.cells.start));
+                    if let Some(kept) = i.and_then(|i| cells.get_mut(i)) {
+                        *kept = value;
+                    }
                 }
+                true
             }
-        } else {
-            self.tape[self.pointer] = 0;
+            None => false,
         }
-        Ok(())
     }
 
-    /// Send byte at pointer to network
-    fn net_send(&mut self) -> Result<()> {
-        if let Some(ref mut stream) = self.connection {
-            let byte = self.tape[self.pointer];
-            stream
-                .write_all(&[byte])
-                .map_err(|e| TrainfuckError::NetworkError(format!("Send failed: {}", e)))?;
-            stream.flush()?;
+    /// Run under `sandbox`'s limits from now on, with networking off, a
+    /// tape that keeps its size and no actors, key-value store or HTTP,
+    /// even if a `#! ext` directive turned them on. Fails if the tape is
+    /// already too large.
+    pub fn sandbox(&mut self, sandbox: &Sandbox) -> Result<()> {
+        if self.tape.len() > sandbox.max_tape_size {
+            return Err(TrainfuckError::TapeLimitExceeded(sandbox.max_tape_size));
         }
+        self.tape_model = TapeModel::Classic;
+        self.network_enabled = false;
+        self.max_steps = Some(sandbox.max_steps);
+        self.deadline = Some(self.now() + sandbox.timeout);
+        self.max_output = Some(sandbox.max_output);
+        self.sandboxed = true;
         Ok(())
     }
 
-    /// Read IPv4 address from tape at pointer position
-    fn read_address_from_tape(&self) -> Ipv4Addr {
-        Ipv4Addr::new(
-            self.tape[self.pointer],
-            self.tape[self.pointer + 1],
-            self.tape[self.pointer + 2],
-            self.tape[self.pointer + 3],
-        )
+    /// Fail with [`TrainfuckError::Sandboxed`] if `command` may not run
+    /// because the VM is sandboxed
+    pub(crate) fn check_sandbox(&self, command: char) -> Result<()> {
+        match self.sandboxed {
+            true => Err(TrainfuckError::Sandboxed(command)),
+            false => Ok(()),
+        }
     }
 
-    /// Read port from tape at pointer+4 position (big-endian)
-    fn read_port_from_tape(&self) -> u16 {
-        ((self.tape[self.pointer + 4] as u16) << 8) | (self.tape[self.pointer + 5] as u16)
+    /// Stop the program with an error when it changes a cell in `guard`,
+    /// which must lie on the tape. The cells must keep the values they hold
+    /// now; change them with [`set_cell`](VM::set_cell).
+    pub fn protect(&mut self, guard: Guard) {
+        let start = self.origin + guard.cells.start;
+        let cells = self.tape[start..start + guard.cells.len()].to_vec();
+        self.guards.push((guard, cells));
+    }
+
+    /// Fail if the last op changed a protected cell or left the pointer in a
+    /// no-access range
+    pub(crate) fn check_guards(&self) -> Result<()> {
+        for (guard, kept) in &self.guards {
+            let start = self.origin + guard.cells.start;
+            let cells = start..start + kept.len();
+            if guard.no_access && cells.contains(&self.pointer) {
+                return Err(TrainfuckError::NoAccess(self.pointer - self.origin));
+            }
+            let changed = self.tape[cells].iter().zip(kept).position(|(a, b)| a != b);
+            if let Some(i) = changed {
+                return Err(TrainfuckError::ProtectedWrite(guard.cells.start + i));
+            }
+        }
+        Ok(())
+    }
+
+    /// Local address of the listener opened by `$`, if any
+    pub fn listener_addr(&self) -> Option<SocketAddr> {
+        self.listener.as_ref().and_then(|l| l.local_addr())
+    }
+
+    /// Remote address of the open connection, if any
+    pub fn connection_peer(&self) -> Option<SocketAddr> {
+        self.connection.as_ref().and_then(|c| c.peer_addr())
+    }
+
+    /// Id, peer and traffic of the open connection, if any
+    pub fn connection_info(&self) -> Option<ConnectionInfo> {
+        self.connection.as_ref().map(|_| self.session.clone())
+    }
+
+    /// Connections `@` has accepted since the VM started
+    pub fn connections_accepted(&self) -> u64 {
+        self.accepted
+    }
+
+    /// Make `stream` the open connection, as if `@` had just accepted it
+    pub fn attach(&mut self, stream: Box<dyn Stream>) {
+        self.open_connection(stream, None);
+    }
+
+    /// Make `stream` the open connection, numbered after the last one.
+    /// `dialed` is the address `%` dialed, if it did.
+    fn open_connection(&mut self, mut stream: Box<dyn Stream>, dialed: Option<SocketAddrV4>) {
+        self.opened += 1;
+        self.session = ConnectionInfo {
+            id: self.opened,
+            peer: stream.peer_addr(),
+            dialed: dialed.is_some(),
+            opened_at_step: self.steps,
+            ..ConnectionInfo::default()
+        };
+        // Wake up from reads to check for an interrupt or an idle peer;
+        // backends without timeouts just block
+        let wake = match self.interrupt {
+            Some(_) => Some(INTERRUPT_POLL),
+            None => self.idle_timeout,
+        };
+        if wake.is_some() {
+            let _ = stream.set_read_timeout(wake);
+        }
+        self.liveness = Liveness::new();
+        self.connection = Some(stream);
+        self.pushback.clear();
+        self.unsent.clear();
+        self.dialed = dialed;
+    }
+
+    fn interrupted(&self) -> bool {
+        self.interrupt
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// Once interrupted, whether to keep running so the open connection can
+    /// finish. The first call closes the listener and starts the grace
+    /// period.
+    fn keep_draining(&mut self) -> bool {
+        let Some(grace) = self.drain_timeout else {
+            return false;
+        };
+        if self.connection.is_none() && self.parked.is_empty() {
+            return false;
+        }
+        let deadline = match self.drain_deadline {
+            Some(deadline) => deadline,
+            None => {
+                eprintln!(
+                    "[trainfuck] Draining: no new connections, {:?} for the open one to finish",
+                    grace
+                );
+                self.listener = None;
+                self.report_listening();
+                *self.drain_deadline.insert(Instant::now() + grace)
+            }
+        };
+        if Instant::now() < deadline {
+            return true;
+        }
+        eprintln!("[trainfuck] Drain timed out");
+        // Stop checking: the interrupt now goes through
+        self.drain_timeout = None;
+        false
+    }
+
+    /// Start over with a zeroed tape, no connections and no steps taken,
+    /// keeping the I/O streams, network backend and limits
+    pub fn reset(&mut self) {
+        self.tape = Tape::new(self.tape.len());
+        self.pointer = 0;
+        self.origin = 0;
+        for (_, cells) in &mut self.guards {
+            cells.fill(0);
+        }
+        if let Some(ref mut history) = self.history {
+            history.reset(&self.tape);
+        }
+        self.listener = None;
+        self.report_listening();
+        self.connection = None;
+        self.channel = FIRST_CHANNEL;
+        self.parked.clear();
+        self.pushback.clear();
+        self.dialed = None;
+        // Virtual time keeps going
+        self.waited = self.virtual_elapsed();
+        self.steps = 0;
+        self.drain_deadline = None;
+        self.liveness = Liveness::new();
+        self.bytes_output = 0;
+        self.bytes_input = 0;
+        self.eof_reads = 0;
+        self.return_steps();
+        self.next_step_at = None;
+        self.bytes_received = 0;
+        self.bytes_sent = 0;
+        self.session = ConnectionInfo::default();
+        self.opened = 0;
+        self.accepted = 0;
+        self.frames.clear();
+        self.loop_depth = 0;
+        self.peak_loop_depth = 0;
+        self.fault_pc = None;
+        self.actors = Actors::default();
+        self.yielded = false;
+    }
+
+    /// Number of operations executed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Times `,` found the input ended. A program that notices the end of
+    /// its input reads it once; more is the `input-after-eof` warning.
+    pub fn eof_reads(&self) -> u64 {
+        self.eof_reads
+    }
+
+    /// Run `ops` to the end or the first error, capturing their output
+    /// instead of writing it to [`output`](VM::output), and report what
+    /// happened. Runs on the VM as it is, so calls can continue each other.
+    pub fn run_collect(&mut self, ops: &[Op]) -> RunReport {
+        let (steps, received, sent) = (self.steps, self.bytes_received, self.bytes_sent);
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let original = std::mem::replace(&mut self.output, Box::new(Collect(output.clone())));
+        let error = self.execute(ops).err();
+        self.output = original;
+
+        let used = self.tape.iter().rposition(|&c| c != 0).unwrap_or(0);
+        let end = used.max(self.pointer) + 1;
+        RunReport {
+            output: output.take(),
+            error,
+            tape: self.tape[..end].to_vec(),
+            pointer: self.pointer,
+            steps: self.steps - steps,
+            bytes_received: self.bytes_received - received,
+            bytes_sent: self.bytes_sent - sent,
+        }
+    }
+
+    /// Execute parsed operations
+    pub fn execute(&mut self, ops: &[Op]) -> Result<()> {
+        for op in ops {
+            self.execute_op(op)?;
+        }
+        Ok(())
+    }
+
+    /// Execute one step of `ops`, resuming where the previous call stopped.
+    /// A step is a single op or one test of a loop condition. Returns true
+    /// once the program has run to completion.
+    pub fn step(&mut self, ops: &[Op]) -> Result<bool> {
+        if self.frames.is_empty() {
+            self.frames.push(0);
+        }
+
+        // Walk down to the op list the innermost frame points into
+        let depth = self.frames.len();
+        let mut list = ops;
+        for &i in &self.frames[..depth - 1] {
+            match &list[i] {
+                Op::Loop(body) => list = body,
+                _ => unreachable!("frames only descend into loops"),
+            }
+        }
+
+        let index = self.frames[depth - 1];
+        if index == list.len() {
+            if depth == 1 {
+                return Ok(true);
+            }
+            // End of a loop body: test the condition again
+            self.tick()?;
+            if self.tape[self.pointer] != 0 {
+                self.frames[depth - 1] = 0;
+            } else {
+                self.frames.pop();
+                self.frames[depth - 2] += 1;
+            }
+        } else {
+            match &list[index] {
+                Op::Loop(_) => {
+                    self.tick()?;
+                    if self.tape[self.pointer] != 0 {
+                        if depth > self.max_loop_depth {
+                            return Err(TrainfuckError::LoopDepthExceeded(self.max_loop_depth));
+                        }
+                        self.frames.push(0);
+                        self.peak_loop_depth = self.peak_loop_depth.max(depth);
+                    } else {
+                        self.frames[depth - 1] += 1;
+                    }
+                }
+                op => {
+                    self.execute_op(op)?;
+                    self.frames[depth - 1] += 1;
+                }
+            }
+        }
+
+        Ok(self.frames.len() == 1 && self.frames[0] == ops.len())
+    }
+
+    /// Run `ops` to completion, continuing from any position left by `step`
+    pub fn resume(&mut self, ops: &[Op]) -> Result<()> {
+        if self.frames.is_empty() {
+            self.execute(ops)?;
+            self.frames.push(ops.len());
+            return Ok(());
+        }
+        while !self.step(ops)? {}
+        Ok(())
+    }
+
+    /// Whether a controller may pause at breakpoints
+    pub(crate) fn debugging(&self) -> bool {
+        #[cfg(unix)]
+        return self.controller.is_some();
+        #[cfg(not(unix))]
+        false
+    }
+
+    /// Account for one step, checking interrupts and sandbox limits
+    pub(crate) fn tick(&mut self) -> Result<()> {
+        #[cfg(unix)]
+        if let Some(mut controller) = self.controller.take() {
+            controller.poll(self);
+            self.controller = Some(controller);
+        }
+
+        if self.interrupted() && !self.keep_draining() {
+            return Err(TrainfuckError::Interrupted);
+        }
+
+        if let Some(delay) = self.step_delay {
+            self.pace(delay)?;
+        }
+
+        self.steps += 1;
+        self.charge_step()?;
+        self.report_steps();
+        if let Some(max) = self.max_steps {
+            if self.steps > max {
+                return Err(TrainfuckError::StepLimitExceeded(max));
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if self.steps.is_multiple_of(DEADLINE_CHECK_INTERVAL) && self.now() >= deadline {
+                return Err(TrainfuckError::TimeLimitExceeded);
+            }
+        }
+        if self
+            .watchers
+            .iter()
+            .any(|watcher| self.steps.is_multiple_of(watcher.interval))
+        {
+            let mut watchers = std::mem::take(&mut self.watchers);
+            for watcher in &mut watchers {
+                if self.steps.is_multiple_of(watcher.interval) {
+                    (watcher.callback)(self);
+                }
+            }
+            self.watchers = watchers;
+        }
+        Ok(())
+    }
+
+    fn execute_op(&mut self, op: &Op) -> Result<()> {
+        self.tick()?;
+        let pointer = self.pointer;
+
+        if !self.network_enabled && op.is_network() {
+            return Err(TrainfuckError::NetworkDisabled);
+        }
+
+        match op {
+            Op::MoveRight(n) => self.move_right(*n),
+            Op::MoveLeft(n) => self.move_left(*n),
+            Op::Increment(n) => {
+                self.tape[self.pointer] = self.tape[self.pointer].wrapping_add(*n);
+            }
+            Op::Decrement(n) => {
+                self.tape[self.pointer] = self.tape[self.pointer].wrapping_sub(*n);
+            }
+            Op::Output => self.output_cell()?,
+            Op::Input => self.input_cell()?,
+            Op::Loop(inner_ops) => {
+                if self.loop_depth >= self.max_loop_depth {
+                    return Err(TrainfuckError::LoopDepthExceeded(self.max_loop_depth));
+                }
+                self.enter_loop();
+                let result = self.run_loop(inner_ops);
+                self.leave_loop();
+                result?;
+            }
+            Op::Clear => self.tape[self.pointer] = 0,
+            Op::ScanRight(stride) => self.scan_right(*stride)?,
+            Op::ScanLeft(stride) => self.scan_left(*stride)?,
+            Op::MulAdd(targets) => self.mul_add(targets.iter().copied()),
+            Op::Print(bytes) => self.print(bytes)?,
+            Op::Load(start, cells) => self.load(*start, cells),
+
+            // Networking operations
+            Op::Listen => self.net_listen()?,
+            Op::Accept => self.net_accept()?,
+            Op::Connect => self.net_connect()?,
+            Op::Receive => self.net_receive()?,
+            Op::Send => self.net_send()?,
+
+            Op::CompareSwap => self.compare_swap()?,
+            Op::Spawn => self.spawn_actor()?,
+            Op::SendMessage => self.send_message()?,
+            Op::ReceiveMessage => self.receive_message()?,
+            Op::Yield => self.yielded = true,
+            Op::KvPut => self.kv_put()?,
+            Op::KvGet => self.kv_get()?,
+            Op::Http => self.http_request()?,
+            Op::Checksum => self.checksum(),
+            Op::Base64Encode => self.base64_encode(),
+            Op::Base64Decode => self.base64_decode(),
+            Op::Xor => self.xor(),
+            Op::Trap => self.trap()?,
+            Op::Features(mask) => self.detect_features(*mask),
+            Op::Peek => self.net_peek()?,
+            Op::Unread => self.unread(),
+            Op::ReceiveLine => self.net_receive_line()?,
+            Op::Resolve => self.resolve()?,
+            Op::SelectChannel => self.select_channel(),
+            Op::Channels => self.channel_status(),
+            Op::Flush => self.net_flush()?,
+            Op::SendBlock => self.net_send_block()?,
+            Op::ReceiveBlock => self.net_receive_block()?,
+        }
+        if !self.guards.is_empty() {
+            self.check_guards()?;
+        }
+        // A loop's body recorded itself
+        if self.history.is_some() && !matches!(op, Op::Loop(_)) {
+            self.record_op(op, pointer);
+        }
+        Ok(())
+    }
+
+    fn run_loop(&mut self, body: &[Op]) -> Result<()> {
+        while self.tape[self.pointer] != 0 {
+            // Count the iteration itself so empty loops still hit limits
+            self.tick()?;
+            self.execute(body)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn move_right(&mut self, n: usize) {
+        if self.tape_model == TapeModel::Unbounded {
+            self.pointer = self.reach(n as isize);
+            return;
+        }
+        self.pointer = kernel::wrap_right(self.pointer, n, self.tape.len());
+    }
+
+    pub(crate) fn move_left(&mut self, n: usize) {
+        if self.tape_model == TapeModel::Unbounded {
+            self.pointer = self.reach(-(n as isize));
+            return;
+        }
+        self.pointer = kernel::wrap_left(self.pointer, n, self.tape.len());
+    }
+
+    /// Index of the cell `offset` away from the pointer on an unbounded
+    /// tape, first adding cells (at least doubling the tape) if it is past
+    /// an end
+    fn reach(&mut self, offset: isize) -> usize {
+        let len = self.tape.len();
+        let target = (self.pointer as isize).saturating_add(offset);
+        if target < 0 {
+            let added = target.unsigned_abs().max(len);
+            self.tape.grow(added, 0);
+            self.pointer += added;
+            self.origin += added;
+            added - target.unsigned_abs()
+        } else if target as usize >= len {
+            self.tape.grow(0, (target as usize + 1 - len).max(len));
+            target as usize
+        } else {
+            target as usize
+        }
+    }
+
+    pub(crate) fn output_cell(&mut self) -> Result<()> {
+        let c = self.tape[self.pointer];
+        self.write_output(&[c])
+    }
+
+    pub(crate) fn input_cell(&mut self) -> Result<()> {
+        let mut buf = [0u8; 1];
+        let waiting = self.waiting();
+        let read = self.input.read(&mut buf);
+        drop(waiting);
+        match read {
+            Ok(0) => {
+                self.tape[self.pointer] = 0;
+                self.eof_reads += 1;
+            }
+            Ok(_) => {
+                self.tape[self.pointer] = buf[0];
+                self.bytes_input += 1;
+                self.charge_bytes(1)?;
+            }
+            // Non-blocking input with nothing pending reads as 0
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.tape[self.pointer] = 0,
+            Err(e) => return Err(TrainfuckError::IoError(e)),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn print(&mut self, bytes: &[u8]) -> Result<()> {
+        self.write_output(bytes)
+    }
+
+    /// Write and flush `bytes`, telling a reader that went away (a closed
+    /// pipe) apart from other I/O errors
+    fn write_output(&mut self, bytes: &[u8]) -> Result<()> {
+        self.bytes_output += bytes.len() as u64;
+        if let Some(max) = self.max_output {
+            if self.bytes_output > max {
+                return Err(TrainfuckError::OutputLimitExceeded(max));
+            }
+        }
+        self.charge_bytes(bytes.len())?;
+        match self
+            .output
+            .write_all(bytes)
+            .and_then(|()| self.output.flush())
+        {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Err(TrainfuckError::OutputClosed),
+            Err(e) => Err(TrainfuckError::IoError(e)),
+        }
+    }
+
+    /// Overwrite cells from `start`, wrapping at the tape end
+    pub(crate) fn load(&mut self, start: usize, cells: &[u8]) {
+        let tape_size = self.tape.len();
+        for (i, &value) in cells.iter().enumerate() {
+            self.tape[(start + i) % tape_size] = value;
+        }
+    }
+
+    /// `cell[p + offset] += cell[p] * factor` for each target, then clear `cell[p]`
+    pub(crate) fn mul_add(&mut self, targets: impl Iterator<Item = (isize, u8)>) {
+        let value = self.tape[self.pointer];
+        if value == 0 {
+            return;
+        }
+        let tape_size = self.tape.len();
+        for (offset, factor) in targets {
+            let target = match self.tape_model {
+                TapeModel::Classic => kernel::wrap_offset(self.pointer, offset, tape_size),
+                TapeModel::Unbounded => self.reach(offset),
+            };
+            self.tape[target] = self.tape[target].wrapping_add(value.wrapping_mul(factor));
+        }
+        self.tape[self.pointer] = 0;
+    }
+
+    /// Move right by `stride` until a zero cell, wrapping at the tape end
+    pub(crate) fn scan_right(&mut self, stride: usize) -> Result<()> {
+        if self.tape_model == TapeModel::Unbounded {
+            return self.scan_unbounded(stride as isize);
+        }
+        let len = self.tape.len();
+        if stride % len == 1 % len {
+            let found = memchr::memchr(0, &self.tape[self.pointer..])
+                .map(|i| self.pointer + i)
+                .or_else(|| memchr::memchr(0, &self.tape[..self.pointer]));
+            return match found {
+                Some(index) => {
+                    self.pointer = index;
+                    Ok(())
+                }
+                None => self.spin(),
+            };
+        }
+        self.scan_by(stride % len)
+    }
+
+    /// Move left by `stride` until a zero cell, wrapping at the tape start
+    pub(crate) fn scan_left(&mut self, stride: usize) -> Result<()> {
+        if self.tape_model == TapeModel::Unbounded {
+            return self.scan_unbounded(-(stride as isize));
+        }
+        let len = self.tape.len();
+        if stride % len == 1 % len {
+            let found = memchr::memrchr(0, &self.tape[..=self.pointer]).or_else(|| {
+                memchr::memrchr(0, &self.tape[self.pointer..]).map(|i| self.pointer + i)
+            });
+            return match found {
+                Some(index) => {
+                    self.pointer = index;
+                    Ok(())
+                }
+                None => self.spin(),
+            };
+        }
+        self.scan_by(len - stride % len)
+    }
+
+    /// Scan an unbounded tape, which always ends in a zero cell: at worst a
+    /// new one just past the end
+    fn scan_unbounded(&mut self, step: isize) -> Result<()> {
+        if step == 0 {
+            return match self.tape[self.pointer] {
+                0 => Ok(()),
+                _ => self.spin(),
+            };
+        }
+        let found = match step {
+            1 => memchr::memchr(0, &self.tape[self.pointer..]).map(|i| self.pointer + i),
+            -1 => memchr::memrchr(0, &self.tape[..=self.pointer]),
+            _ => None,
+        };
+        if let Some(index) = found {
+            self.pointer = index;
+            return Ok(());
+        }
+        while self.tape[self.pointer] != 0 {
+            self.pointer = self.reach(step);
+        }
+        Ok(())
+    }
+
+    /// Scan with an arbitrary forward step (already reduced modulo the tape length)
+    fn scan_by(&mut self, step: usize) -> Result<()> {
+        match kernel::scan(&self.tape, self.pointer, step) {
+            Some(index) => {
+                self.pointer = index;
+                Ok(())
+            }
+            None => self.spin(),
+        }
+    }
+
+    /// A scan that never finds a zero cell loops forever, like the loop it
+    /// replaced; keep ticking so interrupts and limits still apply
+    fn spin(&mut self) -> Result<()> {
+        loop {
+            self.tick()?;
+        }
+    }
+
+    /// Flush output and close the listener and any open connections
+    pub fn shutdown(&mut self) -> Result<()> {
+        // One last try for what `'` couldn't send
+        let _ = self.send_unsent();
+        if let Some(mut stream) = self.connection.take() {
+            stream.shutdown();
+            eprintln!("[trainfuck] Connection closed");
+        }
+        self.close_parked();
+        if self.listener.take().is_some() {
+            self.report_listening();
+            eprintln!("[trainfuck] Listener closed");
+        }
+        self.output.flush()?;
+        Ok(())
+    }
+
+    /// Write a hex dump of the tape up to the last non-zero cell (or the
+    /// pointer). Runs of all-zero rows are collapsed into a single `*` line.
+    pub fn dump_tape(&self, w: &mut dyn Write) -> io::Result<()> {
+        let last_used = self.tape.iter().rposition(|&c| c != 0).unwrap_or(0);
+        let end = last_used.max(self.pointer) + 1;
+        let pointer_row = self.pointer / 16;
+
+        writeln!(w, "pointer: {}", self.pointer)?;
+        let mut skipping = false;
+        for (row, chunk) in self.tape[..end].chunks(16).enumerate() {
+            if row > 0 && row != pointer_row && chunk.iter().all(|&c| c == 0) {
+                if !skipping {
+                    writeln!(w, "*")?;
+                    skipping = true;
+                }
+                continue;
+            }
+            skipping = false;
+
+            write!(w, "{:05}:", row * 16)?;
+            for cell in chunk {
+                write!(w, " {:02x}", cell)?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    /// Listen on address:port from tape, or on [`listen_addrs`](Self::listen_addrs)
+    /// Address: 4 bytes at pointer (big-endian IPv4)
+    /// Port: 2 bytes at pointer+4 (big-endian); 0 picks a free port, which
+    /// is written back there
+    pub(crate) fn net_listen(&mut self) -> Result<()> {
+        if self.listener.is_some() {
+            // Already listening, close existing
+            self.listener = None;
+            self.report_listening();
+            self.report(NetStatus::Ok);
+            return Ok(());
+        }
+
+        let from_tape = self.listen_addrs.is_empty() && self.addr_map.is_none();
+        let addrs = match self.listen_addrs.is_empty() {
+            false => self.listen_addrs.clone(),
+            true => match self.mapped_addr()? {
+                Some(addr) => vec![addr],
+                None => {
+                    let addr = self.read_address_from_tape();
+                    let port = self.read_port_from_tape();
+                    vec![SocketAddr::V4(SocketAddrV4::new(addr, port))]
+                }
+            },
+        };
+        let mut listeners = Vec::with_capacity(addrs.len());
+        // The port picked for the first address with port 0, which the
+        // others with port 0 share
+        let mut picked = None;
+        for mut addr in addrs {
+            let ephemeral = addr.port() == 0;
+            if let (true, Some(port)) = (ephemeral, picked) {
+                addr.set_port(port);
+            }
+            let listener = self
+                .network
+                .listen(addr)
+                .map_err(|e| net_error(&format!("Failed to bind {}", addr), e))?;
+            let bound = listener.local_addr().unwrap_or(addr);
+            eprintln!("[trainfuck] Listening on {}", bound);
+            if ephemeral && picked.is_none() && bound.port() != 0 {
+                // One line harnesses can look for without parsing the rest
+                eprintln!("[trainfuck] port={}", bound.port());
+                picked = Some(bound.port());
+            }
+            listeners.push(listener);
+        }
+        if let (true, Some(port)) = (from_tape, picked) {
+            let [high, low] = port.to_be_bytes();
+            self.set_cell(self.pointer + 4, high);
+            self.set_cell(self.pointer + 5, low);
+        }
+
+        self.listener = Some(match listeners.len() {
+            1 => listeners.remove(0),
+            _ => Box::new(MultiListener::new(listeners)),
+        });
+        self.report_listening();
+        self.report(NetStatus::Ok);
+        Ok(())
+    }
+
+    /// Accept incoming connection
+    pub(crate) fn net_accept(&mut self) -> Result<()> {
+        if self.connection.is_some() {
+            // Close existing connection
+            self.connection = None;
+            self.pushback.clear();
+            self.dialed = None;
+            eprintln!("[trainfuck] Connection closed");
+            self.report(NetStatus::Ok);
+            return Ok(());
+        }
+
+        let waiting = self.waiting();
+        if let Some(ref mut listener) = self.listener {
+            let accepted = loop {
+                let accepted = match (&self.interrupt, self.accept_timeout) {
+                    // Nothing is open, so an interrupt stops the wait at once
+                    (Some(flag), timeout) => {
+                        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+                        loop {
+                            let wait = match deadline {
+                                Some(deadline) => INTERRUPT_POLL
+                                    .min(deadline.saturating_duration_since(Instant::now())),
+                                None => INTERRUPT_POLL,
+                            };
+                            match listener.accept_timeout(wait) {
+                                Ok(None) if flag.load(Ordering::Relaxed) => {
+                                    return Err(TrainfuckError::Interrupted);
+                                }
+                                Ok(None)
+                                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) =>
+                                {
+                                    break Ok(None)
+                                }
+                                Ok(None) => {}
+                                accepted => break accepted,
+                            }
+                        }
+                    }
+                    (None, Some(timeout)) => listener.accept_timeout(timeout),
+                    (None, None) => listener.accept().map(Some),
+                };
+                match accepted {
+                    Ok(Some(mut stream))
+                        if self
+                            .access
+                            .as_ref()
+                            .is_some_and(|access| !access.admits(stream.peer_addr())) =>
+                    {
+                        match stream.peer_addr() {
+                            Some(peer) => eprintln!("[trainfuck] Refused connection from {}", peer),
+                            None => eprintln!("[trainfuck] Refused connection from unknown peer"),
+                        }
+                        stream.shutdown();
+                    }
+                    accepted => break accepted,
+                }
+            };
+            drop(waiting);
+            let Some(stream) = accepted.map_err(|e| net_error("Accept failed", e))? else {
+                self.report(NetStatus::NoConnection);
+                return Ok(());
+            };
+            let peer = stream.peer_addr();
+            match peer {
+                Some(peer) => eprintln!("[trainfuck] Accepted connection from {}", peer),
+                None => eprintln!("[trainfuck] Accepted connection"),
+            }
+            self.open_connection(stream, None);
+            self.report_peer(peer);
+            self.accepted += 1;
+        }
+        self.report(NetStatus::Ok);
+        Ok(())
+    }
+
+    /// Connect to address:port from tape, or to [`connect_addr`](Self::connect_addr)
+    pub(crate) fn net_connect(&mut self) -> Result<()> {
+        if self.connection.is_some() {
+            // Already connected, close
+            self.connection = None;
+            self.pushback.clear();
+            self.dialed = None;
+            self.report(NetStatus::Ok);
+            return Ok(());
+        }
+
+        let mapped = match self.connect_addr {
+            Some(addr) => Some(SocketAddr::V4(addr)),
+            None => self.mapped_addr()?,
+        };
+        let socket_addr = match mapped {
+            Some(SocketAddr::V4(addr)) => addr,
+            Some(SocketAddr::V6(addr)) => {
+                return Err(TrainfuckError::NetworkError(format!(
+                    "Cannot dial {}: `%` only dials IPv4 addresses",
+                    addr
+                )));
+            }
+            None => SocketAddrV4::new(self.read_address_from_tape(), self.read_port_from_tape()),
+        };
+        let stream = self.dial(socket_addr)?;
+        eprintln!("[trainfuck] Connected to {}", socket_addr);
+        self.open_connection(stream, Some(socket_addr));
+        self.report(NetStatus::Ok);
+        Ok(())
+    }
+
+    /// Open a connection, retrying as [`connect_retry`](Self::connect_retry) says
+    fn dial(&mut self, socket_addr: SocketAddrV4) -> Result<Box<dyn Stream>> {
+        let mut backoff = self.connect_retry.backoff;
+        let mut retries = self.connect_retry.retries;
+        let _waiting = self.waiting();
+        let stream = loop {
+            match self.network.connect(socket_addr) {
+                Ok(stream) => break stream,
+                Err(e)
+                    if retries > 0
+                        && matches!(
+                            e.kind(),
+                            io::ErrorKind::ConnectionRefused | io::ErrorKind::TimedOut
+                        ) =>
+                {
+                    eprintln!(
+                        "[trainfuck] Connect to {} failed ({}), retrying in {:?}",
+                        socket_addr, e, backoff
+                    );
+                    self.wait(backoff)?;
+                    backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+                    retries -= 1;
+                }
+                Err(e) => return Err(net_error("Connect failed", e)),
+            }
+        };
+        Ok(stream)
+    }
+
+    /// For [`reconnect`](Self::reconnect): replace a dropped connection
+    /// opened by `%` with a new one to the same address. Returns whether
+    /// there is a new connection to retry the op on.
+    fn redial(&mut self, problem: &str) -> Result<bool> {
+        let Some(addr) = self.dialed.filter(|_| self.reconnect) else {
+            return Ok(false);
+        };
+        eprintln!(
+            "[trainfuck] Connection to {} {}, reconnecting",
+            addr, problem
+        );
+        self.connection = None;
+        let stream = self.dial(addr)?;
+        eprintln!("[trainfuck] Reconnected to {}", addr);
+        self.open_connection(stream, Some(addr));
+        Ok(true)
+    }
+
+    /// Write `status` to the status cell, if there is one
+    pub(crate) fn report(&mut self, status: NetStatus) {
+        if let Some(cell) = self.status_cell {
+            let len = self.tape.len();
+            self.tape[(self.origin + cell) % len] = status as u8;
+        }
+    }
+
+    /// Report `status`, or that the connection went over its flood limits
+    fn report_flood(&mut self, status: NetStatus) {
+        match self.connection.as_mut().is_some_and(|c| c.over_limit()) {
+            true => self.report(NetStatus::Flooded),
+            false => self.report(status),
+        }
+    }
+
+    /// Write a record of a connection `@` just accepted to the peer cells
+    fn report_peer(&mut self, peer: Option<SocketAddr>) {
+        let Some(cell) = self.peer_cell else {
+            return;
+        };
+        let (ip, port) = match peer.map(|peer| (peer.ip().to_canonical(), peer.port())) {
+            Some((IpAddr::V4(ip), port)) => (ip, port),
+            _ => (Ipv4Addr::UNSPECIFIED, 0),
+        };
+        let now = self.unix_time().as_secs() as u32;
+        let mut record = [0u8; PEER_CELLS];
+        record[0] = self.channel;
+        record[1..5].copy_from_slice(&ip.octets());
+        record[5..7].copy_from_slice(&port.to_be_bytes());
+        record[7..].copy_from_slice(&now.to_be_bytes());
+        let len = self.tape.len();
+        for (i, byte) in record.into_iter().enumerate() {
+            self.set_cell((self.origin + cell + i) % len, byte);
+        }
+    }
+
+    /// Write the room of the connection's last message to the room cells
+    fn report_room(&mut self) {
+        let (Some(cell), Some(ref connection)) = (self.room_cell, &self.connection) else {
+            return;
+        };
+        let Some(room) = connection.room() else {
+            return;
+        };
+        let mut name = [0u8; ROOM_CELLS];
+        let n = room.len().min(ROOM_CELLS - 1);
+        name[..n].copy_from_slice(&room[..n]);
+        let len = self.tape.len();
+        for (i, byte) in name.into_iter().enumerate() {
+            self.set_cell((self.origin + cell + i) % len, byte);
+        }
+    }
+
+    /// Hold the next operation back until `delay` after the last one
+    fn pace(&mut self, delay: Duration) -> Result<()> {
+        let now = self.now();
+        let due = self.next_step_at.unwrap_or(now);
+        if due > now {
+            self.wait(due - now)?;
+        }
+        // Catch up after oversleeping, but not after blocking in `@` or `,`
+        self.next_step_at = Some(due.max(now) + delay);
+        Ok(())
+    }
+
+    /// Sleep, but stop early for an interrupt or the deadline
+    fn wait(&mut self, duration: Duration) -> Result<()> {
+        let until = self.now() + duration;
+        loop {
+            if let Some(ref flag) = self.interrupt {
+                if flag.load(Ordering::Relaxed) {
+                    return Err(TrainfuckError::Interrupted);
+                }
+            }
+            let now = self.now();
+            if self.deadline.is_some_and(|deadline| now >= deadline) {
+                return Err(TrainfuckError::TimeLimitExceeded);
+            }
+            if now >= until {
+                return Ok(());
+            }
+            self.sleep(INTERRUPT_POLL.min(until - now));
+        }
+    }
+
+    /// Receive a byte from network, store at pointer
+    pub(crate) fn net_receive(&mut self) -> Result<()> {
+        self.tape[self.pointer] = self.receive_byte()?.unwrap_or(0);
+        Ok(())
+    }
+
+    /// `=`: receive a byte and push it back
+    pub(crate) fn net_peek(&mut self) -> Result<()> {
+        let byte = self.receive_byte()?;
+        self.pushback.extend(byte);
+        self.tape[self.pointer] = byte.unwrap_or(0);
+        Ok(())
+    }
+
+    /// `"`: receive up to a newline or the limit in the current cell into
+    /// the cells after it, writing the count over the limit
+    pub(crate) fn net_receive_line(&mut self) -> Result<()> {
+        let len = self.tape.len();
+        let limit = self.tape[self.pointer];
+        let mut line = Vec::with_capacity(usize::from(limit));
+        while line.len() < usize::from(limit) {
+            let byte = match self.receive_byte() {
+                Ok(Some(byte)) => byte,
+                Ok(None) => break,
+                Err(e) => {
+                    // Nothing is written yet, so hand the bytes back for
+                    // the retry after `WouldBlock`
+                    self.pushback.extend(line.iter().rev());
+                    return Err(e);
+                }
+            };
+            line.push(byte);
+            if byte == b'\n' {
+                break;
+            }
+        }
+        for (i, &byte) in line.iter().enumerate() {
+            self.tape[(self.pointer + 1 + i) % len] = byte;
+        }
+        self.tape[self.pointer] = line.len() as u8;
+        Ok(())
+    }
+
+    /// `` ` `` with [`Extensions::blocks`]: wait for data and receive as
+    /// much as has arrived, up to what the current cell says, into the
+    /// cells after the pointer, writing how much to the current cell
+    pub(crate) fn net_receive_block(&mut self) -> Result<()> {
+        let len = self.tape.len();
+        let count = usize::from(self.tape[self.pointer]);
+        let mut block = vec![0u8; count];
+        let filled = match count {
+            0 => 0,
+            _ => self.receive_some(&mut block)?,
+        };
+        for (i, &byte) in block[..filled].iter().enumerate() {
+            self.tape[(self.pointer + 1 + i) % len] = byte;
+        }
+        self.tape[self.pointer] = filled as u8;
+        Ok(())
+    }
+
+    /// `\`: push the current cell back onto the connection
+    pub(crate) fn unread(&mut self) {
+        self.pushback.push(self.tape[self.pointer]);
+    }
+
+    /// The next byte from the push-back buffer or the connection, or `None`
+    /// if there is no connection or it ended
+    fn receive_byte(&mut self) -> Result<Option<u8>> {
+        let mut buf = [0u8; 1];
+        match self.receive_some(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+
+    /// Fill the start of `buf` from the push-back buffer or with what the
+    /// connection has, waiting for at least a byte, and return how much;
+    /// 0 if there is no connection or it ended
+    fn receive_some(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.pushback.is_empty() {
+            let n = buf.len().min(self.pushback.len());
+            for slot in &mut buf[..n] {
+                *slot = self.pushback.pop().expect("counted");
+            }
+            self.report(NetStatus::Ok);
+            return Ok(n);
+        }
+        let read = loop {
+            let Some(ref mut stream) = self.connection else {
+                self.report(NetStatus::Ok);
+                return Ok(0);
+            };
+            let waiting = Waiting::of(&self.health);
+            let read = stream.read(buf);
+            drop(waiting);
+            match read {
+                // The interrupt poll's or the idle timeout's read timeout
+                // ran out, or the signal itself broke off the read
+                Err(ref e)
+                    if (self.interrupt.is_some() || self.idle_timeout.is_some())
+                        && matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock
+                                | io::ErrorKind::TimedOut
+                                | io::ErrorKind::Interrupted
+                        ) =>
+                {
+                    if self.interrupted() && !self.keep_draining() {
+                        return Err(TrainfuckError::Interrupted);
+                    }
+                    if self.close_if_idle() {
+                        return Ok(0);
+                    }
+                }
+                read => break read,
+            }
+        };
+        let error = match read {
+            Ok(0) => None,
+            Ok(n) => {
+                self.bytes_received += n as u64;
+                self.session.bytes_received += n as u64;
+                self.charge_bytes(n)?;
+                self.heard();
+                self.report_flood(NetStatus::Ok);
+                self.report_room();
+                return Ok(n);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Err(TrainfuckError::WouldBlock);
+            }
+            Err(e) => Some(e),
+        };
+        // Still reads as 0: the new peer may be waiting for the program to
+        // repeat what the old one never answered
+        if self.redial(if error.is_some() { "failed" } else { "closed" })? {
+            self.report(NetStatus::Reconnected);
+            return Ok(0);
+        }
+        let status = match error {
+            // Connection closed
+            None if self.connection.as_mut().is_some_and(|c| c.over_limit()) => NetStatus::Flooded,
+            None => NetStatus::Closed,
+            Some(e) if self.net_errors == Some(NetErrors::Abort) => {
+                return Err(net_error("Receive failed", e));
+            }
+            Some(e) => {
+                eprintln!("[trainfuck] Receive error: {}", e);
+                NetStatus::from_error(&e)
+            }
+        };
+        self.report(status);
+        Ok(0)
+    }
+
+    /// Send byte at pointer to network
+    pub(crate) fn net_send(&mut self) -> Result<()> {
+        self.send(&[self.tape[self.pointer]])
+    }
+
+    /// `'` with [`Extensions::blocks`]: send the counted cells after the
+    /// pointer in one write
+    pub(crate) fn net_send_block(&mut self) -> Result<()> {
+        let len = self.tape.len();
+        let count = usize::from(self.tape[self.pointer]);
+        let start = (self.pointer + 1) % len;
+        let block = match start + count <= len {
+            true => self.tape[start..start + count].to_vec(),
+            false => (0..count).map(|i| self.tape[(start + i) % len]).collect(),
+        };
+        self.send(&block)
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        let mut status = NetStatus::Ok;
+        let mut result = self.write_connection(bytes);
+        if let Err(ref e) = result {
+            if e.kind() != io::ErrorKind::WouldBlock && self.redial(&format!("failed ({})", e))? {
+                status = NetStatus::Reconnected;
+                result = self.write_connection(bytes);
+            }
+        }
+        match result {
+            Ok(()) => {
+                if self.connection.is_some() {
+                    self.charge_bytes(bytes.len())?;
+                }
+                if !self.unsent.is_empty() {
+                    status = NetStatus::Pending;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Err(TrainfuckError::WouldBlock);
+            }
+            Err(e) if self.net_errors == Some(NetErrors::Status) => {
+                eprintln!("[trainfuck] Send error: {}", e);
+                status = NetStatus::from_error(&e);
+            }
+            Err(e) => return Err(net_error("Send failed", e)),
+        }
+        self.report(status);
+        Ok(())
+    }
+
+    /// Write and flush bytes to the open connection, if any
+    fn write_connection(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.extensions.backpressure {
+            return self.send_or_keep(bytes);
+        }
+        match self.connection {
+            Some(ref mut stream) => {
+                stream.write_all(bytes)?;
+                stream.flush()?;
+                self.bytes_sent += bytes.len() as u64;
+                self.session.bytes_sent += bytes.len() as u64;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// `~`: compare-and-swap on the shared segment, operands at the pointer
+    pub(crate) fn compare_swap(&mut self) -> Result<()> {
+        let Some(ref shared) = self.shared else {
+            return Err(TrainfuckError::NoSharedSegment);
+        };
+        let len = self.tape.len();
+        let at = |i: usize| (self.pointer + i) % len;
+        let (index, expected, new) = (self.tape[at(0)], self.tape[at(1)], self.tape[at(2)]);
+        self.tape[at(1)] = shared.compare_swap(index, expected, new);
+        Ok(())
+    }
+
+    /// `#`: CRC-32 of the counted cells before the pointer, written at it
+    pub(crate) fn checksum(&mut self) {
+        let len = self.tape.len();
+        let count = usize::from(self.tape[self.pointer]);
+        let message: Vec<u8> = (1..=count)
+            .rev()
+            .map(|i| self.tape[(self.pointer + len - i % len) % len])
+            .collect();
+        let crc = crc32fast::hash(&message);
+        for (i, byte) in crc.to_be_bytes().into_iter().enumerate() {
+            self.tape[(self.pointer + i) % len] = byte;
+        }
+    }
+
+    /// `:`: base64-encode the counted cells after the pointer in place
+    pub(crate) fn base64_encode(&mut self) {
+        let len = self.tape.len();
+        let count = usize::from(self.tape[self.pointer]);
+        let bytes: Vec<u8> = (1..=count)
+            .map(|i| self.tape[(self.pointer + i) % len])
+            .collect();
+        let text = BASE64.encode(bytes);
+        for (i, &byte) in text.as_bytes().iter().chain(&[0]).enumerate() {
+            self.tape[(self.pointer + 1 + i) % len] = byte;
+        }
+    }
+
+    /// `;`: decode the base64 text after the pointer in place
+    pub(crate) fn base64_decode(&mut self) {
+        let len = self.tape.len();
+        let text: Vec<u8> = (1..len)
+            .map(|i| self.tape[(self.pointer + i) % len])
+            .take_while(|&c| c != 0)
+            .collect();
+        let bytes = match BASE64.decode(&text) {
+            Ok(bytes) if bytes.len() <= usize::from(u8::MAX) => bytes,
+            _ => {
+                self.tape[self.pointer] = 0;
+                return;
+            }
+        };
+        self.tape[self.pointer] = bytes.len() as u8;
+        // Clear the rest of the text, which is longer than what it encodes
+        let cleared = bytes.iter().copied().chain(std::iter::repeat(0));
+        for (i, byte) in cleared.take(text.len()).enumerate() {
+            self.tape[(self.pointer + 1 + i) % len] = byte;
+        }
+    }
+
+    /// `!`: XOR the counted cells after the pointer with the key after them
+    pub(crate) fn xor(&mut self) {
+        let len = self.tape.len();
+        let count = usize::from(self.tape[self.pointer]);
+        let at = |i: usize| (self.pointer + i) % len;
+        let key: Vec<u8> = (count + 1..len)
+            .map(|i| self.tape[at(i)])
+            .take_while(|&c| c != 0)
+            .collect();
+        if key.is_empty() {
+            return;
+        }
+        for (i, &k) in (1..=count).zip(key.iter().cycle()) {
+            self.tape[at(i)] ^= k;
+        }
+    }
+
+    /// `/`: replace the hostname at the pointer with its IPv4 address
+    pub(crate) fn resolve(&mut self) -> Result<()> {
+        if !self.network_enabled {
+            return Err(TrainfuckError::NetworkDisabled);
+        }
+        let len = self.tape.len();
+        let bytes: Vec<u8> = (0..len)
+            .map(|i| self.tape[(self.pointer + i) % len])
+            .take_while(|&c| c != 0)
+            .collect();
+        let name = String::from_utf8_lossy(&bytes);
+        let found = (name.as_ref(), 0).to_socket_addrs().map(|mut addrs| {
+            addrs.find_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(*addr.ip()),
+                SocketAddr::V6(_) => None,
+            })
+        });
+        let ip = match found {
+            Ok(Some(ip)) => ip,
+            Ok(None) => {
+                eprintln!("[trainfuck] No IPv4 address for {}", name);
+                Ipv4Addr::UNSPECIFIED
+            }
+            Err(e) => {
+                eprintln!("[trainfuck] Could not resolve {}: {}", name, e);
+                Ipv4Addr::UNSPECIFIED
+            }
+        };
+        let cleared = ip.octets().into_iter().chain(std::iter::repeat(0));
+        for (i, byte) in cleared.take(bytes.len().max(4)).enumerate() {
+            self.tape[(self.pointer + i) % len] = byte;
+        }
+        Ok(())
+    }
+
+    /// With an [`addr_map`](Self::addr_map), the address the current cell
+    /// names
+    fn mapped_addr(&self) -> Result<Option<SocketAddr>> {
+        let Some(ref map) = self.addr_map else {
+            return Ok(None);
+        };
+        let index = self.tape[self.pointer];
+        match map.get(&index) {
+            Some(&addr) => Ok(Some(addr)),
+            None => Err(TrainfuckError::NetworkError(format!(
+                "No address {} in the address map",
+                index
+            ))),
+        }
+    }
+
+    /// Read IPv4 address from tape at pointer position
+    fn read_address_from_tape(&self) -> Ipv4Addr {
+        Ipv4Addr::new(
+            self.cell_after(0),
+            self.cell_after(1),
+            self.cell_after(2),
+            self.cell_after(3),
+        )
+    }
+
+    /// Read port from tape at pointer+4 position (big-endian)
+    fn read_port_from_tape(&self) -> u16 {
+        u16::from_be_bytes([self.cell_after(4), self.cell_after(5)])
+    }
+
+    /// The cell `n` cells right of the pointer, wrapping around the tape
+    fn cell_after(&self, n: usize) -> u8 {
+        self.tape[kernel::wrap_right(self.pointer, n, self.tape.len())]
+    }
+}
+
+/// Map a backend error, keeping would-block distinct so the op can be retried
+pub(crate) fn net_error(context: &str, e: io::Error) -> TrainfuckError {
+    if e.kind() == io::ErrorKind::WouldBlock {
+        TrainfuckError::WouldBlock
+    } else {
+        TrainfuckError::NetworkError(format!("{}: {}", context, e))
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -368,3 +2690,77 @@ impl Default for VM {
         Self::new()
     }
 }
+
+/// Output sink for [`VM::run_str`] and [`VM::run_collect`], read back once
+/// the VM is done with it
+struct Collect(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Collect {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reader that never blocks: bytes are pulled from the wrapped reader on a
+/// background thread, and `read` returns `WouldBlock` when none are pending.
+/// Lets a program poll the keyboard between network operations.
+pub struct NonBlockingReader {
+    rx: Receiver<io::Result<u8>>,
+    eof: bool,
+}
+
+impl NonBlockingReader {
+    pub fn new<R: Read + Send + 'static>(mut inner: R) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1];
+            loop {
+                let byte = match inner.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(_) => Ok(buf[0]),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = byte.is_err();
+                if tx.send(byte).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        NonBlockingReader { rx, eof: false }
+    }
+}
+
+impl Read for NonBlockingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.eof || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut n = 0;
+        while n < buf.len() {
+            match self.rx.try_recv() {
+                Ok(Ok(byte)) => {
+                    buf[n] = byte;
+                    n += 1;
+                }
+                Ok(Err(e)) => return if n > 0 { Ok(n) } else { Err(e) },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.eof = true;
+                    break;
+                }
+            }
+        }
+
+        if n == 0 && !self.eof {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(n)
+    }
+}
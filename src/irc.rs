@@ -0,0 +1,259 @@
+//! `trainfuck irc-bridge`: host a program as an IRC bot
+//!
+//! The bridge registers with the server, joins one channel and presents the
+//! conversation to the program twice over, so both styles of program work:
+//!
+//! - as its standard input and output (`,` and `.`)
+//! - as the connection that `$`/`@` accepts or `%` opens (`` ` `` and `'`)
+//!
+//! Every PRIVMSG to the channel arrives as its text followed by `\n`, and
+//! each line the program writes is sent to the channel as a PRIVMSG. The
+//! stream ends when the server closes the connection.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, SocketAddrV4, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use trainfuck::net::{Listener, Network, Stream};
+
+/// Longest message text sent at once; IRC lines are limited to 512 bytes
+/// including the command and channel
+const MAX_MESSAGE: usize = 400;
+
+/// Where to connect and what to call the bot
+pub struct IrcConfig {
+    /// `host` or `host:port`; the port defaults to 6667
+    pub server: String,
+    pub channel: String,
+    pub nick: String,
+}
+
+/// A registered connection to the server, in the channel
+#[derive(Clone)]
+pub struct IrcSession {
+    writer: Arc<Mutex<TcpStream>>,
+    messages: Arc<Mutex<Receiver<Vec<u8>>>>,
+    channel: Arc<str>,
+    /// Set once the server has closed the connection
+    closed: Arc<AtomicBool>,
+}
+
+impl IrcSession {
+    /// Connect, register and start joining the channel
+    pub fn connect(config: &IrcConfig) -> io::Result<Self> {
+        let addr = match config.server.contains(':') {
+            true => config.server.clone(),
+            false => format!("{}:6667", config.server),
+        };
+        let stream = TcpStream::connect(&addr)?;
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
+        let (sender, messages) = mpsc::channel();
+        let session = IrcSession {
+            writer,
+            messages: Arc::new(Mutex::new(messages)),
+            channel: config.channel.as_str().into(),
+            closed: Arc::new(AtomicBool::new(false)),
+        };
+        session.command(&format!("NICK {}", config.nick))?;
+        session.command(&format!("USER {} 0 * :Trainfuck bridge", config.nick))?;
+        eprintln!("[trainfuck] Connected to IRC server {}", addr);
+
+        let reader = session.clone();
+        let nick = config.nick.clone();
+        thread::spawn(move || {
+            if let Err(e) = reader.read_lines(BufReader::new(stream), nick, sender) {
+                eprintln!("[trainfuck] IRC connection failed: {}", e);
+            }
+            reader.closed.store(true, Ordering::Relaxed);
+        });
+        Ok(session)
+    }
+
+    fn command(&self, line: &str) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\r\n")
+    }
+
+    /// Answer the server and pass channel messages on until it disconnects
+    fn read_lines(
+        &self,
+        reader: impl BufRead,
+        mut nick: String,
+        messages: Sender<Vec<u8>>,
+    ) -> io::Result<()> {
+        for line in reader.split(b'\n') {
+            let line = line?;
+            let line = String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(&line));
+            let Some(message) = Message::parse(&line) else {
+                continue;
+            };
+            match message.command {
+                "PING" => self.command(&format!("PONG :{}", message.trailing))?,
+                // Registered
+                "001" => {
+                    self.command(&format!("JOIN {}", self.channel))?;
+                    eprintln!("[trainfuck] Joining {} as {}", self.channel, nick);
+                }
+                // Nickname in use
+                "433" => {
+                    nick.push('_');
+                    self.command(&format!("NICK {}", nick))?;
+                }
+                "PRIVMSG" if message.target.eq_ignore_ascii_case(&self.channel) => {
+                    let mut text = message.trailing.as_bytes().to_vec();
+                    text.push(b'\n');
+                    if messages.send(text).is_err() {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        eprintln!("[trainfuck] IRC server closed the connection");
+        Ok(())
+    }
+
+    /// A new view of the conversation, for `$`/`@` and `%`; fails once
+    /// the server is gone, so a program waiting for connections ends
+    fn open(&self) -> io::Result<Box<dyn Stream>> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "the IRC server closed the connection",
+            ));
+        }
+        Ok(Box::new(self.stream()))
+    }
+
+    /// A new view of the conversation, for one of the program's streams
+    pub fn stream(&self) -> IrcStream {
+        IrcStream {
+            session: self.clone(),
+            pending: Vec::new(),
+            line: Vec::new(),
+        }
+    }
+
+    fn say(&self, text: &[u8]) -> io::Result<()> {
+        let text = String::from_utf8_lossy(text);
+        self.command(&format!("PRIVMSG {} :{}", self.channel, text))
+    }
+}
+
+/// The parts of a server line the bridge looks at
+struct Message<'a> {
+    command: &'a str,
+    /// First parameter, e.g. the channel of a PRIVMSG
+    target: &'a str,
+    trailing: &'a str,
+}
+
+impl<'a> Message<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        // Drop the `:prefix`, if any
+        let line = match line.strip_prefix(':') {
+            Some(rest) => rest.split_once(' ')?.1,
+            None => line,
+        };
+        let (params, trailing) = line.split_once(" :").unwrap_or((line, ""));
+        let mut params = params.split(' ');
+        let command = params.next()?;
+        let target = params.next().unwrap_or("");
+        Some(Message {
+            command,
+            target,
+            trailing,
+        })
+    }
+}
+
+/// Channel messages in, program lines out
+pub struct IrcStream {
+    session: IrcSession,
+    /// Rest of a message not read yet
+    pending: Vec<u8>,
+    /// Output since the last newline
+    line: Vec<u8>,
+}
+
+impl Read for IrcStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.session.messages.lock().unwrap().recv() {
+                Ok(message) => self.pending = message,
+                // Server gone: end of stream
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for IrcStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            match byte {
+                b'\n' => {
+                    // IRC has no empty messages
+                    if !self.line.is_empty() {
+                        self.session.say(&self.line)?;
+                    }
+                    self.line.clear();
+                }
+                // Would end or corrupt the IRC line
+                b'\r' | 0 => {}
+                _ => {
+                    self.line.push(byte);
+                    if self.line.len() >= MAX_MESSAGE {
+                        self.session.say(&self.line)?;
+                        self.line.clear();
+                    }
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Lines are only sent once complete, as `'` flushes after every byte
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for IrcStream {}
+
+impl Drop for IrcStream {
+    /// Send a last line that wasn't ended with a newline
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            let _ = self.session.say(&self.line);
+        }
+    }
+}
+
+/// `$`, `@` and `%` all lead to the channel
+pub struct IrcNetwork(pub IrcSession);
+
+impl Network for IrcNetwork {
+    fn listen(&mut self, _addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(IrcListener(self.0.clone())))
+    }
+
+    fn connect(&mut self, _addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        self.0.open()
+    }
+}
+
+struct IrcListener(IrcSession);
+
+impl Listener for IrcListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        self.0.open()
+    }
+}
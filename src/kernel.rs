@@ -0,0 +1,803 @@
+//! The interpreter core, for targets without `std`
+//!
+//! Everything else in the library needs the standard library, for files,
+//! sockets, threads and clocks. This module needs only `alloc`, so with the
+//! crate's default `std` feature turned off it still builds, for embedded
+//! targets and WASM hosts that offer no more than an allocator:
+//!
+//! ```toml
+//! trainfuck = { version = "0.1", default-features = false }
+//! ```
+//!
+//! It holds the core of the [`VM`](crate::interpreter::VM): the bytecode
+//! instruction set, the tape logic and the step that runs every instruction
+//! but the opt-in extensions' ones, which the VM's dispatch loop hands here
+//! first. [`compile`] turns source into the bytecode the VM would run for it
+//! unoptimized, and a [`Machine`] runs it with that same step over a plain
+//! tape. `,`, `.` and the five networking commands go through the
+//! [`Input`], [`Output`] and [`Network`] traits, which the embedder
+//! implements for whatever its platform has: a UART, a radio, a JS
+//! callback. A `Network` that implements nothing fails every networking
+//! command with [`Error::NetworkDisabled`], as the VM does under `--sandbox`.
+//!
+//! ```
+//! use trainfuck::kernel::{self, Machine, NoNetwork};
+//!
+//! let code = kernel::compile(b"++++++++[>++++++++<-]>+.,[.,]")?;
+//! let mut output = Vec::new();
+//! let mut machine = Machine::new(kernel::TAPE_SIZE);
+//! machine.run(&code, &mut &b"BC"[..], &mut output, &mut NoNetwork)?;
+//! assert_eq!(output, b"ABC");
+//! # Ok::<(), trainfuck::kernel::Error>(())
+//! ```
+//!
+//! The source is plain Trainfuck: the eight Brainfuck commands and the five
+//! networking ones, with `#!` lines and `;;` comments skipped. Directives
+//! aren't read and the opt-in extensions aren't there; the tape wraps at
+//! either end.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Memory tape size (30KB as per original Brainfuck spec)
+pub const TAPE_SIZE: usize = 30_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    UnmatchedOpenBracket(usize),
+    UnmatchedCloseBracket(usize),
+    /// From an [`Input`] or [`Output`]
+    Io(String),
+    /// From a [`Network`]
+    Network(String),
+    NetworkDisabled,
+    StepLimitExceeded(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnmatchedOpenBracket(position) => {
+                write!(f, "Unmatched '[' at position {}", position)
+            }
+            Error::UnmatchedCloseBracket(position) => {
+                write!(f, "Unmatched ']' at position {}", position)
+            }
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Network(e) => write!(f, "Network error: {}", e),
+            Error::NetworkDisabled => f.write_str("Networking is disabled"),
+            Error::StepLimitExceeded(limit) => write!(f, "Step limit of {} exceeded", limit),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Where `,` reads from
+pub trait Input {
+    /// The next byte, or None once the input has ended, which `,` reads as 0
+    fn read(&mut self) -> Result<Option<u8>>;
+}
+
+/// Where `.` writes to
+pub trait Output {
+    fn write(&mut self, byte: u8) -> Result<()>;
+}
+
+/// What the networking commands do. Addresses are read from the tape as
+/// the VM reads them: four cells of IPv4 address from the pointer, then two
+/// of big-endian port.
+///
+/// Every method fails with [`Error::NetworkDisabled`] unless implemented.
+pub trait Network {
+    /// `$`: listen on `address`, or stop listening if already listening
+    fn listen(&mut self, address: [u8; 4], port: u16) -> Result<()> {
+        let _ = (address, port);
+        Err(Error::NetworkDisabled)
+    }
+
+    /// `@`: wait for a connection, or close the open one
+    fn accept(&mut self) -> Result<()> {
+        Err(Error::NetworkDisabled)
+    }
+
+    /// `%`: connect to `address`, or close the open connection
+    fn connect(&mut self, address: [u8; 4], port: u16) -> Result<()> {
+        let _ = (address, port);
+        Err(Error::NetworkDisabled)
+    }
+
+    /// `` ` ``: the next byte from the connection, or None once the peer
+    /// has closed it, which reads as 0
+    fn receive(&mut self) -> Result<Option<u8>> {
+        Err(Error::NetworkDisabled)
+    }
+
+    /// `'`: send `byte` on the connection
+    fn send(&mut self, byte: u8) -> Result<()> {
+        let _ = byte;
+        Err(Error::NetworkDisabled)
+    }
+}
+
+/// Networking turned off
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoNetwork;
+
+impl Network for NoNetwork {}
+
+impl Input for &[u8] {
+    fn read(&mut self) -> Result<Option<u8>> {
+        let Some((&byte, rest)) = self.split_first() else {
+            return Ok(None);
+        };
+        *self = rest;
+        Ok(Some(byte))
+    }
+}
+
+impl Output for Vec<u8> {
+    fn write(&mut self, byte: u8) -> Result<()> {
+        self.push(byte);
+        Ok(())
+    }
+}
+
+impl<T: Input + ?Sized> Input for &mut T {
+    fn read(&mut self) -> Result<Option<u8>> {
+        (**self).read()
+    }
+}
+
+impl<T: Output + ?Sized> Output for &mut T {
+    fn write(&mut self, byte: u8) -> Result<()> {
+        (**self).write(byte)
+    }
+}
+
+impl<T: Network + ?Sized> Network for &mut T {
+    fn listen(&mut self, address: [u8; 4], port: u16) -> Result<()> {
+        (**self).listen(address, port)
+    }
+
+    fn accept(&mut self) -> Result<()> {
+        (**self).accept()
+    }
+
+    fn connect(&mut self, address: [u8; 4], port: u16) -> Result<()> {
+        (**self).connect(address, port)
+    }
+
+    fn receive(&mut self) -> Result<Option<u8>> {
+        (**self).receive()
+    }
+
+    fn send(&mut self, byte: u8) -> Result<()> {
+        (**self).send(byte)
+    }
+}
+
+/// Index of the cell `n` cells right of `pointer` on a tape of `len` cells
+/// that wraps around at its ends
+#[inline]
+pub fn wrap_right(pointer: usize, n: usize, len: usize) -> usize {
+    let pointer = pointer + n % len;
+    if pointer >= len {
+        pointer - len
+    } else {
+        pointer
+    }
+}
+
+/// Index of the cell `n` cells left of `pointer` on a tape of `len` cells
+/// that wraps around at its ends
+#[inline]
+pub fn wrap_left(pointer: usize, n: usize, len: usize) -> usize {
+    let n = n % len;
+    if n > pointer {
+        // Wrap around
+        len - (n - pointer)
+    } else {
+        pointer - n
+    }
+}
+
+/// Index of the cell `offset` away from `pointer` on a tape of `len` cells
+/// that wraps around at its ends
+#[inline]
+pub(crate) fn wrap_offset(pointer: usize, offset: isize, len: usize) -> usize {
+    match (pointer as isize).wrapping_add(offset) {
+        // Only wrap targets that are off the tape
+        target @ 0.. if (target as usize) < len => target as usize,
+        target => target.rem_euclid(len as isize) as usize,
+    }
+}
+
+/// Index of the first zero cell found moving `step` cells right at a time
+/// (already reduced modulo the tape length) from `pointer`, or None if
+/// there is none to reach
+pub(crate) fn scan(tape: &[u8], mut pointer: usize, step: usize) -> Option<usize> {
+    // Every reachable cell is visited within `len` moves
+    for _ in 0..tape.len() {
+        if tape[pointer] == 0 {
+            return Some(pointer);
+        }
+        pointer = (pointer + step) % tape.len();
+    }
+    None
+}
+
+/// The bytecode instruction set, shared by [`compile`] and the VM's
+/// [`Bytecode`](crate::bytecode::Bytecode); without `std` only the core
+/// instructions are used
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+pub(crate) mod opcode {
+    use super::read_u32;
+
+    pub(crate) const HALT: u8 = 0;
+    pub(crate) const RIGHT: u8 = 1; // n: u32
+    pub(crate) const LEFT: u8 = 2; // n: u32
+    pub(crate) const ADD: u8 = 3; // delta: u8
+    pub(crate) const OUTPUT: u8 = 4;
+    pub(crate) const INPUT: u8 = 5;
+    pub(crate) const JUMP_ZERO: u8 = 6; // target: u32, just past the matching JUMP_NONZERO
+    pub(crate) const JUMP_NONZERO: u8 = 7; // target: u32, just past the matching JUMP_ZERO
+    pub(crate) const CLEAR: u8 = 8;
+    pub(crate) const SCAN_RIGHT: u8 = 9; // stride: u32
+    pub(crate) const SCAN_LEFT: u8 = 10; // stride: u32
+    pub(crate) const MUL_ADD: u8 = 11; // count: u32, then count * (offset: i64, factor: u8)
+    pub(crate) const RIGHT_ADD: u8 = 12; // n: u32, delta: u8
+    pub(crate) const LEFT_ADD: u8 = 13; // n: u32, delta: u8
+    pub(crate) const ADD_OUTPUT: u8 = 14; // delta: u8
+    pub(crate) const LISTEN: u8 = 15;
+    pub(crate) const ACCEPT: u8 = 16;
+    pub(crate) const CONNECT: u8 = 17;
+    pub(crate) const RECEIVE: u8 = 18;
+    pub(crate) const SEND: u8 = 19;
+    // Written over a hot loop's JUMP_ZERO by the tiered executor; same operand
+    pub(crate) const COMPILED: u8 = 20;
+    pub(crate) const PRINT: u8 = 21; // len: u32, then len bytes
+    pub(crate) const LOAD: u8 = 22; // start: u64, len: u32, then len bytes
+    pub(crate) const COMPARE_SWAP: u8 = 23;
+    pub(crate) const SPAWN: u8 = 24;
+    pub(crate) const SEND_MESSAGE: u8 = 25;
+    pub(crate) const RECEIVE_MESSAGE: u8 = 26;
+    pub(crate) const YIELD: u8 = 27;
+    pub(crate) const KV_PUT: u8 = 28;
+    pub(crate) const KV_GET: u8 = 29;
+    pub(crate) const HTTP: u8 = 30;
+    pub(crate) const CHECKSUM: u8 = 31;
+    pub(crate) const BASE64_ENCODE: u8 = 32;
+    pub(crate) const BASE64_DECODE: u8 = 33;
+    pub(crate) const XOR: u8 = 34;
+    pub(crate) const PEEK: u8 = 35;
+    pub(crate) const UNREAD: u8 = 36;
+    pub(crate) const RECEIVE_LINE: u8 = 37;
+    pub(crate) const RESOLVE: u8 = 38;
+    pub(crate) const SELECT_CHANNEL: u8 = 39;
+    pub(crate) const CHANNELS: u8 = 40;
+    pub(crate) const FLUSH: u8 = 41;
+    pub(crate) const SEND_BLOCK: u8 = 42;
+    pub(crate) const RECEIVE_BLOCK: u8 = 43;
+    pub(crate) const TRAP: u8 = 44;
+    pub(crate) const FEATURES: u8 = 45; // mask: u32
+
+    /// Size of the instruction at `pc` with its operands, or None for an
+    /// unknown opcode or a truncated length operand
+    pub(crate) fn width(code: &[u8], pc: usize) -> Option<usize> {
+        Some(match code[pc] {
+            HALT | OUTPUT | INPUT | CLEAR | LISTEN | ACCEPT | CONNECT | RECEIVE | SEND
+            | COMPARE_SWAP | SPAWN | SEND_MESSAGE | RECEIVE_MESSAGE | YIELD | KV_PUT | KV_GET
+            | HTTP | CHECKSUM | BASE64_ENCODE | BASE64_DECODE | XOR | PEEK | UNREAD
+            | RECEIVE_LINE | RESOLVE | SELECT_CHANNEL | CHANNELS | FLUSH | SEND_BLOCK
+            | RECEIVE_BLOCK | TRAP => 1,
+            ADD | ADD_OUTPUT => 2,
+            RIGHT | LEFT | SCAN_RIGHT | SCAN_LEFT | JUMP_ZERO | JUMP_NONZERO | FEATURES => 5,
+            RIGHT_ADD | LEFT_ADD => 6,
+            MUL_ADD => 5 + code.get(pc + 1..pc + 5).map(|_| read_u32(code, pc + 1))? * 9,
+            PRINT => 5 + code.get(pc + 1..pc + 5).map(|_| read_u32(code, pc + 1))?,
+            LOAD => 13 + code.get(pc + 9..pc + 13).map(|_| read_u32(code, pc + 9))?,
+            _ => return None,
+        })
+    }
+}
+
+use opcode::*;
+
+pub(crate) fn fits(n: usize) -> bool {
+    u32::try_from(n).is_ok()
+}
+
+pub(crate) fn address(n: usize) -> u32 {
+    u32::try_from(n).expect("bytecode operand exceeds 32 bits")
+}
+
+pub(crate) fn push_u32(code: &mut Vec<u8>, n: usize) {
+    code.extend(address(n).to_le_bytes());
+}
+
+/// Moves above `u32::MAX` are split into several instructions
+pub(crate) fn push_move(code: &mut Vec<u8>, opcode: u8, mut n: usize) {
+    loop {
+        let chunk = n.min(u32::MAX as usize);
+        code.push(opcode);
+        push_u32(code, chunk);
+        n -= chunk;
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+pub(crate) fn read_u32(code: &[u8], at: usize) -> usize {
+    u32::from_le_bytes(code[at..at + 4].try_into().unwrap()) as usize
+}
+
+/// Offsets and factors of the `MUL_ADD` at `pc`
+pub(crate) fn read_targets(code: &[u8], pc: usize) -> impl Iterator<Item = (isize, u8)> + '_ {
+    let start = pc + 5;
+    code[start..start + read_u32(code, pc + 1) * 9]
+        .chunks_exact(9)
+        .map(|target| {
+            let offset = i64::from_le_bytes(target[..8].try_into().unwrap());
+            (offset as isize, target[8])
+        })
+}
+
+/// What [`step`] runs the core instructions against: a tape, its pointer
+/// and somewhere for I/O to go. The [`Machine`] and the VM each implement
+/// it over their own tape and streams.
+pub(crate) trait Core {
+    type Error;
+
+    /// The cell under the pointer
+    fn cell(&mut self) -> &mut u8;
+    fn move_right(&mut self, n: usize);
+    fn move_left(&mut self, n: usize);
+    /// Move right by `stride` until a zero cell
+    fn scan_right(&mut self, stride: usize) -> core::result::Result<(), Self::Error>;
+    /// Move left by `stride` until a zero cell
+    fn scan_left(&mut self, stride: usize) -> core::result::Result<(), Self::Error>;
+    /// `cell[p + offset] += cell[p] * factor` for each target, then clear `cell[p]`
+    fn mul_add(&mut self, targets: impl Iterator<Item = (isize, u8)>);
+    /// Overwrite cells from `start`
+    fn load(&mut self, start: usize, cells: &[u8]);
+    fn output(&mut self, bytes: &[u8]) -> core::result::Result<(), Self::Error>;
+    /// Read a byte into the cell
+    fn input(&mut self) -> core::result::Result<(), Self::Error>;
+    /// Run the networking instruction `opcode`: `LISTEN` to `SEND`
+    fn network(&mut self, opcode: u8) -> core::result::Result<(), Self::Error>;
+    /// A loop's body is about to run
+    fn enter_loop(&mut self) {}
+    /// A loop ended
+    fn leave_loop(&mut self) {}
+}
+
+/// Execute the instruction at `pc`, returning where to continue, or None
+/// for `HALT` and the instructions of the opt-in extensions, which are the
+/// caller's to run
+#[inline(always)]
+pub(crate) fn step<C: Core>(
+    core: &mut C,
+    code: &[u8],
+    pc: usize,
+) -> core::result::Result<Option<usize>, C::Error> {
+    let next = match code[pc] {
+        RIGHT => {
+            core.move_right(read_u32(code, pc + 1));
+            pc + 5
+        }
+        LEFT => {
+            core.move_left(read_u32(code, pc + 1));
+            pc + 5
+        }
+        ADD => {
+            add(core, code[pc + 1]);
+            pc + 2
+        }
+        OUTPUT => {
+            let cell = *core.cell();
+            core.output(&[cell])?;
+            pc + 1
+        }
+        INPUT => {
+            core.input()?;
+            pc + 1
+        }
+        JUMP_ZERO if *core.cell() == 0 => read_u32(code, pc + 1),
+        JUMP_ZERO => {
+            core.enter_loop();
+            pc + 5
+        }
+        JUMP_NONZERO if *core.cell() != 0 => read_u32(code, pc + 1),
+        JUMP_NONZERO => {
+            core.leave_loop();
+            pc + 5
+        }
+        CLEAR => {
+            *core.cell() = 0;
+            pc + 1
+        }
+        SCAN_RIGHT => {
+            core.scan_right(read_u32(code, pc + 1))?;
+            pc + 5
+        }
+        SCAN_LEFT => {
+            core.scan_left(read_u32(code, pc + 1))?;
+            pc + 5
+        }
+        PRINT => {
+            let start = pc + 5;
+            let end = start + read_u32(code, pc + 1);
+            core.output(&code[start..end])?;
+            end
+        }
+        LOAD => {
+            let first = u64::from_le_bytes(code[pc + 1..pc + 9].try_into().unwrap()) as usize;
+            let start = pc + 13;
+            let end = start + read_u32(code, pc + 9);
+            core.load(first, &code[start..end]);
+            end
+        }
+        MUL_ADD => {
+            core.mul_add(read_targets(code, pc));
+            pc + 5 + read_u32(code, pc + 1) * 9
+        }
+        RIGHT_ADD => {
+            core.move_right(read_u32(code, pc + 1));
+            add(core, code[pc + 5]);
+            pc + 6
+        }
+        LEFT_ADD => {
+            core.move_left(read_u32(code, pc + 1));
+            add(core, code[pc + 5]);
+            pc + 6
+        }
+        ADD_OUTPUT => {
+            add(core, code[pc + 1]);
+            let cell = *core.cell();
+            core.output(&[cell])?;
+            pc + 2
+        }
+        LISTEN | ACCEPT | CONNECT | RECEIVE | SEND => {
+            core.network(code[pc])?;
+            pc + 1
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(next))
+}
+
+fn add(core: &mut impl Core, delta: u8) {
+    let cell = core.cell();
+    *cell = cell.wrapping_add(delta);
+}
+
+/// A compiled program for a [`Machine`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Code(Vec<u8>);
+
+impl Code {
+    /// The encoded instructions, the same bytecode the VM runs
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// A command as [`compile`] reads it, before fusing
+#[derive(Clone, Copy)]
+enum Token {
+    /// A run of `>` or `<`
+    Move(u8, usize),
+    /// A run of `+` or `-`, as the wrapping amount it adds
+    Add(u8),
+    Open(usize),
+    Close(usize),
+    /// Anything else, by opcode
+    Simple(u8),
+}
+
+/// Compile `source` for a [`Machine`], into the bytecode the VM would run
+/// for it unoptimized. Bracket errors give the byte position in `source`.
+pub fn compile(source: &[u8]) -> Result<Code> {
+    let tokens = tokenize(source);
+    let mut code = Vec::new();
+    // Address of each open `JUMP_ZERO`, and the position of its `[`
+    let mut open: Vec<(usize, usize)> = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        // A move then `+`/`-`, or `+`/`-` then `.`, fuse into one instruction
+        match (tokens[i], tokens.get(i + 1)) {
+            (Token::Move(opcode, n), Some(&Token::Add(delta))) if fits(n) => {
+                code.push(if opcode == RIGHT { RIGHT_ADD } else { LEFT_ADD });
+                push_u32(&mut code, n);
+                code.push(delta);
+                i += 2;
+                continue;
+            }
+            (Token::Add(delta), Some(&Token::Simple(OUTPUT))) => {
+                code.extend([ADD_OUTPUT, delta]);
+                i += 2;
+                continue;
+            }
+            _ => {}
+        }
+        match tokens[i] {
+            Token::Move(opcode, n) => push_move(&mut code, opcode, n),
+            Token::Add(delta) => code.extend([ADD, delta]),
+            Token::Open(position) => {
+                open.push((code.len(), position));
+                code.push(JUMP_ZERO);
+                push_u32(&mut code, 0);
+            }
+            Token::Close(position) => {
+                let (start, _) = open.pop().ok_or(Error::UnmatchedCloseBracket(position))?;
+                code.push(JUMP_NONZERO);
+                push_u32(&mut code, start + 5);
+                let after = code.len();
+                code[start + 1..start + 5].copy_from_slice(&address(after).to_le_bytes());
+            }
+            Token::Simple(opcode) => code.push(opcode),
+        }
+        i += 1;
+    }
+    match open.first() {
+        Some(&(_, position)) => Err(Error::UnmatchedOpenBracket(position)),
+        None => {
+            code.push(HALT);
+            Ok(Code(code))
+        }
+    }
+}
+
+/// The commands in `source`, with runs of the same one merged as the
+/// parser merges them: only while nothing else, not even a space, comes
+/// between
+fn tokenize(source: &[u8]) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    // The command of the run being read, where it started and its length
+    let mut run: Option<(u8, usize)> = None;
+    let mut line_start = true;
+    let mut i = 0;
+    while i < source.len() {
+        let byte = source[i];
+        match run {
+            Some((command, ref mut count)) if command == byte => {
+                *count += 1;
+                i += 1;
+                continue;
+            }
+            Some((command, count)) => tokens.push(merged(command, count)),
+            None => {}
+        }
+        run = None;
+        let comment = match byte {
+            b'#' => line_start && source.get(i + 1) == Some(&b'!'),
+            b';' => source.get(i + 1) == Some(&b';'),
+            _ => false,
+        };
+        if comment {
+            i = match source[i..].iter().position(|&byte| byte == b'\n') {
+                Some(newline) => i + newline,
+                None => source.len(),
+            };
+            continue;
+        }
+        line_start = byte == b'\n';
+        let token = match byte {
+            b'>' | b'<' | b'+' | b'-' => {
+                run = Some((byte, 1));
+                i += 1;
+                continue;
+            }
+            b'[' => Token::Open(i),
+            b']' => Token::Close(i),
+            b'.' => Token::Simple(OUTPUT),
+            b',' => Token::Simple(INPUT),
+            b'$' => Token::Simple(LISTEN),
+            b'@' => Token::Simple(ACCEPT),
+            b'%' => Token::Simple(CONNECT),
+            b'`' => Token::Simple(RECEIVE),
+            b'\'' => Token::Simple(SEND),
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        tokens.push(token);
+        i += 1;
+    }
+    if let Some((command, count)) = run {
+        tokens.push(merged(command, count));
+    }
+    tokens
+}
+
+/// The token for a run of `count` `command`s
+fn merged(command: u8, count: usize) -> Token {
+    let wrapped = (count % 256) as u8;
+    match command {
+        b'>' => Token::Move(RIGHT, count),
+        b'<' => Token::Move(LEFT, count),
+        b'+' => Token::Add(wrapped),
+        _ => Token::Add(wrapped.wrapping_neg()),
+    }
+}
+
+/// A tape and its pointer, running [`compile`]d code
+#[derive(Debug, Clone)]
+pub struct Machine {
+    pub tape: Vec<u8>,
+    pub pointer: usize,
+    /// Instructions to run at most, across every [`run`](Machine::run)
+    pub max_steps: Option<u64>,
+    /// Instructions run so far
+    pub steps: u64,
+}
+
+impl Machine {
+    /// A machine with a zeroed tape of `tape_size` cells (at least one)
+    pub fn new(tape_size: usize) -> Self {
+        Machine {
+            tape: vec![0; tape_size.max(1)],
+            pointer: 0,
+            max_steps: None,
+            steps: 0,
+        }
+    }
+
+    /// Run `code` from its start until it ends or fails. The tape and
+    /// pointer carry over from the last run.
+    pub fn run(
+        &mut self,
+        code: &Code,
+        input: &mut impl Input,
+        output: &mut impl Output,
+        network: &mut impl Network,
+    ) -> Result<()> {
+        let code = code.as_bytes();
+        let mut running = Running {
+            machine: self,
+            input,
+            output,
+            network,
+        };
+        let mut pc = 0;
+        while code[pc] != HALT {
+            running.machine.tick()?;
+            pc = step(&mut running, code, pc)?.expect("compiled code has only core instructions");
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        if let Some(limit) = self.max_steps {
+            if self.steps >= limit {
+                return Err(Error::StepLimitExceeded(limit));
+            }
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    /// A scan that never finds a zero cell loops forever, like the loop it
+    /// replaced, using up whatever steps are left
+    fn spin(&mut self) -> Result<()> {
+        loop {
+            self.tick()?;
+        }
+    }
+
+    /// The address and port in the six cells from the pointer
+    fn address(&self) -> ([u8; 4], u16) {
+        let len = self.tape.len();
+        let cell = |i| self.tape[wrap_right(self.pointer, i, len)];
+        (
+            [cell(0), cell(1), cell(2), cell(3)],
+            u16::from_be_bytes([cell(4), cell(5)]),
+        )
+    }
+}
+
+/// A [`Machine`] in the middle of a run, with the run's I/O
+struct Running<'a, I, O, N> {
+    machine: &'a mut Machine,
+    input: &'a mut I,
+    output: &'a mut O,
+    network: &'a mut N,
+}
+
+impl<I: Input, O: Output, N: Network> Core for Running<'_, I, O, N> {
+    type Error = Error;
+
+    fn cell(&mut self) -> &mut u8 {
+        &mut self.machine.tape[self.machine.pointer]
+    }
+
+    fn move_right(&mut self, n: usize) {
+        let machine = &mut *self.machine;
+        machine.pointer = wrap_right(machine.pointer, n, machine.tape.len());
+    }
+
+    fn move_left(&mut self, n: usize) {
+        let machine = &mut *self.machine;
+        machine.pointer = wrap_left(machine.pointer, n, machine.tape.len());
+    }
+
+    fn scan_right(&mut self, stride: usize) -> Result<()> {
+        let machine = &mut *self.machine;
+        let step = stride % machine.tape.len();
+        match scan(&machine.tape, machine.pointer, step) {
+            Some(index) => {
+                machine.pointer = index;
+                Ok(())
+            }
+            None => machine.spin(),
+        }
+    }
+
+    fn scan_left(&mut self, stride: usize) -> Result<()> {
+        let machine = &mut *self.machine;
+        let len = machine.tape.len();
+        match scan(&machine.tape, machine.pointer, len - stride % len) {
+            Some(index) => {
+                machine.pointer = index;
+                Ok(())
+            }
+            None => machine.spin(),
+        }
+    }
+
+    fn mul_add(&mut self, targets: impl Iterator<Item = (isize, u8)>) {
+        let machine = &mut *self.machine;
+        let value = machine.tape[machine.pointer];
+        for (offset, factor) in targets {
+            let target = wrap_offset(machine.pointer, offset, machine.tape.len());
+            machine.tape[target] = machine.tape[target].wrapping_add(value.wrapping_mul(factor));
+        }
+        machine.tape[machine.pointer] = 0;
+    }
+
+    fn load(&mut self, start: usize, cells: &[u8]) {
+        let len = self.machine.tape.len();
+        for (i, &value) in cells.iter().enumerate() {
+            self.machine.tape[(start + i) % len] = value;
+        }
+    }
+
+    fn output(&mut self, bytes: &[u8]) -> Result<()> {
+        bytes.iter().try_for_each(|&byte| self.output.write(byte))
+    }
+
+    fn input(&mut self) -> Result<()> {
+        *self.cell() = self.input.read()?.unwrap_or(0);
+        Ok(())
+    }
+
+    fn network(&mut self, opcode: u8) -> Result<()> {
+        match opcode {
+            LISTEN => {
+                let (address, port) = self.machine.address();
+                self.network.listen(address, port)
+            }
+            ACCEPT => self.network.accept(),
+            CONNECT => {
+                let (address, port) = self.machine.address();
+                self.network.connect(address, port)
+            }
+            RECEIVE => {
+                *self.cell() = self.network.receive()?.unwrap_or(0);
+                Ok(())
+            }
+            _ => {
+                let cell = *self.cell();
+                self.network.send(cell)
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Persistent key-value store (`--ext kv`)
+//!
+//! - `{` stores the zero-terminated value that follows the zero-terminated
+//!   key at the pointer; an empty value deletes the key
+//! - `}` looks up the zero-terminated key at the pointer and writes its
+//!   value, zero-terminated, right after the key's terminator (just the
+//!   terminator if the key is missing)
+//!
+//! The store is an append-only log of `key length, value length, key,
+//! value` records (lengths as little-endian u32) replayed on open, so a
+//! crash loses at most the record being written. Logs with mostly stale
+//! records are compacted when opened.
+
+use crate::interpreter::{Result, TrainfuckError, VM};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Records below which a log is never worth compacting
+const COMPACT_MIN_RECORDS: usize = 1024;
+
+/// A store shared by the VMs of one process
+pub type SharedKvStore = Arc<Mutex<KvStore>>;
+
+pub struct KvStore {
+    path: PathBuf,
+    entries: HashMap<Vec<u8>, Vec<u8>>,
+    log: File,
+}
+
+impl KvStore {
+    /// Open the store at `path`, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = HashMap::new();
+        let (mut at, mut records) = (0, 0);
+        while let Some((key, value, next)) = record(&bytes, at) {
+            match value.is_empty() {
+                true => entries.remove(key),
+                false => entries.insert(key.to_vec(), value.to_vec()),
+            };
+            at = next;
+            records += 1;
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        // Drop a record cut short by a crash, so appends start clean
+        log.set_len(at as u64)?;
+        let mut store = KvStore { path, entries, log };
+        if records >= COMPACT_MIN_RECORDS && records > 2 * store.entries.len() {
+            store.compact()?;
+        }
+        Ok(store)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(key).map(Vec::as_slice)
+    }
+
+    /// Store `value` under `key`, or delete `key` if `value` is empty
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.log.write_all(&encode(key, value))?;
+        self.log.flush()?;
+        match value.is_empty() {
+            true => self.entries.remove(key),
+            false => self.entries.insert(key.to_vec(), value.to_vec()),
+        };
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Rewrite the log with one record per live key
+    fn compact(&mut self) -> io::Result<()> {
+        let partial = self.path.with_extension("compacting");
+        let mut bytes = Vec::new();
+        for (key, value) in &self.entries {
+            bytes.extend(encode(key, value));
+        }
+        fs::write(&partial, bytes)?;
+        fs::rename(&partial, &self.path)?;
+        self.log = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// The record at `at` and the offset after it, if it is complete
+fn record(bytes: &[u8], at: usize) -> Option<(&[u8], &[u8], usize)> {
+    let len = |at: usize| -> Option<usize> {
+        Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().unwrap()) as usize)
+    };
+    let (key_len, value_len) = (len(at)?, len(at + 4)?);
+    let key = bytes.get(at + 8..at + 8 + key_len)?;
+    let end = at + 8 + key_len + value_len;
+    let value = bytes.get(at + 8 + key_len..end)?;
+    Some((key, value, end))
+}
+
+fn encode(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(8 + key.len() + value.len());
+    bytes.extend((key.len() as u32).to_le_bytes());
+    bytes.extend((value.len() as u32).to_le_bytes());
+    bytes.extend(key);
+    bytes.extend(value);
+    bytes
+}
+
+impl VM {
+    /// `{`: store the value after the key at the pointer
+    pub(crate) fn kv_put(&mut self) -> Result<()> {
+        self.check_sandbox('{')?;
+        let store = self.kv.clone().ok_or(TrainfuckError::NoKvStore)?;
+        let key = self.read_string(0);
+        let value = self.read_string(key.len() + 1);
+        store.lock().unwrap().put(&key, &value)?;
+        Ok(())
+    }
+
+    /// `}`: write the value of the key at the pointer after the key
+    pub(crate) fn kv_get(&mut self) -> Result<()> {
+        self.check_sandbox('}')?;
+        let store = self.kv.clone().ok_or(TrainfuckError::NoKvStore)?;
+        let key = self.read_string(0);
+        let store = store.lock().unwrap();
+        let value = store.get(&key).unwrap_or_default();
+        let len = self.tape.len();
+        let start = self.pointer + key.len() + 1;
+        for (i, &byte) in value.iter().chain(&[0]).enumerate() {
+            self.tape[(start + i) % len] = byte;
+        }
+        Ok(())
+    }
+
+    /// Bytes from `offset` cells past the pointer up to the next zero cell
+    fn read_string(&self, offset: usize) -> Vec<u8> {
+        let len = self.tape.len();
+        (offset..len)
+            .map(|i| self.tape[(self.pointer + i) % len])
+            .take_while(|&c| c != 0)
+            .collect()
+    }
+}
@@ -0,0 +1,94 @@
+//! Trainfuck - Brainfuck with networking extensions
+//!
+//! The interpreter is usable as a library: parse a [`program::Program`]
+//! with [`interpreter::parse`] and run it on an [`interpreter::VM`], whose
+//! streams can be set with [`VM::with_io`](interpreter::VM::with_io), or
+//! run a string against in-memory input with
+//! [`VM::run_str`](interpreter::VM::run_str).
+//!
+//! [`kernel`] is the part that needs no more than `alloc`; building with
+//! `default-features = false` leaves out everything else.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+// The cdylib needs a panic handler and allocator, which linking std brings
+// on targets that have it; bare-metal ones drop the cdylib instead. Linked
+// anonymously, so nothing here can name it and the kernel stays no_std.
+#[cfg(all(not(feature = "std"), not(target_os = "none")))]
+extern crate std as _;
+
+pub mod kernel;
+
+/// Declare modules that need the standard library
+macro_rules! with_std {
+    ($($item:item)*) => {
+        $(
+            #[cfg(feature = "std")]
+            $item
+        )*
+    };
+}
+
+with_std! {
+    pub mod access;
+    pub mod actors;
+    pub mod analyzer;
+    pub mod auth;
+    pub mod backpressure;
+    pub mod balance;
+    pub mod budget;
+    pub mod bytecode;
+    pub mod channels;
+    pub mod clock;
+    pub mod debug;
+    pub mod directives;
+    pub mod features;
+    pub mod flood;
+    pub mod handler;
+    pub mod health;
+    pub mod histogram;
+    pub mod history;
+    pub mod http;
+    pub mod idle;
+    pub mod interpreter;
+    pub mod kv;
+    pub mod mock_net;
+    pub mod net;
+    pub mod optimizer;
+    pub mod pipeline;
+    pub mod pool;
+    pub mod program;
+    pub mod reload;
+    pub mod scheduler;
+    pub mod shared;
+    pub mod span;
+    pub mod suggest;
+    pub mod tape;
+    pub mod tiered;
+    pub mod traffic;
+    pub mod trap;
+    pub mod virtual_net;
+    pub mod warnings;
+
+    #[cfg(unix)]
+    pub mod control;
+}
+
+// Each of these features turns on `std`
+
+#[cfg(feature = "capi")]
+pub mod capi;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(feature = "compress")]
+pub mod compress;
+
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+
+#[cfg(feature = "serial")]
+pub mod serial;
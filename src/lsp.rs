@@ -0,0 +1,328 @@
+//! Language server (`trainfuck lsp`)
+//!
+//! Speaks LSP over stdin/stdout and provides:
+//! - diagnostics for unmatched `[` / `]`
+//! - hover over a run of `+-<>` showing its net pointer and cell effect
+//! - document symbols for `#define NAME ...` macro lines
+//! - go-to-definition on a bracket jumps to its partner
+//!
+//! Documents are synchronized in full on every change; Trainfuck sources
+//! are small enough that incremental sync isn't worth the complexity.
+
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+
+/// Serve LSP requests on stdin/stdout until the client sends `exit`
+pub fn run() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut output = io::stdout();
+    let mut server = Server::default();
+
+    while let Some(message) = read_message(&mut input)? {
+        let method = message.get("method").and_then(Value::as_str);
+        let id = message.get("id").cloned();
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+
+        match (method, id) {
+            (Some("exit"), _) => break,
+            (Some(method), Some(id)) => {
+                let result = server.request(method, &params);
+                let response = match result {
+                    Some(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                    None => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": {"code": -32601, "message": format!("unsupported method {}", method)},
+                    }),
+                };
+                write_message(&mut output, &response)?;
+            }
+            (Some(method), None) => {
+                for notification in server.notify(method, &params) {
+                    write_message(&mut output, &notification)?;
+                }
+            }
+            // Responses to requests we never send
+            (None, _) => {}
+        }
+    }
+    Ok(())
+}
+
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+    let mut body = vec![0u8; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, Document>,
+}
+
+impl Server {
+    fn request(&mut self, method: &str, params: &Value) -> Option<Value> {
+        match method {
+            "initialize" => Some(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                    "definitionProvider": true,
+                },
+                "serverInfo": {"name": "trainfuck", "version": env!("CARGO_PKG_VERSION")},
+            })),
+            "shutdown" => Some(Value::Null),
+            "textDocument/hover" => {
+                let (doc, offset) = self.locate(params)?;
+                Some(doc.hover(offset).map_or(Value::Null, |(text, range)| {
+                    json!({
+                        "contents": {"kind": "markdown", "value": text},
+                        "range": doc.range(range.0, range.1),
+                    })
+                }))
+            }
+            "textDocument/definition" => {
+                let uri = params["textDocument"]["uri"].as_str()?;
+                let (doc, offset) = self.locate(params)?;
+                Some(doc.brackets.get(&offset).map_or(
+                    Value::Null,
+                    |&partner| json!({"uri": uri, "range": doc.range(partner, partner + 1)}),
+                ))
+            }
+            "textDocument/documentSymbol" => {
+                let uri = params["textDocument"]["uri"].as_str()?;
+                let doc = self.documents.get(uri)?;
+                Some(Value::Array(doc.symbols()))
+            }
+            _ => None,
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let document = &params["textDocument"];
+        let Some(uri) = document["uri"].as_str() else {
+            return Vec::new();
+        };
+
+        let text = match method {
+            "textDocument/didOpen" => document["text"].as_str(),
+            // Full sync: the last change holds the whole document
+            "textDocument/didChange" => params["contentChanges"]
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change["text"].as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return vec![publish_diagnostics(uri, Vec::new())];
+            }
+            _ => None,
+        };
+
+        match text {
+            Some(text) => {
+                let doc = Document::new(text);
+                let diagnostics = doc.diagnostics();
+                self.documents.insert(uri.to_string(), doc);
+                vec![publish_diagnostics(uri, diagnostics)]
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Find the document and character offset a position request refers to
+    fn locate(&self, params: &Value) -> Option<(&Document, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let doc = self.documents.get(uri)?;
+        let line = params["position"]["line"].as_u64()? as usize;
+        let character = params["position"]["character"].as_u64()? as usize;
+        Some((doc, doc.offset(line, character)?))
+    }
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": {"uri": uri, "diagnostics": diagnostics},
+    })
+}
+
+struct Document {
+    chars: Vec<char>,
+    /// Char offset of the first character of each line
+    line_starts: Vec<usize>,
+    /// Matched bracket pairs, both directions
+    brackets: HashMap<usize, usize>,
+    unmatched: Vec<usize>,
+}
+
+impl Document {
+    fn new(text: &str) -> Self {
+        let chars: Vec<char> = text.chars().collect();
+        let mut line_starts = vec![0];
+        let mut brackets = HashMap::new();
+        let mut unmatched = Vec::new();
+        let mut open = Vec::new();
+
+        for (i, &c) in chars.iter().enumerate() {
+            match c {
+                '\n' => line_starts.push(i + 1),
+                '[' => open.push(i),
+                ']' => match open.pop() {
+                    Some(start) => {
+                        brackets.insert(start, i);
+                        brackets.insert(i, start);
+                    }
+                    None => unmatched.push(i),
+                },
+                _ => {}
+            }
+        }
+        unmatched.extend(open);
+        unmatched.sort_unstable();
+
+        Document {
+            chars,
+            line_starts,
+            brackets,
+            unmatched,
+        }
+    }
+
+    /// Convert an LSP position (UTF-16 columns) into a char offset
+    fn offset(&self, line: usize, character: usize) -> Option<usize> {
+        let start = *self.line_starts.get(line)?;
+        let mut units = 0;
+        let mut offset = start;
+        while offset < self.chars.len() && self.chars[offset] != '\n' && units < character {
+            units += self.chars[offset].len_utf16();
+            offset += 1;
+        }
+        Some(offset)
+    }
+
+    fn position(&self, offset: usize) -> Value {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let start = self.line_starts[line];
+        let character: usize = self.chars[start..offset]
+            .iter()
+            .map(|c| c.len_utf16())
+            .sum();
+        json!({"line": line, "character": character})
+    }
+
+    fn range(&self, start: usize, end: usize) -> Value {
+        json!({"start": self.position(start), "end": self.position(end)})
+    }
+
+    fn diagnostics(&self) -> Vec<Value> {
+        self.unmatched
+            .iter()
+            .map(|&i| {
+                json!({
+                    "range": self.range(i, i + 1),
+                    "severity": 1,
+                    "source": "trainfuck",
+                    "message": format!("Unmatched '{}'", self.chars[i]),
+                })
+            })
+            .collect()
+    }
+
+    /// Describe the run of `+-<>` under the cursor
+    fn hover(&self, offset: usize) -> Option<(String, (usize, usize))> {
+        let is_arith = |c: &char| matches!(c, '+' | '-' | '<' | '>');
+        if !self.chars.get(offset).is_some_and(is_arith) {
+            return None;
+        }
+
+        let start = self.chars[..offset]
+            .iter()
+            .rposition(|c| !is_arith(c))
+            .map_or(0, |i| i + 1);
+        let end = self.chars[offset..]
+            .iter()
+            .position(|c| !is_arith(c))
+            .map_or(self.chars.len(), |i| offset + i);
+
+        let mut pointer: isize = 0;
+        let mut cells: BTreeMap<isize, i32> = BTreeMap::new();
+        for &c in &self.chars[start..end] {
+            match c {
+                '>' => pointer += 1,
+                '<' => pointer -= 1,
+                '+' => *cells.entry(pointer).or_default() += 1,
+                '-' => *cells.entry(pointer).or_default() -= 1,
+                _ => unreachable!(),
+            }
+        }
+
+        let mut text = format!("**{} ops** — pointer {:+}", end - start, pointer);
+        let changes: Vec<String> = cells
+            .iter()
+            .filter(|(_, &delta)| delta.rem_euclid(256) != 0)
+            .map(|(cell, delta)| format!("- `cell[p{:+}]` {:+}", cell, delta))
+            .collect();
+        if !changes.is_empty() {
+            text.push_str("\n\n");
+            text.push_str(&changes.join("\n"));
+        }
+        Some((text, (start, end)))
+    }
+
+    /// `#define NAME body` lines, reported as symbols
+    fn symbols(&self) -> Vec<Value> {
+        let mut symbols = Vec::new();
+        for (line, &start) in self.line_starts.iter().enumerate() {
+            let end = self
+                .line_starts
+                .get(line + 1)
+                .map_or(self.chars.len(), |&next| next - 1);
+            let text: String = self.chars[start..end].iter().collect();
+            let Some(rest) = text.trim_start().strip_prefix("#define") else {
+                continue;
+            };
+            let Some(name) = rest.split_whitespace().next() else {
+                continue;
+            };
+            let name_byte = text.find(name).unwrap_or(0);
+            let name_start = start + text[..name_byte].chars().count();
+            symbols.push(json!({
+                "name": name,
+                "kind": 12, // Function
+                "range": self.range(start, end),
+                "selectionRange": self.range(name_start, name_start + name.chars().count()),
+            }));
+        }
+        symbols
+    }
+}
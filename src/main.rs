@@ -1,61 +1,2398 @@
-mod interpreter;
+mod addr_map;
+mod animation;
+mod audio;
+mod bundle;
+mod cache;
+mod chat;
+mod conformance;
+mod daemon;
+mod equiv;
+mod highlight;
+mod http_server;
+mod irc;
+mod lsp;
+mod native;
+mod obfuscate;
+mod probes;
+mod rng;
+mod serve;
+mod supervise;
+mod tape_image;
+mod transport;
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::cell::RefCell;
+use std::ffi::OsString;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::iter;
+use std::net::{SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use trainfuck::access::{AccessList, Cidr};
+use trainfuck::bytecode::Bytecode;
+use trainfuck::clock::Clock;
+use trainfuck::directives::Directives;
+use trainfuck::flood::FloodLimits;
+use trainfuck::health::Health;
+use trainfuck::kv::{KvStore, SharedKvStore};
+use trainfuck::net::{NonBlockingTcpNetwork, TcpNetwork};
+use trainfuck::pipeline::{self, Pipeline};
+use trainfuck::reload::Reload;
+use trainfuck::scheduler::Scheduler;
+use trainfuck::shared::SharedSegment;
+use trainfuck::span::{SourceFiles, SourceMap};
+use trainfuck::suggest;
+use trainfuck::traffic::{LoggingNetwork, ReplayNetwork, TrafficLog};
+use trainfuck::warnings;
+use trainfuck::{analyzer, interpreter, optimizer, tape, tiered};
 
 #[derive(Parser, Debug)]
 #[command(name = "trainfuck")]
 #[command(author = "Hitesh")]
 #[command(version = "0.1.0")]
 #[command(about = "Trainfuck interpreter - Brainfuck with networking extensions")]
-struct Args {
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    script: Script,
+}
+
+/// `trainfuck FILE [ARGS]...`, as run by a `#!` line
+#[derive(Args, Debug)]
+struct Script {
     /// The Trainfuck source file to execute
     #[arg(required = true)]
-    file: PathBuf,
+    file: Option<PathBuf>,
+
+    /// Arguments for the program, read with `,` before standard input,
+    /// each followed by a NUL byte. Everything after the first one goes to
+    /// the program too; put `--` before them if that one starts with `-`.
+    #[arg(trailing_var_arg = true)]
+    program_args: Vec<OsString>,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run several source files as one program, concatenated in order
+    Run {
+        /// Source files, e.g. libraries before the main program
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Arguments for the program, after `--`; see the top-level command
+        #[arg(last = true)]
+        program_args: Vec<OsString>,
+
+        #[command(flatten)]
+        run: Box<RunArgs>,
+    },
+
+    /// Compile a program to a standalone executable
+    Build {
+        /// Source files, concatenated in order as `run` does
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Transpile to Rust and compile that with rustc (the only kind of
+        /// build there is so far)
+        #[arg(long, required = true)]
+        native: bool,
+
+        /// Executable to write [default: the first file without its
+        /// extension]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Tape size of the executable (e.g. 30000, 64K, 1G) [default: the
+        /// program's `#! tape-size`, or 30000]
+        #[arg(long, value_parser = tape::parse_size)]
+        tape_size: Option<usize>,
+
+        /// Optimization level, as for running
+        #[arg(short = 'O', long = "opt-level", default_value_t = 1,
+              value_parser = clap::value_parser!(u8).range(0..=3))]
+        opt_level: u8,
+    },
+
+    /// Make a copy of this executable that runs a program
+    Bundle {
+        /// Source files, concatenated in order as `run` does
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Executable to write [default: the first file without its
+        /// extension]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Options to run the program with, after `--`, e.g. `-- --ext trap`
+        #[arg(last = true)]
+        options: Vec<String>,
+    },
+
+    /// Stop a daemonized interpreter
+    Stop {
+        /// Pidfile written by `--daemon`
+        pidfile: PathBuf,
+    },
+
+    /// Run submitted programs over HTTP in a sandboxed VM
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+
+        /// Maximum operations a program may execute
+        #[arg(long, default_value_t = 10_000_000)]
+        max_steps: u64,
+
+        /// Wall-clock limit per program, in milliseconds
+        #[arg(long, default_value_t = 5_000)]
+        timeout_ms: u64,
+
+        /// Most bytes a program may print
+        #[arg(long, default_value_t = 1 << 20)]
+        max_output_bytes: u64,
+
+        /// Tape size given to each program (e.g. 30000, 64K, 16M)
+        #[arg(long, default_value = "30000", value_parser = tape::parse_size)]
+        tape_size: usize,
+
+        /// Let programs use the networking ops
+        #[arg(long)]
+        allow_net: bool,
+
+        /// Largest accepted request body, in bytes
+        #[arg(long, default_value_t = 1 << 20)]
+        max_request_bytes: usize,
+
+        /// Number of programs that can run concurrently
+        #[arg(long, default_value_t = 4)]
+        workers: usize,
+
+        /// Reject programs with loops nested deeper than this
+        #[arg(long, default_value_t = interpreter::DEFAULT_MAX_LOOP_DEPTH)]
+        max_loop_depth: usize,
+    },
+
+    /// Run a language server on stdin/stdout
+    Lsp,
+
+    /// Print a program with syntax highlighting
+    Highlight {
+        /// The Trainfuck source file to render
+        file: PathBuf,
+
+        /// Output format
+        #[arg(long, value_enum, default_value = "ansi")]
+        format: highlight::Format,
+
+        /// Execution counts to annotate with, as `--profile` writes them: a
+        /// JSON object mapping byte offsets in the file to counts
+        #[arg(long)]
+        counts: Option<PathBuf>,
+    },
+
+    /// Report warnings about a program without running it
+    Check {
+        /// Source files, checked as one program
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Tape size the program is meant to run with [default: the
+        /// program's `#! tape-size`, or 30000]
+        #[arg(long, value_parser = tape::parse_size)]
+        tape_size: Option<usize>,
+
+        /// Enable an extension's commands (repeatable)
+        #[arg(long = "ext", value_enum, value_name = "EXT")]
+        extensions: Vec<Extension>,
+
+        #[command(flatten)]
+        lints: LintArgs,
+    },
+
+    /// Report likely bugs found by static analysis
+    Analyze {
+        /// The Trainfuck source file to check
+        file: PathBuf,
+
+        /// Tape size the program is meant to run with [default: the
+        /// program's `#! tape-size`, or 30000]
+        #[arg(long, value_parser = tape::parse_size)]
+        tape_size: Option<usize>,
+    },
+
+    /// Run the bundled conformance programs and report which Brainfuck
+    /// semantics each configuration implements
+    Conformance {
+        /// Only this optimization level [default: each of 0 to 3]
+        #[arg(short = 'O', long = "opt-level",
+              value_parser = clap::value_parser!(u8).range(0..=3))]
+        opt_level: Option<u8>,
+
+        /// Only this tape model [default: both]
+        #[arg(long, value_enum)]
+        tape_model: Option<TapeModel>,
+
+        /// Number of tape cells
+        #[arg(long, default_value = "30000", value_parser = tape::parse_size)]
+        tape_size: usize,
+
+        /// Operations a program may execute before it is considered non-terminating
+        #[arg(long, default_value_t = 10_000_000)]
+        max_steps: u64,
+    },
+
+    /// Check that two programs print the same output for the same input
+    Equiv {
+        /// The original program
+        a: PathBuf,
+
+        /// The program that should behave like it
+        b: PathBuf,
+
+        /// Directory whose files are each run as one input
+        #[arg(long, value_name = "DIR")]
+        inputs: Option<PathBuf>,
+
+        /// Random inputs to try in addition to the corpus
+        #[arg(long, default_value_t = 256)]
+        fuzz: usize,
+
+        /// Seed for the random inputs
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Operations a run may execute before it is considered non-terminating
+        #[arg(long, default_value_t = 10_000_000)]
+        max_steps: u64,
+
+        /// Tape size both programs run with [default: each program's
+        /// `#! tape-size`, or 30000]
+        #[arg(long, value_parser = tape::parse_size)]
+        tape_size: Option<usize>,
+    },
+
+    /// Run several programs on one thread, switching at `|` and blocking ops
+    Schedule {
+        /// The Trainfuck source files to run
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Tape size given to each program [default: its `#! tape-size`,
+        /// or 30000]
+        #[arg(long, value_parser = tape::parse_size)]
+        tape_size: Option<usize>,
+
+        /// Enable more extensions' commands (`yield` is always on)
+        #[arg(long = "ext", value_enum, value_name = "EXT")]
+        extensions: Vec<Extension>,
+
+        /// Store file every program's kv commands share, enabled by `--ext
+        /// kv` or its `#! ext kv`
+        #[arg(long, default_value = "trainfuck.kv", value_name = "PATH")]
+        kv_store: PathBuf,
+
+        /// Tape cell each program's networking ops write their outcome to,
+        /// as with `run --status-cell`
+        #[arg(long, value_name = "INDEX")]
+        status_cell: Option<usize>,
+    },
+
+    /// Run the programs a TOML config lists, restarting them as it says
+    Supervise {
+        /// Config with a `[[program]]` table for each program
+        config: PathBuf,
+    },
+
+    /// Run a program as an IRC bot in one channel
+    IrcBridge {
+        /// The Trainfuck source file to run
+        file: PathBuf,
+
+        /// IRC server as host or host:port
+        #[arg(long)]
+        server: String,
+
+        /// Channel to join, e.g. #trainfuck
+        #[arg(long)]
+        channel: String,
+
+        /// Nickname to register; `_` is appended while it is taken
+        #[arg(long, default_value = "trainfuck")]
+        nick: String,
 
+        /// Tape size given to the program [default: its `#! tape-size`, or
+        /// 30000]
+        #[arg(long, value_parser = tape::parse_size)]
+        tape_size: Option<usize>,
+
+        /// Enable an extension's commands (repeatable)
+        #[arg(long = "ext", value_enum, value_name = "EXT")]
+        extensions: Vec<Extension>,
+
+        /// File `--ext kv` keeps its keys in, created if missing
+        #[arg(long, default_value = "trainfuck.kv", value_name = "PATH")]
+        kv_store: PathBuf,
+    },
+
+    /// Run a program as a multi-user chat server: the runtime handles
+    /// nicknames and `/who`, the program relays messages
+    ChatServer {
+        /// The Trainfuck source file to run
+        file: PathBuf,
+
+        /// Fill the 17 cells from INDEX with the zero-terminated name of
+        /// the room each received byte was said in
+        #[arg(long, value_name = "INDEX")]
+        room_cell: Option<usize>,
+
+        /// Append relayed lines to FILE and replay them from it
+        #[arg(long, value_name = "FILE")]
+        history: Option<PathBuf>,
+
+        /// Lines of a room's history shown to users arriving in it
+        #[arg(long, default_value = "50", value_name = "N", requires = "history")]
+        history_replay: usize,
+
+        /// Tape size given to the program [default: its `#! tape-size`, or
+        /// 30000]
+        #[arg(long, value_parser = tape::parse_size)]
+        tape_size: Option<usize>,
+
+        /// Enable an extension's commands (repeatable)
+        #[arg(long = "ext", value_enum, value_name = "EXT")]
+        extensions: Vec<Extension>,
+
+        /// File `--ext kv` keeps its keys in, created if missing
+        #[arg(long, default_value = "trainfuck.kv", value_name = "PATH")]
+        kv_store: PathBuf,
+    },
+
+    /// Print an equivalent but unreadable version of a program
+    Obfuscate {
+        /// The Trainfuck source file to rewrite
+        file: PathBuf,
+
+        /// Seed for the rewrite [default: random, printed to stderr]
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+}
+
+/// Opt-in language extensions for `--ext`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Extension {
+    /// `~` compare-and-swap on a memory segment shared between VMs
+    Shared,
+    /// `&`, `^` and `?`: child VMs exchanging bytes through mailboxes
+    Actors,
+    /// `|`: hand the thread to the next program under `schedule`
+    Yield,
+    /// `{` and `}`: store and look up byte strings in `--kv-store`
+    Kv,
+    /// `*`: HTTP GET or POST to a URL on the tape, reading back the body
+    Http,
+    /// `#`: CRC-32 of the counted cells before the pointer
+    Checksum,
+    /// `:` and `;`: base64-encode and decode cells after the pointer in place
+    Base64,
+    /// `!`: XOR counted cells after the pointer with the key that follows
+    Xor,
+    /// `=` and `\`: peek at the next received byte, push a byte back
+    Peek,
+    /// `"`: receive a line into the cells after the pointer
+    Line,
+    /// `/`: resolve the hostname at the pointer to an IPv4 address
+    Dns,
+    /// `(` and `)`: several connections on numbered channels
+    Channels,
+    /// `_`: `'` keeps what a non-blocking connection can't take yet, `_`
+    /// sends it
+    Backpressure,
+    /// `'` and `` ` ``: send and receive counted blocks of cells at once
+    Blocks,
+    /// `!`: call a host function (for embedders; the CLI installs none)
+    Trap,
+    /// `?`: write the interpreter version and available features to the
+    /// tape (takes `?` over from `actors`)
+    Features,
+}
+
+/// `--dialect`: which characters are commands
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Dialect {
+    /// Trainfuck: everything that isn't a command is a comment
+    Trainfuck,
+    /// Brainfuck's eight commands and whitespace, and nothing else
+    StrictBf,
+}
+
+/// A lint for `-W`, `-A` and `-D`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Lint {
+    /// 256 or more `+` or `-` in a row, which wrap around to fewer
+    WrappingRun,
+    /// Code after a loop that never terminates, and a loop right after
+    /// another loop, which is never entered
+    Unreachable,
+    /// `,` reading on after the input has ended (found while running)
+    InputAfterEof,
+}
+
+/// Which lints warn, which are errors and which are quiet
+#[derive(Args, Debug, Clone)]
+struct LintArgs {
+    /// Warn about LINT, as all lints do by default (repeatable). A lint
+    /// named by several of -W, -A and -D takes the strictest
+    #[arg(short = 'W', long = "warn", value_enum, value_name = "LINT")]
+    warn: Vec<Lint>,
+
+    /// Don't report LINT (repeatable)
+    #[arg(short = 'A', long = "allow", value_enum, value_name = "LINT")]
+    allow: Vec<Lint>,
+
+    /// Report LINT as an error, which stops the program from running
+    /// (repeatable)
+    #[arg(short = 'D', long = "deny", value_enum, value_name = "LINT")]
+    deny: Vec<Lint>,
+}
+
+impl LintArgs {
+    fn levels(&self) -> warnings::Levels {
+        let mut levels = warnings::Levels::default();
+        let flags = [
+            (&self.allow, warnings::Level::Allow),
+            (&self.warn, warnings::Level::Warn),
+            (&self.deny, warnings::Level::Deny),
+        ];
+        for (lints, level) in flags {
+            for &lint in lints {
+                let lint = match lint {
+                    Lint::WrappingRun => warnings::Lint::WrappingRun,
+                    Lint::Unreachable => warnings::Lint::Unreachable,
+                    Lint::InputAfterEof => warnings::Lint::InputAfterEof,
+                };
+                levels.set(lint, level);
+            }
+        }
+        levels
+    }
+}
+
+/// `--tape-model`: what moving past an end of the tape does
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum TapeModel {
+    /// A fixed tape that wraps around at both ends
+    Classic,
+    /// A tape that grows when the pointer moves past either end
+    Unbounded,
+}
+
+/// `--on-sigpipe`: what writing to a closed stdout does
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OnSigpipe {
+    /// Stop without a message, with the exit status of a process killed by
+    /// SIGPIPE (141)
+    ExitQuietly,
+    /// Stop with a runtime error
+    Error,
+}
+
+/// `--reload-on`: what makes a running program reload its source
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ReloadOn {
+    /// The SIGHUP signal (`kill -HUP PID`)
+    Sighup,
+    /// Saving the source file
+    Change,
+}
+
+/// `--net-errors`: what a connection error in `` ` `` or `'` does
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum NetErrors {
+    /// Carry on and write the error to the `--status-cell`
+    Status,
+    /// Stop the program
+    Abort,
+}
+
+#[derive(Args, Debug)]
+#[command(group(clap::ArgGroup::new("debugger").args(["control", "debug_script"]).multiple(true)))]
+struct RunArgs {
     /// Enable debug mode (prints tape state)
     #[arg(short, long)]
     debug: bool,
+
+    /// Run untrusted code: no networking (except --replay-traffic), no
+    /// `--ext kv` or `--ext actors`, and limits of 10M steps, 5s, a 16M-cell
+    /// tape and 1MiB of output
+    #[arg(long, conflicts_with_all = ["http_server", "control"])]
+    sandbox: bool,
+
+    /// Make runs repeatable for the same input: time is virtual (each op
+    /// takes 1µs and waits take none), networking stays on `--transport
+    /// virtual`, and `--ext http` and `--ext actors` are ruled out
+    #[arg(long, conflicts_with_all = ["http_server", "idle_timeout", "flood_messages", "flood_bytes"])]
+    deterministic: bool,
+
+    /// Make `,` return 0 immediately when no input byte is available
+    #[arg(long)]
+    nonblocking_input: bool,
+
+    /// What happens when stdout is a pipe whose reader has gone (as in
+    /// `trainfuck prog.tf | head`)
+    #[arg(long, value_enum, default_value = "exit-quietly")]
+    on_sigpipe: OnSigpipe,
+
+    /// Dump the tape to stderr when interrupted by Ctrl-C/SIGTERM
+    #[arg(long)]
+    dump_on_interrupt: bool,
+
+    /// Run in the background, detached from the terminal
+    #[arg(long)]
+    daemon: bool,
+
+    /// Where `--daemon` records its pid
+    #[arg(long, default_value = "trainfuck.pid", requires = "daemon")]
+    pidfile: PathBuf,
+
+    /// Where `--daemon` sends stdout and stderr
+    #[arg(long, default_value = "trainfuck.log", requires = "daemon")]
+    log_file: PathBuf,
+
+    /// Re-parse the source and swap it in at the end of the next main loop
+    /// iteration, keeping the tape, listener and connections
+    #[arg(long, value_enum, ignore_case = true,
+          conflicts_with_all = ["http_server", "histogram", "profile"])]
+    reload_on: Option<ReloadOn>,
+
+    /// Run the program up to its first `@` outside a loop once, then the
+    /// rest once per accepted connection, each time with the tape from
+    /// that `@`'s cell on as it was the first time
+    #[arg(long, conflicts_with_all = ["reload_on", "http_server"])]
+    handler: bool,
+
+    /// Serve `/healthz` (running and making progress) and `/readyz`
+    /// (listening) on this address, e.g. 127.0.0.1:9090
+    #[arg(long, value_name = "ADDR", conflicts_with = "http_server")]
+    health_addr: Option<String>,
+
+    /// How long steps may stand still, outside of waiting on I/O, before
+    /// `/healthz` fails
+    #[arg(long, default_value = "10s", requires = "health_addr", value_parser = parse_duration)]
+    health_stall: Duration,
+
+    /// Number of tape cells (e.g. 30000, 64K, 1G); large tapes only commit
+    /// touched pages. Defaults to the program's `#! tape-size`, or 30000
+    #[arg(long, value_parser = tape::parse_size)]
+    tape_size: Option<usize>,
+
+    /// What moving past an end of the tape does: `classic` wraps around,
+    /// `unbounded` adds cells there (--tape-size is then just the start)
+    #[arg(long, value_enum, default_value = "classic")]
+    tape_model: TapeModel,
+
+    /// Stop with an error when the program changes a cell in START..END
+    /// (end excluded); with `:no-access`, also when the pointer stops there.
+    /// Repeatable
+    #[arg(long, value_name = "START..END[:no-access]", value_parser = parse_guard)]
+    protect: Vec<interpreter::Guard>,
+
+    /// Unix socket accepting JSON-RPC commands (pause, step, read_tape, ...)
+    #[arg(long, value_name = "SOCKET")]
+    control: Option<PathBuf>,
+
+    /// Run the debugger commands in FILE (`break 12:4 if cell(3) > 10`,
+    /// `continue`, `print cell(3)`, ...), starting before the first
+    /// instruction; the program runs on once they are used up
+    #[arg(long, value_name = "FILE")]
+    debug_script: Option<PathBuf>,
+
+    /// Remember what the last N instructions did, so the control socket can
+    /// show earlier tape states and which instructions wrote a cell
+    #[arg(long, value_name = "N", requires = "debugger")]
+    record_history: Option<usize>,
+
+    /// Run at most N operations per second, to watch the program work
+    /// (with -O0, every instruction is one operation)
+    #[arg(long, value_name = "N", conflicts_with = "step_delay",
+          value_parser = clap::value_parser!(u32).range(1..))]
+    speed: Option<u32>,
+
+    /// Spend at least this long on each operation, e.g. 50ms
+    #[arg(long, value_parser = parse_duration)]
+    step_delay: Option<Duration>,
+
+    /// Write an animation of the tape over the run to FILE (.gif or .svg)
+    #[arg(long, value_name = "FILE")]
+    render_animation: Option<PathBuf>,
+
+    /// Steps between the animation's frames
+    #[arg(long, value_name = "N", default_value_t = 1000, requires = "render_animation",
+          value_parser = clap::value_parser!(u64).range(1..))]
+    frame_interval: u64,
+
+    /// Write the tape to FILE (.pgm or .ppm) as grayscale pixels, one per
+    /// cell, when the program stops
+    #[arg(long, value_name = "FILE")]
+    render_tape: Option<PathBuf>,
+
+    /// Cells per row of the --render-tape image
+    #[arg(long, default_value_t = 256, requires = "render_tape",
+          value_parser = clap::value_parser!(u64).range(1..))]
+    width: u64,
+
+    /// Also rewrite the --render-tape image every N steps, to watch it form
+    #[arg(long, value_name = "N", requires = "render_tape",
+          value_parser = clap::value_parser!(u64).range(1..))]
+    render_tape_every: Option<u64>,
+
+    /// Count the instructions run, instruction pairs, command characters
+    /// and loop iterations, and write them to FILE (.csv or .json)
+    #[arg(long, value_name = "FILE")]
+    histogram: Option<PathBuf>,
+
+    /// Count how often each command in the source ran and write the counts
+    /// to FILE as a JSON object keyed by byte offset, for `highlight
+    /// --counts`
+    #[arg(long, value_name = "FILE")]
+    profile: Option<PathBuf>,
+
+    /// Profile the run, then print source rewrites that would speed up its
+    /// hot loops to stderr
+    #[arg(long, conflicts_with_all = ["reload_on", "http_server"])]
+    suggest: bool,
+
+    /// Play what `.` outputs as notes (MIDI note numbers, 0 for a rest)
+    /// into a WAV file, or `-` to stream it to stdout
+    #[arg(long, value_name = "FILE")]
+    audio: Option<PathBuf>,
+
+    /// How long each --audio note lasts
+    #[arg(long, default_value = "150ms", requires = "audio", value_parser = parse_duration)]
+    note_length: Duration,
+
+    /// Optimization level: 0 runs loops as written, 1 merges `+-`/`<>` runs
+    /// and fuses clear/scan/copy loops, 2 also runs the input-free start of
+    /// the program at compile time and removes or unrolls loops whose
+    /// counter is known, 3 does so with bigger budgets
+    #[arg(short = 'O', long = "opt-level", default_value_t = 1,
+          value_parser = clap::value_parser!(u8).range(0..=3))]
+    opt_level: u8,
+
+    /// Run exactly these optimization passes, in this order, instead of
+    /// those of the -O level
+    #[arg(long, value_delimiter = ',', conflicts_with = "opt_level",
+          value_parser = clap::builder::PossibleValuesParser::new(pipeline::BUILTIN))]
+    passes: Option<Vec<String>>,
+
+    /// Print what each optimization pass changed, and where, to stderr
+    #[arg(long)]
+    opt_report: bool,
+
+    /// Loop iterations after which a loop is compiled instead of interpreted
+    #[arg(long, default_value_t = tiered::DEFAULT_THRESHOLD)]
+    hot_loop_threshold: u32,
+
+    /// Where compiled bytecode is cached [default: ~/.cache/trainfuck]
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Always compile from source and don't write the cache
+    #[arg(long, conflicts_with = "cache_dir")]
+    no_cache: bool,
+
+    /// Reject programs with loops nested deeper than this
+    #[arg(long, default_value_t = interpreter::DEFAULT_MAX_LOOP_DEPTH)]
+    max_loop_depth: usize,
+
+    /// Enable an extension's commands (repeatable)
+    #[arg(long = "ext", value_enum, value_name = "EXT")]
+    extensions: Vec<Extension>,
+
+    /// Which characters are commands; `strict-bf` makes any character but
+    /// Brainfuck's eight commands and whitespace a parse error, to check a
+    /// file is plain Brainfuck before sharing it
+    #[arg(
+        long,
+        value_enum,
+        default_value = "trainfuck",
+        conflicts_with = "extensions"
+    )]
+    dialect: Dialect,
+
+    #[command(flatten)]
+    lints: LintArgs,
+
+    /// File `--ext kv` keeps its keys in, created if missing
+    #[arg(long, default_value = "trainfuck.kv", value_name = "PATH")]
+    kv_store: PathBuf,
+
+    /// Record every byte sent and received, per connection, to this file
+    #[arg(long, value_name = "FILE")]
+    log_traffic: Option<PathBuf>,
+
+    /// Serve the connections recorded by `--log-traffic` instead of real ones
+    #[arg(long, value_name = "FILE")]
+    replay_traffic: Option<PathBuf>,
+
+    /// Replay received bytes at once instead of at their logged times
+    #[arg(long, requires = "replay_traffic")]
+    fast: bool,
+
+    /// What the networking ops talk to: tcp, mqtt (with --topic),
+    /// serial:PATH:BAUD or virtual (an in-process network)
+    #[arg(long, default_value = "tcp", value_parser = clap::value_parser!(transport::Transport))]
+    transport: transport::Transport,
+
+    /// MQTT broker for `--transport mqtt`, as host or host:port
+    #[arg(long, default_value = "localhost:1883")]
+    broker: String,
+
+    /// MQTT topics for `--transport mqtt`: `` ` `` reads IN, `'` publishes to OUT
+    #[arg(long, value_name = "IN,OUT", value_parser = transport::parse_topics)]
+    topic: Option<(String, String)>,
+
+    /// Compress every connection's byte stream; the peer must use the same
+    #[arg(long, value_enum, value_name = "CODEC")]
+    compress: Option<transport::Compression>,
+
+    /// Require accepted connections to start with this token (stripped
+    /// before the program sees them); `%` sends it on connect
+    #[arg(long, value_name = "TOKEN", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    auth_token: Option<String>,
+
+    /// Most lines per second each accepted client may send
+    #[arg(long, value_name = "N")]
+    flood_messages: Option<u32>,
+
+    /// Most bytes per second each accepted client may send
+    #[arg(long, value_name = "N")]
+    flood_bytes: Option<u32>,
+
+    /// What happens to data over the flood limits
+    #[arg(long, value_enum, default_value = "drop")]
+    flood_action: transport::FloodAction,
+
+    /// Retry a refused or timed-out `%` this many times before failing
+    #[arg(long, default_value = "0", value_name = "N")]
+    connect_retries: u32,
+
+    /// Wait before the first connect retry, doubled for each further one
+    #[arg(long, default_value = "500ms", value_parser = parse_duration)]
+    connect_backoff: Duration,
+
+    /// When a connection opened by `%` drops during `` ` `` or `'`, dial the
+    /// same address again and carry on (see --status-cell)
+    #[arg(long)]
+    reconnect: bool,
+
+    /// Tape cell the networking ops write their outcome to: 0 for success,
+    /// 1 after reconnecting, 2 when `@` timed out, 3 when the peer closed
+    /// the connection, 4 when it was reset, 5 for other errors, 6 over
+    /// flood limits, 7 after an idle timeout, 8 when bytes wait to be sent
+    #[arg(long, value_name = "INDEX")]
+    status_cell: Option<usize>,
+
+    /// First of 11 tape cells `@` fills with the accepted connection's
+    /// channel, peer IPv4 address and port, and Unix time, big-endian
+    #[arg(long, value_name = "INDEX")]
+    peer_cell: Option<usize>,
+
+    /// Longest `@` waits for a connection before carrying on without one;
+    /// 0ms just checks for one already waiting
+    #[arg(long, value_parser = parse_duration)]
+    accept_timeout: Option<Duration>,
+
+    /// Make `$` listen on this address instead of the one on the tape,
+    /// e.g. [::]:7000 (repeatable: `$` listens on all of them)
+    #[arg(long, visible_alias = "listen", value_name = "ADDR")]
+    bind: Vec<SocketAddr>,
+
+    /// Make `%` dial this host instead of the address on the tape, e.g.
+    /// chat.example.org:7000
+    #[arg(long, value_name = "HOST:PORT", value_parser = parse_peer)]
+    connect: Option<SocketAddrV4>,
+
+    /// TOML file naming addresses by number, e.g. `1 = "host:7000"`: `$`
+    /// and `%` use the one the current cell names instead of reading an
+    /// address and port from the tape
+    #[arg(long, value_name = "FILE")]
+    addr_map: Option<PathBuf>,
+
+    /// On Ctrl-C/SIGTERM, stop accepting but give the open connection this
+    /// long to finish before closing it (e.g. 30s)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    drain_timeout: Option<Duration>,
+
+    /// Only accept connections from this address range, e.g.
+    /// 10.0.0.0/8 (repeatable)
+    #[arg(long, value_name = "CIDR", value_parser = clap::value_parser!(Cidr))]
+    allow_from: Vec<Cidr>,
+
+    /// Refuse connections from this address range, even if allowed
+    /// (repeatable)
+    #[arg(long, value_name = "CIDR", value_parser = clap::value_parser!(Cidr))]
+    deny_from: Vec<Cidr>,
+
+    /// Close connections the peer has sent nothing on for this long
+    /// (e.g. 300s)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    idle_timeout: Option<Duration>,
+
+    /// Byte to ping an idle connection with before closing it, which then
+    /// gets another --idle-timeout to send anything back
+    #[arg(long, value_name = "BYTE", requires = "idle_timeout")]
+    keepalive: Option<u8>,
+
+    /// What a connection error in `` ` `` or `'` does; by default `'` stops
+    /// the program while `` ` `` reads 0
+    #[arg(long, value_enum)]
+    net_errors: Option<NetErrors>,
+
+    /// Answer HTTP requests on this address by running the program once per
+    /// request, with the path and body on the tape and the output as response
+    #[arg(long, value_name = "ADDR", conflicts_with_all = ["control", "replay_traffic", "nonblocking_input"])]
+    http_server: Option<String>,
+}
+
+impl RunArgs {
+    fn extensions(&self) -> interpreter::Extensions {
+        extensions(&self.extensions)
+    }
+
+    fn tape_size(&self) -> usize {
+        self.tape_size.unwrap_or(interpreter::TAPE_SIZE)
+    }
+
+    /// Take the tape size and extensions the program's `#!` lines ask for,
+    /// failing if they contradict `--tape-size`
+    fn apply_directives(&mut self, directives: &Directives) -> Result<(), String> {
+        if let Some(size) = directives.tape_size {
+            match self.tape_size {
+                Some(given) if given != size => {
+                    return Err(format!(
+                        "the program's `#! tape-size {}` conflicts with --tape-size {}",
+                        size, given
+                    ))
+                }
+                _ => self.tape_size = Some(size),
+            }
+        }
+        for name in &directives.extensions {
+            let extension = <Extension as clap::ValueEnum>::from_str(name, false)
+                .expect("directives check the names");
+            if !self.extensions.contains(&extension) {
+                self.extensions.push(extension);
+            }
+        }
+        Ok(())
+    }
+
+    fn dialect(&self) -> interpreter::Dialect {
+        match self.dialect {
+            Dialect::Trainfuck => interpreter::Dialect::Trainfuck,
+            Dialect::StrictBf => interpreter::Dialect::StrictBf,
+        }
+    }
+
+    /// The optimizer passes `--passes` or the `-O` level ask for, less
+    /// those that don't hold with the other settings
+    fn pipeline(&self) -> Pipeline {
+        let mut pipeline = match self.passes {
+            Some(ref names) => {
+                let mut pipeline = Pipeline::new();
+                for name in names {
+                    let pass = Pipeline::builtin(name, self.tape_size());
+                    pipeline.push(pass.expect("clap checks the pass names"));
+                }
+                pipeline
+            }
+            None => Pipeline::level(self.opt_level, self.tape_size()),
+        };
+        // Both -O2 passes assume a tape that wraps at --tape-size
+        if self.tape_model == TapeModel::Unbounded {
+            pipeline.retain(|name| name != "evaluate" && name != "fold");
+        }
+        // Folding can't see the networking ops writing the status cell
+        if self.status_cell.is_some() {
+            pipeline.retain(|name| name != "fold");
+        }
+        pipeline
+    }
+}
+
+fn extensions(enabled: &[Extension]) -> interpreter::Extensions {
+    interpreter::Extensions {
+        shared: enabled.contains(&Extension::Shared),
+        actors: enabled.contains(&Extension::Actors),
+        yielding: enabled.contains(&Extension::Yield),
+        kv: enabled.contains(&Extension::Kv),
+        http: enabled.contains(&Extension::Http),
+        checksum: enabled.contains(&Extension::Checksum),
+        base64: enabled.contains(&Extension::Base64),
+        xor: enabled.contains(&Extension::Xor),
+        peek: enabled.contains(&Extension::Peek),
+        line: enabled.contains(&Extension::Line),
+        dns: enabled.contains(&Extension::Dns),
+        channels: enabled.contains(&Extension::Channels),
+        backpressure: enabled.contains(&Extension::Backpressure),
+        blocks: enabled.contains(&Extension::Blocks),
+        trap: enabled.contains(&Extension::Trap),
+        features: enabled.contains(&Extension::Features),
+    }
+}
+
+/// Parse a duration such as `500ms`, `2s` or `1m`
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("{:?} needs a unit: ms, s or m", text))?;
+    let (digits, unit) = text.split_at(split);
+    let count: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", text))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(count)),
+        "s" => Ok(Duration::from_secs(count)),
+        "m" => Ok(Duration::from_secs(count.saturating_mul(60))),
+        _ => Err(format!(
+            "unknown unit {:?} in {:?} (expected ms, s or m)",
+            unit, text
+        )),
+    }
+}
+
+/// `host:port`, resolved to its first IPv4 address, since `%` dials IPv4
+fn parse_peer(text: &str) -> Result<SocketAddrV4, String> {
+    text.to_socket_addrs()
+        .map_err(|e| format!("cannot resolve {:?}: {}", text, e))?
+        .find_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        })
+        .ok_or_else(|| format!("{:?} has no IPv4 address", text))
+}
+
+/// Parse a `--protect` range such as `0..16` or `0..6:no-access`
+fn parse_guard(text: &str) -> Result<interpreter::Guard, String> {
+    let (range, no_access) = match text.trim().strip_suffix(":no-access") {
+        Some(range) => (range, true),
+        None => (text.trim(), false),
+    };
+    let (start, end) = range
+        .split_once("..")
+        .ok_or_else(|| format!("{:?} is not a range like 0..16", text))?;
+    let bound = |n: &str| {
+        n.parse::<usize>()
+            .map_err(|_| format!("invalid cell index {:?} in {:?}", n, text))
+    };
+    let cells = bound(start)?..bound(end)?;
+    if cells.is_empty() {
+        return Err(format!("{:?} covers no cells", text));
+    }
+    Ok(interpreter::Guard { cells, no_access })
+}
+
+fn open_kv_store(path: &Path) -> Option<SharedKvStore> {
+    match KvStore::open(path) {
+        Ok(store) => Some(Arc::new(Mutex::new(store))),
+        Err(e) => {
+            eprintln!("Error opening key-value store {:?}: {}", path, e);
+            None
+        }
+    }
 }
 
 fn main() {
-    let args = Args::parse();
+    // A bundled copy runs its program, passing it every argument
+    if let Some(bundle) = bundle::read() {
+        std::process::exit(run_bundled(bundle));
+    }
+    let cli = Cli::parse();
+
+    let code = match cli.command {
+        Some(Command::Stop { pidfile }) => match daemon::stop(&pidfile) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Error stopping daemon: {}", e);
+                1
+            }
+        },
+        Some(Command::Serve {
+            addr,
+            max_steps,
+            timeout_ms,
+            max_output_bytes,
+            tape_size,
+            allow_net,
+            max_request_bytes,
+            workers,
+            max_loop_depth,
+        }) => {
+            let config = serve::ServeConfig {
+                sandbox: interpreter::Sandbox {
+                    max_steps,
+                    timeout: Duration::from_millis(timeout_ms),
+                    max_tape_size: tape_size,
+                    max_output: max_output_bytes,
+                },
+                tape_size,
+                allow_net,
+                max_request_bytes,
+                workers,
+                max_loop_depth,
+            };
+            match serve::serve(&addr, config) {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("Error serving on {}: {}", addr, e);
+                    1
+                }
+            }
+        }
+        Some(Command::Lsp) => match lsp::run() {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Language server error: {}", e);
+                1
+            }
+        },
+        Some(Command::Highlight {
+            file,
+            format,
+            counts,
+        }) => run_highlight(&file, format, counts.as_deref()),
+        Some(Command::Check {
+            files,
+            tape_size,
+            extensions,
+            lints,
+        }) => run_check(&files, tape_size, self::extensions(&extensions), &lints),
+        Some(Command::Analyze { file, tape_size }) => run_analyze(&file, tape_size),
+        Some(Command::Conformance {
+            opt_level,
+            tape_model,
+            tape_size,
+            max_steps,
+        }) => {
+            let config = conformance::ConformanceConfig {
+                opt_levels: opt_level.map_or_else(|| (0..=3).collect(), |level| vec![level]),
+                tape_models: match tape_model {
+                    Some(TapeModel::Classic) => vec![interpreter::TapeModel::Classic],
+                    Some(TapeModel::Unbounded) => vec![interpreter::TapeModel::Unbounded],
+                    None => vec![
+                        interpreter::TapeModel::Classic,
+                        interpreter::TapeModel::Unbounded,
+                    ],
+                },
+                tape_size,
+                max_steps,
+            };
+            conformance::run(&config)
+        }
+        Some(Command::Equiv {
+            a,
+            b,
+            inputs,
+            fuzz,
+            seed,
+            max_steps,
+            tape_size,
+        }) => {
+            let config = equiv::EquivConfig {
+                inputs,
+                fuzz,
+                seed,
+                max_steps,
+                tape_size,
+            };
+            equiv::run(&a, &b, &config)
+        }
+        Some(Command::Schedule {
+            files,
+            tape_size,
+            mut extensions,
+            kv_store,
+            status_cell,
+        }) => {
+            extensions.push(Extension::Yield);
+            let extensions = self::extensions(&extensions);
+            run_schedule(&files, tape_size, extensions, &kv_store, status_cell)
+        }
+        Some(Command::Supervise { config }) => run_supervise(&config),
+        Some(Command::IrcBridge {
+            file,
+            server,
+            channel,
+            nick,
+            tape_size,
+            extensions,
+            kv_store,
+        }) => {
+            let config = irc::IrcConfig {
+                server,
+                channel,
+                nick,
+            };
+            let extensions = self::extensions(&extensions);
+            run_irc_bridge(&file, &config, tape_size, extensions, &kv_store)
+        }
+        Some(Command::ChatServer {
+            file,
+            room_cell,
+            history,
+            history_replay,
+            tape_size,
+            extensions,
+            kv_store,
+        }) => {
+            let history = history.map(|path| (path, history_replay));
+            run_chat_server(
+                &file,
+                room_cell,
+                history,
+                tape_size,
+                self::extensions(&extensions),
+                &kv_store,
+            )
+        }
+        Some(Command::Obfuscate { file, seed }) => run_obfuscate(&file, seed),
+        Some(Command::Run {
+            files,
+            program_args,
+            run: args,
+        }) => run(*args, &files, &program_args),
+        Some(Command::Bundle {
+            files,
+            output,
+            options,
+        }) => {
+            let output = output.unwrap_or_else(|| files[0].with_extension(""));
+            run_bundle(&files, &output, options)
+        }
+        Some(Command::Build {
+            files,
+            native: _,
+            output,
+            tape_size,
+            opt_level,
+        }) => {
+            let output = output.unwrap_or_else(|| files[0].with_extension(""));
+            run_build(&files, &output, tape_size, opt_level)
+        }
+        None => {
+            let Script {
+                file,
+                program_args,
+                run: args,
+            } = cli.script;
+            let file = file.expect("clap enforces the file argument");
+            run(args, &[file], &program_args)
+        }
+    };
+    std::process::exit(code);
+}
+
+fn run_highlight(file: &Path, format: highlight::Format, counts: Option<&Path>) -> i32 {
+    let source = match fs::read(file) {
+        Ok(s) => String::from_utf8_lossy(&s).into_owned(),
+        Err(e) => {
+            eprintln!("Error reading file {:?}: {}", file, e);
+            return 1;
+        }
+    };
+    let counts = match counts.map(highlight::load_counts).transpose() {
+        Ok(counts) => counts,
+        Err(e) => {
+            eprintln!("Error reading counts: {}", e);
+            return 1;
+        }
+    };
+
+    print!("{}", highlight::highlight(&source, format, counts.as_ref()));
+    0
+}
+
+/// Print the warnings about `files`; exits 1 if they don't parse or a
+/// denied lint finds something
+fn run_check(
+    files: &[PathBuf],
+    tape_size: Option<usize>,
+    extensions: interpreter::Extensions,
+    lints: &LintArgs,
+) -> i32 {
+    let sources = match read_sources(files) {
+        Ok(sources) => sources,
+        Err((file, e)) => {
+            eprintln!("Error reading file {:?}: {}", file, e);
+            return 1;
+        }
+    };
+    let mut parser = interpreter::Parser::new().with_extensions(extensions);
+    let parsed = sources
+        .files()
+        .enumerate()
+        .try_for_each(|(index, (_, bytes))| {
+            if index > 0 {
+                parser.next_file()?;
+            }
+            parser.feed(bytes)
+        });
+    let program = match parsed.and_then(|()| parser.finish_program()) {
+        Ok(program) => program,
+        Err(e) => {
+            print_parse_error(&e, &sources);
+            return 1;
+        }
+    };
+
+    let tape_size = tape_size
+        .or(program.metadata().directives.tape_size)
+        .unwrap_or(interpreter::TAPE_SIZE);
+    let levels = lints.levels();
+    let found = warnings::check(program.ops(), program.spans(), tape_size, &levels);
+    for warning in &found {
+        println!("{}", describe_warning(warning, &levels, &sources));
+    }
+    let denied = found
+        .iter()
+        .any(|warning| levels.get(warning.lint) == warnings::Level::Deny);
+    i32::from(denied)
+}
+
+/// `file:line:column: warning: message [lint]`, or `error:` for a lint
+/// `levels` denies
+fn describe_warning(
+    warning: &warnings::Warning,
+    levels: &warnings::Levels,
+    sources: &SourceFiles,
+) -> String {
+    let kind = match levels.get(warning.lint) {
+        warnings::Level::Deny => "error",
+        _ => "warning",
+    };
+    let (file, line, column) = sources.locate(warning.span.start).unwrap_or(("?", 0, 0));
+    format!(
+        "{}:{}:{}: {}: {} [{}]",
+        file, line, column, kind, warning.message, warning.lint
+    )
+}
+
+/// Print findings as `file:line:column: warning: ...`; exits 1 if there are any
+fn run_analyze(file: &Path, tape_size: Option<usize>) -> i32 {
+    let source = match fs::read(file) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error reading file {:?}: {}", file, e);
+            return 1;
+        }
+    };
+    let program = match interpreter::parse_bytes(&source) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("Parse error: {}", e);
+            return 1;
+        }
+    };
+
+    let tape_size = tape_size
+        .or(program.metadata().directives.tape_size)
+        .unwrap_or(interpreter::TAPE_SIZE);
+    let findings = analyzer::analyze(program.ops(), program.spans(), tape_size);
+    for finding in &findings {
+        let (line, column) = finding.span.line_col(&source);
+        println!(
+            "{}:{}:{}: warning: {}",
+            file.display(),
+            line,
+            column,
+            finding.message
+        );
+    }
+    i32::from(!findings.is_empty())
+}
+
+/// Run `files` under one scheduler; exits 1 if any of them fails
+fn run_schedule(
+    files: &[PathBuf],
+    tape_size: Option<usize>,
+    extensions: interpreter::Extensions,
+    kv_store: &Path,
+    status_cell: Option<usize>,
+) -> i32 {
+    let mut scheduler = Scheduler::new();
+    let shared = SharedSegment::new();
+    // Opened for the first program that asks for it, by `--ext` or `#! ext`
+    let mut kv: Option<SharedKvStore> = None;
+    for file in files {
+        let source = match fs::read(file) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error reading file {:?}: {}", file, e);
+                return 1;
+            }
+        };
+        let mut parser = interpreter::Parser::new().with_extensions(extensions);
+        let program = match parser.feed(&source).and_then(|()| parser.finish_program()) {
+            Ok(program) => program,
+            Err(e) => {
+                eprintln!("Parse error in {:?}: {}", file, e);
+                return 1;
+            }
+        };
+        let ops = optimizer::optimize(program.ops().to_vec());
+        let tape_size = tape_size
+            .or(program.metadata().directives.tape_size)
+            .unwrap_or(interpreter::TAPE_SIZE);
+        let mut vm = interpreter::VM::with_tape_size(tape_size);
+        vm.input = Box::new(io::empty());
+        vm.network = Box::new(NonBlockingTcpNetwork);
+        vm.extensions = program.metadata().extensions;
+        vm.status_cell = status_cell;
+        if vm.extensions.shared {
+            vm.shared = Some(shared.clone());
+        }
+        if vm.extensions.kv && kv.is_none() {
+            match open_kv_store(kv_store) {
+                Some(store) => kv = Some(store),
+                None => return 1,
+            }
+        }
+        if vm.extensions.kv {
+            vm.kv = kv.clone();
+        }
+        scheduler.spawn(vm, ops);
+    }
 
-    // Read source file
-    let source = match fs::read_to_string(&args.file) {
+    let mut code = 0;
+    for (file, (result, mut vm)) in files.iter().zip(scheduler.run()) {
+        let _ = vm.shutdown();
+        if let Err(e) = result {
+            eprintln!("Runtime error in {:?}: {}", file, e);
+            code = 1;
+        }
+    }
+    code
+}
+
+fn run_supervise(config: &Path) -> i32 {
+    // The first Ctrl-C/SIGTERM stops every program; a second exits at once
+    let interrupted = Arc::new(AtomicBool::new(false));
+    for sig in [SIGINT, SIGTERM] {
+        let registered = flag::register_conditional_shutdown(sig, 130, interrupted.clone())
+            .and_then(|_| flag::register(sig, interrupted.clone()));
+        if let Err(e) = registered {
+            eprintln!("[trainfuck] Failed to install signal handler: {}", e);
+        }
+    }
+    supervise::supervise(config, interrupted)
+}
+
+/// Run `file` with its I/O and network bridged to an IRC channel
+fn run_irc_bridge(
+    file: &Path,
+    config: &irc::IrcConfig,
+    tape_size: Option<usize>,
+    extensions: interpreter::Extensions,
+    kv_store: &Path,
+) -> i32 {
+    let source = match fs::read(file) {
         Ok(s) => s,
         Err(e) => {
-            eprintln!("Error reading file {:?}: {}", args.file, e);
-            std::process::exit(1);
+            eprintln!("Error reading file {:?}: {}", file, e);
+            return 1;
+        }
+    };
+    let mut parser = interpreter::Parser::new().with_extensions(extensions);
+    let (program, tape_size, extensions) =
+        match parser.feed(&source).and_then(|()| parser.finish_program()) {
+            Ok(program) => (
+                Bytecode::compile(&optimizer::optimize(program.ops().to_vec())),
+                tape_size
+                    .or(program.metadata().directives.tape_size)
+                    .unwrap_or(interpreter::TAPE_SIZE),
+                program.metadata().extensions,
+            ),
+            Err(e) => {
+                eprintln!("Parse error in {:?}: {}", file, e);
+                return 1;
+            }
+        };
+    let kv = match extensions.kv {
+        true => match open_kv_store(kv_store) {
+            Some(store) => Some(store),
+            None => return 1,
+        },
+        false => None,
+    };
+    let session = match irc::IrcSession::connect(config) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Error connecting to IRC server {}: {}", config.server, e);
+            return 1;
         }
     };
 
-    if args.debug {
+    let mut vm = interpreter::VM::with_tape_size(tape_size);
+    vm.extensions = extensions;
+    if extensions.shared {
+        vm.shared = Some(SharedSegment::new());
+    }
+    vm.kv = kv;
+    vm.input = Box::new(io::BufReader::new(session.stream()));
+    vm.output = Box::new(session.stream());
+    vm.network = Box::new(irc::IrcNetwork(session));
+    let result = vm.execute_bytecode(&program);
+    // Sends any unfinished output line
+    drop(vm);
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Runtime error: {}", e);
+            1
+        }
+    }
+}
+
+/// Run `file` as the relay of a multi-user chat server
+fn run_chat_server(
+    file: &Path,
+    room_cell: Option<usize>,
+    history: Option<(PathBuf, usize)>,
+    tape_size: Option<usize>,
+    extensions: interpreter::Extensions,
+    kv_store: &Path,
+) -> i32 {
+    let source = match fs::read(file) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error reading file {:?}: {}", file, e);
+            return 1;
+        }
+    };
+    let mut parser = interpreter::Parser::new().with_extensions(extensions);
+    let (program, tape_size, extensions) =
+        match parser.feed(&source).and_then(|()| parser.finish_program()) {
+            Ok(program) => (
+                Bytecode::compile(&optimizer::optimize(program.ops().to_vec())),
+                tape_size
+                    .or(program.metadata().directives.tape_size)
+                    .unwrap_or(interpreter::TAPE_SIZE),
+                program.metadata().extensions,
+            ),
+            Err(e) => {
+                eprintln!("Parse error in {:?}: {}", file, e);
+                return 1;
+            }
+        };
+    let kv = match extensions.kv {
+        true => match open_kv_store(kv_store) {
+            Some(store) => Some(store),
+            None => return 1,
+        },
+        false => None,
+    };
+
+    let log = match history.map(|(path, keep)| (chat::ChatLog::open(&path, keep), path)) {
+        None => None,
+        Some((Ok(log), _)) => Some(log),
+        Some((Err(e), path)) => {
+            eprintln!("Error opening chat history {:?}: {}", path, e);
+            return 1;
+        }
+    };
+
+    let mut vm = interpreter::VM::with_tape_size(tape_size);
+    vm.extensions = extensions;
+    if extensions.shared {
+        vm.shared = Some(SharedSegment::new());
+    }
+    vm.kv = kv;
+    vm.network = Box::new(chat::ChatNetwork(chat::ChatHub::new(log)));
+    vm.room_cell = room_cell;
+    match vm.execute_bytecode(&program) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Runtime error: {}", e);
+            1
+        }
+    }
+}
+
+fn run_obfuscate(file: &Path, seed: Option<u64>) -> i32 {
+    let source = match fs::read(file) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error reading file {:?}: {}", file, e);
+            return 1;
+        }
+    };
+    let program = match interpreter::parse_bytes(&source) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("Parse error: {}", e);
+            return 1;
+        }
+    };
+
+    let seed = seed.unwrap_or_else(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let seed = now.as_nanos() as u64;
+        eprintln!("[trainfuck] Obfuscating with --seed {}", seed);
+        seed
+    });
+    print!("{}", obfuscate::obfuscate(&program, seed));
+    0
+}
+
+/// The run options a bundle was made with
+#[derive(Parser, Debug)]
+#[command(name = "trainfuck")]
+struct Bundled {
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+/// `bundle`: copy this executable to `output` with `files` and `options`
+fn run_bundle(files: &[PathBuf], output: &Path, options: Vec<String>) -> i32 {
+    if files.iter().any(|file| file == output) {
         eprintln!(
-            "[trainfuck] Loaded {} bytes from {:?}",
-            source.len(),
-            args.file
+            "Error: the executable would overwrite {:?}; pick another with -o",
+            output
         );
+        return 1;
+    }
+    // Check the options now rather than when the bundle starts
+    let bundled = match Bundled::try_parse_from(
+        iter::once("trainfuck".into()).chain(options.iter().cloned()),
+    ) {
+        Ok(bundled) => bundled,
+        Err(e) => {
+            let _ = e.print();
+            return 2;
+        }
+    };
+    if bundled.run.reload_on.is_some() {
+        eprintln!(
+            "Error: a bundled program can't --reload-on; its source is inside the executable"
+        );
+        return 1;
+    }
+    let sources = match read_sources(files) {
+        Ok(sources) => sources,
+        Err((file, e)) => {
+            eprintln!("Error reading file {:?}: {}", file, e);
+            return 1;
+        }
+    };
+    // Parse errors are the bundler's to see, not its users'
+    if compile(&sources, &bundled.run, None).is_none() {
+        return 1;
     }
+    match bundle::write(output, &bundle::Bundle { options, sources }) {
+        Ok(()) => {
+            eprintln!("[trainfuck] Bundled {}", output.display());
+            0
+        }
+        Err(e) => {
+            eprintln!("Error writing {:?}: {}", output, e);
+            1
+        }
+    }
+}
 
-    // Parse
-    let ops = match interpreter::parse(&source) {
-        Ok(ops) => ops,
+/// Run the program bundled into this executable
+fn run_bundled(bundle: bundle::Bundle) -> i32 {
+    let options = iter::once("trainfuck".into()).chain(bundle.options);
+    let args = match Bundled::try_parse_from(options) {
+        Ok(bundled) => bundled.run,
         Err(e) => {
-            eprintln!("Parse error: {}", e);
-            std::process::exit(1);
+            eprintln!("Error: the bundled run options are invalid: {}", e);
+            return 1;
+        }
+    };
+    let program_args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    run_sources(args, bundle.sources, &[], &program_args)
+}
+
+/// `build --native`: transpile `files` and compile them to `output`
+fn run_build(files: &[PathBuf], output: &Path, tape_size: Option<usize>, opt_level: u8) -> i32 {
+    if files.iter().any(|file| file == output) {
+        eprintln!(
+            "Error: the executable would overwrite {:?}; pick another with -o",
+            output
+        );
+        return 1;
+    }
+    let sources = match read_sources(files) {
+        Ok(sources) => sources,
+        Err((file, e)) => {
+            eprintln!("Error reading file {:?}: {}", file, e);
+            return 1;
+        }
+    };
+    let mut parser = interpreter::Parser::default();
+    let parsed = sources
+        .files()
+        .enumerate()
+        .try_for_each(|(index, (_, bytes))| {
+            if index > 0 {
+                parser.next_file()?;
+            }
+            parser.feed(bytes)
+        });
+    let program = match parsed.and_then(|()| parser.finish_program()) {
+        Ok(program) => program,
+        Err(e) => {
+            print_parse_error(&e, &sources);
+            return 1;
+        }
+    };
+    let directives = &program.metadata().directives;
+    if let Some(name) = directives.extensions.first() {
+        eprintln!(
+            "Error: the program's `#! ext {}` can't be built; native executables have no extensions",
+            name
+        );
+        return 1;
+    }
+    let tape_size = match (tape_size, directives.tape_size) {
+        (Some(given), Some(size)) if given != size => {
+            eprintln!(
+                "Error: the program's `#! tape-size {}` conflicts with --tape-size {}",
+                size, given
+            );
+            return 1;
+        }
+        (given, size) => given.or(size).unwrap_or(interpreter::TAPE_SIZE),
+    };
+    let spans = program.spans();
+    let ops = Pipeline::level(opt_level, tape_size)
+        .run(program.ops().to_vec(), spans)
+        .0;
+
+    match native::transpile(&ops, tape_size).and_then(|source| native::compile(&source, output)) {
+        Ok(()) => {
+            eprintln!("[trainfuck] Built {}", output.display());
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+/// How often `--reload-on change` looks at the source file
+const RELOAD_POLL: Duration = Duration::from_millis(500);
+
+/// Request a reload whenever the modification time of one of `files` changes
+fn watch_source(files: &[PathBuf], requested: Arc<AtomicBool>) {
+    let modified = |files: &[PathBuf]| -> Vec<_> {
+        files
+            .iter()
+            .map(|file| fs::metadata(file).and_then(|m| m.modified()).ok())
+            .collect()
+    };
+    let files = files.to_vec();
+    let mut last = modified(&files);
+    thread::spawn(move || loop {
+        thread::sleep(RELOAD_POLL);
+        let now = modified(&files);
+        if now.iter().all(Option::is_some) && now != last {
+            last = now;
+            requested.store(true, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Read `files` to run as one program, or the file that can't be read and why
+fn read_sources(files: &[PathBuf]) -> Result<SourceFiles, (&Path, io::Error)> {
+    let mut sources = SourceFiles::new();
+    for file in files {
+        // Bytes that aren't valid UTF-8 are comments too
+        let bytes = fs::read(file).map_err(|e| (file.as_path(), e))?;
+        sources.push(file.display().to_string(), &bytes);
+    }
+    Ok(sources)
+}
+
+/// Parse and optimize `sources`, or reuse the cached result of doing so
+fn compile(sources: &SourceFiles, args: &RunArgs, cache_dir: Option<&Path>) -> Option<Bytecode> {
+    let key = cache::key(sources, args);
+    // The report needs the passes to run
+    let cached = match args.opt_report {
+        true => None,
+        false => cache_dir.and_then(|dir| cache::load(dir, &key)),
+    };
+    let levels = args.lints.levels();
+    if let Some((bytecode, found)) = cached {
+        if args.debug {
+            eprintln!(
+                "[trainfuck] Loaded {} bytes of cached bytecode",
+                bytecode.len()
+            );
+        }
+        for warning in &found {
+            eprintln!("{}", describe_warning(warning, &levels, sources));
+        }
+        return Some(bytecode);
+    }
+
+    let (ops, spans) = match parse_sources(sources, args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            print_parse_error(&e, sources);
+            return None;
         }
     };
 
+    // Warnings are about the source as written, so lint before optimizing
+    let found = warnings::check(&ops, &spans, args.tape_size(), &levels);
+    let mut denied = false;
+    for warning in &found {
+        denied |= levels.get(warning.lint) == warnings::Level::Deny;
+        eprintln!("{}", describe_warning(warning, &levels, sources));
+    }
+    if denied {
+        return None;
+    }
+
+    let mut report = optimizer::Report::default();
+    let (ops, spans) = args.pipeline().run_with_report(ops, &spans, &mut report);
+
     if args.debug {
         eprintln!("[trainfuck] Parsed {} operations", ops.len());
     }
+    if args.opt_report {
+        print_opt_report(&report, sources);
+    }
+
+    let bytecode = Bytecode::compile_with_spans(&ops, &spans);
+    if let Some(dir) = cache_dir {
+        // A read-only or full cache directory shouldn't stop the program
+        if let Err(e) = cache::store(dir, &key, &bytecode, &found) {
+            if args.debug {
+                eprintln!("[trainfuck] Not caching bytecode in {:?}: {}", dir, e);
+            }
+        }
+    }
+    Some(bytecode)
+}
+
+/// Parse `sources` as one program, as `args` say, without optimizing it
+fn parse_sources(
+    sources: &SourceFiles,
+    args: &RunArgs,
+) -> interpreter::Result<(Vec<interpreter::Op>, SourceMap)> {
+    let mut parser = interpreter::Parser::with_max_depth(args.max_loop_depth)
+        .with_extensions(args.extensions())
+        .with_dialect(args.dialect());
+    for (index, (_, bytes)) in sources.files().enumerate() {
+        if index > 0 {
+            parser.next_file()?;
+        }
+        parser.feed(bytes)?;
+    }
+    parser.finish_with_spans()
+}
+
+/// Report `e`, at the file, line and column it points to if it does
+fn print_parse_error(e: &interpreter::TrainfuckError, sources: &SourceFiles) {
+    match e.position().and_then(|position| sources.locate(position)) {
+        Some((file, line, column)) => {
+            eprintln!("Parse error at {}:{}:{}: {}", file, line, column, e)
+        }
+        None => eprintln!("Parse error: {}", e),
+    }
+}
+
+/// `--opt-report`: a summary line per pass, then each change at the
+/// source it was made to
+fn print_opt_report(report: &optimizer::Report, sources: &SourceFiles) {
+    if report.passes.is_empty() {
+        eprintln!("[trainfuck] No optimization passes ran");
+    }
+    for pass in &report.passes {
+        eprintln!("[trainfuck] {}", pass);
+        for (span, change) in &pass.changes {
+            let Some((file, line, column)) = sources.locate(span.start) else {
+                continue;
+            };
+            let text = &sources.source()[span.start..span.end.min(sources.source().len())];
+            let text = String::from_utf8_lossy(text)
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+            let text = match text.chars().count() > 24 {
+                true => format!("{}...", text.chars().take(24).collect::<String>()),
+                false => text,
+            };
+            eprintln!(
+                "[trainfuck]   {}:{}:{}: `{}`: {}",
+                file, line, column, text, change
+            );
+        }
+    }
+}
+
+/// Run `files` as one program, passing it `program_args`
+fn run(args: RunArgs, files: &[PathBuf], program_args: &[OsString]) -> i32 {
+    // Failing to load exits as a shell does for a command it can't find
+    // (127) or can't run (126), since scripts are run with `#!`
+    let sources = match read_sources(files) {
+        Ok(sources) => sources,
+        Err((file, e)) => {
+            eprintln!("Error reading file {:?}: {}", file, e);
+            return match e.kind() {
+                io::ErrorKind::NotFound => 127,
+                _ => 126,
+            };
+        }
+    };
+    run_sources(args, sources, files, program_args)
+}
+
+/// Run the program in `sources`, which `--reload-on` reads again from
+/// `files`, passing it `program_args`
+fn run_sources(
+    mut args: RunArgs,
+    sources: SourceFiles,
+    files: &[PathBuf],
+    program_args: &[OsString],
+) -> i32 {
+    if args.debug {
+        for (file, bytes) in sources.files() {
+            eprintln!("[trainfuck] Loaded {} bytes from {:?}", bytes.len(), file);
+        }
+    }
+
+    // Settings the program asks for itself, before anything depends on them
+    if args.dialect == Dialect::Trainfuck {
+        let directives = match Directives::scan(&sources, args.extensions()) {
+            Ok(directives) => directives,
+            Err(e) => {
+                print_parse_error(&e, &sources);
+                return 126;
+            }
+        };
+        if let Err(e) = args.apply_directives(&directives) {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+    }
+
+    let cache_dir = match args.no_cache {
+        true => None,
+        false => args.cache_dir.clone().or_else(cache::default_dir),
+    };
+    if let Some(ref passes) = args.passes {
+        let unbounded = args.tape_model == TapeModel::Unbounded;
+        if unbounded
+            && passes
+                .iter()
+                .any(|name| name == "evaluate" || name == "fold")
+        {
+            eprintln!("Error: --passes evaluate and fold need a --tape-model classic tape");
+            return 1;
+        }
+        if args.status_cell.is_some() && passes.iter().any(|name| name == "fold") {
+            eprintln!("Error: --passes fold can't see the ops writing the --status-cell");
+            return 1;
+        }
+    }
+    // -O2 runs the start of the program at compile time, which can remove
+    // top-level loops, so two versions' loops wouldn't line up
+    let names = args.pipeline().names();
+    if args.reload_on.is_some()
+        && names
+            .iter()
+            .any(|&name| name == "evaluate" || name == "fold")
+    {
+        eprintln!("Error: --reload-on needs -O0 or -O1, without the evaluate and fold passes");
+        return 1;
+    }
+    let Some(mut bytecode) = compile(&sources, &args, cache_dir.as_deref()) else {
+        return 126;
+    };
+
+    // Detach only once the program is known to load, so errors stay visible
+    let _pidfile = if args.daemon {
+        match daemon::daemonize(&args.pidfile, &args.log_file) {
+            Ok(guard) => Some(guard),
+            Err(e) => {
+                eprintln!("Error starting daemon: {}", e);
+                return 1;
+            }
+        }
+    } else {
+        None
+    };
+
+    // Stop at the next instruction on the first Ctrl-C/SIGTERM; exit
+    // immediately on the second in case we are stuck in a blocking call
+    let interrupted = Arc::new(AtomicBool::new(false));
+    for sig in [SIGINT, SIGTERM] {
+        let registered = flag::register_conditional_shutdown(sig, 130, interrupted.clone())
+            .and_then(|_| flag::register(sig, interrupted.clone()));
+        if let Err(e) = registered {
+            eprintln!("[trainfuck] Failed to install signal handler: {}", e);
+        }
+    }
 
     // Execute
-    let mut vm = interpreter::VM::new();
-    if let Err(e) = vm.execute(&ops) {
-        eprintln!("Runtime error: {}", e);
-        std::process::exit(1);
+    let mut vm = interpreter::VM::with_tape_size(args.tape_size());
+    vm.max_loop_depth = args.max_loop_depth;
+    vm.tape_model = match args.tape_model {
+        TapeModel::Classic => interpreter::TapeModel::Classic,
+        TapeModel::Unbounded => interpreter::TapeModel::Unbounded,
+    };
+    vm.interrupt = Some(interrupted.clone());
+    vm.extensions = args.extensions();
+    if vm.extensions.trap && vm.extensions.xor {
+        eprintln!("Error: --ext trap and --ext xor both use `!`");
+        return 1;
+    }
+    if args.deterministic {
+        if vm.extensions.http || vm.extensions.actors {
+            eprintln!("Error: --deterministic doesn't allow --ext http or --ext actors");
+            return 1;
+        }
+        vm.clock = Clock::Virtual;
+    }
+    vm.connect_retry = interpreter::ConnectRetry {
+        retries: args.connect_retries,
+        backoff: args.connect_backoff,
+    };
+    vm.reconnect = args.reconnect;
+    vm.status_cell = args.status_cell;
+    vm.peer_cell = args.peer_cell;
+    for guard in &args.protect {
+        if guard.cells.end > args.tape_size() {
+            eprintln!(
+                "Error: --protect {}..{} goes past the end of the tape",
+                guard.cells.start, guard.cells.end
+            );
+            return 1;
+        }
+        vm.protect(guard.clone());
+    }
+    vm.accept_timeout = args.accept_timeout;
+    vm.listen_addrs = args.bind.clone();
+    vm.connect_addr = args.connect;
+    if let Some(ref path) = args.addr_map {
+        match addr_map::load(path) {
+            Ok(map) => vm.addr_map = Some(map),
+            Err(e) => {
+                eprintln!("Error reading address map {:?}: {}", path, e);
+                return 1;
+            }
+        }
+    }
+    vm.drain_timeout = args.drain_timeout;
+    if !args.allow_from.is_empty() || !args.deny_from.is_empty() {
+        vm.access = Some(AccessList {
+            allow: args.allow_from.clone(),
+            deny: args.deny_from.clone(),
+        });
+    }
+    vm.idle_timeout = args.idle_timeout;
+    vm.keepalive = args.keepalive;
+    vm.step_delay = args
+        .step_delay
+        .or(args.speed.map(|n| Duration::from_secs(1) / n));
+    vm.net_errors = args.net_errors.map(|policy| match policy {
+        NetErrors::Status => interpreter::NetErrors::Status,
+        NetErrors::Abort => interpreter::NetErrors::Abort,
+    });
+    if args.sandbox {
+        if !vm.extensions.sandboxed() {
+            eprintln!("Error: --sandbox doesn't allow --ext kv or --ext actors");
+            return 1;
+        }
+        if args.tape_model == TapeModel::Unbounded {
+            eprintln!("Error: --sandbox needs --tape-model classic");
+            return 1;
+        }
+        if let Err(e) = vm.sandbox(&interpreter::Sandbox::default()) {
+            eprintln!("Error: --sandbox: {}", e);
+            return 1;
+        }
+        // Replayed connections never leave the process
+        vm.network_enabled = args.replay_traffic.is_some();
+    }
+    if vm.extensions.shared {
+        // Nothing else shares it here, but `~` behaves as it would in a pool
+        vm.shared = Some(SharedSegment::new());
+    }
+    if vm.extensions.kv {
+        match open_kv_store(&args.kv_store) {
+            Some(store) => vm.kv = Some(store),
+            None => return 1,
+        }
+    }
+    if let Some(ref addr) = args.http_server {
+        let template = http_server::Template {
+            tape_size: args.tape_size(),
+            tape_model: vm.tape_model,
+            max_loop_depth: args.max_loop_depth,
+            extensions: vm.extensions,
+            shared: vm.shared.clone(),
+            kv: vm.kv.clone(),
+            interrupt: interrupted,
+        };
+        return match http_server::serve(addr, bytecode, template) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Error serving HTTP on {}: {}", addr, e);
+                1
+            }
+        };
+    }
+    let transport = transport::Options {
+        transport: match (args.deterministic, &args.transport) {
+            (false, transport) | (true, transport @ transport::Transport::Virtual) => transport,
+            (true, transport::Transport::Tcp) => &transport::Transport::Virtual,
+            (true, _) => {
+                eprintln!("Error: --deterministic needs --transport virtual");
+                return 1;
+            }
+        },
+        compress: args.compress,
+        auth_token: args.auth_token.as_deref(),
+        broker: &args.broker,
+        topics: args.topic.as_ref(),
+        flood: (args.flood_messages.is_some() || args.flood_bytes.is_some()).then(|| FloodLimits {
+            messages_per_sec: args.flood_messages,
+            bytes_per_sec: args.flood_bytes,
+            action: args.flood_action.into(),
+        }),
+    };
+    match transport::network(&transport) {
+        Ok(network) => vm.network = network,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+    }
+    if let Some(ref path) = args.replay_traffic {
+        match ReplayNetwork::open(path, args.fast) {
+            Ok(replay) => vm.network = Box::new(replay),
+            Err(e) => {
+                eprintln!("Error reading traffic log {:?}: {}", path, e);
+                return 1;
+            }
+        }
+    }
+    if let Some(ref path) = args.log_traffic {
+        let network = std::mem::replace(&mut vm.network, Box::new(TcpNetwork));
+        match TrafficLog::create(path) {
+            Ok(log) => vm.network = Box::new(LoggingNetwork::new(network, log)),
+            Err(e) => {
+                eprintln!("Error creating traffic log {:?}: {}", path, e);
+                return 1;
+            }
+        }
+    }
+    if args.control.is_some() || args.debug_script.is_some() {
+        #[cfg(unix)]
+        {
+            use trainfuck::control::Controller;
+            let mut controller = match args.control {
+                Some(ref path) => match Controller::bind(path) {
+                    Ok(controller) => controller,
+                    Err(e) => {
+                        eprintln!("Error opening control socket {:?}: {}", path, e);
+                        return 1;
+                    }
+                },
+                None => Controller::without_socket(),
+            };
+            controller.set_program(&bytecode, sources.source());
+            if let Some(ref path) = args.debug_script {
+                match fs::read_to_string(path) {
+                    Ok(text) => controller.run_script(trainfuck::debug::Script::parse(&text)),
+                    Err(e) => {
+                        eprintln!("Error reading debug script {:?}: {}", path, e);
+                        return 1;
+                    }
+                }
+            }
+            if let Some(capacity) = args.record_history {
+                vm.history = Some(trainfuck::history::History::new(capacity, vm.tape()));
+            }
+            vm.controller = Some(controller);
+        }
+        #[cfg(not(unix))]
+        {
+            eprintln!("Error: --control and --debug-script require Unix");
+            return 1;
+        }
+    }
+    if args.nonblocking_input {
+        vm.input = Box::new(io::BufReader::new(interpreter::NonBlockingReader::new(
+            io::stdin(),
+        )));
+    }
+    if !program_args.is_empty() {
+        let mut bytes = Vec::new();
+        for arg in program_args {
+            bytes.extend_from_slice(arg.as_encoded_bytes());
+            bytes.push(0);
+        }
+        let input = std::mem::replace(&mut vm.input, Box::new(io::empty()));
+        vm.input = Box::new(io::Read::chain(io::Cursor::new(bytes), input));
+    }
+    let animation = match args
+        .render_animation
+        .as_deref()
+        .map(animation::Animation::new)
+    {
+        Some(Ok(mut animation)) => {
+            animation.capture(&vm);
+            let animation = Rc::new(RefCell::new(animation));
+            let frames = Rc::clone(&animation);
+            vm.watchers.push(interpreter::Watcher {
+                interval: args.frame_interval,
+                callback: Box::new(move |vm| frames.borrow_mut().capture(vm)),
+            });
+            Some(animation)
+        }
+        Some(Err(e)) => {
+            eprintln!("Error: --render-animation {}", e);
+            return 1;
+        }
+        None => None,
+    };
+    if let Some(ref path) = args.audio {
+        match audio::Synth::create(path, args.note_length) {
+            Ok(synth) => vm.output = Box::new(synth),
+            Err(e) => {
+                eprintln!("Error creating {:?}: {}", path, e);
+                return 1;
+            }
+        }
+    }
+    let histogram_json = match args.histogram.as_deref().map(|path| path.extension()) {
+        Some(Some(e)) if e.eq_ignore_ascii_case("json") => true,
+        Some(Some(e)) if e.eq_ignore_ascii_case("csv") => false,
+        Some(_) => {
+            eprintln!("Error: --histogram file should end in .csv or .json");
+            return 1;
+        }
+        None => false,
+    };
+    if args.histogram.is_some() || args.profile.is_some() || args.suggest {
+        vm.histogram = Some(trainfuck::histogram::Histogram::default());
+    }
+    let tape_image = match args
+        .render_tape
+        .as_deref()
+        .map(tape_image::Format::for_path)
+    {
+        Some(Ok(format)) => Some(format),
+        Some(Err(e)) => {
+            eprintln!("Error: --render-tape {}", e);
+            return 1;
+        }
+        None => None,
+    };
+    if let (Some(format), Some(path), Some(every)) =
+        (tape_image, args.render_tape.clone(), args.render_tape_every)
+    {
+        let width = args.width as usize;
+        vm.watchers.push(interpreter::Watcher {
+            interval: every,
+            callback: Box::new(move |vm| {
+                if let Err(e) = tape_image::write(&path, format, vm.tape(), width) {
+                    eprintln!("[trainfuck] Could not write {:?}: {}", path, e);
+                }
+            }),
+        });
+    }
+    if args.handler && args.tape_model == TapeModel::Unbounded {
+        eprintln!("Error: --handler needs --tape-model classic");
+        return 1;
+    }
+    if let Some(ref addr) = args.health_addr {
+        let health = Health::new();
+        vm.health = Some(health.clone());
+        if let Err(e) = probes::serve(addr, health, args.health_stall) {
+            eprintln!("Error serving health checks on {}: {}", addr, e);
+            return 1;
+        }
+    }
+    // The source of the version running now, once it has been reloaded
+    let reloaded: RefCell<Option<SourceFiles>> = RefCell::new(None);
+    let result = match args.reload_on {
+        Some(on) => {
+            let requested = Arc::new(AtomicBool::new(false));
+            match on {
+                ReloadOn::Sighup => {
+                    if let Err(e) = flag::register(SIGHUP, requested.clone()) {
+                        eprintln!("[trainfuck] Failed to install SIGHUP handler: {}", e);
+                    }
+                }
+                ReloadOn::Change => watch_source(files, requested.clone()),
+            }
+            let mut reload = Reload {
+                requested,
+                load: Box::new(|| {
+                    let sources = match read_sources(files) {
+                        Ok(sources) => sources,
+                        Err((file, e)) => {
+                            eprintln!("[trainfuck] Not reloading {:?}: {}", file, e);
+                            return None;
+                        }
+                    };
+                    let bytecode = compile(&sources, &args, cache_dir.as_deref())?;
+                    *reloaded.borrow_mut() = Some(sources);
+                    Some(bytecode)
+                }),
+            };
+            vm.execute_reloadable(&mut bytecode, &mut reload)
+        }
+        None if args.handler => vm.execute_handler(&bytecode),
+        None => vm.execute_tiered(&bytecode, args.hot_loop_threshold),
+    };
+    if let Some(ref health) = vm.health {
+        health.finish();
+    }
+    let sources = reloaded.take().unwrap_or(sources);
+    if let (Some(histogram), Some(path)) = (&vm.histogram, &args.histogram) {
+        let characters =
+            trainfuck::histogram::character_counts(sources.source(), &args.extensions());
+        let locate = |pc| {
+            let span = bytecode.span_at(pc)?;
+            sources
+                .locate(span.start)
+                .map(|(_, line, column)| (line, column))
+        };
+        let written = if histogram_json {
+            let json = histogram.to_json(&characters, locate);
+            fs::write(path, format!("{:#}\n", json))
+        } else {
+            fs::File::create(path).and_then(|file| {
+                let mut out = io::BufWriter::new(file);
+                histogram.write_csv(&mut out, &characters, locate)?;
+                io::Write::flush(&mut out)
+            })
+        };
+        if let Err(e) = written {
+            eprintln!("Error writing {:?}: {}", path, e);
+        }
+    }
+    if let (Some(histogram), Some(path)) = (&vm.histogram, &args.profile) {
+        let json = serde_json::json!(histogram.profile(&bytecode));
+        if let Err(e) = fs::write(path, format!("{:#}\n", json)) {
+            eprintln!("Error writing {:?}: {}", path, e);
+        }
+    }
+    if let (true, Some(histogram)) = (args.suggest, &vm.histogram) {
+        // The sources parsed when they were compiled
+        if let Ok((ops, spans)) = parse_sources(&sources, &args) {
+            let found = suggest::suggest(&ops, &spans, &sources, &bytecode, histogram);
+            if found.is_empty() {
+                eprintln!("[trainfuck] No suggestions");
+            }
+            for suggestion in found {
+                match sources.locate(suggestion.span.start) {
+                    Some((file, line, column)) => eprintln!(
+                        "[trainfuck] {}:{}:{}: {}",
+                        file, line, column, suggestion.message
+                    ),
+                    None => eprintln!("[trainfuck] {}", suggestion.message),
+                }
+            }
+        }
+    }
+    if let (Some(format), Some(path)) = (tape_image, &args.render_tape) {
+        if let Err(e) = tape_image::write(path, format, vm.tape(), args.width as usize) {
+            eprintln!("Error writing {:?}: {}", path, e);
+        }
+    }
+    if let Some(animation) = animation {
+        let mut animation = animation.borrow_mut();
+        match animation.finish(&vm) {
+            Ok(frames) => eprintln!(
+                "[trainfuck] Wrote {} frames to {:?}",
+                frames,
+                animation.path()
+            ),
+            Err(e) => eprintln!("Error writing {:?}: {}", animation.path(), e),
+        }
+    }
+    // `,` reading on past the end is only a problem if it kept going
+    let eof_level = args.lints.levels().get(warnings::Lint::InputAfterEof);
+    let eof_denied = eof_level == warnings::Level::Deny && vm.eof_reads() > 1;
+    if eof_level != warnings::Level::Allow && vm.eof_reads() > 1 {
+        eprintln!(
+            "{}: `,` read {} times after the input ended [{}]",
+            if eof_denied { "error" } else { "warning" },
+            vm.eof_reads() - 1,
+            warnings::Lint::InputAfterEof
+        );
+    }
+    #[cfg(unix)]
+    let script_failed = match vm.controller.as_ref().map_or(0, |c| c.script_errors()) {
+        0 => false,
+        1 => {
+            eprintln!("[trainfuck] 1 debug script line failed");
+            true
+        }
+        n => {
+            eprintln!("[trainfuck] {} debug script lines failed", n);
+            true
+        }
+    };
+    #[cfg(not(unix))]
+    let script_failed = false;
+    match result {
+        Ok(()) => i32::from(eof_denied || script_failed),
+        Err(interpreter::TrainfuckError::Interrupted) => {
+            eprintln!("[trainfuck] Interrupted, shutting down");
+            if let Err(e) = vm.shutdown() {
+                eprintln!("[trainfuck] Shutdown error: {}", e);
+            }
+            if args.dump_on_interrupt {
+                let _ = vm.dump_tape(&mut io::stderr());
+            }
+            130
+        }
+        Err(interpreter::TrainfuckError::OutputClosed)
+            if args.on_sigpipe == OnSigpipe::ExitQuietly =>
+        {
+            // 128 + SIGPIPE, as the shell reports for tools the signal killed
+            141
+        }
+        Err(e) => {
+            let span = vm.fault_pc().and_then(|pc| bytecode.span_at(pc));
+            match span.and_then(|span| sources.locate(span.start)) {
+                Some((file, line, column)) => {
+                    eprintln!("Runtime error at {}:{}:{}: {}", file, line, column, e);
+                }
+                None => eprintln!("Runtime error: {}", e),
+            }
+            1
+        }
     }
 }
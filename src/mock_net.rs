@@ -0,0 +1,120 @@
+//! A scripted network for tests and fuzzing
+//!
+//! A [`MockNetwork`] never waits and never touches a real socket: `$`
+//! listens on any address, `@` accepts a connection at once and `%` always
+//! connects. Every connection, dialed or accepted, reads the same canned
+//! bytes and then ends, and whatever the program sends goes to one log
+//! shared by all of them. A run's outcome depends only on the program and
+//! the script, which is what a fuzzer needs to replay a crash.
+//!
+//! ```
+//! use trainfuck::interpreter::{parse, VM};
+//! use trainfuck::mock_net::MockNetwork;
+//!
+//! let network = MockNetwork::new(b"hi".to_vec());
+//! let mut vm = VM::with_io(std::io::empty(), std::io::sink());
+//! vm.network = Box::new(network.clone());
+//! vm.execute(&parse("%`'`'`'")?)?;
+//! assert_eq!(network.sent(), b"hi\0");
+//! # Ok::<(), trainfuck::interpreter::TrainfuckError>(())
+//! ```
+
+use crate::net::{Listener, Network, Stream};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Connections that read `script`; clones share the log of sent bytes
+#[derive(Clone, Default)]
+pub struct MockNetwork {
+    script: Arc<Vec<u8>>,
+    sent: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MockNetwork {
+    /// A network whose every connection reads `script`
+    pub fn new(script: Vec<u8>) -> Self {
+        MockNetwork {
+            script: Arc::new(script),
+            sent: Arc::default(),
+        }
+    }
+
+    /// Everything sent on any connection so far
+    pub fn sent(&self) -> Vec<u8> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    fn stream(&self) -> Box<dyn Stream> {
+        Box::new(MockStream {
+            script: self.script.clone(),
+            read: 0,
+            sent: self.sent.clone(),
+        })
+    }
+}
+
+impl Network for MockNetwork {
+    fn listen(&mut self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(MockListener {
+            network: self.clone(),
+            addr,
+        }))
+    }
+
+    fn connect(&mut self, _addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        Ok(self.stream())
+    }
+}
+
+struct MockListener {
+    network: MockNetwork,
+    addr: SocketAddr,
+}
+
+impl Listener for MockListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        Ok(self.network.stream())
+    }
+
+    fn accept_timeout(&mut self, _timeout: Duration) -> io::Result<Option<Box<dyn Stream>>> {
+        self.accept().map(Some)
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.addr)
+    }
+}
+
+struct MockStream {
+    script: Arc<Vec<u8>>,
+    /// Bytes of the script read so far
+    read: usize,
+    sent: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (&self.script[self.read..]).read(buf)?;
+        self.read += n;
+        Ok(n)
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for MockStream {
+    fn has_pending(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,210 @@
+//! MQTT transport (`--transport mqtt`, `mqtt` feature)
+//!
+//! [`MqttNetwork`] maps the networking ops onto two topics on a broker:
+//! `$`/`@` and `%` all open the same conversation, in which `` ` `` reads
+//! messages published to the input topic, each followed by `\n`, and every
+//! line sent with `'` is published to the output topic. The broker address
+//! comes from the command line, not the tape, since MQTT clients connect
+//! to a broker rather than to each other.
+
+use crate::net::{Listener, Network, Stream};
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Publishes and subscriptions queued before the client waits
+const REQUEST_QUEUE: usize = 64;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Networking over an MQTT broker, connected on first use
+pub struct MqttNetwork {
+    host: String,
+    port: u16,
+    input_topic: String,
+    output_topic: String,
+    session: Option<MqttSession>,
+}
+
+impl MqttNetwork {
+    /// Read from `input_topic` and publish to `output_topic` on the broker
+    /// at `host:port`
+    pub fn new(host: &str, port: u16, input_topic: &str, output_topic: &str) -> Self {
+        MqttNetwork {
+            host: host.to_string(),
+            port,
+            input_topic: input_topic.to_string(),
+            output_topic: output_topic.to_string(),
+            session: None,
+        }
+    }
+
+    fn session(&mut self) -> io::Result<&MqttSession> {
+        if self.session.is_none() {
+            self.session = Some(MqttSession::connect(
+                &self.host,
+                self.port,
+                &self.input_topic,
+                &self.output_topic,
+            )?);
+        }
+        Ok(self.session.as_ref().unwrap())
+    }
+}
+
+impl Network for MqttNetwork {
+    fn listen(&mut self, _addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(MqttListener(self.session()?.clone())))
+    }
+
+    fn connect(&mut self, _addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        self.session()?.stream()
+    }
+}
+
+#[derive(Clone)]
+struct MqttSession {
+    client: Client,
+    output_topic: Arc<str>,
+    messages: Arc<Mutex<Receiver<Vec<u8>>>>,
+    /// Set once the connection to the broker has failed
+    closed: Arc<AtomicBool>,
+}
+
+impl MqttSession {
+    fn connect(host: &str, port: u16, input_topic: &str, output_topic: &str) -> io::Result<Self> {
+        let id = format!("trainfuck-{}", std::process::id());
+        let mut options = MqttOptions::new(id, host, port);
+        options.set_keep_alive(KEEP_ALIVE);
+        let (client, mut connection) = Client::new(options, REQUEST_QUEUE);
+        client
+            .subscribe(input_topic, QoS::AtLeastOnce)
+            .map_err(io::Error::other)?;
+
+        let (sender, messages) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let input_topic = input_topic.to_string();
+        let done = closed.clone();
+        thread::spawn(move || {
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::Publish(publish)))
+                        if publish.topic == input_topic =>
+                    {
+                        let mut message = publish.payload.to_vec();
+                        message.push(b'\n');
+                        if sender.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("[trainfuck] MQTT connection failed: {}", e);
+                        break;
+                    }
+                }
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+        eprintln!("[trainfuck] Using MQTT broker {}:{}", host, port);
+
+        Ok(MqttSession {
+            client,
+            output_topic: output_topic.into(),
+            messages: Arc::new(Mutex::new(messages)),
+            closed,
+        })
+    }
+
+    /// A new view of the conversation; fails once the broker is gone, so a
+    /// program waiting for connections ends
+    fn stream(&self) -> io::Result<Box<dyn Stream>> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "lost the connection to the MQTT broker",
+            ));
+        }
+        Ok(Box::new(MqttStream {
+            session: self.clone(),
+            pending: Vec::new(),
+            line: Vec::new(),
+        }))
+    }
+
+    fn publish(&self, payload: &[u8]) -> io::Result<()> {
+        self.client
+            .publish(&*self.output_topic, QoS::AtLeastOnce, false, payload)
+            .map_err(io::Error::other)
+    }
+}
+
+struct MqttListener(MqttSession);
+
+impl Listener for MqttListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        self.0.stream()
+    }
+}
+
+/// Input topic messages in, lines out to the output topic
+struct MqttStream {
+    session: MqttSession,
+    /// Rest of a message not read yet
+    pending: Vec<u8>,
+    /// Output since the last newline
+    line: Vec<u8>,
+}
+
+impl Read for MqttStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.session.messages.lock().unwrap().recv() {
+                Ok(message) => self.pending = message,
+                // Broker gone: end of stream
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for MqttStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            match byte {
+                b'\n' => {
+                    self.session.publish(&self.line)?;
+                    self.line.clear();
+                }
+                _ => self.line.push(byte),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Messages are only published once a line is complete, as `'` flushes
+    /// after every byte
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for MqttStream {}
+
+impl Drop for MqttStream {
+    /// Publish a last line that wasn't ended with a newline
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            let _ = self.session.publish(&self.line);
+        }
+    }
+}
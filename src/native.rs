@@ -0,0 +1,370 @@
+//! `trainfuck build --native`: standalone executables
+//!
+//! The optimized ops are transpiled to a Rust program that carries its own
+//! small runtime: a classic wrapping tape, stdin and stdout, and the
+//! networking commands over plain TCP. `rustc` compiles it, linking with
+//! the system C compiler (`cc`), into an executable that needs neither
+//! the interpreter nor a Rust toolchain to run.
+//!
+//! The runtime behaves like `trainfuck` run with default options: `,` reads
+//! the program's arguments, NUL-terminated, before stdin and 0 at the end
+//! of input, and the networking commands print the same `[trainfuck]`
+//! lines. Output is flushed at each newline and before the program waits
+//! for input or a peer, rather than after every byte. There are no
+//! extensions, so programs that turn one on with `#! ext` can't be built,
+//! and none of `run`'s limits, status cells or debugging aids.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use trainfuck::interpreter::Op;
+
+/// Rust source of a program running `ops` on a tape of `tape_size` cells;
+/// fails on ops of an extension, which the runtime doesn't have
+pub fn transpile(ops: &[Op], tape_size: usize) -> Result<String, String> {
+    let mut out = String::from(RUNTIME);
+    let _ = writeln!(out, "\nconst TAPE_SIZE: usize = {};", tape_size);
+    out.push_str("\nfn program(vm: &mut Vm) -> Result<(), Stop> {\n");
+    block(&mut out, ops, tape_size, 1)?;
+    out.push_str("    Ok(())\n}\n");
+    Ok(out)
+}
+
+/// Write `source` to a scratch directory and compile it to `output`
+pub fn compile(source: &str, output: &Path) -> Result<(), String> {
+    let dir = std::env::temp_dir().join(format!("trainfuck-build-{}", std::process::id()));
+    let result = fs::create_dir_all(&dir)
+        .and_then(|()| fs::write(dir.join("main.rs"), source))
+        .map_err(|e| format!("Error writing {:?}: {}", dir, e))
+        .and_then(|()| rustc(&dir.join("main.rs"), output));
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// Run `$RUSTC` (default `rustc`) on `main`
+fn rustc(main: &Path, output: &Path) -> Result<(), String> {
+    let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let status = Command::new(&rustc)
+        .args([
+            "--edition",
+            "2021",
+            "-C",
+            "opt-level=3",
+            "-C",
+            "panic=abort",
+            "-o",
+        ])
+        .arg(output)
+        .arg(main)
+        .status()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => format!(
+                "Error: --native builds need rustc and a C linker such as cc; {:?} was not found \
+                 (set RUSTC to use another compiler)",
+                rustc
+            ),
+            _ => format!("Error running {:?}: {}", rustc, e),
+        })?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(format!("Error: {:?} failed ({})", rustc, status)),
+    }
+}
+
+fn block(out: &mut String, ops: &[Op], tape_size: usize, depth: usize) -> Result<(), String> {
+    let indent = "    ".repeat(depth);
+    for op in ops {
+        out.push_str(&indent);
+        match op {
+            Op::MoveRight(n) => {
+                let _ = writeln!(out, "vm.right({});", n % tape_size);
+            }
+            Op::MoveLeft(n) => {
+                let _ = writeln!(out, "vm.right({});", backwards(*n, tape_size));
+            }
+            Op::Increment(n) => {
+                let _ = writeln!(out, "vm.add({});", n);
+            }
+            Op::Decrement(n) => {
+                let _ = writeln!(out, "vm.add({});", n.wrapping_neg());
+            }
+            Op::Output => out.push_str("vm.output(&[vm.tape[vm.p]])?;\n"),
+            Op::Input => out.push_str("vm.input()?;\n"),
+            Op::Loop(body) => {
+                out.push_str("while vm.tape[vm.p] != 0 {\n");
+                block(out, body, tape_size, depth + 1)?;
+                let _ = writeln!(out, "{}}}", indent);
+            }
+            Op::Clear => out.push_str("vm.tape[vm.p] = 0;\n"),
+            Op::ScanRight(stride) => {
+                let _ = writeln!(out, "vm.scan({});", stride % tape_size);
+            }
+            Op::ScanLeft(stride) => {
+                let _ = writeln!(out, "vm.scan({});", backwards(*stride, tape_size));
+            }
+            Op::MulAdd(targets) => {
+                out.push_str("vm.mul_add(&[");
+                for (i, (offset, factor)) in targets.iter().enumerate() {
+                    let offset = offset.rem_euclid(tape_size as isize);
+                    let separator = if i == 0 { "" } else { ", " };
+                    let _ = write!(out, "{}({}, {})", separator, offset, factor);
+                }
+                out.push_str("]);\n");
+            }
+            Op::Print(bytes) => {
+                let _ = writeln!(out, "vm.output({})?;", byte_string(bytes));
+            }
+            Op::Load(start, cells) => {
+                let _ = writeln!(
+                    out,
+                    "vm.load({}, {});",
+                    start % tape_size,
+                    byte_string(cells)
+                );
+            }
+            Op::Listen => out.push_str("vm.listen()?;\n"),
+            Op::Accept => out.push_str("vm.accept()?;\n"),
+            Op::Connect => out.push_str("vm.connect()?;\n"),
+            Op::Receive => out.push_str("vm.receive();\n"),
+            Op::Send => out.push_str("vm.send()?;\n"),
+            op => {
+                return Err(format!(
+                    "Error: {:?} is an extension op and has no native code",
+                    op
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A move of `n` cells left as the equivalent move right
+fn backwards(n: usize, tape_size: usize) -> usize {
+    (tape_size - n % tape_size) % tape_size
+}
+
+fn byte_string(bytes: &[u8]) -> String {
+    let mut literal = String::from("b\"");
+    for &byte in bytes {
+        let _ = write!(literal, "\\x{:02x}", byte);
+    }
+    literal.push('"');
+    literal
+}
+
+/// Everything in the generated program but `TAPE_SIZE` and `program`
+const RUNTIME: &str = r#"// Generated by `trainfuck build --native`
+
+// Not every program uses every command
+#![allow(dead_code)]
+
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
+
+/// Why the program stopped early
+enum Stop {
+    /// Stdout is a pipe whose reader has gone
+    OutputClosed,
+    Error(String),
+}
+
+struct Vm {
+    tape: Vec<u8>,
+    p: usize,
+    input: Box<dyn Read>,
+    output: BufWriter<io::Stdout>,
+    listener: Option<TcpListener>,
+    connection: Option<TcpStream>,
+}
+
+impl Vm {
+    /// Moves left are moves right by the rest of the tape
+    #[inline(always)]
+    fn right(&mut self, n: usize) {
+        self.p += n;
+        if self.p >= TAPE_SIZE {
+            self.p -= TAPE_SIZE;
+        }
+    }
+
+    #[inline(always)]
+    fn add(&mut self, n: u8) {
+        self.tape[self.p] = self.tape[self.p].wrapping_add(n);
+    }
+
+    #[inline(always)]
+    fn scan(&mut self, step: usize) {
+        while self.tape[self.p] != 0 {
+            self.right(step);
+        }
+    }
+
+    #[inline(always)]
+    fn mul_add(&mut self, targets: &[(usize, u8)]) {
+        let value = self.tape[self.p];
+        if value == 0 {
+            return;
+        }
+        for &(offset, factor) in targets {
+            let mut target = self.p + offset;
+            if target >= TAPE_SIZE {
+                target -= TAPE_SIZE;
+            }
+            self.tape[target] = self.tape[target].wrapping_add(value.wrapping_mul(factor));
+        }
+        self.tape[self.p] = 0;
+    }
+
+    fn load(&mut self, start: usize, cells: &[u8]) {
+        for (i, &value) in cells.iter().enumerate() {
+            self.tape[(start + i) % TAPE_SIZE] = value;
+        }
+    }
+
+    fn cell(&self, offset: usize) -> u8 {
+        self.tape[(self.p + offset) % TAPE_SIZE]
+    }
+
+    fn output(&mut self, bytes: &[u8]) -> Result<(), Stop> {
+        let mut result = self.output.write_all(bytes);
+        if bytes.contains(&b'\n') {
+            result = result.and_then(|()| self.output.flush());
+        }
+        result.map_err(output_error)
+    }
+
+    /// Flush before waiting, so prompts show
+    fn flush(&mut self) -> Result<(), Stop> {
+        self.output.flush().map_err(output_error)
+    }
+
+    fn input(&mut self) -> Result<(), Stop> {
+        self.flush()?;
+        let mut byte = [0];
+        self.tape[self.p] = match self.input.read(&mut byte) {
+            Ok(0) => 0,
+            Ok(_) => byte[0],
+            Err(e) => return Err(Stop::Error(format!("I/O error: {}", e))),
+        };
+        Ok(())
+    }
+
+    /// `$`: listen on the address and port at the pointer, or stop
+    fn listen(&mut self) -> Result<(), Stop> {
+        if self.listener.take().is_some() {
+            return Ok(());
+        }
+        let addr = self.address();
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| network(&format!("Failed to bind {}", addr), e))?;
+        let bound = listener.local_addr().map_err(|e| network("Listen failed", e))?;
+        eprintln!("[trainfuck] Listening on {}", bound);
+        if addr.port() == 0 {
+            eprintln!("[trainfuck] port={}", bound.port());
+            let [high, low] = bound.port().to_be_bytes();
+            self.tape[(self.p + 4) % TAPE_SIZE] = high;
+            self.tape[(self.p + 5) % TAPE_SIZE] = low;
+        }
+        self.listener = Some(listener);
+        Ok(())
+    }
+
+    /// `@`: wait for a peer, or close the connection
+    fn accept(&mut self) -> Result<(), Stop> {
+        if self.connection.take().is_some() {
+            eprintln!("[trainfuck] Connection closed");
+            return Ok(());
+        }
+        self.flush()?;
+        if let Some(ref listener) = self.listener {
+            let (stream, peer) = listener.accept().map_err(|e| network("Accept failed", e))?;
+            eprintln!("[trainfuck] Accepted connection from {}", peer);
+            self.connection = Some(stream);
+        }
+        Ok(())
+    }
+
+    /// `%`: dial the address and port at the pointer, or hang up
+    fn connect(&mut self) -> Result<(), Stop> {
+        if self.connection.take().is_some() {
+            return Ok(());
+        }
+        self.flush()?;
+        let addr = self.address();
+        let stream = TcpStream::connect(addr).map_err(|e| network("Connect failed", e))?;
+        eprintln!("[trainfuck] Connected to {}", addr);
+        self.connection = Some(stream);
+        Ok(())
+    }
+
+    /// `` ` ``: a byte from the peer, 0 once it is gone
+    fn receive(&mut self) {
+        let _ = self.flush();
+        let mut byte = [0];
+        self.tape[self.p] = match self.connection.as_mut().map(|c| c.read(&mut byte)) {
+            Some(Ok(1)) => byte[0],
+            Some(Err(e)) => {
+                eprintln!("[trainfuck] Receive error: {}", e);
+                0
+            }
+            _ => 0,
+        };
+    }
+
+    /// `'`: send the current cell to the peer
+    fn send(&mut self) -> Result<(), Stop> {
+        let byte = self.tape[self.p];
+        match self.connection {
+            Some(ref mut stream) => stream
+                .write_all(&[byte])
+                .map_err(|e| network("Send failed", e)),
+            None => Ok(()),
+        }
+    }
+
+    fn address(&self) -> SocketAddrV4 {
+        let ip = Ipv4Addr::new(self.cell(0), self.cell(1), self.cell(2), self.cell(3));
+        SocketAddrV4::new(ip, u16::from_be_bytes([self.cell(4), self.cell(5)]))
+    }
+}
+
+fn output_error(e: io::Error) -> Stop {
+    match e.kind() {
+        io::ErrorKind::BrokenPipe => Stop::OutputClosed,
+        _ => Stop::Error(format!("I/O error: {}", e)),
+    }
+}
+
+fn network(context: &str, e: io::Error) -> Stop {
+    Stop::Error(format!("Network error: {}: {}", context, e))
+}
+
+fn main() {
+    let mut arguments = Vec::new();
+    for arg in std::env::args_os().skip(1) {
+        arguments.extend_from_slice(arg.as_encoded_bytes());
+        arguments.push(0);
+    }
+    let mut vm = Vm {
+        tape: vec![0; TAPE_SIZE],
+        p: 0,
+        input: Box::new(io::Cursor::new(arguments).chain(io::stdin())),
+        output: BufWriter::new(io::stdout()),
+        listener: None,
+        connection: None,
+    };
+    let code = match program(&mut vm).and_then(|()| vm.flush()) {
+        Ok(()) => 0,
+        // 128 + SIGPIPE, as the shell reports for tools the signal killed
+        Err(Stop::OutputClosed) => 141,
+        Err(Stop::Error(e)) => {
+            let _ = vm.flush();
+            eprintln!("Runtime error: {}", e);
+            1
+        }
+    };
+    std::process::exit(code);
+}
+"#;
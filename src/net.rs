@@ -0,0 +1,343 @@
+//! Network backends for the Trainfuck networking ops
+//!
+//! The VM never touches sockets directly: `$` and `%` ask its [`Network`]
+//! for a [`Listener`] or [`Stream`], and `@`, `` ` `` and `'` operate on
+//! those. [`TcpNetwork`] is the default; other backends (browser
+//! WebSockets, recorded sessions, ...) plug in by implementing the traits.
+//!
+//! A backend that cannot make progress without blocking should return
+//! `io::ErrorKind::WouldBlock`; the VM surfaces that as
+//! [`TrainfuckError::WouldBlock`](crate::interpreter::TrainfuckError::WouldBlock)
+//! so the op can be retried later.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often [`Listener::accept_timeout`] on a TCP listener checks for a
+/// connection
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+
+/// A byte stream behind `` ` `` and `'`
+pub trait Stream: Read + Write {
+    /// Address of the remote end, if the backend has one
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Close both directions; the stream is dropped afterwards
+    fn shutdown(&mut self) {}
+
+    /// Make reads give up with `WouldBlock` or `TimedOut` after `timeout`.
+    /// Backends that can't time out keep blocking.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        let _ = timeout;
+        Ok(())
+    }
+
+    /// Whether a read would return at once, with data, the end of the
+    /// stream or an error. Backends that can't tell without reading say no.
+    fn has_pending(&self) -> bool {
+        false
+    }
+
+    /// Name of the room the bytes read last were said in, on backends that
+    /// carry several conversations over one stream; see
+    /// [`VM::room_cell`](crate::interpreter::VM::room_cell)
+    fn room(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Whether the peer's data was dropped or held back for going over a
+    /// rate limit since the last call, or the connection was closed for it;
+    /// see [`NetStatus::Flooded`](crate::interpreter::NetStatus::Flooded)
+    fn over_limit(&mut self) -> bool {
+        false
+    }
+}
+
+/// A listener opened by `$`
+pub trait Listener {
+    /// Wait for the next incoming connection
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>>;
+
+    /// Like [`accept`](Self::accept), but give up after `timeout` (at once
+    /// for zero) and return `None`. Backends that can't wait with a timeout
+    /// block as `accept` does.
+    fn accept_timeout(&mut self, timeout: Duration) -> io::Result<Option<Box<dyn Stream>>> {
+        let _ = timeout;
+        self.accept().map(Some)
+    }
+
+    /// Address the listener is bound to, if the backend has one
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Creates listeners and connections for the VM
+pub trait Network {
+    fn listen(&mut self, addr: SocketAddr) -> io::Result<Box<dyn Listener>>;
+    fn connect(&mut self, addr: SocketAddrV4) -> io::Result<Box<dyn Stream>>;
+}
+
+/// Bind a TCP listener. IPv6 addresses take IPv6 connections only, so
+/// `[::]` and `0.0.0.0` can listen on the same port side by side.
+pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    match addr {
+        #[cfg(unix)]
+        SocketAddr::V6(addr) => bind_v6_only(addr),
+        addr => TcpListener::bind(addr),
+    }
+}
+
+#[cfg(unix)]
+fn bind_v6_only(addr: std::net::SocketAddrV6) -> io::Result<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    fn check(result: libc::c_int) -> io::Result<()> {
+        match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    let on: libc::c_int = 1;
+    let on_len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    unsafe {
+        let fd = libc::socket(libc::AF_INET6, libc::SOCK_STREAM, 0);
+        check(fd)?;
+        // Owns the socket from here on, closing it on the way out of an error
+        let listener = TcpListener::from_raw_fd(fd);
+        check(libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC))?;
+        for (level, option) in [
+            (libc::SOL_SOCKET, libc::SO_REUSEADDR),
+            (libc::IPPROTO_IPV6, libc::IPV6_V6ONLY),
+        ] {
+            let value = &on as *const libc::c_int as *const libc::c_void;
+            check(libc::setsockopt(fd, level, option, value, on_len))?;
+        }
+        let mut sockaddr: libc::sockaddr_in6 = std::mem::zeroed();
+        sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        sockaddr.sin6_port = addr.port().to_be();
+        sockaddr.sin6_flowinfo = addr.flowinfo();
+        sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+        sockaddr.sin6_scope_id = addr.scope_id();
+        check(libc::bind(
+            fd,
+            &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        ))?;
+        check(libc::listen(fd, 128))?;
+        Ok(listener)
+    }
+}
+
+/// Plain TCP via `std::net`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TcpNetwork;
+
+impl Network for TcpNetwork {
+    fn listen(&mut self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(bind(addr)?))
+    }
+
+    fn connect(&mut self, addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(TcpStream::connect(addr)?))
+    }
+}
+
+/// TCP whose sockets never block: `@`, `` ` `` and `'` report
+/// `WouldBlock` instead of waiting, for VMs sharing a thread under a
+/// [`Scheduler`](crate::scheduler::Scheduler). Connecting still waits for
+/// the handshake.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NonBlockingTcpNetwork;
+
+impl Network for NonBlockingTcpNetwork {
+    fn listen(&mut self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        let listener = bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Box::new(NonBlockingListener(listener)))
+    }
+
+    fn connect(&mut self, addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nonblocking(true)?;
+        Ok(Box::new(NonBlockingStream(stream)))
+    }
+}
+
+/// Accepted sockets don't inherit non-blocking mode everywhere, so set it
+struct NonBlockingListener(TcpListener);
+
+impl Listener for NonBlockingListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        let (stream, _) = self.0.accept()?;
+        stream.set_nonblocking(true)?;
+        Ok(Box::new(NonBlockingStream(stream)))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.0.local_addr().ok()
+    }
+}
+
+/// A socket already in non-blocking mode, which checking for pending data
+/// must leave that way
+struct NonBlockingStream(TcpStream);
+
+impl Read for NonBlockingStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for NonBlockingStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Stream for NonBlockingStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.0.peer_addr().ok()
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.0.shutdown(Shutdown::Both);
+    }
+
+    fn has_pending(&self) -> bool {
+        !matches!(self.0.peek(&mut [0]), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+impl Stream for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn shutdown(&mut self) {
+        // The peer may already be gone; nothing useful to do about it
+        let _ = TcpStream::shutdown(self, Shutdown::Both);
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn has_pending(&self) -> bool {
+        if self.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let peeked = self.peek(&mut [0]);
+        let _ = self.set_nonblocking(false);
+        !matches!(peeked, Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        let (stream, _) = TcpListener::accept(self)?;
+        Ok(Box::new(stream))
+    }
+
+    fn accept_timeout(&mut self, timeout: Duration) -> io::Result<Option<Box<dyn Stream>>> {
+        let deadline = Instant::now() + timeout;
+        self.set_nonblocking(true)?;
+        let result = loop {
+            match TcpListener::accept(self) {
+                Ok((stream, _)) => break stream.set_nonblocking(false).map(|()| Some(stream)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break Ok(None);
+                    }
+                    thread::sleep(ACCEPT_POLL.min(deadline - now));
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.set_nonblocking(false)?;
+        Ok(result?.map(|stream| Box::new(stream) as Box<dyn Stream>))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpListener::local_addr(self).ok()
+    }
+}
+
+/// Listeners on several addresses at once, accepting from whichever has a
+/// connection first; see [`VM::listen_addrs`](crate::interpreter::VM::listen_addrs).
+/// Backends whose listeners can't wait with a timeout block on the first.
+pub struct MultiListener {
+    listeners: Vec<Box<dyn Listener>>,
+    /// Where the next poll starts, so a busy listener can't starve the rest
+    next: usize,
+}
+
+impl MultiListener {
+    pub fn new(listeners: Vec<Box<dyn Listener>>) -> Self {
+        MultiListener { listeners, next: 0 }
+    }
+
+    /// Ask each listener once for a waiting connection. `WouldBlock` when
+    /// all of them would block.
+    fn poll(&mut self) -> io::Result<Option<Box<dyn Stream>>> {
+        let len = self.listeners.len();
+        let mut blocked = 0;
+        for i in 0..len {
+            let index = (self.next + i) % len;
+            match self.listeners[index].accept_timeout(Duration::ZERO) {
+                Ok(Some(stream)) => {
+                    self.next = (index + 1) % len;
+                    return Ok(Some(stream));
+                }
+                Ok(None) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => blocked += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        if blocked == len {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(None)
+    }
+}
+
+impl Listener for MultiListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        loop {
+            if let Some(stream) = self.poll()? {
+                return Ok(stream);
+            }
+            thread::sleep(ACCEPT_POLL);
+        }
+    }
+
+    fn accept_timeout(&mut self, timeout: Duration) -> io::Result<Option<Box<dyn Stream>>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(stream) = self.poll()? {
+                return Ok(Some(stream));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(ACCEPT_POLL.min(deadline - now));
+        }
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.listeners
+            .first()
+            .and_then(|listener| listener.local_addr())
+    }
+}
@@ -0,0 +1,152 @@
+//! `trainfuck obfuscate`: equivalent but unreadable rewrites
+//!
+//! Works on the parsed ops, so comments are dropped and runs are re-emitted
+//! in a disguised form, after the program's directives:
+//!
+//! - constants are built from interleaved `+` and `-` runs, sometimes the
+//!   long way round the 256-value wrap
+//! - moves overshoot and come back, and `+-`/`><` pairs are sprinkled in
+//! - decoy loops full of random code are placed where the current cell is
+//!   known to be zero (program start and right after a `]`), so they never run
+//! - the result is wrapped at random widths with a few stray comment letters
+//!
+//! The same seed always produces the same output.
+
+use crate::rng::XorShift;
+use trainfuck::interpreter::{self, Op};
+use trainfuck::program::Program;
+
+/// Rewrite `program` as obfuscated source
+pub fn obfuscate(program: &Program, seed: u64) -> String {
+    let mut obfuscator = Obfuscator {
+        rng: XorShift::new(seed),
+        code: Vec::new(),
+        zero: true,
+    };
+    obfuscator.block(program.ops());
+    program.metadata().directives.to_source() + &obfuscator.layout()
+}
+
+/// Letters mixed into the layout; none of them is a command
+const FILLER: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+struct Obfuscator {
+    rng: XorShift,
+    code: Vec<u8>,
+    /// Whether the current cell is known to be zero
+    zero: bool,
+}
+
+impl Obfuscator {
+    fn block(&mut self, ops: &[Op]) {
+        for op in ops {
+            self.noise();
+            match op {
+                Op::Increment(n) => self.constant(i16::from(*n)),
+                Op::Decrement(n) => self.constant(-i16::from(*n)),
+                Op::MoveRight(n) => self.movement(b'>', b'<', *n),
+                Op::MoveLeft(n) => self.movement(b'<', b'>', *n),
+                Op::Loop(body) => {
+                    self.code.push(b'[');
+                    self.zero = false;
+                    self.block(body);
+                    self.noise();
+                    self.code.push(b']');
+                }
+                op => match op.command() {
+                    Some(command) => interpreter::push_command(&mut self.code, command),
+                    // Only produced by the optimizer
+                    None => unreachable!("obfuscating optimized ops"),
+                },
+            }
+            self.zero = matches!(op, Op::Loop(_));
+        }
+        self.noise();
+    }
+
+    /// A canceling pair, or a decoy loop when it can't run
+    fn noise(&mut self) {
+        if self.zero && self.rng.one_in(3) {
+            self.code.push(b'[');
+            self.decoy(2);
+            self.code.push(b']');
+        } else if self.rng.one_in(4) {
+            let pair: &[u8] = match self.rng.below(4) {
+                0 => b"+-",
+                1 => b"-+",
+                2 => b"><",
+                _ => b"<>",
+            };
+            self.code.extend_from_slice(pair);
+        }
+    }
+
+    /// Random but balanced code for a loop body that never runs
+    fn decoy(&mut self, depth: u32) {
+        for _ in 0..3 + self.rng.below(10) {
+            match self.rng.below(12) {
+                0 if depth > 0 => {
+                    self.code.push(b'[');
+                    self.decoy(depth - 1);
+                    self.code.push(b']');
+                }
+                n => self.code.push(b"+-<>.,+-<>+-"[n as usize]),
+            }
+        }
+    }
+
+    /// Add `delta` to the current cell
+    fn constant(&mut self, delta: i16) {
+        // Sometimes go the long way round, as long as it stays reasonable
+        let delta = match delta.signum() * 256 - delta {
+            around if around.abs() <= 160 && self.rng.one_in(3) => -around,
+            _ => delta,
+        };
+        let extra = self.rng.below(3) as usize;
+        let plus = delta.max(0) as usize + extra;
+        let minus = (-delta).max(0) as usize + extra;
+        self.interleave(b'+', plus, b'-', minus);
+    }
+
+    fn movement(&mut self, forward: u8, back: u8, n: usize) {
+        let extra = self.rng.below(3) as usize;
+        self.interleave(forward, n + extra, back, extra);
+    }
+
+    /// `a` and `b` repeated `a_count` and `b_count` times, in chunks
+    fn interleave(&mut self, a: u8, mut a_count: usize, b: u8, mut b_count: usize) {
+        while a_count + b_count > 0 {
+            let (byte, count) = match self.rng.below((a_count + b_count) as u64) < a_count as u64 {
+                true => (a, &mut a_count),
+                false => (b, &mut b_count),
+            };
+            let chunk = 1 + self.rng.below(*count as u64) as usize;
+            *count -= chunk;
+            self.code.extend(std::iter::repeat_n(byte, chunk));
+        }
+    }
+
+    /// Wrap the code at random widths, with the odd stray letter
+    fn layout(&mut self) -> String {
+        let mut out = String::with_capacity(self.code.len() * 9 / 8);
+        let mut width = 0;
+        let mut line = 40 + self.rng.below(40) as usize;
+        for &byte in &self.code {
+            if self.rng.one_in(40) {
+                out.push(FILLER[self.rng.below(FILLER.len() as u64) as usize] as char);
+                width += 1;
+            }
+            out.push(byte as char);
+            width += 1;
+            if width >= line {
+                out.push('\n');
+                width = 0;
+                line = 40 + self.rng.below(40) as usize;
+            }
+        }
+        if width > 0 {
+            out.push('\n');
+        }
+        out
+    }
+}
@@ -0,0 +1,918 @@
+//! Superinstruction fusion
+//!
+//! The parser already folds runs of `+-<>`; this pass goes one level up and
+//! replaces common loop idioms with single ops the VM runs over the tape
+//! slice directly:
+//!
+//! - `[-]` / `[+]` becomes [`Op::Clear`]
+//! - `[>]` / `[<<]` becomes [`Op::ScanRight`] / [`Op::ScanLeft`]
+//! - `[->+>++<<]` and other balanced copy loops become [`Op::MulAdd`]
+//!
+//! Each fused op counts as a single step towards `max_steps`. Before that,
+//! [`merge_adjacent`] merges the neighbouring `+-` and `<>` runs the parser
+//! keeps apart.
+//!
+//! At `-O2`, [`evaluate_prefix`] additionally runs the input-free start of
+//! a program at compile time, so the constant strings most programs begin
+//! by building cost nothing at run time, and [`fold_known_cells`] drops
+//! loops that can never run and unrolls short counted ones.
+//!
+//! The `_with_report` version of each pass also records what it changed,
+//! and where, in a [`Report`]. [`pipeline`](crate::pipeline) chooses which
+//! passes run.
+
+use crate::features::FEATURE_CELLS;
+use crate::interpreter::{Op, VM};
+use crate::span::{SourceMap, Span};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::iter;
+use std::rc::Rc;
+
+/// What the passes run through a `_with_report` function did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub passes: Vec<PassReport>,
+}
+
+/// What one pass did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassReport {
+    pub pass: &'static str,
+    /// Ops in the program before and after the pass, counting loop bodies
+    pub ops_before: usize,
+    pub ops_after: usize,
+    /// Each change with the span of the source it was made to, in source
+    /// order
+    pub changes: Vec<(Span, Change)>,
+}
+
+/// A rewrite made by a pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// This many neighbouring `+-` or `<>` ops were merged into one
+    Merged(usize),
+    /// This many neighbouring `+-` or `<>` ops cancelled out
+    Cancelled(usize),
+    /// A loop became [`Op::Clear`]
+    Clear,
+    /// A loop became [`Op::ScanRight`] or [`Op::ScanLeft`]
+    Scan,
+    /// A loop became an [`Op::MulAdd`] into this many cells
+    MulAdd(usize),
+    /// This many ops ran at compile time, leaving the second number
+    Evaluated(usize, usize),
+    /// A loop that is never entered was dropped
+    DeadLoop,
+    /// A clear, scan or copy of a cell that is already zero was dropped
+    DeadOp,
+    /// A loop that runs this many times was unrolled
+    Unrolled(u8),
+}
+
+impl Report {
+    /// Add what `pass` did to a program of `ops_before` ops, leaving `after`
+    pub(crate) fn record(
+        &mut self,
+        pass: &'static str,
+        ops_before: usize,
+        after: &[Op],
+        mut changes: Vec<(Span, Change)>,
+    ) {
+        // Changes inside unrolled loops are made once per copy
+        changes.sort_by_key(|&(span, _)| (span.start, span.end));
+        changes.dedup();
+        self.passes.push(PassReport {
+            pass,
+            ops_before,
+            ops_after: after.iter().map(count_ops).sum(),
+            changes,
+        });
+    }
+}
+
+impl PassReport {
+    /// How many of the changes are of the same kind as `change`
+    pub fn count(&self, change: Change) -> usize {
+        let kind = std::mem::discriminant(&change);
+        self.changes
+            .iter()
+            .filter(|(_, c)| std::mem::discriminant(c) == kind)
+            .count()
+    }
+}
+
+impl fmt::Display for PassReport {
+    /// `fuse: 3 clear loops, 1 scan loop; 45 -> 30 ops`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kinds = [
+            (Change::Merged(0), "merged run"),
+            (Change::Cancelled(0), "cancelled run"),
+            (Change::Clear, "clear loop"),
+            (Change::Scan, "scan loop"),
+            (Change::MulAdd(0), "multiply-add loop"),
+            (Change::Evaluated(0, 0), "evaluated prefix"),
+            (Change::DeadLoop, "dead loop"),
+            (Change::DeadOp, "dead op"),
+            (Change::Unrolled(0), "unrolled loop"),
+        ];
+        let counts: Vec<String> = kinds
+            .iter()
+            .map(|&(kind, name)| (self.count(kind), name))
+            .filter(|&(n, _)| n > 0)
+            .map(|(n, name)| format!("{} {}{}", n, name, if n == 1 { "" } else { "s" }))
+            .collect();
+        match counts.is_empty() {
+            true => write!(f, "{}: no changes", self.pass)?,
+            false => write!(f, "{}: {}", self.pass, counts.join(", "))?,
+        }
+        write!(f, "; {} -> {} ops", self.ops_before, self.ops_after)
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Change::Merged(n) => write!(f, "{} ops merged into one", n),
+            Change::Cancelled(n) => write!(f, "{} ops cancel out; removed", n),
+            Change::Clear => write!(f, "loop became a clear"),
+            Change::Scan => write!(f, "loop became a scan"),
+            Change::MulAdd(1) => write!(f, "loop became a multiply-add into 1 cell"),
+            Change::MulAdd(n) => write!(f, "loop became a multiply-add into {} cells", n),
+            Change::Evaluated(ops, left) => {
+                write!(f, "{} ops ran at compile time, leaving {}", ops, left)
+            }
+            Change::DeadLoop => write!(f, "loop is never entered; removed"),
+            Change::DeadOp => write!(f, "cell is already zero; removed"),
+            Change::Unrolled(n) => write!(f, "loop runs {} times; unrolled", n),
+        }
+    }
+}
+
+/// Loop idioms [`fuse_loop`] rewrites
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Idioms {
+    pub clear: bool,
+    pub scan: bool,
+    pub mul_add: bool,
+}
+
+impl Idioms {
+    pub(crate) const ALL: Idioms = Idioms {
+        clear: true,
+        scan: true,
+        mul_add: true,
+    };
+    pub(crate) const CLEAR: Idioms = Idioms {
+        clear: true,
+        scan: false,
+        mul_add: false,
+    };
+    pub(crate) const SCAN: Idioms = Idioms {
+        clear: false,
+        scan: true,
+        mul_add: false,
+    };
+    pub(crate) const MUL_ADD: Idioms = Idioms {
+        clear: false,
+        scan: false,
+        mul_add: true,
+    };
+}
+
+/// Rewrite `ops` with superinstructions, recursing into loop bodies
+pub fn optimize(ops: Vec<Op>) -> Vec<Op> {
+    optimize_list(
+        ops,
+        &mut iter::repeat(Span::default()),
+        &mut Vec::new(),
+        Idioms::ALL,
+        &mut Vec::new(),
+    )
+}
+
+/// Like [`optimize`], carrying each op's span along; a fused loop keeps
+/// the span of the whole loop
+pub fn optimize_with_spans(ops: Vec<Op>, spans: &SourceMap) -> (Vec<Op>, SourceMap) {
+    fuse_idioms(ops, spans, Idioms::ALL, &mut Vec::new())
+}
+
+/// Like [`optimize_with_spans`], adding what it fused to `report` as the
+/// `clear`, `scan` and `muladd` passes
+pub fn optimize_with_report(
+    ops: Vec<Op>,
+    spans: &SourceMap,
+    report: &mut Report,
+) -> (Vec<Op>, SourceMap) {
+    let passes = [
+        ("clear", Idioms::CLEAR),
+        ("scan", Idioms::SCAN),
+        ("muladd", Idioms::MUL_ADD),
+    ];
+    let mut program = (ops, spans.clone());
+    for (pass, idioms) in passes {
+        let before = program.0.iter().map(count_ops).sum();
+        let mut changes = Vec::new();
+        program = fuse_idioms(program.0, &program.1, idioms, &mut changes);
+        report.record(pass, before, &program.0, changes);
+    }
+    program
+}
+
+/// Rewrite the loops matching `idioms`, adding each to `changes`
+pub(crate) fn fuse_idioms(
+    ops: Vec<Op>,
+    spans: &SourceMap,
+    idioms: Idioms,
+    changes: &mut Vec<(Span, Change)>,
+) -> (Vec<Op>, SourceMap) {
+    let mut out = Vec::with_capacity(spans.len());
+    let ops = optimize_list(
+        ops,
+        &mut spans.spans().iter().copied(),
+        &mut out,
+        idioms,
+        changes,
+    );
+    (ops, SourceMap::new(out))
+}
+
+fn optimize_list(
+    ops: Vec<Op>,
+    spans: &mut dyn Iterator<Item = Span>,
+    out: &mut Vec<Span>,
+    idioms: Idioms,
+    changes: &mut Vec<(Span, Change)>,
+) -> Vec<Op> {
+    ops.into_iter()
+        .map(|op| {
+            let span = spans.next().unwrap_or_default();
+            out.push(span);
+            match op {
+                Op::Loop(body) => {
+                    let mut body_spans = Vec::new();
+                    let body = optimize_list(body, spans, &mut body_spans, idioms, changes);
+                    let op = fuse_loop(body, idioms);
+                    match op {
+                        Op::Loop(_) => out.extend(body_spans),
+                        Op::Clear => changes.push((span, Change::Clear)),
+                        Op::MulAdd(ref targets) => {
+                            changes.push((span, Change::MulAdd(targets.len())))
+                        }
+                        _ => changes.push((span, Change::Scan)),
+                    }
+                    op
+                }
+                op => op,
+            }
+        })
+        .collect()
+}
+
+fn fuse_loop(body: Vec<Op>, idioms: Idioms) -> Op {
+    match body.as_slice() {
+        // An odd step always reaches zero; an even one may cycle forever
+        [Op::Decrement(n)] | [Op::Increment(n)] if idioms.clear && n % 2 == 1 => return Op::Clear,
+        [Op::MoveRight(n)] if idioms.scan => return Op::ScanRight(*n),
+        [Op::MoveLeft(n)] if idioms.scan => return Op::ScanLeft(*n),
+        _ => {}
+    }
+    match mul_add_targets(&body) {
+        Some(targets) if idioms.mul_add => Op::MulAdd(targets),
+        _ => Op::Loop(body),
+    }
+}
+
+/// Merge neighbouring `+`/`-` ops and neighbouring `>`/`<` ops, including
+/// opposite ones, recursing into loop bodies: `+-+` becomes `+`, and
+/// `>+-<` disappears. The parser only folds runs of one command.
+pub fn merge_adjacent(ops: Vec<Op>, spans: &SourceMap) -> (Vec<Op>, SourceMap) {
+    merge_adjacent_with_report(ops, spans, &mut Report::default())
+}
+
+/// Like [`merge_adjacent`], adding what it merged to `report` as the `fuse`
+/// pass
+pub fn merge_adjacent_with_report(
+    ops: Vec<Op>,
+    spans: &SourceMap,
+    report: &mut Report,
+) -> (Vec<Op>, SourceMap) {
+    let before = ops.iter().map(count_ops).sum();
+    let mut changes = Vec::new();
+    let merged = merge(ops, spans, &mut changes);
+    report.record("fuse", before, &merged.0, changes);
+    merged
+}
+
+pub(crate) fn merge(
+    ops: Vec<Op>,
+    spans: &SourceMap,
+    changes: &mut Vec<(Span, Change)>,
+) -> (Vec<Op>, SourceMap) {
+    let merged = merge_list(ops, &mut spans.spans().iter().copied(), changes);
+    let mut out = Vec::with_capacity(spans.len());
+    let ops = merged
+        .into_iter()
+        .map(|(op, op_spans)| {
+            out.extend(op_spans);
+            op
+        })
+        .collect();
+    (ops, SourceMap::new(out))
+}
+
+/// Merged ops, each with its spans in pre-order
+fn merge_list(
+    ops: Vec<Op>,
+    spans: &mut dyn Iterator<Item = Span>,
+    changes: &mut Vec<(Span, Change)>,
+) -> Vec<(Op, Vec<Span>)> {
+    // Each op with its spans and how many source ops it stands for
+    let mut merged: Vec<(Op, Vec<Span>, usize)> = Vec::with_capacity(ops.len());
+    for op in ops {
+        let span = spans.next().unwrap_or_default();
+        if let Op::Loop(body) = op {
+            let mut loop_spans = vec![span];
+            let body = merge_list(body, spans, changes)
+                .into_iter()
+                .map(|(op, spans)| {
+                    loop_spans.extend(spans);
+                    op
+                })
+                .collect();
+            merged.push((Op::Loop(body), loop_spans, 1));
+            continue;
+        }
+        let Some((last, last_spans, count)) = merged.last_mut() else {
+            merged.push((op, vec![span], 1));
+            continue;
+        };
+        match combine(last, &op) {
+            Some(Some(combined)) => {
+                *last = combined;
+                last_spans[0] = last_spans[0].to(span);
+                *count += 1;
+            }
+            Some(None) => {
+                let (_, last_spans, count) = merged.pop().expect("just looked at it");
+                changes.push((last_spans[0].to(span), Change::Cancelled(count + 1)));
+            }
+            None => merged.push((op, vec![span], 1)),
+        }
+    }
+    merged
+        .into_iter()
+        .map(|(op, spans, count)| {
+            if count > 1 {
+                changes.push((spans[0], Change::Merged(count)));
+            }
+            (op, spans)
+        })
+        .collect()
+}
+
+/// The op doing what `a` then `b` do, `Some(None)` if they cancel out, or
+/// None if they can't be merged
+fn combine(a: &Op, b: &Op) -> Option<Option<Op>> {
+    let step = |op: &Op| match *op {
+        Op::Increment(n) => Some(n),
+        Op::Decrement(n) => Some(n.wrapping_neg()),
+        _ => None,
+    };
+    let offset = |op: &Op| match *op {
+        Op::MoveRight(n) => Some(n as i128),
+        Op::MoveLeft(n) => Some(-(n as i128)),
+        _ => None,
+    };
+    if let (Some(a), Some(b)) = (step(a), step(b)) {
+        return Some(match a.wrapping_add(b) {
+            0 => None,
+            delta if delta <= 128 => Some(Op::Increment(delta)),
+            delta => Some(Op::Decrement(delta.wrapping_neg())),
+        });
+    }
+    let (a, b) = (offset(a)?, offset(b)?);
+    Some(match a + b {
+        0 => None,
+        net if net > 0 => Some(Op::MoveRight(usize::try_from(net).ok()?)),
+        net => Some(Op::MoveLeft(usize::try_from(-net).ok()?)),
+    })
+}
+
+/// Targets of a loop that decrements its own cell by one per iteration,
+/// adds constants to cells at fixed offsets and returns to where it started
+fn mul_add_targets(body: &[Op]) -> Option<Vec<(isize, u8)>> {
+    let mut offset = 0isize;
+    let mut deltas: Vec<(isize, u8)> = Vec::new();
+    for op in body {
+        let change = match op {
+            Op::MoveRight(n) => {
+                offset = offset.checked_add_unsigned(*n)?;
+                continue;
+            }
+            Op::MoveLeft(n) => {
+                offset = offset.checked_sub_unsigned(*n)?;
+                continue;
+            }
+            Op::Increment(n) => *n,
+            Op::Decrement(n) => n.wrapping_neg(),
+            _ => return None,
+        };
+        match deltas.iter_mut().find(|(o, _)| *o == offset) {
+            Some((_, delta)) => *delta = delta.wrapping_add(change),
+            None => deltas.push((offset, change)),
+        }
+    }
+
+    if offset != 0 {
+        return None;
+    }
+    let origin = deltas.iter().position(|&(o, _)| o == 0)?;
+    if deltas.remove(origin).1 != u8::MAX {
+        return None;
+    }
+    deltas.retain(|&(_, delta)| delta != 0);
+    Some(deltas)
+}
+
+/// Steps the partial evaluator may spend before leaving the rest of the
+/// program to run time
+pub const EVALUATION_BUDGET: u64 = 1_000_000;
+
+/// Run the longest prefix of top-level ops that needs no input or network
+/// at compile time, replacing it with the output it printed, the tape it
+/// left behind and a move to where the pointer ended up. The rest of the
+/// program is kept as is.
+///
+/// The result only behaves like the original on a tape of `tape_size`
+/// cells, since pointer wrapping depends on it.
+pub fn evaluate_prefix(ops: Vec<Op>, spans: &SourceMap, tape_size: usize) -> (Vec<Op>, SourceMap) {
+    evaluate_prefix_with_report(ops, spans, tape_size, &mut Report::default())
+}
+
+/// Like [`evaluate_prefix`], adding the prefix it evaluated to `report` as
+/// the `evaluate` pass
+pub fn evaluate_prefix_with_report(
+    ops: Vec<Op>,
+    spans: &SourceMap,
+    tape_size: usize,
+    report: &mut Report,
+) -> (Vec<Op>, SourceMap) {
+    let before = ops.iter().map(count_ops).sum();
+    let mut changes = Vec::new();
+    let evaluated = evaluate(ops, spans, tape_size, EVALUATION_BUDGET, &mut changes);
+    report.record("evaluate", before, &evaluated.0, changes);
+    evaluated
+}
+
+/// [`evaluate_prefix`] spending at most `budget` steps
+pub(crate) fn evaluate(
+    ops: Vec<Op>,
+    spans: &SourceMap,
+    tape_size: usize,
+    budget: u64,
+    changes: &mut Vec<(Span, Change)>,
+) -> (Vec<Op>, SourceMap) {
+    // First find how far evaluation gets, then replay exactly that far on
+    // a fresh VM so a prefix that ran out of budget leaves no trace
+    let mut vm = evaluation_vm(tape_size, budget).0;
+    let prefix = ops
+        .iter()
+        .take_while(|op| !needs_io(op) && vm.execute(std::slice::from_ref(*op)).is_ok())
+        .count();
+    if prefix == 0 {
+        return (ops, spans.clone());
+    }
+
+    let (mut vm, output) = evaluation_vm(tape_size, budget);
+    vm.execute(&ops[..prefix])
+        .expect("prefix completed within budget before");
+
+    let mut folded = Vec::new();
+    let output = output.take();
+    if !output.is_empty() {
+        folded.push(Op::Print(output));
+    }
+    let tape = vm.tape();
+    if let Some(first) = tape.iter().position(|&c| c != 0) {
+        let last = tape.iter().rposition(|&c| c != 0).unwrap();
+        folded.push(Op::Load(first, tape[first..=last].to_vec()));
+    }
+    if vm.pointer() != 0 {
+        folded.push(Op::MoveRight(vm.pointer()));
+    }
+
+    // Folded ops stand for the whole evaluated prefix
+    let consumed: usize = ops[..prefix].iter().map(count_ops).sum();
+    let prefix_spans = &spans.spans()[..consumed.min(spans.len())];
+    let span = match (prefix_spans.first(), prefix_spans.last()) {
+        (Some(first), Some(last)) => first.to(*last),
+        _ => Span::default(),
+    };
+    let mut new_spans = vec![span; folded.len()];
+    new_spans.extend(spans.spans().iter().skip(consumed));
+    changes.push((span, Change::Evaluated(consumed, folded.len())));
+
+    folded.extend(ops.into_iter().skip(prefix));
+    (folded, SourceMap::new(new_spans))
+}
+
+fn evaluation_vm(tape_size: usize, budget: u64) -> (VM, Rc<RefCell<Vec<u8>>>) {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = VM::with_tape_size(tape_size);
+    vm.output = Box::new(Collect(output.clone()));
+    vm.input = Box::new(io::empty());
+    vm.network_enabled = false;
+    vm.max_steps = Some(budget);
+    (vm, output)
+}
+
+/// Whether the op (or anything in its body) reads input, uses the network
+/// or touches memory other VMs can change
+fn needs_io(op: &Op) -> bool {
+    match op {
+        Op::Input
+        | Op::CompareSwap
+        | Op::Spawn
+        | Op::SendMessage
+        | Op::ReceiveMessage
+        | Op::KvPut
+        | Op::KvGet
+        | Op::Http
+        | Op::Unread
+        | Op::Resolve
+        | Op::SelectChannel
+        | Op::Channels
+        | Op::Features(_) => true,
+        Op::Loop(body) => body.iter().any(needs_io),
+        op => op.is_network(),
+    }
+}
+
+/// Number of ops in the tree rooted at `op`, i.e. its entries in a source map
+pub(crate) fn count_ops(op: &Op) -> usize {
+    match op {
+        Op::Loop(body) => 1 + body.iter().map(count_ops).sum::<usize>(),
+        _ => 1,
+    }
+}
+
+/// Iterations up to which [`fold_known_cells`] unrolls a counted loop
+pub const UNROLL_MAX_ITERATIONS: u8 = 8;
+
+/// Ops an unrolled loop may grow to
+pub const UNROLL_MAX_OPS: usize = 64;
+
+/// Dataflow pass over cells whose value is known at compile time: every
+/// cell is zero at program start, and cleared, scanned-to and exited-loop
+/// cells are zero afterwards. Loops (and clears, scans and copies) on a
+/// cell known to be zero are dropped, and a loop counting a known small
+/// value down to zero is replaced by that many copies of its body.
+///
+/// Like [`evaluate_prefix`], the result is only valid for a tape of
+/// `tape_size` cells.
+pub fn fold_known_cells(ops: Vec<Op>, spans: &SourceMap, tape_size: usize) -> (Vec<Op>, SourceMap) {
+    fold_known_cells_with_report(ops, spans, tape_size, &mut Report::default())
+}
+
+/// Like [`fold_known_cells`], adding the loops and ops it dropped or
+/// unrolled to `report` as the `fold` pass
+pub fn fold_known_cells_with_report(
+    ops: Vec<Op>,
+    spans: &SourceMap,
+    tape_size: usize,
+    report: &mut Report,
+) -> (Vec<Op>, SourceMap) {
+    let before = ops.iter().map(count_ops).sum();
+    let mut changes = Vec::new();
+    let unroll = (UNROLL_MAX_ITERATIONS, UNROLL_MAX_OPS);
+    let folded = fold(ops, spans, tape_size, unroll, &mut changes);
+    report.record("fold", before, &folded.0, changes);
+    folded
+}
+
+/// [`fold_known_cells`] unrolling loops of up to `unroll.0` iterations
+/// that grow to at most `unroll.1` ops
+pub(crate) fn fold(
+    ops: Vec<Op>,
+    spans: &SourceMap,
+    tape_size: usize,
+    unroll: (u8, usize),
+    changes: &mut Vec<(Span, Change)>,
+) -> (Vec<Op>, SourceMap) {
+    let mut folder = Folder {
+        tape_size: tape_size as isize,
+        unroll,
+        out: Vec::with_capacity(spans.len()),
+        changes: Vec::new(),
+    };
+    let mut state = Known::zeroed(tape_size as isize);
+    let mut spans = spans.spans().iter().copied();
+    let ops = folder.block(ops, &mut spans, &mut state);
+    changes.append(&mut folder.changes);
+    (ops, SourceMap::new(folder.out))
+}
+
+/// What the pass knows about the tape, relative to a base cell that is
+/// cell 0 until a data-dependent pointer move loses track of it
+#[derive(Debug, Clone)]
+struct Known {
+    tape_size: isize,
+    pointer: isize,
+    /// Whether the base is cell 0
+    absolute: bool,
+    cells: HashMap<isize, Option<u8>>,
+    /// What every cell not in `cells` holds
+    rest: Option<u8>,
+}
+
+impl Known {
+    fn zeroed(tape_size: isize) -> Self {
+        Known {
+            tape_size,
+            pointer: 0,
+            absolute: true,
+            cells: HashMap::new(),
+            rest: Some(0),
+        }
+    }
+
+    fn get(&self, offset: isize) -> Option<u8> {
+        let cell = (self.pointer + offset).rem_euclid(self.tape_size);
+        self.cells.get(&cell).copied().unwrap_or(self.rest)
+    }
+
+    fn set(&mut self, offset: isize, value: Option<u8>) {
+        let cell = (self.pointer + offset).rem_euclid(self.tape_size);
+        self.cells.insert(cell, value);
+    }
+
+    fn current(&self) -> Option<u8> {
+        self.get(0)
+    }
+
+    fn step(&mut self, by: isize) {
+        self.pointer = (self.pointer + by).rem_euclid(self.tape_size);
+    }
+
+    /// Lose track of everything, including where the pointer is
+    fn forget(&mut self) {
+        self.pointer = 0;
+        self.absolute = false;
+        self.cells.clear();
+        self.rest = None;
+    }
+}
+
+struct Folder {
+    tape_size: isize,
+    /// Most iterations and ops of a loop to unroll
+    unroll: (u8, usize),
+    out: Vec<Span>,
+    changes: Vec<(Span, Change)>,
+}
+
+impl Folder {
+    fn block(
+        &mut self,
+        ops: Vec<Op>,
+        spans: &mut dyn Iterator<Item = Span>,
+        state: &mut Known,
+    ) -> Vec<Op> {
+        let mut folded = Vec::with_capacity(ops.len());
+        for op in ops {
+            let span = spans.next().unwrap_or_default();
+            match op {
+                Op::Loop(body) => self.fold_loop(body, span, spans, state, &mut folded),
+                op => match self.fold_op(op, state) {
+                    Some(op) => {
+                        self.out.push(span);
+                        folded.push(op);
+                    }
+                    None => self.changes.push((span, Change::DeadOp)),
+                },
+            }
+        }
+        folded
+    }
+
+    /// Track the effect of a loop-free op, or drop it if it does nothing
+    fn fold_op(&mut self, op: Op, state: &mut Known) -> Option<Op> {
+        let zero = state.current() == Some(0);
+        match &op {
+            Op::MoveRight(n) => state.step(self.wrap(*n)),
+            Op::MoveLeft(n) => state.step(-self.wrap(*n)),
+            Op::Increment(n) => state.set(0, state.current().map(|v| v.wrapping_add(*n))),
+            Op::Decrement(n) => state.set(0, state.current().map(|v| v.wrapping_sub(*n))),
+            Op::Input | Op::Receive | Op::Peek => state.set(0, None),
+            Op::CompareSwap => state.set(1, None),
+            Op::Spawn | Op::SelectChannel => state.set(0, None),
+            Op::ReceiveMessage | Op::Channels => {
+                state.set(0, None);
+                state.set(1, None);
+            }
+            Op::KvGet
+            | Op::Http
+            | Op::Base64Encode
+            | Op::Base64Decode
+            | Op::Xor
+            | Op::Trap
+            | Op::ReceiveLine
+            | Op::ReceiveBlock
+            | Op::Resolve => state.forget(),
+            Op::Checksum => (0..4).for_each(|offset| state.set(offset, None)),
+            Op::Features(_) => {
+                (0..FEATURE_CELLS as isize).for_each(|offset| state.set(offset, None))
+            }
+            Op::Clear | Op::ScanRight(_) | Op::ScanLeft(_) | Op::MulAdd(_) if zero => return None,
+            Op::Clear => state.set(0, Some(0)),
+            Op::ScanRight(_) | Op::ScanLeft(_) => {
+                // Wherever it stops, the cell there is zero
+                state.forget();
+                state.set(0, Some(0));
+            }
+            Op::MulAdd(targets) => {
+                let source = state.current();
+                for &(offset, factor) in targets {
+                    let value = source
+                        .zip(state.get(offset))
+                        .map(|(s, v)| v.wrapping_add(s.wrapping_mul(factor)));
+                    state.set(offset, value);
+                }
+                state.set(0, Some(0));
+            }
+            Op::Load(start, cells) => {
+                if state.absolute {
+                    let base = *start as isize - state.pointer;
+                    for (i, &value) in cells.iter().enumerate() {
+                        state.set(base + i as isize, Some(value));
+                    }
+                } else {
+                    state.forget();
+                }
+            }
+            Op::Output
+            | Op::Send
+            | Op::Flush
+            | Op::SendBlock
+            | Op::SendMessage
+            | Op::Yield
+            | Op::KvPut
+            | Op::Unread
+            | Op::Listen
+            | Op::Accept
+            | Op::Connect
+            | Op::Print(_)
+            | Op::Loop(_) => {}
+        }
+        Some(op)
+    }
+
+    fn fold_loop(
+        &mut self,
+        body: Vec<Op>,
+        span: Span,
+        spans: &mut dyn Iterator<Item = Span>,
+        state: &mut Known,
+        folded: &mut Vec<Op>,
+    ) {
+        let body_spans: Vec<Span> = spans.take(body.iter().map(count_ops).sum()).collect();
+        match state.current() {
+            // Never entered
+            Some(0) => {
+                self.changes.push((span, Change::DeadLoop));
+                return;
+            }
+            Some(n) if self.unrollable(&body, n) => {
+                self.changes.push((span, Change::Unrolled(n)));
+                let copies = usize::from(n);
+                let unrolled = iter::repeat_n(body, copies).flatten().collect();
+                let mut unrolled_spans = body_spans
+                    .iter()
+                    .copied()
+                    .cycle()
+                    .take(body_spans.len() * copies);
+                let ops = self.block(unrolled, &mut unrolled_spans, state);
+                folded.extend(ops);
+                return;
+            }
+            _ => {}
+        }
+
+        // Start the body from a state covering every iteration
+        match loop_writes(&body) {
+            Some(writes) => {
+                for offset in writes {
+                    state.set(offset, None);
+                }
+            }
+            None => state.forget(),
+        }
+        self.out.push(span);
+        let mut inside = state.clone();
+        let body = self.block(body, &mut body_spans.into_iter(), &mut inside);
+        // The loop exits on a zero cell
+        state.set(0, Some(0));
+        folded.push(Op::Loop(body));
+    }
+
+    /// Whether a loop entered with `count` in its cell runs its body exactly
+    /// `count` times and is small enough to copy out that often
+    fn unrollable(&self, body: &[Op], count: u8) -> bool {
+        let (max_iterations, max_ops) = self.unroll;
+        if count > max_iterations || body.len() * usize::from(count) > max_ops {
+            return false;
+        }
+        let mut offset = 0isize;
+        let mut delta = 0u8;
+        for op in body {
+            match op {
+                Op::MoveRight(n) => offset += self.wrap(*n),
+                Op::MoveLeft(n) => offset -= self.wrap(*n),
+                Op::Increment(n) | Op::Decrement(n) if offset.rem_euclid(self.tape_size) == 0 => {
+                    delta = match op {
+                        Op::Increment(_) => delta.wrapping_add(*n),
+                        _ => delta.wrapping_sub(*n),
+                    }
+                }
+                Op::Increment(_) | Op::Decrement(_) | Op::Output | Op::Send | Op::Print(_) => {}
+                _ => return false,
+            }
+        }
+        offset.rem_euclid(self.tape_size) == 0 && delta == u8::MAX
+    }
+
+    /// A move of `n` cells as an offset less than a tape length
+    fn wrap(&self, n: usize) -> isize {
+        (n % self.tape_size as usize) as isize
+    }
+}
+
+/// Offsets a loop body may write, relative to its entry, if it always
+/// returns to the cell it started on
+fn loop_writes(body: &[Op]) -> Option<Vec<isize>> {
+    let mut offset = 0isize;
+    let mut writes = Vec::new();
+    for op in body {
+        match op {
+            Op::MoveRight(n) => offset = offset.checked_add_unsigned(*n)?,
+            Op::MoveLeft(n) => offset = offset.checked_sub_unsigned(*n)?,
+            Op::Increment(_)
+            | Op::Decrement(_)
+            | Op::Input
+            | Op::Receive
+            | Op::Peek
+            | Op::Clear => writes.push(offset),
+            Op::MulAdd(targets) => {
+                writes.push(offset);
+                writes.extend(targets.iter().map(|&(target, _)| offset + target));
+            }
+            Op::Loop(inner) => writes.extend(loop_writes(inner)?.into_iter().map(|w| w + offset)),
+            Op::CompareSwap => writes.push(offset + 1),
+            Op::Spawn | Op::SelectChannel => writes.push(offset),
+            Op::ReceiveMessage | Op::Channels => writes.extend([offset, offset + 1]),
+            Op::Checksum => writes.extend(offset..offset + 4),
+            Op::Features(_) => writes.extend(offset..offset + FEATURE_CELLS as isize),
+            Op::ScanRight(_)
+            | Op::ScanLeft(_)
+            | Op::Load(..)
+            | Op::KvGet
+            | Op::Http
+            | Op::Base64Encode
+            | Op::Base64Decode
+            | Op::Xor
+            | Op::Trap
+            | Op::ReceiveLine
+            | Op::ReceiveBlock
+            | Op::Resolve => return None,
+            Op::Output
+            | Op::Send
+            | Op::Flush
+            | Op::SendBlock
+            | Op::SendMessage
+            | Op::Yield
+            | Op::KvPut
+            | Op::Unread
+            | Op::Listen
+            | Op::Accept
+            | Op::Connect
+            | Op::Print(_) => {}
+        }
+    }
+    (offset == 0).then_some(writes)
+}
+
+struct Collect(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Collect {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
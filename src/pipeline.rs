@@ -0,0 +1,261 @@
+//! Choosing which optimizer passes run (`-O`, `--passes`)
+//!
+//! A [`Pipeline`] runs [`Pass`]es over a parsed program in order, each
+//! rewriting the ops and their source map. [`Pipeline::level`] builds the
+//! pipeline of an `-O` level from the built-in passes, and
+//! [`Pipeline::builtin`] looks one up by the name `--passes` uses:
+//!
+//! | Name       | Pass                                                  | From  |
+//! | ---------- | ----------------------------------------------------- | ----- |
+//! | `fuse`     | [`optimizer::merge_adjacent`]                         | `-O1` |
+//! | `clear`    | `[-]` becomes [`Op::Clear`]                           | `-O1` |
+//! | `scan`     | `[>]` becomes [`Op::ScanRight`]                       | `-O1` |
+//! | `muladd`   | `[->+<]` becomes [`Op::MulAdd`]                       | `-O1` |
+//! | `evaluate` | [`optimizer::evaluate_prefix`]                        | `-O2` |
+//! | `fold`     | [`optimizer::fold_known_cells`]                       | `-O2` |
+//!
+//! `-O3` runs the same passes as `-O2` with bigger budgets: the input-free
+//! start of the program may take [`O3_EVALUATION_BUDGET`] steps, and loops
+//! of up to [`O3_UNROLL_MAX_ITERATIONS`] iterations are unrolled.
+//!
+//! Embedders can add passes of their own:
+//!
+//! ```
+//! use trainfuck::interpreter::{parse, Op};
+//! use trainfuck::optimizer::Change;
+//! use trainfuck::pipeline::{Pass, Pipeline};
+//! use trainfuck::span::{SourceMap, Span};
+//!
+//! /// Drops every `.`
+//! struct Quiet;
+//!
+//! impl Pass for Quiet {
+//!     fn name(&self) -> &'static str {
+//!         "quiet"
+//!     }
+//!
+//!     fn run(&self, ops: Vec<Op>, spans: &SourceMap, _: &mut Vec<(Span, Change)>) -> (Vec<Op>, SourceMap) {
+//!         let (ops, spans) = ops
+//!             .into_iter()
+//!             .zip(spans.spans().iter().copied())
+//!             .filter(|(op, _)| *op != Op::Output)
+//!             .unzip();
+//!         (ops, SourceMap::new(spans))
+//!     }
+//! }
+//!
+//! let program = parse("+++.>+.")?;
+//! let pipeline = Pipeline::level(1, 30_000).with(Quiet);
+//! let (ops, _) = pipeline.run(program.ops().to_vec(), program.spans());
+//! assert_eq!(ops, [Op::Increment(3), Op::MoveRight(1), Op::Increment(1)]);
+//! # Ok::<(), trainfuck::interpreter::TrainfuckError>(())
+//! ```
+
+use crate::interpreter::Op;
+use crate::optimizer::{self, Change, Idioms, Report};
+use crate::span::{SourceMap, Span};
+
+/// Steps [`Pipeline::level`]`(3, ..)` lets the partial evaluator spend
+pub const O3_EVALUATION_BUDGET: u64 = 10 * optimizer::EVALUATION_BUDGET;
+
+/// Iterations up to which [`Pipeline::level`]`(3, ..)` unrolls a counted loop
+pub const O3_UNROLL_MAX_ITERATIONS: u8 = 32;
+
+/// Ops an unrolled loop may grow to at `-O3`
+pub const O3_UNROLL_MAX_OPS: usize = 256;
+
+/// Names of the built-in passes, in the order `-O` levels run them
+pub const BUILTIN: [&str; 6] = ["fuse", "clear", "scan", "muladd", "evaluate", "fold"];
+
+/// A rewrite of a parsed program
+pub trait Pass: Send + Sync {
+    /// What reports and `--passes` call it
+    fn name(&self) -> &'static str;
+
+    /// Rewrite `ops`, whose spans in pre-order are `spans`, adding what
+    /// changed to `changes`
+    fn run(
+        &self,
+        ops: Vec<Op>,
+        spans: &SourceMap,
+        changes: &mut Vec<(Span, Change)>,
+    ) -> (Vec<Op>, SourceMap);
+}
+
+/// Passes to run in order
+#[derive(Default)]
+pub struct Pipeline {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Pipeline {
+    /// A pipeline running no passes, like `-O0`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The passes of `-O<level>` for a tape of `tape_size` cells; levels
+    /// above 3 are `-O3`
+    pub fn level(level: u8, tape_size: usize) -> Self {
+        let count = match level {
+            0 => 0,
+            1 => 4,
+            _ => BUILTIN.len(),
+        };
+        let mut pipeline = Self::new();
+        for &name in &BUILTIN[..count] {
+            pipeline.push(match (name, level) {
+                ("evaluate", 3..) => Box::new(Evaluate {
+                    tape_size,
+                    budget: O3_EVALUATION_BUDGET,
+                }),
+                ("fold", 3..) => Box::new(Fold {
+                    tape_size,
+                    unroll: (O3_UNROLL_MAX_ITERATIONS, O3_UNROLL_MAX_OPS),
+                }),
+                _ => Self::builtin(name, tape_size).expect("built-in pass"),
+            });
+        }
+        pipeline
+    }
+
+    /// The built-in pass called `name`, as `-O2` runs it. `evaluate` and
+    /// `fold` only keep the program's meaning on a tape of `tape_size` cells.
+    pub fn builtin(name: &str, tape_size: usize) -> Option<Box<dyn Pass>> {
+        Some(match name {
+            "fuse" => Box::new(Fuse),
+            "clear" => Box::new(Loops("clear", Idioms::CLEAR)),
+            "scan" => Box::new(Loops("scan", Idioms::SCAN)),
+            "muladd" => Box::new(Loops("muladd", Idioms::MUL_ADD)),
+            "evaluate" => Box::new(Evaluate {
+                tape_size,
+                budget: optimizer::EVALUATION_BUDGET,
+            }),
+            "fold" => Box::new(Fold {
+                tape_size,
+                unroll: (optimizer::UNROLL_MAX_ITERATIONS, optimizer::UNROLL_MAX_OPS),
+            }),
+            _ => return None,
+        })
+    }
+
+    /// Run `pass` after the others
+    pub fn with(mut self, pass: impl Pass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    pub fn push(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    /// Keep only the passes whose names `keep` accepts
+    pub fn retain(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        self.passes.retain(|pass| keep(pass.name()));
+    }
+
+    /// Names of the passes, in the order they run
+    pub fn names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    pub fn run(&self, ops: Vec<Op>, spans: &SourceMap) -> (Vec<Op>, SourceMap) {
+        self.run_with_report(ops, spans, &mut Report::default())
+    }
+
+    /// Like [`run`](Self::run), adding what each pass did to `report`
+    pub fn run_with_report(
+        &self,
+        ops: Vec<Op>,
+        spans: &SourceMap,
+        report: &mut Report,
+    ) -> (Vec<Op>, SourceMap) {
+        let mut program = (ops, spans.clone());
+        for pass in &self.passes {
+            let before = program.0.iter().map(optimizer::count_ops).sum();
+            let mut changes = Vec::new();
+            program = pass.run(program.0, &program.1, &mut changes);
+            report.record(pass.name(), before, &program.0, changes);
+        }
+        program
+    }
+}
+
+struct Fuse;
+
+impl Pass for Fuse {
+    fn name(&self) -> &'static str {
+        "fuse"
+    }
+
+    fn run(
+        &self,
+        ops: Vec<Op>,
+        spans: &SourceMap,
+        changes: &mut Vec<(Span, Change)>,
+    ) -> (Vec<Op>, SourceMap) {
+        optimizer::merge(ops, spans, changes)
+    }
+}
+
+/// Rewrites one kind of loop idiom
+struct Loops(&'static str, Idioms);
+
+impl Pass for Loops {
+    fn name(&self) -> &'static str {
+        self.0
+    }
+
+    fn run(
+        &self,
+        ops: Vec<Op>,
+        spans: &SourceMap,
+        changes: &mut Vec<(Span, Change)>,
+    ) -> (Vec<Op>, SourceMap) {
+        optimizer::fuse_idioms(ops, spans, self.1, changes)
+    }
+}
+
+struct Evaluate {
+    tape_size: usize,
+    budget: u64,
+}
+
+impl Pass for Evaluate {
+    fn name(&self) -> &'static str {
+        "evaluate"
+    }
+
+    fn run(
+        &self,
+        ops: Vec<Op>,
+        spans: &SourceMap,
+        changes: &mut Vec<(Span, Change)>,
+    ) -> (Vec<Op>, SourceMap) {
+        optimizer::evaluate(ops, spans, self.tape_size, self.budget, changes)
+    }
+}
+
+struct Fold {
+    tape_size: usize,
+    unroll: (u8, usize),
+}
+
+impl Pass for Fold {
+    fn name(&self) -> &'static str {
+        "fold"
+    }
+
+    fn run(
+        &self,
+        ops: Vec<Op>,
+        spans: &SourceMap,
+        changes: &mut Vec<(Span, Change)>,
+    ) -> (Vec<Op>, SourceMap) {
+        optimizer::fold(ops, spans, self.tape_size, self.unroll, changes)
+    }
+}
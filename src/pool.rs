@@ -0,0 +1,162 @@
+//! A pool of VMs running one handler program per connection
+//!
+//! For embedding Trainfuck handlers in a service: the embedder accepts
+//! connections however it likes and hands them to a [`VmPool`], which
+//! runs the shared [`Program`] on an idle worker with the connection
+//! already open, so the handler only needs `` ` `` and `'`. Each worker
+//! keeps its VM between connections and resets it before the next one.
+//! All workers see the same [`SharedSegment`] through `~`.
+//!
+//! ```no_run
+//! use std::net::TcpListener;
+//! use trainfuck::interpreter::parse;
+//! use trainfuck::pool::VmPool;
+//!
+//! let echo = parse("`['`]").unwrap();
+//! let pool = VmPool::new(echo, 8);
+//! pool.serve(TcpListener::bind("127.0.0.1:9000")?.incoming())?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use crate::interpreter::{Result, VM};
+use crate::net::Stream;
+use crate::program::Program;
+use crate::shared::SharedSegment;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Callbacks around each worker's VM; every method has a default
+pub trait Hooks: Send + Sync {
+    /// Build the VM for worker `worker`. The default has the standard tape,
+    /// no input and discards `.` output.
+    fn create(&self, worker: usize) -> VM {
+        let _ = worker;
+        let mut vm = VM::new();
+        vm.input = Box::new(io::empty());
+        vm.output = Box::new(io::sink());
+        vm
+    }
+
+    /// Before the handler runs for a connection from `peer`, e.g. to set a
+    /// deadline or seed the tape
+    fn on_connect(&self, worker: usize, vm: &mut VM, peer: Option<SocketAddr>) {
+        let _ = (worker, vm, peer);
+    }
+
+    /// After the handler stopped, before its connection is closed
+    fn on_finish(&self, worker: usize, vm: &mut VM, result: &Result<()>) {
+        let _ = (worker, vm, result);
+    }
+}
+
+/// [`Hooks`] with every default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultHooks;
+
+impl Hooks for DefaultHooks {}
+
+type Connection = Box<dyn Stream + Send>;
+
+/// Worker threads each running `program` for one connection at a time
+pub struct VmPool {
+    shared: SharedSegment,
+    sender: Option<SyncSender<Connection>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl VmPool {
+    /// A pool of `workers` VMs (at least one) with [`DefaultHooks`]
+    pub fn new(program: Program, workers: usize) -> Self {
+        Self::with_hooks(program, workers, DefaultHooks)
+    }
+
+    pub fn with_hooks(program: Program, workers: usize, hooks: impl Hooks + 'static) -> Self {
+        let hooks: Arc<dyn Hooks> = Arc::new(hooks);
+        // Rendezvous channel: a connection is only handed over once a
+        // worker is idle, the rest wait in the embedder's accept queue
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let shared = SharedSegment::new();
+        let workers = (0..workers.max(1))
+            .map(|worker| {
+                let program = program.clone();
+                let hooks = hooks.clone();
+                let receiver = receiver.clone();
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let mut vm = hooks.create(worker);
+                    vm.shared = Some(shared);
+                    work(worker, &mut vm, &program, &*hooks, &receiver)
+                })
+            })
+            .collect();
+        VmPool {
+            shared,
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// The segment every worker's `~` operates on
+    pub fn shared(&self) -> &SharedSegment {
+        &self.shared
+    }
+
+    /// Run the handler for `stream` on the next idle worker, blocking until
+    /// one is free
+    pub fn dispatch(&self, stream: impl Stream + Send + 'static) -> io::Result<()> {
+        self.sender
+            .as_ref()
+            .expect("only taken on drop")
+            .send(Box::new(stream))
+            .map_err(|_| io::Error::other("all pool workers have exited"))
+    }
+
+    /// Dispatch every connection from `incoming` (e.g.
+    /// `TcpListener::incoming()`), stopping at its end or first error
+    pub fn serve<S: Stream + Send + 'static>(
+        &self,
+        incoming: impl IntoIterator<Item = io::Result<S>>,
+    ) -> io::Result<()> {
+        for stream in incoming {
+            self.dispatch(stream?)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VmPool {
+    /// Let running handlers finish, then stop the workers
+    fn drop(&mut self) {
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work(
+    worker: usize,
+    vm: &mut VM,
+    program: &Program,
+    hooks: &dyn Hooks,
+    queue: &Mutex<Receiver<Connection>>,
+) {
+    loop {
+        // Hold the lock only while waiting, not while running
+        let next = queue.lock().unwrap().recv();
+        let Ok(stream) = next else {
+            return;
+        };
+        let peer = stream.peer_addr();
+        vm.reset();
+        vm.attach(stream);
+        hooks.on_connect(worker, vm, peer);
+        let result = vm.execute_program(program);
+        hooks.on_finish(worker, vm, &result);
+        let _ = vm.shutdown();
+    }
+}
@@ -0,0 +1,73 @@
+//! Health probe endpoints (`--health-addr ADDR`)
+//!
+//! Serves two paths for orchestrators and load balancers, each answering
+//! 200 or 503 with a small JSON body:
+//!
+//! - `/healthz`: the program is still running and either its step count
+//!   has moved within the stall limit or it is waiting on I/O (an idle
+//!   server blocked in `@` is healthy)
+//! - `/readyz`: the program is running and has a listener open with `$`
+
+use serde_json::json;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Response, Server};
+use trainfuck::health::Health;
+
+/// Progress as of the last probe
+struct Progress {
+    steps: u64,
+    changed_at: Instant,
+}
+
+/// Bind `addr` and answer probes on a background thread
+pub fn serve(addr: &str, health: Arc<Health>, stall: Duration) -> io::Result<()> {
+    let server = Server::http(addr).map_err(io::Error::other)?;
+    eprintln!("[trainfuck] Health checks on http://{}", addr);
+    thread::spawn(move || {
+        let mut progress = Progress {
+            steps: health.steps(),
+            changed_at: Instant::now(),
+        };
+        for request in server.incoming_requests() {
+            let path = request.url().split('?').next().unwrap_or("");
+            let (ok, body) = match path {
+                "/healthz" => healthz(&health, &mut progress, stall),
+                "/readyz" => {
+                    let ready = health.is_listening() && !health.is_finished();
+                    (ready, json!({ "ready": ready }))
+                }
+                _ => {
+                    let _ = request.respond(Response::empty(404));
+                    continue;
+                }
+            };
+            let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+            let response = Response::from_string(body.to_string())
+                .with_status_code(if ok { 200 } else { 503 })
+                .with_header(header);
+            let _ = request.respond(response);
+        }
+    });
+    Ok(())
+}
+
+fn healthz(health: &Health, progress: &mut Progress, stall: Duration) -> (bool, serde_json::Value) {
+    let steps = health.steps();
+    if steps != progress.steps {
+        progress.steps = steps;
+        progress.changed_at = Instant::now();
+    }
+    let waiting = health.is_waiting();
+    let status = if health.is_finished() {
+        "finished"
+    } else if waiting || progress.changed_at.elapsed() < stall {
+        "ok"
+    } else {
+        "stalled"
+    };
+    let body = json!({ "status": status, "steps": steps, "waiting": waiting });
+    (status == "ok", body)
+}
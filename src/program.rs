@@ -0,0 +1,200 @@
+//! Parsed programs, ready to run any number of times
+//!
+//! [`parse`](crate::interpreter::parse) returns a [`Program`]: the op tree
+//! together with its [`SourceMap`] and [`Metadata`] about how it was made.
+//! Cloning one only bumps a reference count, so a server can hand the same
+//! program to every worker, and it is compiled to [`Bytecode`] once, the
+//! first time [`VM::execute_program`] runs it, however many fresh VMs run
+//! it after that. Programs implement serde's `Serialize` and
+//! `Deserialize`, for storing them parsed or sending them to another
+//! process.
+//!
+//! ```
+//! use trainfuck::interpreter::{Extensions, VM};
+//! use trainfuck::program::Program;
+//!
+//! let program = Program::compile(b"++++++++[>++++++++<-]>+.", Extensions::default())?;
+//! for _ in 0..3 {
+//!     let mut vm = VM::with_io(std::io::empty(), std::io::sink());
+//!     vm.execute_program(&program)?;
+//! }
+//! # Ok::<(), trainfuck::interpreter::TrainfuckError>(())
+//! ```
+
+use crate::bytecode::Bytecode;
+use crate::directives::Directives;
+use crate::interpreter::{Extensions, Op, Parser, Result, VM};
+use crate::optimizer;
+use crate::span::SourceMap;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, OnceLock};
+
+/// How a [`Program`] came to be
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// The extensions whose commands were parsed
+    pub extensions: Extensions,
+    /// Length of the source in bytes
+    pub source_len: usize,
+    /// Whether the optimizer has rewritten the ops
+    pub optimized: bool,
+    /// What the source's `#!` lines asked for
+    pub directives: Directives,
+}
+
+/// Ops with their source map and metadata, shared between clones.
+/// Dereferences to the ops.
+#[derive(Clone)]
+pub struct Program {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    ops: Vec<Op>,
+    spans: SourceMap,
+    metadata: Metadata,
+    bytecode: OnceLock<Bytecode>,
+}
+
+impl Program {
+    /// `spans` lists one span per op in pre-order, or none at all
+    pub fn new(ops: Vec<Op>, spans: SourceMap, metadata: Metadata) -> Self {
+        Program {
+            inner: Arc::new(Inner {
+                ops,
+                spans,
+                metadata,
+                bytecode: OnceLock::new(),
+            }),
+        }
+    }
+
+    /// Parse `source`, also recognizing the commands of `extensions`
+    pub fn parse(source: &[u8], extensions: Extensions) -> Result<Self> {
+        let mut parser = Parser::new().with_extensions(extensions);
+        parser.feed(source)?;
+        parser.finish_program()
+    }
+
+    /// Parse and optimize `source`
+    pub fn compile(source: &[u8], extensions: Extensions) -> Result<Self> {
+        Ok(Self::parse(source, extensions)?.optimize())
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.inner.ops
+    }
+
+    pub fn spans(&self) -> &SourceMap {
+        &self.inner.spans
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.inner.metadata
+    }
+
+    /// The program with its loops fused into superinstructions; see
+    /// [`optimizer::optimize`]
+    pub fn optimize(&self) -> Self {
+        if self.inner.metadata.optimized {
+            return self.clone();
+        }
+        let ops = self.ops().to_vec();
+        let (ops, spans) = match self.spans().is_empty() {
+            true => (optimizer::optimize(ops), SourceMap::default()),
+            false => optimizer::optimize_with_spans(ops, self.spans()),
+        };
+        let metadata = Metadata {
+            optimized: true,
+            ..self.inner.metadata.clone()
+        };
+        Self::new(ops, spans, metadata)
+    }
+
+    /// The program compiled for [`VM::execute_bytecode`], compiling it on
+    /// the first call
+    pub fn bytecode(&self) -> &Bytecode {
+        self.inner
+            .bytecode
+            .get_or_init(|| Bytecode::compile_with_spans(self.ops(), self.spans()))
+    }
+}
+
+impl Deref for Program {
+    type Target = [Op];
+
+    fn deref(&self) -> &[Op] {
+        self.ops()
+    }
+}
+
+impl fmt::Debug for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Program")
+            .field("ops", &self.inner.ops)
+            .field("metadata", &self.inner.metadata)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Program {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+            || (self.inner.ops == other.inner.ops
+                && self.inner.spans == other.inner.spans
+                && self.inner.metadata == other.inner.metadata)
+    }
+}
+
+/// What a program serializes to; the bytecode is compiled again on demand
+#[derive(Serialize)]
+struct PartsRef<'a> {
+    ops: &'a [Op],
+    spans: &'a SourceMap,
+    metadata: &'a Metadata,
+}
+
+#[derive(Deserialize)]
+struct Parts {
+    ops: Vec<Op>,
+    spans: SourceMap,
+    metadata: Metadata,
+}
+
+impl Serialize for Program {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        PartsRef {
+            ops: self.ops(),
+            spans: self.spans(),
+            metadata: self.metadata(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Program {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let parts = Parts::deserialize(deserializer)?;
+        // Errors are located by walking ops and spans together
+        let count: usize = parts.ops.iter().map(optimizer::count_ops).sum();
+        if !parts.spans.is_empty() && parts.spans.len() != count {
+            return Err(D::Error::custom(format!(
+                "{} spans for {} ops",
+                parts.spans.len(),
+                count
+            )));
+        }
+        Ok(Self::new(parts.ops, parts.spans, parts.metadata))
+    }
+}
+
+impl VM {
+    /// Run `program` in the bytecode interpreter, compiling it first if
+    /// no VM has run it yet
+    pub fn execute_program(&mut self, program: &Program) -> Result<()> {
+        self.execute_bytecode(program.bytecode())
+    }
+}
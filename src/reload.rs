@@ -0,0 +1,80 @@
+//! Hot code reload (`--reload-on`)
+//!
+//! [`VM::execute_reloadable`] runs a program in the bytecode interpreter
+//! and, once a reload has been requested, swaps in a new version of it
+//! without stopping: the tape, the pointer, the listener and every open
+//! connection carry over, so a live chat server can be patched without
+//! dropping its users.
+//!
+//! The swap happens at a safe point: the end of an iteration of a loop that
+//! isn't inside another loop, such as a server's main loop. Execution
+//! carries on at the start of the body of the same loop (by position among
+//! the top-level loops) in the new program, so the new version must have as
+//! many top-level loops as the old one; if it doesn't, the old program keeps
+//! running. A program blocked inside its main loop, say waiting in `@` for
+//! the next client, picks the new version up once it gets to the end of the
+//! iteration.
+
+use crate::bytecode::{Bytecode, HALT, JUMP_NONZERO};
+use crate::interpreter::{Result, VM};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Where a new version of the running program comes from
+pub struct Reload<'a> {
+    /// Set (by a signal handler, say) to swap programs at the next safe
+    /// point
+    pub requested: Arc<AtomicBool>,
+    /// Compiles the new version; None keeps the running one
+    pub load: Box<dyn FnMut() -> Option<Bytecode> + 'a>,
+}
+
+impl VM {
+    /// Run a compiled program, replacing it with the one `reload` loads
+    /// whenever a reload is requested; `bytecode` is left holding the
+    /// version that ran last, for looking up [`fault_pc`](Self::fault_pc)
+    pub fn execute_reloadable(
+        &mut self,
+        bytecode: &mut Bytecode,
+        reload: &mut Reload<'_>,
+    ) -> Result<()> {
+        let mut outer = bytecode.outer_loops();
+        let mut pc = 0;
+        self.fault_pc = None;
+        while bytecode.code[pc] != HALT {
+            let next = self
+                .dispatch(&bytecode.code, pc)
+                .inspect_err(|_| self.fault_pc = Some(pc))?;
+            // Jumping back to the start of a top-level loop
+            let safe = bytecode.code[pc] == JUMP_NONZERO && next != pc + 5;
+            pc = next;
+            if !safe || !reload.requested.load(Ordering::Relaxed) {
+                continue;
+            }
+            let Ok(index) = outer.binary_search(&(next - 5)) else {
+                continue;
+            };
+            reload.requested.store(false, Ordering::Relaxed);
+            let Some(new) = (reload.load)() else {
+                continue;
+            };
+            let new_outer = new.outer_loops();
+            if new_outer.len() != outer.len() {
+                eprintln!(
+                    "[trainfuck] Not reloading: the new program has {} top-level loops, the running one {}",
+                    new_outer.len(),
+                    outer.len()
+                );
+                continue;
+            }
+            eprintln!(
+                "[trainfuck] Reloaded, continuing in top-level loop {}",
+                index + 1
+            );
+            pc = new_outer[index] + 5;
+            *bytecode = new;
+            outer = new_outer;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+//! Seeded pseudo-random numbers for the generating subcommands
+//!
+//! xorshift64 is plenty for fuzz inputs and obfuscation, and unlike a
+//! library generator its sequence for a given seed never changes between
+//! releases, so a reported seed stays reproducible.
+
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero
+        XorShift(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in `0..n`
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// True about once in `n` calls
+    pub fn one_in(&mut self, n: u64) -> bool {
+        self.below(n) == 0
+    }
+}
@@ -0,0 +1,97 @@
+//! Cooperative multitasking of VMs on one thread
+//!
+//! A [`Scheduler`] steps each program in turn. A program keeps the thread
+//! until it executes `|` (with the `yielding` extension) or an op that
+//! would block, such as `` ` `` with no data yet on a
+//! [`NonBlockingTcpNetwork`](crate::net::NonBlockingTcpNetwork)
+//! connection; the blocked op is retried on the program's next turn. A
+//! program that does neither never gives the thread up.
+
+use crate::interpreter::{Op, Result, TrainfuckError, VM};
+use std::thread;
+use std::time::Duration;
+
+/// How long to sleep when every program is blocked
+const IDLE_WAIT: Duration = Duration::from_millis(1);
+
+struct Task {
+    vm: VM,
+    ops: Vec<Op>,
+}
+
+enum Turn {
+    Finished(Result<()>),
+    Yielded,
+    /// Blocked, after running `ran` steps
+    Blocked {
+        ran: u64,
+    },
+}
+
+impl Task {
+    fn turn(&mut self) -> Turn {
+        let mut ran = 0;
+        loop {
+            match self.vm.step(&self.ops) {
+                Ok(true) => return Turn::Finished(Ok(())),
+                Ok(false) if self.vm.yielded => {
+                    self.vm.yielded = false;
+                    return Turn::Yielded;
+                }
+                Ok(false) => ran += 1,
+                Err(TrainfuckError::WouldBlock) => return Turn::Blocked { ran },
+                Err(e) => return Turn::Finished(Err(e)),
+            }
+        }
+    }
+}
+
+/// Round-robin scheduler over programs that share the calling thread
+#[derive(Default)]
+pub struct Scheduler {
+    tasks: Vec<Task>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a program to run on `vm`; returns its index in [`run`](Self::run)'s results
+    pub fn spawn(&mut self, vm: VM, ops: Vec<Op>) -> usize {
+        self.tasks.push(Task { vm, ops });
+        self.tasks.len() - 1
+    }
+
+    /// Run every program to completion, returning each one's result and
+    /// VM in the order they were spawned
+    pub fn run(self) -> Vec<(Result<()>, VM)> {
+        let mut tasks: Vec<(usize, Task)> = self.tasks.into_iter().enumerate().collect();
+        let mut done: Vec<Option<(Result<()>, VM)>> = tasks.iter().map(|_| None).collect();
+        while !tasks.is_empty() {
+            let mut progressed = false;
+            tasks.retain_mut(|(index, task)| match task.turn() {
+                Turn::Finished(result) => {
+                    let vm = std::mem::take(&mut task.vm);
+                    done[*index] = Some((result, vm));
+                    progressed = true;
+                    false
+                }
+                Turn::Yielded => {
+                    progressed = true;
+                    true
+                }
+                Turn::Blocked { ran } => {
+                    progressed |= ran > 0;
+                    true
+                }
+            });
+            if !progressed {
+                thread::sleep(IDLE_WAIT);
+            }
+        }
+        done.into_iter()
+            .map(|d| d.expect("every task ran to completion"))
+            .collect()
+    }
+}
@@ -0,0 +1,86 @@
+//! Serial-port transport (`--transport serial:PATH:BAUD`, `serial` feature)
+//!
+//! [`SerialNetwork`] points the networking ops at a serial device: `$`/`@`
+//! and `%` open it (the address on the tape is ignored), and `` ` `` and
+//! `'` read and write its bytes unchanged. Closing the connection with `@`
+//! closes the device; the next `@` or `%` opens it again.
+
+use crate::net::{Listener, Network, Stream};
+use serialport::SerialPort;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+/// How long one read waits before trying again; reads block until a byte
+/// arrives, like on a socket
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Networking over a serial device
+#[derive(Debug, Clone)]
+pub struct SerialNetwork {
+    path: String,
+    baud_rate: u32,
+}
+
+impl SerialNetwork {
+    pub fn new(path: &str, baud_rate: u32) -> Self {
+        SerialNetwork {
+            path: path.to_string(),
+            baud_rate,
+        }
+    }
+
+    fn open(&self) -> io::Result<Box<dyn Stream>> {
+        let port = serialport::new(&self.path, self.baud_rate)
+            .timeout(READ_TIMEOUT)
+            .open()?;
+        eprintln!(
+            "[trainfuck] Opened serial port {} at {} baud",
+            self.path, self.baud_rate
+        );
+        Ok(Box::new(SerialStream(port)))
+    }
+}
+
+impl Network for SerialNetwork {
+    fn listen(&mut self, _addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(SerialListener(self.clone())))
+    }
+
+    fn connect(&mut self, _addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        self.open()
+    }
+}
+
+struct SerialListener(SerialNetwork);
+
+impl Listener for SerialListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        self.0.open()
+    }
+}
+
+struct SerialStream(Box<dyn SerialPort>);
+
+impl Read for SerialStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Write for SerialStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Stream for SerialStream {}
@@ -0,0 +1,198 @@
+//! Remote execution service (`trainfuck serve`)
+//!
+//! Accepts `POST /run` with a JSON body `{"source": "...", "input": "..."}`,
+//! runs the program in a sandboxed VM and answers with
+//! `{"output": "...", "steps": N, "error": null}`. Every program runs under
+//! the same [`Sandbox`] limits as `--sandbox`, and when it hits one,
+//! `"limit"` names it (`"steps"`, `"time"`, `"output"`, `"tape"` or
+//! `"loop_depth"`). A program's `#! tape-size` may ask for a tape of up to
+//! `--tape-size` cells.
+//! Networking ops are disabled unless the service was started with
+//! `--allow-net`, and programs that turn on extensions with `#! ext` are
+//! rejected.
+//!
+//! With the `playground` feature, `GET /` serves a small browser UI on top of
+//! the same endpoint.
+
+use serde::{Deserialize, Serialize};
+use std::io::{self, Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+use trainfuck::bytecode::Bytecode;
+use trainfuck::interpreter::{self, Sandbox, TrainfuckError, VM};
+use trainfuck::optimizer;
+
+/// Limits applied to every submitted program
+#[derive(Debug, Clone)]
+pub struct ServeConfig {
+    pub sandbox: Sandbox,
+    pub tape_size: usize,
+    pub allow_net: bool,
+    pub max_request_bytes: usize,
+    pub workers: usize,
+    pub max_loop_depth: usize,
+}
+
+#[derive(Deserialize)]
+struct RunRequest {
+    source: String,
+    #[serde(default)]
+    input: String,
+}
+
+#[derive(Serialize)]
+struct RunResponse {
+    output: String,
+    steps: u64,
+    error: Option<String>,
+    /// The limit that stopped the program, if one did
+    limit: Option<&'static str>,
+    /// Tape up to the last non-zero cell or the pointer, whichever is further
+    tape: Vec<u8>,
+    pointer: usize,
+}
+
+/// Output sink that can be read back after the VM is done with it
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serve requests on `addr` until the process is killed
+pub fn serve(addr: &str, config: ServeConfig) -> io::Result<()> {
+    let server = Server::http(addr).map_err(io::Error::other)?;
+    eprintln!("[trainfuck] Serving on http://{}", server.server_addr());
+
+    let server = Arc::new(server);
+    let config = Arc::new(config);
+    let workers: Vec<_> = (0..config.workers.max(1))
+        .map(|_| {
+            let server = server.clone();
+            let config = config.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    if let Err(e) = handle(request, &config) {
+                        eprintln!("[trainfuck] Failed to answer request: {}", e);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+#[cfg(feature = "playground")]
+const PLAYGROUND_HTML: &str = include_str!("../assets/playground.html");
+
+fn handle(mut request: Request, config: &ServeConfig) -> io::Result<()> {
+    #[cfg(feature = "playground")]
+    if request.method() == &Method::Get && request.url() == "/" {
+        let content_type = Header::from_bytes("Content-Type", "text/html; charset=utf-8").unwrap();
+        return request.respond(Response::from_string(PLAYGROUND_HTML).with_header(content_type));
+    }
+
+    if request.method() != &Method::Post || request.url() != "/run" {
+        return request.respond(Response::from_string("not found\n").with_status_code(404));
+    }
+
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(config.max_request_bytes as u64 + 1)
+        .read_to_end(&mut body)?;
+    if body.len() > config.max_request_bytes {
+        return request.respond(Response::from_string("request too large\n").with_status_code(413));
+    }
+
+    let run: RunRequest = match serde_json::from_slice(&body) {
+        Ok(run) => run,
+        Err(e) => {
+            let message = format!("invalid request: {}\n", e);
+            return request.respond(Response::from_string(message).with_status_code(400));
+        }
+    };
+
+    let response = run_sandboxed(&run, config);
+    let json = serde_json::to_string(&response).map_err(io::Error::other)?;
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    request.respond(Response::from_string(json).with_header(content_type))
+}
+
+fn run_sandboxed(run: &RunRequest, config: &ServeConfig) -> RunResponse {
+    let rejected = |error: String, limit| RunResponse {
+        output: String::new(),
+        steps: 0,
+        limit,
+        error: Some(error),
+        tape: Vec::new(),
+        pointer: 0,
+    };
+    let mut parser = interpreter::Parser::with_max_depth(config.max_loop_depth);
+    let program = match parser
+        .feed(run.source.as_bytes())
+        .and_then(|()| parser.finish_program())
+    {
+        Ok(program) => program,
+        Err(e) => return rejected(format!("Parse error: {}", e), e.limit()),
+    };
+    // Submissions get the service's extensions, which are none
+    let directives = &program.metadata().directives;
+    if let Some(name) = directives.extensions.first() {
+        let error = format!("Parse error: `#! ext {}` is not allowed here", name);
+        return rejected(error, None);
+    }
+    let tape_size = directives.tape_size.unwrap_or(config.tape_size);
+    if tape_size > config.sandbox.max_tape_size {
+        let e = TrainfuckError::TapeLimitExceeded(config.sandbox.max_tape_size);
+        return rejected(format!("Parse error: {}", e), e.limit());
+    }
+    let (ops, spans) = optimizer::optimize_with_spans(program.ops().to_vec(), program.spans());
+
+    let output = SharedBuffer::default();
+    let mut vm = VM::with_tape_size(tape_size);
+    vm.max_loop_depth = config.max_loop_depth;
+    vm.input = Box::new(Cursor::new(run.input.clone().into_bytes()));
+    vm.output = Box::new(output.clone());
+    let bytecode = Bytecode::compile_with_spans(&ops, &spans);
+    let result = vm.sandbox(&config.sandbox).and_then(|()| {
+        vm.network_enabled = config.allow_net;
+        vm.execute_bytecode(&bytecode)
+    });
+    let _ = vm.shutdown();
+
+    let tape = vm.tape();
+    let used = tape.iter().rposition(|&c| c != 0).unwrap_or(0);
+    let end = used.max(vm.pointer()) + 1;
+
+    let bytes = output.0.lock().unwrap();
+    RunResponse {
+        output: String::from_utf8_lossy(&bytes).into_owned(),
+        steps: vm.steps(),
+        limit: result.as_ref().err().and_then(|e| e.limit()),
+        error: result.err().map(
+            |e| match vm.fault_pc().and_then(|pc| bytecode.span_at(pc)) {
+                Some(span) => {
+                    let (line, column) = span.line_col(run.source.as_bytes());
+                    format!("Runtime error at line {}, column {}: {}", line, column, e)
+                }
+                None => format!("Runtime error: {}", e),
+            },
+        ),
+        tape: tape[..end].to_vec(),
+        pointer: vm.pointer(),
+    }
+}
@@ -0,0 +1,65 @@
+//! Memory shared between VMs in one process
+//!
+//! A [`SharedSegment`] is 256 atomic cells that every VM holding a clone
+//! of it sees. Programs reach it only through the `~` compare-and-swap
+//! extension, so the tape itself stays unsynchronized and fast; a `~` whose
+//! expected and new values are equal reads a cell without changing it.
+//!
+//! [`VmPool`](crate::pool::VmPool) gives all of its workers the same
+//! segment, which lets per-connection handlers keep e.g. a user list.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+/// Number of cells in a segment; a cell value addresses any of them
+pub const SHARED_CELLS: usize = 256;
+
+#[derive(Clone)]
+pub struct SharedSegment(Arc<[AtomicU8; SHARED_CELLS]>);
+
+impl SharedSegment {
+    /// A segment of zeroed cells
+    pub fn new() -> Self {
+        SharedSegment(Arc::new(std::array::from_fn(|_| AtomicU8::new(0))))
+    }
+
+    pub fn get(&self, index: u8) -> u8 {
+        self.0[usize::from(index)].load(Ordering::SeqCst)
+    }
+
+    pub fn set(&self, index: u8, value: u8) {
+        self.0[usize::from(index)].store(value, Ordering::SeqCst)
+    }
+
+    /// Store `new` if the cell holds `expected`; returns what it held
+    pub fn compare_swap(&self, index: u8, expected: u8, new: u8) -> u8 {
+        match self.0[usize::from(index)].compare_exchange(
+            expected,
+            new,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(previous) | Err(previous) => previous,
+        }
+    }
+
+    /// Copy of every cell
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.0.iter().map(|c| c.load(Ordering::SeqCst)).collect()
+    }
+}
+
+impl Default for SharedSegment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for SharedSegment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("SharedSegment")
+            .field(&self.snapshot())
+            .finish()
+    }
+}
@@ -0,0 +1,127 @@
+//! Source positions for parsed ops
+//!
+//! The op tree itself carries no positions; a [`SourceMap`] lists one
+//! [`Span`] per op in pre-order (a loop before the ops in its body), which
+//! is also the order ops start in the source. The optimizer and bytecode
+//! compiler carry it along so errors in fused or compiled code can still be
+//! reported against the original file.
+//!
+//! A program can also be made of several files run one after the other;
+//! spans then point into their concatenation, and [`SourceFiles`] finds the
+//! file and position within it.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// UTF-8 byte order mark, which some editors put at the start of a file
+pub const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Byte range `start..end` of the source an op came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// The smallest span covering both
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+
+    /// 1-based line and column of the start of the span. A UTF-8 byte
+    /// order mark at the start of the source takes up no column.
+    pub fn line_col(&self, source: &[u8]) -> (usize, usize) {
+        let before = &source[..self.start.min(source.len())];
+        let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+        let column = match before.iter().rposition(|&b| b == b'\n') {
+            Some(newline) => self.start - newline,
+            None if source.starts_with(BOM) => self.start.saturating_sub(BOM.len()) + 1,
+            None => self.start + 1,
+        };
+        (line, column)
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+/// Spans of an op tree, in pre-order
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceMap {
+    spans: Vec<Span>,
+}
+
+/// Source files concatenated in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceFiles {
+    source: Vec<u8>,
+    /// Name of each file and the offset it starts at
+    files: Vec<(String, usize)>,
+}
+
+impl SourceFiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `bytes`, read from the file called `name`
+    pub fn push(&mut self, name: impl Into<String>, bytes: &[u8]) {
+        self.files.push((name.into(), self.source.len()));
+        self.source.extend_from_slice(bytes);
+    }
+
+    /// All the files' bytes, one after the other
+    pub fn source(&self) -> &[u8] {
+        &self.source
+    }
+
+    /// Name and bytes of each file
+    pub fn files(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.files.iter().enumerate().map(|(index, (name, start))| {
+            let end = self.files.get(index + 1).map_or(self.source.len(), |f| f.1);
+            (name.as_str(), &self.source[*start..end])
+        })
+    }
+
+    /// The file `offset` into the concatenation falls in, with the 1-based
+    /// line and column there
+    pub fn locate(&self, offset: usize) -> Option<(&str, usize, usize)> {
+        let index = self
+            .files
+            .partition_point(|&(_, start)| start <= offset)
+            .checked_sub(1)?;
+        let (name, bytes) = self.files().nth(index)?;
+        let offset = offset - self.files[index].1;
+        let (line, column) = Span::new(offset, offset).line_col(bytes);
+        Some((name, line, column))
+    }
+}
+
+impl SourceMap {
+    pub fn new(spans: Vec<Span>) -> Self {
+        SourceMap { spans }
+    }
+
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+}
@@ -0,0 +1,265 @@
+//! Optimization suggestions (`--suggest`)
+//!
+//! After a run with a [`Histogram`] attached, [`suggest`] goes over the
+//! loops that ran at least [`HOT_ITERATIONS`] iterations and points out
+//! source rewrites that would make them cheaper:
+//!
+//! - a cell set to a constant (`[-]` and `+`s) on every iteration of a loop
+//!   that doesn't otherwise change it, which could be set once before it
+//! - a loop of only `+-<>` that steps its counter down by more than one,
+//!   which `-O1` can't turn into multiplications, written to step by one
+//!
+//! Loops are matched to the bytecode that ran by their spans, so those the
+//! optimizer removed, such as the ones `-O2` runs at compile time, have no
+//! counts and get no suggestions.
+
+use crate::bytecode::{self, Bytecode};
+use crate::histogram::Histogram;
+use crate::interpreter::Op;
+use crate::span::{SourceFiles, SourceMap, Span};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Iterations after which a loop is worth a suggestion
+pub const HOT_ITERATIONS: u64 = 1000;
+
+/// A rewrite worth making, at the code it's about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    pub span: Span,
+    pub message: String,
+}
+
+/// Suggestions for `ops` (unoptimized, as parsed from `sources`), given
+/// what `histogram` counted while `bytecode`, compiled from them, ran.
+/// Hottest loops first.
+pub fn suggest(
+    ops: &[Op],
+    spans: &SourceMap,
+    sources: &SourceFiles,
+    bytecode: &Bytecode,
+    histogram: &Histogram,
+) -> Vec<Suggestion> {
+    // Iterations of each loop still a loop in the bytecode, by its span
+    let starts: HashMap<Span, usize> = bytecode
+        .spans()
+        .iter()
+        .filter(|&&(pc, _)| bytecode.code[pc] == bytecode::JUMP_ZERO)
+        .map(|&(pc, span)| (span, pc))
+        .collect();
+    let iterations = |span: &Span| {
+        let pc = starts.get(span)?;
+        Some(histogram.loops().get(pc)?.iterations)
+    };
+
+    let mut spans = spans.spans().iter().copied();
+    let tree = nodes(ops, &mut spans);
+    let mut found = Vec::new();
+    visit(&tree, &mut |node| {
+        let Some(count) = iterations(&node.span).filter(|&n| n >= HOT_ITERATIONS) else {
+            return;
+        };
+        let text = |span: Span| String::from_utf8_lossy(&sources.source()[span.start..span.end]);
+        let at = |span: Span| match sources.locate(span.start) {
+            Some((file, line, column)) => format!("{}:{}:{}", file, line, column),
+            None => format!("offset {}", span.start),
+        };
+        for (set, value) in hoistable(&node.children) {
+            let message = format!(
+                "`{}` sets a cell to {} on each of the {} iterations of the loop at {}, \
+                 which changes it nowhere else; consider setting it once before that loop",
+                text(set),
+                value,
+                count,
+                at(node.span)
+            );
+            found.push((count, Suggestion { span: set, message }));
+        }
+        if let Some((step, rewrite)) = stepped(&node.children) {
+            let message = format!(
+                "loop runs {} iterations counting down by {}, so -O1 can't turn it into \
+                 multiplications; if its counter is always a multiple of {} on entry, \
+                 `{}` does the same",
+                count, step, step, rewrite
+            );
+            found.push((
+                count,
+                Suggestion {
+                    span: node.span,
+                    message,
+                },
+            ));
+        }
+    });
+    found.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.span.start.cmp(&b.1.span.start)));
+    found
+        .into_iter()
+        .map(|(_, suggestion)| suggestion)
+        .collect()
+}
+
+/// An op with its span and, for a loop, its body
+struct Node<'a> {
+    op: &'a Op,
+    span: Span,
+    children: Vec<Node<'a>>,
+}
+
+/// `ops` as nodes, taking their spans in pre-order from `spans`
+fn nodes<'a>(ops: &'a [Op], spans: &mut impl Iterator<Item = Span>) -> Vec<Node<'a>> {
+    ops.iter()
+        .map(|op| {
+            let span = spans.next().unwrap_or_default();
+            let children = match op {
+                Op::Loop(body) => nodes(body, spans),
+                _ => Vec::new(),
+            };
+            Node { op, span, children }
+        })
+        .collect()
+}
+
+/// Call `f` on every loop in `tree`, outer ones first
+fn visit(tree: &[Node], f: &mut impl FnMut(&Node)) {
+    for node in tree {
+        if let Op::Loop(_) = node.op {
+            f(node);
+            visit(&node.children, f);
+        }
+    }
+}
+
+/// `[-]` or `[+]`
+fn is_clear(node: &Node) -> bool {
+    matches!(node.op, Op::Loop(body) if matches!(body[..], [Op::Decrement(1)] | [Op::Increment(1)]))
+}
+
+/// Spans and values of the constants the loop body `body` sets cells to,
+/// where nothing else in the body touches the cell before or changes it
+/// after. None of them is the loop's own cell.
+fn hoistable(body: &[Node]) -> Vec<(Span, u8)> {
+    // Offsets something has touched, writes to each, and the first sets
+    let mut touched: HashSet<isize> = HashSet::new();
+    let mut writes: HashMap<isize, usize> = HashMap::new();
+    let mut sets: BTreeMap<isize, (Span, u8)> = BTreeMap::new();
+    let mut offset = 0isize;
+    let mut i = 0;
+    while i < body.len() {
+        let node = &body[i];
+        i += 1;
+        match node.op {
+            Op::MoveRight(n) => offset += *n as isize,
+            Op::MoveLeft(n) => offset -= *n as isize,
+            Op::Loop(_) if is_clear(node) => {
+                // Take the `+`s or `-`s right after the clear along
+                let (mut span, mut value) = (node.span, 0u8);
+                while let Some(next) = body.get(i) {
+                    match next.op {
+                        Op::Increment(n) => value = value.wrapping_add(*n),
+                        Op::Decrement(n) => value = value.wrapping_sub(*n),
+                        _ => break,
+                    }
+                    span = span.to(next.span);
+                    i += 1;
+                }
+                *writes.entry(offset).or_default() += 1;
+                if !touched.contains(&offset) {
+                    sets.insert(offset, (span, value));
+                }
+                touched.insert(offset);
+            }
+            Op::Increment(_) | Op::Decrement(_) | Op::Input => {
+                *writes.entry(offset).or_default() += 1;
+                touched.insert(offset);
+            }
+            Op::Output => {
+                touched.insert(offset);
+            }
+            Op::Loop(_) => {
+                // A balanced inner loop reads its own cell and writes those
+                // it changes; any other loop leaves the offsets unknown
+                let Some(inner) = balanced_writes(&node.children) else {
+                    return Vec::new();
+                };
+                touched.insert(offset);
+                for written in inner {
+                    *writes.entry(offset + written).or_default() += 1;
+                    touched.insert(offset + written);
+                }
+            }
+            _ => return Vec::new(),
+        }
+    }
+    if offset != 0 {
+        return Vec::new();
+    }
+    sets.into_iter()
+        .filter(|&(offset, _)| offset != 0 && writes.get(&offset) == Some(&1))
+        .map(|(_, set)| set)
+        .collect()
+}
+
+/// Offsets a loop body possibly writes, if it returns to where it started
+fn balanced_writes(body: &[Node]) -> Option<Vec<isize>> {
+    let mut writes = Vec::new();
+    let mut offset = 0isize;
+    for node in body {
+        match node.op {
+            Op::MoveRight(n) => offset += *n as isize,
+            Op::MoveLeft(n) => offset -= *n as isize,
+            Op::Increment(_) | Op::Decrement(_) | Op::Input => writes.push(offset),
+            Op::Output => {}
+            Op::Loop(_) => {
+                writes.extend(balanced_writes(&node.children)?.iter().map(|w| w + offset))
+            }
+            _ => return None,
+        }
+    }
+    (offset == 0).then_some(writes)
+}
+
+/// For a loop body of `+-<>` that returns to its cell and counts it down
+/// by more than one, the step and the body counting down by one, if every
+/// other change divides by the step
+fn stepped(body: &[Node]) -> Option<(u8, String)> {
+    let mut deltas: BTreeMap<isize, i32> = BTreeMap::new();
+    let mut offset = 0isize;
+    for node in body {
+        match node.op {
+            Op::MoveRight(n) => offset += *n as isize,
+            Op::MoveLeft(n) => offset -= *n as isize,
+            Op::Increment(n) => *deltas.entry(offset).or_default() += i32::from(*n),
+            Op::Decrement(n) => *deltas.entry(offset).or_default() -= i32::from(*n),
+            _ => return None,
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+    // As a byte, so 255 `+`s step down by one too
+    let step = (-deltas.remove(&0)?).rem_euclid(256);
+    if !(2..=127).contains(&step) || deltas.values().any(|delta| delta % step != 0) {
+        return None;
+    }
+
+    let mut rewrite = String::from("[-");
+    let mut at = 0isize;
+    for (&offset, &delta) in deltas.iter().filter(|&(_, &delta)| delta != 0) {
+        let (mv, count) = if offset > at {
+            ('>', offset - at)
+        } else {
+            ('<', at - offset)
+        };
+        rewrite.extend(std::iter::repeat_n(mv, count as usize));
+        let (sign, count) = if delta > 0 {
+            ('+', delta / step)
+        } else {
+            ('-', -delta / step)
+        };
+        rewrite.extend(std::iter::repeat_n(sign, count as usize));
+        at = offset;
+    }
+    let back = if at > 0 { '<' } else { '>' };
+    rewrite.extend(std::iter::repeat_n(back, at.unsigned_abs()));
+    rewrite.push(']');
+    Some((step as u8, rewrite))
+}
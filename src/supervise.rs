@@ -0,0 +1,312 @@
+//! `trainfuck supervise config.toml`: run a deployment of programs
+//!
+//! Starts every program listed in the config on its own thread and keeps
+//! them running according to their restart policies, like a process
+//! supervisor for a chat server and its bots:
+//!
+//! ```toml
+//! # "virtual" (the default) connects the programs to each other in-process;
+//! # "tcp" gives them the real network
+//! network = "virtual"
+//!
+//! [[program]]
+//! name = "server"
+//! file = "chat/server.bf"
+//! restart = "always"      # or "on-failure" (the default), "never"
+//! log = "server.log"      # where `.` output goes; stdout when unset
+//!
+//! [[program]]
+//! name = "greeter"
+//! file = "bots/greeter.bf"
+//! ext = ["line"]
+//! max_restarts = 5
+//! restart_delay = "2s"
+//! ```
+//!
+//! On the virtual network only ports matter (see
+//! [`VirtualNetwork`](trainfuck::virtual_net::VirtualNetwork)), so a bot
+//! dialing 127.0.0.1:8888 reaches the server that listened on 0.0.0.0:8888.
+//! Programs read no input. Ctrl-C stops them all.
+
+use crate::{extensions, open_kv_store, parse_duration, Extension};
+use serde::Deserialize;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use trainfuck::bytecode::Bytecode;
+use trainfuck::interpreter::{self, TrainfuckError, VM};
+use trainfuck::kv::SharedKvStore;
+use trainfuck::net::{Network, TcpNetwork};
+use trainfuck::shared::SharedSegment;
+use trainfuck::virtual_net::VirtualNetwork;
+use trainfuck::{optimizer, tiered};
+
+/// How often a program waiting to restart checks for Ctrl-C
+const RESTART_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    network: NetworkKind,
+    /// File `{` and `}` keep their keys in, shared by all programs
+    #[serde(default = "default_kv_store")]
+    kv_store: PathBuf,
+    #[serde(rename = "program")]
+    programs: Vec<Program>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum NetworkKind {
+    #[default]
+    Virtual,
+    Tcp,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum Restart {
+    Never,
+    #[default]
+    OnFailure,
+    Always,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct Program {
+    name: String,
+    file: PathBuf,
+    #[serde(default)]
+    restart: Restart,
+    /// Restarts before giving up; unlimited when unset
+    max_restarts: Option<u32>,
+    #[serde(default = "default_restart_delay")]
+    restart_delay: String,
+    log: Option<PathBuf>,
+    #[serde(default = "default_tape_size")]
+    tape_size: usize,
+    #[serde(default)]
+    ext: Vec<String>,
+}
+
+fn default_kv_store() -> PathBuf {
+    PathBuf::from("trainfuck.kv")
+}
+
+fn default_restart_delay() -> String {
+    "1s".to_string()
+}
+
+fn default_tape_size() -> usize {
+    30_000
+}
+
+/// A program ready to start: compiled, with its settings checked
+struct Prepared {
+    program: Program,
+    bytecode: Bytecode,
+    extensions: interpreter::Extensions,
+    restart_delay: Duration,
+}
+
+/// Run the programs in the config at `path` until they have all stopped for
+/// good or `interrupted` is set
+pub fn supervise(path: &Path, interrupted: Arc<AtomicBool>) -> i32 {
+    let config: Config = match fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| toml::from_str(&text).map_err(|e| e.to_string()))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error reading {:?}: {}", path, e);
+            return 1;
+        }
+    };
+    if config.programs.is_empty() {
+        eprintln!("Error: {:?} lists no [[program]]", path);
+        return 1;
+    }
+
+    // Paths in the config are relative to it
+    let base = path.parent().unwrap_or(Path::new("."));
+    let mut prepared = Vec::new();
+    for mut program in config.programs {
+        program.file = base.join(&program.file);
+        program.log = program.log.map(|log| base.join(log));
+        match prepare(program) {
+            Ok(p) => prepared.push(p),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        }
+    }
+
+    let kv = match prepared.iter().any(|p| p.extensions.kv) {
+        true => match open_kv_store(&base.join(&config.kv_store)) {
+            Some(store) => Some(store),
+            None => return 1,
+        },
+        false => None,
+    };
+    let shared = SharedSegment::new();
+    let network = VirtualNetwork::new();
+
+    let handles: Vec<_> = prepared
+        .into_iter()
+        .map(|prepared| {
+            let name = prepared.program.name.clone();
+            let network: Box<dyn Fn() -> Box<dyn Network> + Send> = match config.network {
+                NetworkKind::Virtual => {
+                    let network = network.clone();
+                    Box::new(move || Box::new(network.clone()))
+                }
+                NetworkKind::Tcp => Box::new(|| Box::new(TcpNetwork)),
+            };
+            let (kv, shared, interrupted) = (kv.clone(), shared.clone(), interrupted.clone());
+            let handle =
+                thread::spawn(move || keep_running(prepared, network, kv, shared, interrupted));
+            (name, handle)
+        })
+        .collect();
+
+    let mut code = 0;
+    for (name, handle) in handles {
+        match handle.join() {
+            Ok(true) => {}
+            Ok(false) => code = 1,
+            Err(_) => {
+                eprintln!("[trainfuck] {}: supervisor thread panicked", name);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+fn prepare(program: Program) -> Result<Prepared, String> {
+    let mut enabled = Vec::new();
+    for name in &program.ext {
+        let extension = <Extension as clap::ValueEnum>::from_str(name, true)
+            .map_err(|_| format!("{}: unknown extension {:?}", program.name, name))?;
+        enabled.push(extension);
+    }
+    let extensions = extensions(&enabled);
+    let restart_delay = parse_duration(&program.restart_delay)
+        .map_err(|e| format!("{}: restart_delay: {}", program.name, e))?;
+    let source = fs::read(&program.file)
+        .map_err(|e| format!("{}: reading {:?}: {}", program.name, program.file, e))?;
+    let mut parser = interpreter::Parser::new().with_extensions(extensions);
+    let (ops, spans) = parser
+        .feed(&source)
+        .and_then(|()| parser.finish_with_spans())
+        .map_err(|e| format!("{}: parse error: {}", program.name, e))?;
+    let (ops, spans) = optimizer::optimize_with_spans(ops, &spans);
+    Ok(Prepared {
+        bytecode: Bytecode::compile_with_spans(&ops, &spans),
+        program,
+        extensions,
+        restart_delay,
+    })
+}
+
+/// Run one program, restarting it as its policy says; returns whether it
+/// finished without a failure it wasn't restarted from
+fn keep_running(
+    prepared: Prepared,
+    network: Box<dyn Fn() -> Box<dyn Network> + Send>,
+    kv: Option<SharedKvStore>,
+    shared: SharedSegment,
+    interrupted: Arc<AtomicBool>,
+) -> bool {
+    let program = &prepared.program;
+    let mut restarts = 0;
+    loop {
+        eprintln!("[trainfuck] {}: starting {:?}", program.name, program.file);
+        let result = run_once(
+            &prepared,
+            network(),
+            kv.clone(),
+            shared.clone(),
+            &interrupted,
+        );
+        let failed = match result {
+            Ok(()) => {
+                eprintln!("[trainfuck] {}: exited", program.name);
+                false
+            }
+            Err(TrainfuckError::Interrupted) => return true,
+            Err(e) => {
+                eprintln!("[trainfuck] {}: failed: {}", program.name, e);
+                true
+            }
+        };
+
+        let restart = match program.restart {
+            Restart::Never => false,
+            Restart::OnFailure => failed,
+            Restart::Always => true,
+        };
+        if !restart {
+            return !failed;
+        }
+        if program.max_restarts.is_some_and(|max| restarts >= max) {
+            eprintln!(
+                "[trainfuck] {}: giving up after {} restarts",
+                program.name, restarts
+            );
+            return !failed;
+        }
+        restarts += 1;
+        if !sleep_unless_interrupted(prepared.restart_delay, &interrupted) {
+            return true;
+        }
+    }
+}
+
+fn run_once(
+    prepared: &Prepared,
+    network: Box<dyn Network>,
+    kv: Option<SharedKvStore>,
+    shared: SharedSegment,
+    interrupted: &Arc<AtomicBool>,
+) -> interpreter::Result<()> {
+    let program = &prepared.program;
+    let mut vm = VM::with_tape_size(program.tape_size);
+    vm.extensions = prepared.extensions;
+    vm.input = Box::new(io::empty());
+    vm.network = network;
+    vm.interrupt = Some(interrupted.clone());
+    if prepared.extensions.shared {
+        vm.shared = Some(shared);
+    }
+    vm.kv = kv;
+    if let Some(ref log) = program.log {
+        let file = OpenOptions::new().create(true).append(true).open(log)?;
+        vm.output = Box::new(file);
+    }
+    let result = vm.execute_tiered(&prepared.bytecode, tiered::DEFAULT_THRESHOLD);
+    let _ = vm.shutdown();
+    let _ = io::stdout().flush();
+    result
+}
+
+/// Wait `delay`; false if Ctrl-C came first
+fn sleep_unless_interrupted(delay: Duration, interrupted: &AtomicBool) -> bool {
+    let mut left = delay;
+    while !left.is_zero() {
+        if interrupted.load(Ordering::Relaxed) {
+            return false;
+        }
+        let step = left.min(RESTART_POLL);
+        thread::sleep(step);
+        left -= step;
+    }
+    !interrupted.load(Ordering::Relaxed)
+}
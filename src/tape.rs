@@ -0,0 +1,129 @@
+//! Tape storage
+//!
+//! Small tapes live in a plain `Vec`. Large ones (`--tape-size 1G`) are
+//! backed by an anonymous `mmap` with `MAP_NORESERVE`, so the kernel only
+//! commits the pages a program actually touches while cell access stays a
+//! plain slice index.
+
+use std::ops::{Deref, DerefMut};
+
+/// Tapes at least this large are memory-mapped rather than heap-allocated
+#[cfg(unix)]
+const MMAP_THRESHOLD: usize = 1 << 20;
+
+pub struct Tape {
+    storage: Storage,
+}
+
+enum Storage {
+    Heap(Vec<u8>),
+    #[cfg(unix)]
+    Mapped {
+        ptr: *mut u8,
+        len: usize,
+    },
+}
+
+// The mapping is owned exclusively by this Tape, like the Vec case
+unsafe impl Send for Tape {}
+
+impl Tape {
+    /// A zeroed tape of `size` cells (at least one)
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1);
+
+        #[cfg(unix)]
+        if size >= MMAP_THRESHOLD {
+            if let Some(storage) = Self::map(size) {
+                return Tape { storage };
+            }
+        }
+
+        Tape {
+            storage: Storage::Heap(vec![0u8; size]),
+        }
+    }
+
+    /// Add `left` zeroed cells before the first cell and `right` after the
+    /// last, moving the contents to the heap
+    pub fn grow(&mut self, left: usize, right: usize) {
+        let mut cells = vec![0u8; left + self.len() + right];
+        cells[left..left + self.len()].copy_from_slice(self);
+        *self = Tape {
+            storage: Storage::Heap(cells),
+        };
+    }
+
+    #[cfg(unix)]
+    fn map(len: usize) -> Option<Storage> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        Some(Storage::Mapped {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+}
+
+impl Deref for Tape {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.storage {
+            Storage::Heap(ref cells) => cells,
+            #[cfg(unix)]
+            Storage::Mapped { ptr, len } => unsafe { std::slice::from_raw_parts(ptr, len) },
+        }
+    }
+}
+
+impl DerefMut for Tape {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self.storage {
+            Storage::Heap(ref mut cells) => cells,
+            #[cfg(unix)]
+            Storage::Mapped { ptr, len } => unsafe { std::slice::from_raw_parts_mut(ptr, len) },
+        }
+    }
+}
+
+impl Drop for Tape {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Storage::Mapped { ptr, len } = self.storage {
+            unsafe {
+                libc::munmap(ptr as *mut libc::c_void, len);
+            }
+        }
+    }
+}
+
+/// Parse a size such as `30000`, `64K`, `16M` or `1G` (binary multiples)
+pub fn parse_size(text: &str) -> Result<usize, String> {
+    let text = text.trim();
+    let (digits, multiplier) = match text.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&text[..i], 1usize << 10),
+        Some((i, 'm' | 'M')) => (&text[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&text[..i], 1 << 30),
+        _ => (text, 1),
+    };
+    let count: usize = digits
+        .parse()
+        .map_err(|_| format!("invalid size {:?}", text))?;
+    match count.checked_mul(multiplier) {
+        Some(0) => Err("size must be at least 1".into()),
+        Some(size) => Ok(size),
+        None => Err(format!("size {:?} is too large", text)),
+    }
+}
@@ -0,0 +1,62 @@
+//! Tape snapshots as images (`--render-tape out.pgm`)
+//!
+//! Each cell becomes one grayscale pixel (0 black, 255 white), `--width`
+//! cells to a row, so a program that draws into memory can be looked at
+//! directly. Rows after the last non-zero cell are left out. The file is a
+//! binary PGM (`.pgm`) or PPM (`.ppm`), which most image viewers open.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Grayscale netpbm (P5)
+    Pgm,
+    /// Color netpbm (P6), with equal red, green and blue
+    Ppm,
+}
+
+impl Format {
+    /// The format `path`'s extension names
+    pub fn for_path(path: &Path) -> Result<Format, String> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("pgm") => Ok(Format::Pgm),
+            Some("ppm") => Ok(Format::Ppm),
+            _ => Err(format!("{:?} should end in .pgm or .ppm", path)),
+        }
+    }
+}
+
+/// Write `tape` to `path` as rows of `width` pixels
+pub fn write(path: &Path, format: Format, tape: &[u8], width: usize) -> io::Result<()> {
+    let used = tape.iter().rposition(|&c| c != 0).map_or(1, |i| i + 1);
+    let height = used.div_ceil(width);
+
+    // Written next to the file and renamed over it, so a viewer watching
+    // the file never opens half an image
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let mut out = BufWriter::new(File::create(&partial)?);
+    let magic = match format {
+        Format::Pgm => "P5",
+        Format::Ppm => "P6",
+    };
+    write!(out, "{}\n{} {}\n255\n", magic, width, height)?;
+    for row in 0..height {
+        for column in 0..width {
+            let value = tape.get(row * width + column).copied().unwrap_or(0);
+            match format {
+                Format::Pgm => out.write_all(&[value])?,
+                Format::Ppm => out.write_all(&[value; 3])?,
+            }
+        }
+    }
+    out.flush()?;
+    drop(out);
+    std::fs::rename(&partial, path)
+}
@@ -0,0 +1,361 @@
+//! Tiered execution
+//!
+//! [`VM::execute_tiered`] starts out in the bytecode interpreter, counting
+//! how often each loop runs. Once a loop passes the threshold its bytecode
+//! is compiled into a tree of closures with operands already decoded, and
+//! the loop's `JUMP_ZERO` is patched so every later entry calls the
+//! compiled loop directly. Short scripts never pay for compilation; long
+//! running ones spend their time in compiled code.
+//!
+//! There is no native code generator: "compiled" here means closure
+//! threaded code, which removes decoding and dispatch but not the
+//! per-instruction step accounting.
+//!
+//! Loops that [`balance`](crate::balance) proves balanced get a second
+//! body that moves the pointer and indexes cells without wrapping. Each
+//! entry runs it if the loop's whole reach is on the tape, and the checked
+//! body otherwise.
+
+use crate::balance::{self, Reach};
+use crate::bytecode::{self, read_targets, read_u32, Bytecode};
+use crate::interpreter::{Result, TapeModel, VM};
+use std::collections::HashMap;
+
+/// Loop iterations after which a loop is compiled
+pub const DEFAULT_THRESHOLD: u32 = 1000;
+
+type Compiled = Box<dyn Fn(&mut VM) -> Result<()>>;
+
+/// A compiled loop body
+struct CompiledLoop {
+    body: Vec<Compiled>,
+    /// The body without wrapping and how far it reaches, for balanced loops
+    fast: Option<(Vec<Compiled>, Reach)>,
+    /// Bytecode address of each body instruction, for error locations
+    pcs: Vec<usize>,
+    /// Address of the loop's `JUMP_ZERO`
+    start: usize,
+}
+
+impl CompiledLoop {
+    /// Run the loop, counting the entry test unless the interpreter has
+    /// already counted it as a back edge
+    fn run(&self, vm: &mut VM, count_entry: bool) -> Result<()> {
+        // The innermost failing instruction is the one reported
+        if count_entry {
+            vm.tick().inspect_err(|_| vm.locate_fault(self.start))?;
+            if vm.tape[vm.pointer] == 0 {
+                return Ok(());
+            }
+            vm.enter_loop();
+        }
+        // Every iteration starts on this cell, so one check covers them all
+        let body = match self.fast {
+            Some((ref fast, reach))
+                if vm.tape_model == TapeModel::Classic && reach.fits(vm.pointer, vm.tape.len()) =>
+            {
+                fast
+            }
+            _ => &self.body,
+        };
+        while vm.tape[vm.pointer] != 0 {
+            for (instruction, &pc) in body.iter().zip(&self.pcs) {
+                instruction(vm).inspect_err(|_| vm.locate_fault(pc))?;
+            }
+            vm.tick().inspect_err(|_| vm.locate_fault(self.start))?;
+        }
+        vm.leave_loop();
+        Ok(())
+    }
+}
+
+impl VM {
+    /// Run a compiled program, compiling loops that iterate more than
+    /// `threshold` times
+    pub fn execute_tiered(&mut self, bytecode: &Bytecode, threshold: u32) -> Result<()> {
+        // Compiled loops don't check protected cells, record history or
+        // counts, stop at breakpoints, keep to a step delay or call watchers
+        // after every instruction
+        if !self.guards.is_empty()
+            || self.history.is_some()
+            || self.histogram.is_some()
+            || self.debugging()
+            || self.step_delay.is_some()
+            || !self.watchers.is_empty()
+        {
+            return self.execute_bytecode(bytecode);
+        }
+        // Patched in place as loops get hot
+        let mut code = bytecode.code.clone();
+        let mut iterations = vec![0u32; code.len()];
+        let mut compiled: HashMap<usize, CompiledLoop> = HashMap::new();
+
+        self.fault_pc = None;
+        self.loop_depth = 0;
+        let mut pc = 0;
+        loop {
+            match code[pc] {
+                bytecode::HALT => return Ok(()),
+                bytecode::COMPILED => {
+                    compiled[&pc]
+                        .run(self, true)
+                        .inspect_err(|_| self.locate_fault(pc))?;
+                    pc = read_u32(&code, pc + 1);
+                }
+                bytecode::JUMP_NONZERO => {
+                    let start = read_u32(&code, pc + 1) - 5;
+                    let next = self
+                        .dispatch(&code, pc)
+                        .inspect_err(|_| self.locate_fault(pc))?;
+                    if next == pc + 5 {
+                        pc = next;
+                        continue;
+                    }
+                    // Saturates at u32::MAX for loops too deep to compile
+                    let count = &mut iterations[start];
+                    *count = count.saturating_add(1);
+                    if *count < threshold || *count == u32::MAX {
+                        pc = next;
+                        continue;
+                    }
+                    // Hot: compile, patch the entry and finish the loop compiled
+                    let depth = self.max_loop_depth.saturating_sub(1);
+                    let Some(hot) = compile_loop(&code, start, depth) else {
+                        *count = u32::MAX;
+                        pc = next;
+                        continue;
+                    };
+                    let hot = compiled.entry(start).or_insert(hot);
+                    code[start] = bytecode::COMPILED;
+                    hot.run(self, false)
+                        .inspect_err(|_| self.locate_fault(pc))?;
+                    pc += 5;
+                }
+                _ => {
+                    pc = self
+                        .dispatch(&code, pc)
+                        .inspect_err(|_| self.locate_fault(pc))?
+                }
+            }
+        }
+    }
+
+    /// Record `pc` as the failing instruction unless a more precise
+    /// location was already recorded
+    fn locate_fault(&mut self, pc: usize) {
+        self.fault_pc.get_or_insert(pc);
+    }
+}
+
+/// Compile the loop whose `JUMP_ZERO` is at `start`, or None if it has
+/// loops nested more than `depth` deep (compiled loops recurse)
+fn compile_loop(code: &[u8], start: usize, depth: usize) -> Option<CompiledLoop> {
+    let end = read_u32(code, start + 1) - 5;
+    let (body, pcs) = compile(code, start + 5, end, depth, false)?;
+    let fast = match balance::body_reach(code, start + 5, end) {
+        Some(reach) => Some((compile(code, start + 5, end, depth, true)?.0, reach)),
+        None => None,
+    };
+    Some(CompiledLoop {
+        body,
+        fast,
+        pcs,
+        start,
+    })
+}
+
+/// Compile the instructions in `code[pc..end]`, returning them with their
+/// addresses, or None if they contain loops nested more than `depth` deep.
+/// `unchecked` code doesn't wrap the pointer, so it may only run where the
+/// enclosing loop's [`Reach`] fits.
+fn compile(
+    code: &[u8],
+    mut pc: usize,
+    end: usize,
+    depth: usize,
+    unchecked: bool,
+) -> Option<(Vec<Compiled>, Vec<usize>)> {
+    let mut out: Vec<Compiled> = Vec::new();
+    let mut pcs = Vec::new();
+    while pc < end {
+        pcs.push(pc);
+        let u32_at = |at| read_u32(code, at);
+        let instruction: Compiled = match code[pc] {
+            bytecode::RIGHT if unchecked => {
+                let n = u32_at(pc + 1);
+                pc += 5;
+                Box::new(move |vm| {
+                    vm.tick()?;
+                    vm.pointer += n;
+                    Ok(())
+                })
+            }
+            bytecode::LEFT if unchecked => {
+                let n = u32_at(pc + 1);
+                pc += 5;
+                Box::new(move |vm| {
+                    vm.tick()?;
+                    vm.pointer -= n;
+                    Ok(())
+                })
+            }
+            bytecode::RIGHT_ADD if unchecked => {
+                let (n, delta) = (u32_at(pc + 1), code[pc + 5]);
+                pc += 6;
+                Box::new(move |vm| {
+                    vm.tick()?;
+                    vm.pointer += n;
+                    vm.add(delta);
+                    Ok(())
+                })
+            }
+            bytecode::LEFT_ADD if unchecked => {
+                let (n, delta) = (u32_at(pc + 1), code[pc + 5]);
+                pc += 6;
+                Box::new(move |vm| {
+                    vm.tick()?;
+                    vm.pointer -= n;
+                    vm.add(delta);
+                    Ok(())
+                })
+            }
+            bytecode::MUL_ADD => {
+                let targets: Vec<_> = read_targets(code, pc).collect();
+                pc = next_instruction(code, pc);
+                match unchecked {
+                    true => Box::new(move |vm| {
+                        vm.tick()?;
+                        let value = vm.tape[vm.pointer];
+                        if value != 0 {
+                            for &(offset, factor) in &targets {
+                                let target = vm.pointer.wrapping_add_signed(offset);
+                                vm.tape[target] =
+                                    vm.tape[target].wrapping_add(value.wrapping_mul(factor));
+                            }
+                        }
+                        Ok(())
+                    }),
+                    false => Box::new(move |vm| {
+                        vm.tick()?;
+                        vm.mul_add(targets.iter().copied());
+                        Ok(())
+                    }),
+                }
+            }
+            bytecode::RIGHT => {
+                let n = u32_at(pc + 1);
+                pc += 5;
+                Box::new(move |vm| {
+                    vm.tick()?;
+                    vm.move_right(n);
+                    Ok(())
+                })
+            }
+            bytecode::LEFT => {
+                let n = u32_at(pc + 1);
+                pc += 5;
+                Box::new(move |vm| {
+                    vm.tick()?;
+                    vm.move_left(n);
+                    Ok(())
+                })
+            }
+            bytecode::ADD => {
+                let delta = code[pc + 1];
+                pc += 2;
+                Box::new(move |vm| {
+                    vm.tick()?;
+                    vm.add(delta);
+                    Ok(())
+                })
+            }
+            bytecode::RIGHT_ADD => {
+                let (n, delta) = (u32_at(pc + 1), code[pc + 5]);
+                pc += 6;
+                Box::new(move |vm| {
+                    vm.tick()?;
+                    vm.move_right(n);
+                    vm.add(delta);
+                    Ok(())
+                })
+            }
+            bytecode::LEFT_ADD => {
+                let (n, delta) = (u32_at(pc + 1), code[pc + 5]);
+                pc += 6;
+                Box::new(move |vm| {
+                    vm.tick()?;
+                    vm.move_left(n);
+                    vm.add(delta);
+                    Ok(())
+                })
+            }
+            bytecode::CLEAR => {
+                pc += 1;
+                Box::new(|vm| {
+                    vm.tick()?;
+                    vm.tape[vm.pointer] = 0;
+                    Ok(())
+                })
+            }
+            bytecode::SCAN_RIGHT => {
+                let stride = u32_at(pc + 1);
+                pc += 5;
+                Box::new(move |vm| {
+                    vm.tick()?;
+                    vm.scan_right(stride)
+                })
+            }
+            bytecode::SCAN_LEFT => {
+                let stride = u32_at(pc + 1);
+                pc += 5;
+                Box::new(move |vm| {
+                    vm.tick()?;
+                    vm.scan_left(stride)
+                })
+            }
+            bytecode::JUMP_ZERO | bytecode::COMPILED => {
+                let after = u32_at(pc + 1);
+                let depth = depth.checked_sub(1)?;
+                // Inside a balanced loop, inner loops are within its reach
+                let inner = match unchecked {
+                    true => {
+                        let (body, pcs) = compile(code, pc + 5, after - 5, depth, true)?;
+                        CompiledLoop {
+                            body,
+                            fast: None,
+                            pcs,
+                            start: pc,
+                        }
+                    }
+                    false => compile_loop(code, pc, depth)?,
+                };
+                pc = after;
+                Box::new(move |vm| inner.run(vm, true))
+            }
+            // Everything else does I/O or decodes variable-length operands;
+            // keep using the interpreter for it
+            _ => {
+                let at = pc;
+                pc = next_instruction(code, pc);
+                let single = code[at..pc].to_vec();
+                Box::new(move |vm| vm.dispatch(&single, 0).map(|_| ()))
+            }
+        };
+        out.push(instruction);
+    }
+    Some((out, pcs))
+}
+
+/// Offset of the instruction after the one at `pc`, for opcodes without
+/// jumps
+fn next_instruction(code: &[u8], pc: usize) -> usize {
+    match code[pc] {
+        bytecode::ADD | bytecode::ADD_OUTPUT => pc + 2,
+        bytecode::MUL_ADD => pc + 5 + read_u32(code, pc + 1) * 9,
+        bytecode::PRINT => pc + 5 + read_u32(code, pc + 1),
+        bytecode::LOAD => pc + 13 + read_u32(code, pc + 9),
+        bytecode::RIGHT | bytecode::LEFT | bytecode::SCAN_RIGHT | bytecode::SCAN_LEFT => pc + 5,
+        bytecode::RIGHT_ADD | bytecode::LEFT_ADD => pc + 6,
+        _ => pc + 1,
+    }
+}
@@ -0,0 +1,457 @@
+//! Traffic logging (`--log-traffic`) and replay (`--replay-traffic`)
+//!
+//! [`LoggingNetwork`] wraps another [`Network`] and writes every byte its
+//! connections carry to a [`TrafficLog`], one line per event:
+//!
+//! ```text
+//! # trainfuck traffic log, started at unix time 1700000000.000000
+//! 0.000000 1 accept 127.0.0.1:50712
+//! 0.204117 1 < 68 "h"
+//! 0.204231 1 > 68 "h"
+//! 3.051208 1 eof
+//! 3.051340 1 close
+//! ```
+//!
+//! Each line is the time since the log started in seconds, the channel (a
+//! connection, numbered from 1 in the order they opened) and the event:
+//! `accept` or `connect` with the peer address (or `-`), `<` for bytes
+//! received and `>` for bytes sent, in hex followed by a readable copy,
+//! `eof` when the peer closed its end and `close` when the connection was
+//! dropped. Lines starting with `#` are comments.
+//!
+//! [`ReplayNetwork`] plays such a log back to a program offline: `@` and
+//! `%` get the logged connections of their kind in order, and each one
+//! delivers its recorded `<` bytes at their original times (or at once when
+//! fast) and then end of stream. What the program sends is compared with
+//! the recorded `>` bytes, and the first difference on each connection is
+//! reported on stderr.
+
+use crate::net::{Listener, Network, Stream};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where logged traffic goes; clones write to the same log
+#[derive(Clone)]
+pub struct TrafficLog {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+    start: Instant,
+    channels: Arc<AtomicU32>,
+}
+
+impl TrafficLog {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        let log = TrafficLog {
+            out: Arc::new(Mutex::new(Box::new(out))),
+            start: Instant::now(),
+            channels: Arc::new(AtomicU32::new(0)),
+        };
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        log.line(format_args!(
+            "# trainfuck traffic log, started at unix time {}.{:06}",
+            started.as_secs(),
+            started.subsec_micros()
+        ));
+        log
+    }
+
+    /// Log to a new file at `path`, replacing any old one
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    fn open_channel(&self, kind: &str, peer: Option<SocketAddr>) -> u32 {
+        let channel = self.channels.fetch_add(1, Ordering::Relaxed) + 1;
+        match peer {
+            Some(peer) => self.event(channel, format_args!("{} {}", kind, peer)),
+            None => self.event(channel, format_args!("{} -", kind)),
+        }
+        channel
+    }
+
+    fn event(&self, channel: u32, event: std::fmt::Arguments) {
+        let elapsed = self.start.elapsed();
+        self.line(format_args!(
+            "{}.{:06} {} {}",
+            elapsed.as_secs(),
+            elapsed.subsec_micros(),
+            channel,
+            event
+        ));
+    }
+
+    fn bytes(&self, channel: u32, direction: char, bytes: &[u8]) {
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        self.event(
+            channel,
+            format_args!("{} {} \"{}\"", direction, hex, bytes.escape_ascii()),
+        );
+    }
+
+    fn line(&self, line: std::fmt::Arguments) {
+        // Flushed per line so the log is complete up to a crash; a failing
+        // log must not take the program down with it
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{}", line).and_then(|()| out.flush());
+    }
+}
+
+/// A [`Network`] whose connections are recorded in a [`TrafficLog`]
+pub struct LoggingNetwork {
+    inner: Box<dyn Network>,
+    log: TrafficLog,
+}
+
+impl LoggingNetwork {
+    pub fn new(inner: Box<dyn Network>, log: TrafficLog) -> Self {
+        LoggingNetwork { inner, log }
+    }
+}
+
+impl Network for LoggingNetwork {
+    fn listen(&mut self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(LoggingListener {
+            inner: self.inner.listen(addr)?,
+            log: self.log.clone(),
+        }))
+    }
+
+    fn connect(&mut self, addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        let inner = self.inner.connect(addr)?;
+        let peer = inner.peer_addr().or(Some(SocketAddr::V4(addr)));
+        let channel = self.log.open_channel("connect", peer);
+        Ok(Box::new(LoggingStream {
+            inner,
+            log: self.log.clone(),
+            channel,
+        }))
+    }
+}
+
+struct LoggingListener {
+    inner: Box<dyn Listener>,
+    log: TrafficLog,
+}
+
+impl LoggingListener {
+    fn log_accepted(&self, inner: Box<dyn Stream>) -> Box<dyn Stream> {
+        let channel = self.log.open_channel("accept", inner.peer_addr());
+        Box::new(LoggingStream {
+            inner,
+            log: self.log.clone(),
+            channel,
+        })
+    }
+}
+
+impl Listener for LoggingListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        let inner = self.inner.accept()?;
+        Ok(self.log_accepted(inner))
+    }
+
+    fn accept_timeout(&mut self, timeout: Duration) -> io::Result<Option<Box<dyn Stream>>> {
+        let inner = self.inner.accept_timeout(timeout)?;
+        Ok(inner.map(|inner| self.log_accepted(inner)))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+struct LoggingStream {
+    inner: Box<dyn Stream>,
+    log: TrafficLog,
+    channel: u32,
+}
+
+impl Read for LoggingStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        match n {
+            _ if buf.is_empty() => {}
+            0 => self.log.event(self.channel, format_args!("eof")),
+            _ => self.log.bytes(self.channel, '<', &buf[..n]),
+        }
+        Ok(n)
+    }
+}
+
+impl Write for LoggingStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            self.log.bytes(self.channel, '>', &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Stream for LoggingStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn has_pending(&self) -> bool {
+        self.inner.has_pending()
+    }
+
+    fn room(&self) -> Option<&[u8]> {
+        self.inner.room()
+    }
+
+    fn over_limit(&mut self) -> bool {
+        self.inner.over_limit()
+    }
+}
+
+impl Drop for LoggingStream {
+    fn drop(&mut self) {
+        self.log.event(self.channel, format_args!("close"));
+    }
+}
+
+/// A logged connection, waiting to be replayed
+struct Channel {
+    id: u32,
+    opened: Duration,
+    peer: Option<SocketAddr>,
+    /// Received bytes by time; an empty chunk is the peer's end of stream
+    inbound: VecDeque<(Duration, Vec<u8>)>,
+    outbound: Vec<u8>,
+}
+
+/// Replay time, starting when the [`ReplayNetwork`] was created
+#[derive(Clone, Copy)]
+struct Clock {
+    start: Instant,
+    fast: bool,
+}
+
+impl Clock {
+    fn wait_until(&self, at: Duration) {
+        if !self.fast {
+            thread::sleep(at.saturating_sub(self.start.elapsed()));
+        }
+    }
+}
+
+/// A [`Network`] that plays back connections from a [`TrafficLog`] file
+pub struct ReplayNetwork {
+    accepts: Rc<RefCell<VecDeque<Channel>>>,
+    connects: VecDeque<Channel>,
+    clock: Clock,
+}
+
+impl ReplayNetwork {
+    /// Replay the log at `path`, keeping its timing unless `fast`
+    pub fn open(path: impl AsRef<Path>, fast: bool) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?, fast)
+    }
+
+    /// Replay the log in `log`, keeping its timing unless `fast`
+    pub fn parse(log: &str, fast: bool) -> io::Result<Self> {
+        let mut channels: Vec<(bool, Channel)> = Vec::new();
+        for (number, line) in log.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |what: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, what),
+                )
+            };
+            let mut fields = line.split_whitespace();
+            let (Some(time), Some(id), Some(event)) = (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid("expected time, channel and event"));
+            };
+            let time = time
+                .parse()
+                .ok()
+                .and_then(|t| Duration::try_from_secs_f64(t).ok())
+                .ok_or_else(|| invalid("bad time"))?;
+            let id: u32 = id.parse().map_err(|_| invalid("bad channel"))?;
+
+            if let "accept" | "connect" = event {
+                let peer = fields.next().and_then(|p| p.parse().ok());
+                let channel = Channel {
+                    id,
+                    opened: time,
+                    peer,
+                    inbound: VecDeque::new(),
+                    outbound: Vec::new(),
+                };
+                channels.push((event == "accept", channel));
+                continue;
+            }
+            let Some((_, channel)) = channels.iter_mut().rev().find(|(_, c)| c.id == id) else {
+                return Err(invalid("event on a channel that was never opened"));
+            };
+            match event {
+                "<" | ">" => {
+                    let hex = fields.next().ok_or_else(|| invalid("missing bytes"))?;
+                    let bytes = decode_hex(hex).ok_or_else(|| invalid("bad hex bytes"))?;
+                    match event {
+                        "<" => channel.inbound.push_back((time, bytes)),
+                        _ => channel.outbound.extend(bytes),
+                    }
+                }
+                "eof" => channel.inbound.push_back((time, Vec::new())),
+                "close" => {}
+                _ => return Err(invalid("unknown event")),
+            }
+        }
+
+        let (accepts, connects) = channels.into_iter().partition(|(accept, _)| *accept);
+        let strip = |channels: Vec<(bool, Channel)>| channels.into_iter().map(|(_, c)| c).collect();
+        Ok(ReplayNetwork {
+            accepts: Rc::new(RefCell::new(strip(accepts))),
+            connects: strip(connects),
+            clock: Clock {
+                start: Instant::now(),
+                fast,
+            },
+        })
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn replayed(channel: Option<Channel>, clock: Clock, kind: &str) -> io::Result<Box<dyn Stream>> {
+    let channel = channel.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no more logged {} connections to replay", kind),
+        )
+    })?;
+    clock.wait_until(channel.opened);
+    Ok(Box::new(ReplayStream {
+        channel,
+        clock,
+        pending: Vec::new(),
+        sent: 0,
+        diverged: false,
+    }))
+}
+
+impl Network for ReplayNetwork {
+    fn listen(&mut self, _addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(ReplayListener {
+            accepts: self.accepts.clone(),
+            clock: self.clock,
+        }))
+    }
+
+    fn connect(&mut self, _addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        replayed(self.connects.pop_front(), self.clock, "outgoing")
+    }
+}
+
+struct ReplayListener {
+    accepts: Rc<RefCell<VecDeque<Channel>>>,
+    clock: Clock,
+}
+
+impl Listener for ReplayListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        let channel = self.accepts.borrow_mut().pop_front();
+        replayed(channel, self.clock, "incoming")
+    }
+}
+
+struct ReplayStream {
+    channel: Channel,
+    clock: Clock,
+    /// Rest of a chunk the program hasn't read yet
+    pending: Vec<u8>,
+    /// Bytes the program has sent so far
+    sent: usize,
+    diverged: bool,
+}
+
+impl Read for ReplayStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            // A log that ends without `eof` ends the stream all the same
+            let Some((at, chunk)) = self.channel.inbound.pop_front() else {
+                return Ok(0);
+            };
+            self.clock.wait_until(at);
+            if chunk.is_empty() {
+                self.channel.inbound.clear();
+                return Ok(0);
+            }
+            self.pending = chunk;
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for ReplayStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.diverged {
+            let logged = self.channel.outbound.get(self.sent..).unwrap_or_default();
+            if let Some(i) = (0..buf.len()).find(|&i| logged.get(i) != Some(&buf[i])) {
+                eprintln!(
+                    "[trainfuck] Replay diverged on channel {} at byte {}: sent {:#04x}, logged {}",
+                    self.channel.id,
+                    self.sent + i,
+                    buf[i],
+                    logged
+                        .get(i)
+                        .map_or("nothing more".to_string(), |b| format!("{:#04x}", b)),
+                );
+                self.diverged = true;
+            }
+        }
+        self.sent += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for ReplayStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.channel.peer
+    }
+}
@@ -0,0 +1,183 @@
+//! `--transport`: what the networking ops talk to
+
+use std::str::FromStr;
+use trainfuck::auth::AuthNetwork;
+use trainfuck::flood::{FloodLimits, FloodNetwork};
+use trainfuck::net::{Network, TcpNetwork};
+use trainfuck::virtual_net::VirtualNetwork;
+
+/// Backend behind `$`, `@`, `%`, `` ` `` and `'`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// TCP sockets at the addresses on the tape
+    Tcp,
+    /// Topics on an MQTT broker (`mqtt` feature)
+    Mqtt,
+    /// A serial device (`serial` feature)
+    Serial { path: String, baud_rate: u32 },
+    /// An in-process network only the program itself can reach, see
+    /// [`VirtualNetwork`]
+    Virtual,
+}
+
+/// Baud rate when `serial:PATH` doesn't give one
+const DEFAULT_BAUD_RATE: u32 = 9600;
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if let Some(device) = s.strip_prefix("serial:") {
+            let (path, baud_rate) = match device.rsplit_once(':') {
+                Some((path, baud)) => match baud.parse() {
+                    Ok(baud_rate) => (path, baud_rate),
+                    Err(_) => return Err(format!("bad baud rate {:?}", baud)),
+                },
+                None => (device, DEFAULT_BAUD_RATE),
+            };
+            if path.is_empty() {
+                return Err("expected serial:PATH or serial:PATH:BAUD".to_string());
+            }
+            let path = path.to_string();
+            return Ok(Transport::Serial { path, baud_rate });
+        }
+        match s {
+            "tcp" => Ok(Transport::Tcp),
+            "mqtt" => Ok(Transport::Mqtt),
+            "virtual" => Ok(Transport::Virtual),
+            _ => Err(format!(
+                "unknown transport {:?} (expected tcp, mqtt, serial:PATH:BAUD or virtual)",
+                s
+            )),
+        }
+    }
+}
+
+/// `--compress`: codec for every connection's byte stream
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+/// `--flood-action`: what happens to data over the flood limits
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloodAction {
+    /// Discard it (whole lines over `--flood-messages`)
+    Drop,
+    /// Read it only once the client is back within its limits
+    Throttle,
+    /// Close the client's connection
+    Disconnect,
+}
+
+impl From<FloodAction> for trainfuck::flood::FloodAction {
+    fn from(action: FloodAction) -> Self {
+        match action {
+            FloodAction::Drop => Self::Drop,
+            FloodAction::Throttle => Self::Throttle,
+            FloodAction::Disconnect => Self::Disconnect,
+        }
+    }
+}
+
+/// Transport settings from the command line
+pub struct Options<'a> {
+    pub transport: &'a Transport,
+    pub compress: Option<Compression>,
+    /// Token connections must start with, see [`AuthNetwork`]
+    pub auth_token: Option<&'a str>,
+    /// MQTT broker as host or host:port
+    pub broker: &'a str,
+    /// MQTT input and output topics
+    pub topics: Option<&'a (String, String)>,
+    /// Rate limits for accepted connections, see [`FloodNetwork`]
+    pub flood: Option<FloodLimits>,
+}
+
+/// Parse `--topic IN,OUT`
+pub fn parse_topics(s: &str) -> Result<(String, String), String> {
+    match s.split_once(',') {
+        Some((input, output)) if !input.is_empty() && !output.is_empty() => {
+            Ok((input.to_string(), output.to_string()))
+        }
+        _ => Err("expected an input and an output topic, as IN,OUT".to_string()),
+    }
+}
+
+/// The network for `options`, or why there can't be one
+pub fn network(options: &Options) -> Result<Box<dyn Network>, String> {
+    let network: Box<dyn Network> = match options.transport {
+        Transport::Tcp => Box::new(TcpNetwork),
+        Transport::Mqtt => {
+            let (input, output) = options
+                .topics
+                .ok_or("--transport mqtt needs --topic IN,OUT")?;
+            let (host, port) = match options.broker.rsplit_once(':') {
+                Some((host, port)) => (
+                    host,
+                    port.parse()
+                        .map_err(|_| format!("bad broker port in {:?}", options.broker))?,
+                ),
+                None => (options.broker, 1883),
+            };
+            mqtt(host, port, input, output)?
+        }
+        Transport::Serial { path, baud_rate } => serial(path, *baud_rate)?,
+        Transport::Virtual => Box::new(VirtualNetwork::new()),
+    };
+    // Checked before decompressing, so unauthenticated peers never reach
+    // the codec
+    let network: Box<dyn Network> = match options.auth_token {
+        Some(token) => Box::new(AuthNetwork::new(network, token.as_bytes())),
+        None => network,
+    };
+    let network = match options.compress {
+        Some(codec) => compress(network, codec)?,
+        None => network,
+    };
+    // Counts the bytes and lines the program would see
+    Ok(match options.flood {
+        Some(limits) => Box::new(FloodNetwork::new(network, limits)),
+        None => network,
+    })
+}
+
+#[cfg(feature = "mqtt")]
+fn mqtt(host: &str, port: u16, input: &str, output: &str) -> Result<Box<dyn Network>, String> {
+    Ok(Box::new(trainfuck::mqtt::MqttNetwork::new(
+        host, port, input, output,
+    )))
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn mqtt(_host: &str, _port: u16, _input: &str, _output: &str) -> Result<Box<dyn Network>, String> {
+    Err("--transport mqtt requires building with the `mqtt` feature".to_string())
+}
+
+#[cfg(feature = "serial")]
+fn serial(path: &str, baud_rate: u32) -> Result<Box<dyn Network>, String> {
+    Ok(Box::new(trainfuck::serial::SerialNetwork::new(
+        path, baud_rate,
+    )))
+}
+
+#[cfg(not(feature = "serial"))]
+fn serial(_path: &str, _baud_rate: u32) -> Result<Box<dyn Network>, String> {
+    Err("--transport serial requires building with the `serial` feature".to_string())
+}
+
+#[cfg(feature = "compress")]
+fn compress(network: Box<dyn Network>, codec: Compression) -> Result<Box<dyn Network>, String> {
+    use trainfuck::compress::{Codec, CompressedNetwork};
+    let codec = match codec {
+        Compression::Gzip => Codec::Gzip,
+        Compression::Zstd => Codec::Zstd,
+    };
+    Ok(Box::new(CompressedNetwork::new(network, codec)))
+}
+
+#[cfg(not(feature = "compress"))]
+fn compress(_network: Box<dyn Network>, _codec: Compression) -> Result<Box<dyn Network>, String> {
+    Err("--compress requires building with the `compress` feature".to_string())
+}
@@ -0,0 +1,100 @@
+//! Host callbacks (`--ext trap`)
+//!
+//! With [`Extensions::trap`](crate::interpreter::Extensions::trap), `!`
+//! calls the function the embedder installed with [`VM::on_trap`], like a
+//! system call: the current cell holds the call number, and the handler
+//! reads its arguments from the cells after the pointer and writes its
+//! results back through a [`Trap`], which gives it the tape and nothing
+//! else of the VM. That way a host can offer programs a database lookup
+//! or a timer without handing them the whole embedding API.
+//!
+//! ```
+//! use trainfuck::interpreter::{Extensions, VM};
+//! use trainfuck::program::Program;
+//!
+//! let extensions = Extensions { trap: true, ..Extensions::default() };
+//! // Call 7 with 20 as its argument
+//! let program = Program::parse(b"+++++++>++++++++++++++++++++<!>.", extensions)?;
+//! let mut vm = VM::with_io(std::io::empty(), std::io::sink());
+//! vm.on_trap(|trap| match trap.number() {
+//!     7 => {
+//!         let doubled = trap.cell(1).wrapping_mul(2);
+//!         trap.set_cell(1, doubled);
+//!         Ok(())
+//!     }
+//!     n => Err(format!("no call {}", n)),
+//! });
+//! assert_eq!(vm.run_collect(&program).output, [40]);
+//! # Ok::<(), trainfuck::interpreter::TrainfuckError>(())
+//! ```
+//!
+//! A handler's error stops the program with [`TrainfuckError::TrapFailed`],
+//! and `!` without a handler stops it with
+//! [`TrainfuckError::NoTrapHandler`]. `!` is `xor`'s command too; with both
+//! extensions on, it traps.
+
+use crate::interpreter::{Result, TrainfuckError, VM};
+
+/// The function `!` calls, returning why the call failed if it did
+pub type TrapHandler = Box<dyn FnMut(&mut Trap) -> std::result::Result<(), String>>;
+
+/// The tape as a trap handler sees it
+pub struct Trap<'a> {
+    vm: &'a mut VM,
+}
+
+impl Trap<'_> {
+    /// The call number: the cell `!` ran on
+    pub fn number(&self) -> u8 {
+        self.vm.tape[self.vm.pointer]
+    }
+
+    /// The cell `offset` cells after the pointer, wrapping around the tape
+    pub fn cell(&self, offset: usize) -> u8 {
+        self.vm.tape[self.index(offset)]
+    }
+
+    pub fn set_cell(&mut self, offset: usize, value: u8) {
+        let index = self.index(offset);
+        self.vm.tape[index] = value;
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.vm.pointer
+    }
+
+    pub fn tape(&self) -> &[u8] {
+        &self.vm.tape
+    }
+
+    /// Writes to protected cells stop the program once the handler
+    /// returns, as they would from any op
+    pub fn tape_mut(&mut self) -> &mut [u8] {
+        &mut self.vm.tape
+    }
+
+    fn index(&self, offset: usize) -> usize {
+        (self.vm.pointer + offset) % self.vm.tape.len()
+    }
+}
+
+impl VM {
+    /// Call `handler` for every `!` under `--ext trap`
+    pub fn on_trap(
+        &mut self,
+        handler: impl FnMut(&mut Trap) -> std::result::Result<(), String> + 'static,
+    ) {
+        self.trap_handler = Some(Box::new(handler));
+    }
+
+    /// `!`: call the trap handler
+    pub(crate) fn trap(&mut self) -> Result<()> {
+        let Some(mut handler) = self.trap_handler.take() else {
+            return Err(TrainfuckError::NoTrapHandler);
+        };
+        let number = self.tape[self.pointer];
+        let result = handler(&mut Trap { vm: self });
+        self.trap_handler = Some(handler);
+        result.map_err(|e| TrainfuckError::TrapFailed(number, e))
+    }
+}
@@ -0,0 +1,228 @@
+//! An in-process network for VMs on different threads
+//!
+//! Every clone of a [`VirtualNetwork`] shares one virtual host: a program
+//! listening with `$` on a port can be reached by `%` from any other VM on
+//! the same network, whatever IPv4 address either side wrote on its tape.
+//! Connections are pairs of in-memory pipes, so nothing is opened on the
+//! real network and a whole chat deployment can run in one process.
+//!
+//! Dialing a port nobody listens on is refused, as on a real host. Reads
+//! block until the peer writes or hangs up; writes to a peer that hung up
+//! fail with `BrokenPipe`. Listening on port 0 picks a free port from
+//! 49152 up, as on a real host.
+
+use crate::net::{Listener, Network, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// First port handed to the dialing end of a connection
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+#[derive(Default)]
+struct Host {
+    /// Where each listening port hands new connections
+    listeners: HashMap<u16, Sender<VirtualStream>>,
+    next_port: u16,
+}
+
+impl Host {
+    /// Hand out the next ephemeral port nobody listens on
+    fn ephemeral_port(&mut self) -> u16 {
+        loop {
+            let port = self.next_port.max(FIRST_EPHEMERAL_PORT);
+            self.next_port = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+            if !self.listeners.contains_key(&port) {
+                return port;
+            }
+        }
+    }
+}
+
+/// A shared virtual host; clone it for each VM that should be on it
+#[derive(Clone, Default)]
+pub struct VirtualNetwork {
+    host: Arc<Mutex<Host>>,
+}
+
+impl VirtualNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Network for VirtualNetwork {
+    fn listen(&mut self, mut addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        let mut host = self.host.lock().unwrap();
+        if addr.port() == 0 {
+            addr.set_port(host.ephemeral_port());
+        }
+        if host.listeners.contains_key(&addr.port()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("port {} is already in use", addr.port()),
+            ));
+        }
+        let (tx, rx) = mpsc::channel();
+        host.listeners.insert(addr.port(), tx);
+        Ok(Box::new(VirtualListener {
+            host: self.host.clone(),
+            addr,
+            incoming: rx,
+        }))
+    }
+
+    fn connect(&mut self, addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        let mut host = self.host.lock().unwrap();
+        let refused = || {
+            io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("nothing is listening on port {}", addr.port()),
+            )
+        };
+        let listener = host
+            .listeners
+            .get(&addr.port())
+            .ok_or_else(refused)?
+            .clone();
+        let local = SocketAddrV4::new(Ipv4Addr::LOCALHOST, host.ephemeral_port());
+
+        let (to_server, to_client) = (Pipe::default(), Pipe::default());
+        let client = VirtualStream {
+            incoming: to_client.clone(),
+            outgoing: to_server.clone(),
+            peer: addr,
+        };
+        let server = VirtualStream {
+            incoming: to_server,
+            outgoing: to_client,
+            peer: local,
+        };
+        // The listener may have been dropped since the lookup
+        listener.send(server).map_err(|_| refused())?;
+        Ok(Box::new(client))
+    }
+}
+
+struct VirtualListener {
+    host: Arc<Mutex<Host>>,
+    addr: SocketAddr,
+    incoming: Receiver<VirtualStream>,
+}
+
+impl Listener for VirtualListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        match self.incoming.recv() {
+            Ok(stream) => Ok(Box::new(stream)),
+            Err(_) => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn accept_timeout(&mut self, timeout: Duration) -> io::Result<Option<Box<dyn Stream>>> {
+        match self.incoming.recv_timeout(timeout) {
+            Ok(stream) => Ok(Some(Box::new(stream))),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.addr)
+    }
+}
+
+impl Drop for VirtualListener {
+    fn drop(&mut self) {
+        self.host
+            .lock()
+            .unwrap()
+            .listeners
+            .remove(&self.addr.port());
+    }
+}
+
+/// Bytes in flight in one direction
+#[derive(Default)]
+struct Buffer {
+    bytes: VecDeque<u8>,
+    /// Either end hung up
+    closed: bool,
+}
+
+#[derive(Clone, Default)]
+struct Pipe(Arc<(Mutex<Buffer>, Condvar)>);
+
+impl Pipe {
+    fn close(&self) {
+        let (buffer, ready) = &*self.0;
+        buffer.lock().unwrap().closed = true;
+        ready.notify_all();
+    }
+}
+
+/// One end of a virtual connection
+struct VirtualStream {
+    incoming: Pipe,
+    outgoing: Pipe,
+    peer: SocketAddrV4,
+}
+
+impl Read for VirtualStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (buffer, ready) = &*self.incoming.0;
+        let mut buffer = buffer.lock().unwrap();
+        while buffer.bytes.is_empty() && !buffer.closed {
+            buffer = ready.wait(buffer).unwrap();
+        }
+        let n = buf.len().min(buffer.bytes.len());
+        for (slot, byte) in buf.iter_mut().zip(buffer.bytes.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for VirtualStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (buffer, ready) = &*self.outgoing.0;
+        let mut buffer = buffer.lock().unwrap();
+        if buffer.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        buffer.bytes.extend(buf);
+        ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for VirtualStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::V4(self.peer))
+    }
+
+    fn shutdown(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+
+    fn has_pending(&self) -> bool {
+        let buffer = self.incoming.0 .0.lock().unwrap();
+        !buffer.bytes.is_empty() || buffer.closed
+    }
+}
+
+impl Drop for VirtualStream {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
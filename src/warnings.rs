@@ -0,0 +1,211 @@
+//! Warnings (`trainfuck check`, `-W`, `-A`, `-D`)
+//!
+//! A lint flags code that is valid but probably doesn't do what was meant.
+//! Every lint warns unless its [`Level`] is changed:
+//!
+//! | Lint              | Flags                                                       |
+//! | ----------------- | ----------------------------------------------------------- |
+//! | `wrapping-run`    | 256 or more `+` or `-` in a row, which wrap around to fewer |
+//! | `unreachable`     | code after a loop that never terminates                     |
+//! |                   | a loop right after a loop, whose cell is then always zero   |
+//! | `input-after-eof` | `,` reading on after the input has ended                    |
+//!
+//! [`check`] finds the first two in a parsed program. Whether the input
+//! runs out is only known while running, so for the last the VM counts the
+//! reads that found it ended, in [`VM::eof_reads`](crate::interpreter::VM::eof_reads).
+//!
+//! ```
+//! use trainfuck::interpreter::parse;
+//! use trainfuck::warnings::{self, Levels, Lint};
+//!
+//! let program = parse(&format!("{}.", "+".repeat(300)))?;
+//! let found = warnings::check(program.ops(), program.spans(), 30_000, &Levels::default());
+//! assert_eq!(found[0].lint, Lint::WrappingRun);
+//! assert_eq!(found[0].message, "300 `+` in a row add 44, since cells wrap at 256");
+//! # Ok::<(), trainfuck::interpreter::TrainfuckError>(())
+//! ```
+
+use crate::analyzer;
+use crate::interpreter::Op;
+use crate::span::{SourceMap, Span};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::iter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Lint {
+    WrappingRun,
+    Unreachable,
+    InputAfterEof,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 3] = [Lint::WrappingRun, Lint::Unreachable, Lint::InputAfterEof];
+
+    /// What `-W` and the messages call it
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::WrappingRun => "wrapping-run",
+            Lint::Unreachable => "unreachable",
+            Lint::InputAfterEof => "input-after-eof",
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What a lint does when it finds something
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Nothing
+    Allow,
+    /// Report it and carry on
+    #[default]
+    Warn,
+    /// Report it as an error
+    Deny,
+}
+
+/// The level of every lint
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Levels([Level; Lint::ALL.len()]);
+
+impl Levels {
+    pub fn get(&self, lint: Lint) -> Level {
+        self.0[lint as usize]
+    }
+
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        self.0[lint as usize] = level;
+    }
+}
+
+/// Something a lint found, and where
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Warning {
+    pub lint: Lint,
+    pub span: Span,
+    pub message: String,
+}
+
+/// Lint `ops` (unoptimized, as parsed) for a tape of `tape_size` cells,
+/// leaving out the lints `levels` allows. Warnings come in source order.
+pub fn check(ops: &[Op], spans: &SourceMap, tape_size: usize, levels: &Levels) -> Vec<Warning> {
+    // Finding endless loops takes the analyzer, so skip it when unneeded
+    let endless = match levels.get(Lint::Unreachable) {
+        Level::Allow => Vec::new(),
+        _ => analyzer::endless_loops(ops, spans, tape_size),
+    };
+    let mut linter = Linter {
+        spans: spans
+            .spans()
+            .iter()
+            .copied()
+            .chain(iter::repeat(Span::default())),
+        endless,
+        warnings: Vec::new(),
+    };
+    linter.block(ops);
+    let mut warnings = linter.warnings;
+    warnings.retain(|warning| levels.get(warning.lint) != Level::Allow);
+    warnings.sort_by_key(|warning| warning.span.start);
+    warnings
+}
+
+struct Linter<I> {
+    spans: I,
+    endless: Vec<Span>,
+    warnings: Vec<Warning>,
+}
+
+impl<I: Iterator<Item = Span>> Linter<I> {
+    fn warn(&mut self, lint: Lint, span: Span, message: String) {
+        self.warnings.push(Warning {
+            lint,
+            span,
+            message,
+        });
+    }
+
+    fn block(&mut self, ops: &[Op]) {
+        // Command, count and span of the `+` or `-` run so far, which the
+        // parser splits wherever a comment interrupts it
+        let mut run: Option<(char, usize, Span)> = None;
+        // Span of the code after an endless loop
+        let mut dead: Option<Span> = None;
+        let mut endless = false;
+        for (i, op) in ops.iter().enumerate() {
+            let span = self.spans.next().unwrap_or_default();
+            if endless {
+                dead = Some(dead.map_or(span, |dead| dead.to(span)));
+            }
+
+            let command = match op {
+                Op::Increment(_) => Some('+'),
+                Op::Decrement(_) => Some('-'),
+                _ => None,
+            };
+            match (&mut run, command) {
+                (Some((previous, count, whole)), Some(command)) if *previous == command => {
+                    *count += span.end - span.start;
+                    *whole = whole.to(span);
+                }
+                _ => {
+                    self.wrapping_run(run.take());
+                    run = command.map(|command| (command, span.end - span.start, span));
+                }
+            }
+
+            let Op::Loop(body) = op else {
+                continue;
+            };
+            if i > 0 && matches!(ops[i - 1], Op::Loop(_)) && !endless {
+                self.warn(
+                    Lint::Unreachable,
+                    span,
+                    "loop is never entered: the loop before it leaves the cell at zero".into(),
+                );
+            }
+            self.block(body);
+            endless |= self.endless.contains(&span);
+        }
+        self.wrapping_run(run);
+        if let Some(dead) = dead {
+            self.warn(
+                Lint::Unreachable,
+                dead,
+                "code after a loop that never terminates never runs".into(),
+            );
+        }
+    }
+
+    fn wrapping_run(&mut self, run: Option<(char, usize, Span)>) {
+        let Some((command, count, span)) = run else {
+            return;
+        };
+        if count < 256 {
+            return;
+        }
+        let verb = match command {
+            '+' => "add",
+            _ => "subtract",
+        };
+        let amount = match count % 256 {
+            0 => "nothing".to_string(),
+            amount => amount.to_string(),
+        };
+        self.warn(
+            Lint::WrappingRun,
+            span,
+            format!(
+                "{} `{}` in a row {} {}, since cells wrap at 256",
+                count, command, verb, amount
+            ),
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Browser build (`wasm` feature)
+//!
+//! `WasmVm` runs a program in slices so the page stays responsive:
+//!
+//! ```js
+//! const vm = new WasmVm(source, {
+//!   output: (bytes) => term.write(bytes),
+//!   input: () => keys.shift() ?? null,     // null: nothing typed yet (reads 0)
+//! }, {
+//!   connect: (host, port) => openSocket(`ws://${host}:${port}`),
+//!   listen: (host, port) => registerWithRelay(host, port),
+//!   accept: () => relayHasPeer(),           // false: `@` is retried later
+//!   send: (bytes) => socket.send(bytes),
+//!   close: () => socket.close(),
+//! });
+//! socket.onmessage = (e) => vm.deliver(new Uint8Array(e.data));
+//! socket.onclose = () => vm.disconnected();
+//! const tick = () => { if (!vm.run(10000)) requestAnimationFrame(tick); };
+//! tick();
+//! ```
+//!
+//! Ops that would block (`` ` `` with no data, `@` with no peer) are retried
+//! on the next `run` call instead of stalling the browser.
+
+use crate::interpreter::{self, TrainfuckError, VM};
+use crate::net::{Listener, Network, Stream};
+use crate::program::Program;
+use js_sys::{Function, Reflect, Uint8Array};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+pub struct WasmVm {
+    vm: VM,
+    ops: Program,
+    inbound: Rc<RefCell<Inbound>>,
+}
+
+#[wasm_bindgen]
+impl WasmVm {
+    /// `io` needs `output(bytes)` and `input()`; `net` is optional
+    #[wasm_bindgen(constructor)]
+    pub fn new(source: &str, io: JsValue, net: JsValue) -> Result<WasmVm, JsValue> {
+        let ops = interpreter::parse(source).map_err(|e| js_error(&e))?;
+        let inbound = Rc::new(RefCell::new(Inbound::default()));
+
+        let mut vm = VM::new();
+        vm.output = Box::new(JsOutput(method(&io, "output")?));
+        // Capacity 1 so bytes are pulled from JS only when `,` runs
+        vm.input = Box::new(BufReader::with_capacity(1, JsInput(method(&io, "input")?)));
+        if net.is_object() {
+            vm.network = Box::new(WebSocketNetwork {
+                net,
+                inbound: inbound.clone(),
+            });
+        } else {
+            vm.network_enabled = false;
+        }
+
+        Ok(WasmVm { vm, ops, inbound })
+    }
+
+    /// Run up to `steps` steps; returns true once the program has finished
+    pub fn run(&mut self, steps: u32) -> Result<bool, JsValue> {
+        for _ in 0..steps {
+            match self.vm.step(&self.ops) {
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                // Give the event loop a chance to deliver data
+                Err(TrainfuckError::WouldBlock) => return Ok(false),
+                Err(e) => return Err(js_error(&e)),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Queue bytes received on the WebSocket for `` ` ``
+    pub fn deliver(&mut self, bytes: &[u8]) {
+        self.inbound.borrow_mut().bytes.extend(bytes);
+    }
+
+    /// The WebSocket closed; `` ` `` reads 0 once queued bytes are drained
+    pub fn disconnected(&mut self) {
+        self.inbound.borrow_mut().closed = true;
+    }
+
+    pub fn tape(&self) -> Vec<u8> {
+        self.vm.tape().to_vec()
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.vm.pointer()
+    }
+}
+
+fn js_error(e: &TrainfuckError) -> JsValue {
+    JsValue::from_str(&e.to_string())
+}
+
+fn method(object: &JsValue, name: &str) -> Result<Function, JsValue> {
+    Reflect::get(object, &JsValue::from_str(name))?
+        .dyn_into::<Function>()
+        .map_err(|_| JsValue::from_str(&format!("missing function {:?}", name)))
+}
+
+fn call(object: &JsValue, name: &str, args: &[JsValue]) -> io::Result<JsValue> {
+    let to_io = |e: JsValue| io::Error::other(e.as_string().unwrap_or_else(|| format!("{:?}", e)));
+    let function = method(object, name).map_err(to_io)?;
+    let args: js_sys::Array = args.iter().collect();
+    function.apply(object, &args).map_err(to_io)
+}
+
+struct JsOutput(Function);
+
+impl Write for JsOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .call1(&JsValue::NULL, &Uint8Array::from(buf))
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct JsInput(Function);
+
+impl Read for JsInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let value = self
+            .0
+            .call0(&JsValue::NULL)
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        match value.as_f64() {
+            Some(byte) if byte < 0.0 => Ok(0),
+            Some(byte) => {
+                buf[0] = byte as u8;
+                Ok(1)
+            }
+            // Same as --nonblocking-input: nothing typed yet reads as 0
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Inbound {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+/// Networking ops bridged to JS callbacks that drive a WebSocket
+struct WebSocketNetwork {
+    net: JsValue,
+    inbound: Rc<RefCell<Inbound>>,
+}
+
+impl WebSocketNetwork {
+    fn stream(&self) -> Box<dyn Stream> {
+        *self.inbound.borrow_mut() = Inbound::default();
+        Box::new(WebSocketStream {
+            net: self.net.clone(),
+            inbound: self.inbound.clone(),
+        })
+    }
+}
+
+impl Network for WebSocketNetwork {
+    fn listen(&mut self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        let args = [addr.ip().to_string().into(), addr.port().into()];
+        call(&self.net, "listen", &args)?;
+        Ok(Box::new(WebSocketListener {
+            net: WebSocketNetwork {
+                net: self.net.clone(),
+                inbound: self.inbound.clone(),
+            },
+        }))
+    }
+
+    fn connect(&mut self, addr: SocketAddrV4) -> io::Result<Box<dyn Stream>> {
+        let args = [addr.ip().to_string().into(), addr.port().into()];
+        call(&self.net, "connect", &args)?;
+        Ok(self.stream())
+    }
+}
+
+struct WebSocketListener {
+    net: WebSocketNetwork,
+}
+
+impl Listener for WebSocketListener {
+    fn accept(&mut self) -> io::Result<Box<dyn Stream>> {
+        if call(&self.net.net, "accept", &[])?.is_truthy() {
+            Ok(self.net.stream())
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+}
+
+struct WebSocketStream {
+    net: JsValue,
+    inbound: Rc<RefCell<Inbound>>,
+}
+
+impl Read for WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inbound = self.inbound.borrow_mut();
+        let n = buf.len().min(inbound.bytes.len());
+        for (slot, byte) in buf.iter_mut().zip(inbound.bytes.drain(..n)) {
+            *slot = byte;
+        }
+        match n {
+            0 if inbound.closed => Ok(0),
+            0 => Err(io::ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        }
+    }
+}
+
+impl Write for WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        call(&self.net, "send", &[Uint8Array::from(buf).into()])?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for WebSocketStream {
+    fn shutdown(&mut self) {
+        let _ = call(&self.net, "close", &[]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Property tests over generated op trees: printing and parsing them back
+//! gives the same ops, and running them, optimized or not, never panics.
+//! The no_std kernel compiles plain source to the VM's own bytecode.
+//! `fuzz/` has the matching cargo-fuzz targets for arbitrary bytes.
+
+use proptest::prelude::*;
+use std::io;
+use trainfuck::bytecode::Bytecode;
+use trainfuck::features;
+use trainfuck::interpreter::{self, Extensions, Op, TrainfuckError, VM};
+use trainfuck::kernel;
+use trainfuck::mock_net::MockNetwork;
+use trainfuck::pipeline::Pipeline;
+use trainfuck::program::{Metadata, Program};
+use trainfuck::span::SourceMap;
+
+/// Steps each run may take, enough for hot loops to get tier-compiled
+const MAX_STEPS: u64 = 20_000;
+
+/// Any set of extensions
+fn extensions() -> impl Strategy<Value = Extensions> {
+    proptest::collection::vec(any::<bool>(), 16).prop_map(|on| Extensions {
+        shared: on[0],
+        actors: on[1],
+        yielding: on[2],
+        kv: on[3],
+        http: on[4],
+        checksum: on[5],
+        base64: on[6],
+        xor: on[7],
+        peek: on[8],
+        line: on[9],
+        dns: on[10],
+        channels: on[11],
+        backpressure: on[12],
+        blocks: on[13],
+        trap: on[14],
+        features: on[15],
+    })
+}
+
+/// Extensions that run without threads, real sockets or a host's stores
+fn local_extensions() -> impl Strategy<Value = Extensions> {
+    extensions().prop_map(|extensions| Extensions {
+        shared: false,
+        actors: false,
+        kv: false,
+        http: false,
+        dns: false,
+        ..extensions
+    })
+}
+
+/// The single-character ops `extensions` parse
+fn commands(extensions: &Extensions) -> Vec<Op> {
+    let mut ops = vec![Op::Output, Op::Input, Op::Connect, Op::Listen, Op::Accept];
+    match extensions.blocks {
+        true => ops.extend([Op::ReceiveBlock, Op::SendBlock]),
+        false => ops.extend([Op::Receive, Op::Send]),
+    }
+    let mut add = |on: bool, more: &[Op]| {
+        if on {
+            ops.extend_from_slice(more);
+        }
+    };
+    add(extensions.shared, &[Op::CompareSwap]);
+    add(extensions.actors, &[Op::Spawn, Op::SendMessage]);
+    add(
+        extensions.features,
+        &[Op::Features(features::mask(extensions))],
+    );
+    add(
+        extensions.actors && !extensions.features,
+        &[Op::ReceiveMessage],
+    );
+    add(extensions.yielding, &[Op::Yield]);
+    add(extensions.kv, &[Op::KvPut, Op::KvGet]);
+    add(extensions.http, &[Op::Http]);
+    add(extensions.checksum, &[Op::Checksum]);
+    add(extensions.base64, &[Op::Base64Encode, Op::Base64Decode]);
+    add(extensions.trap, &[Op::Trap]);
+    add(extensions.xor && !extensions.trap, &[Op::Xor]);
+    add(extensions.peek, &[Op::Peek, Op::Unread]);
+    add(extensions.line, &[Op::ReceiveLine]);
+    add(extensions.dns, &[Op::Resolve]);
+    add(extensions.channels, &[Op::SelectChannel, Op::Channels]);
+    add(extensions.backpressure, &[Op::Flush]);
+    ops
+}
+
+/// Op trees as the parser builds them with `extensions`
+fn ops(extensions: Extensions) -> impl Strategy<Value = Vec<Op>> {
+    let leaf = prop_oneof![
+        (1..=255u8).prop_map(Op::Increment),
+        (1..=255u8).prop_map(Op::Decrement),
+        (1..=40usize).prop_map(Op::MoveRight),
+        (1..=40usize).prop_map(Op::MoveLeft),
+        proptest::sample::select(commands(&extensions)),
+    ];
+    let op = leaf.prop_recursive(6, 64, 8, |inner| {
+        proptest::collection::vec(inner, 0..8).prop_map(|body| Op::Loop(canonical(body)))
+    });
+    proptest::collection::vec(op, 0..24).prop_map(canonical)
+}
+
+/// `ops` with neighbouring runs of the same command merged, as the parser
+/// reads them, and those that wrapped to nothing dropped
+fn canonical(ops: Vec<Op>) -> Vec<Op> {
+    let mut merged: Vec<Op> = Vec::with_capacity(ops.len());
+    for op in ops {
+        let last = merged.last_mut();
+        let combined = match (last, &op) {
+            (Some(Op::Increment(a)), Op::Increment(b)) => Some(a.wrapping_add(*b) as usize),
+            (Some(Op::Decrement(a)), Op::Decrement(b)) => Some(a.wrapping_add(*b) as usize),
+            (Some(Op::MoveRight(a)), Op::MoveRight(b)) => Some(*a + b),
+            (Some(Op::MoveLeft(a)), Op::MoveLeft(b)) => Some(*a + b),
+            _ => None,
+        };
+        match (combined, merged.last_mut()) {
+            (Some(0), _) => {
+                merged.pop();
+            }
+            (Some(n), Some(Op::Increment(a) | Op::Decrement(a))) => *a = n as u8,
+            (Some(n), Some(Op::MoveRight(a) | Op::MoveLeft(a))) => *a = n,
+            _ => merged.push(op),
+        }
+    }
+    merged
+}
+
+/// Plain Trainfuck source, with the odd `#!` line, comment and stray byte
+fn source() -> impl Strategy<Value = Vec<u8>> {
+    let bytes = proptest::sample::select(b"+-<>.,[]$@%`' #!;\na".to_vec());
+    proptest::collection::vec(bytes, 0..64)
+}
+
+/// A VM on a tape of `tape_size` cells reading `input` and, from every
+/// connection, `script`
+fn vm(tape_size: usize, input: Vec<u8>, script: Vec<u8>) -> VM {
+    let mut vm = VM::with_tape_size(tape_size);
+    vm.input = Box::new(io::Cursor::new(input));
+    vm.output = Box::new(io::sink());
+    vm.network = Box::new(MockNetwork::new(script));
+    vm.max_steps = Some(MAX_STEPS);
+    vm
+}
+
+proptest! {
+    #[test]
+    fn printed_ops_parse_back(
+        (extensions, ops) in extensions().prop_flat_map(|extensions| (Just(extensions), ops(extensions)))
+    ) {
+        let source = interpreter::to_source(&ops).expect("parsed ops have source");
+        let program = Program::parse(&source, extensions).unwrap();
+        prop_assert_eq!(program.ops(), &ops[..], "source: {}", String::from_utf8_lossy(&source));
+    }
+
+    #[test]
+    fn ops_run_without_panicking(
+        (extensions, ops) in local_extensions().prop_flat_map(|extensions| (Just(extensions), ops(extensions))),
+        tape_size in 1..64usize,
+        level in 0..=3u8,
+        input in proptest::collection::vec(any::<u8>(), 0..16),
+        script in proptest::collection::vec(any::<u8>(), 0..16),
+    ) {
+        // The tree executor on the ops as they are
+        let _ = vm(tape_size, input.clone(), script.clone()).execute(&ops);
+
+        // Bytecode, with hot loops tier-compiled, on the optimized ops
+        let spans = SourceMap::default();
+        let (optimized, spans) = Pipeline::level(level, tape_size).run(ops, &spans);
+        let metadata = Metadata { extensions, ..Metadata::default() };
+        let program = Program::new(optimized, spans, metadata);
+        let _ = vm(tape_size, input, script).execute_program(&program);
+    }
+
+    #[test]
+    fn kernel_compiles_like_the_vm(source in source()) {
+        let compiled = kernel::compile(&source);
+        match Program::parse(&source, Extensions::default()) {
+            Ok(program) => {
+                let bytecode = Bytecode::compile(program.ops());
+                let code = compiled.unwrap();
+                prop_assert_eq!(code.as_bytes(), bytecode.code());
+            }
+            Err(TrainfuckError::UnmatchedOpenBracket(position)) => {
+                prop_assert_eq!(compiled, Err(kernel::Error::UnmatchedOpenBracket(position)));
+            }
+            Err(TrainfuckError::UnmatchedCloseBracket(position)) => {
+                prop_assert_eq!(compiled, Err(kernel::Error::UnmatchedCloseBracket(position)));
+            }
+            // A `#!` line the parser rejects as a directive and the kernel
+            // skips
+            Err(_) => {}
+        }
+    }
+}
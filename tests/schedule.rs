@@ -0,0 +1,35 @@
+//! `trainfuck schedule` gives each program the extensions its `#! ext`
+//! lines ask for, as `run` does, along with what they need to work.
+
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn schedule_opens_the_kv_store_for_ext_directives() {
+    let dir = std::env::temp_dir().join(format!("trainfuck-schedule-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // Store "v" under "k", clear the copy on the tape and read it back
+    let source = format!(
+        "#! ext kv\n{}>>{}<<{{>>[-]<<}}>>.",
+        "+".repeat(usize::from(b'k')),
+        "+".repeat(usize::from(b'v')),
+    );
+    let program = dir.join("kv.b");
+    fs::write(&program, source).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_trainfuck"))
+        .arg("schedule")
+        .arg("--kv-store")
+        .arg(dir.join("store.kv"))
+        .arg(&program)
+        .output()
+        .unwrap();
+    let _ = fs::remove_dir_all(&dir);
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(output.stdout, b"v");
+}